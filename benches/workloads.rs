@@ -0,0 +1,70 @@
+// Runs `cult bench` on the built-in workloads and compares each backend's
+// MIPS with the previous run, saved under the target directory. A drop of
+// more than CULT_BENCH_THRESHOLD percent (10 by default) fails the bench.
+// CULT_BENCH_COUNT sets the instructions per workload.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::Command;
+
+fn results(text: &str) -> HashMap<(String, String), f64> {
+    text.lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [workload, backend, _, _, mips] => Some((
+                    (workload.to_string(), backend.to_string()),
+                    mips.parse().ok()?,
+                )),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn main() {
+    let count = std::env::var("CULT_BENCH_COUNT").unwrap_or_else(|_| "20000000".to_string());
+    let threshold: f64 = std::env::var("CULT_BENCH_THRESHOLD")
+        .ok()
+        .and_then(|t| t.parse().ok())
+        .unwrap_or(10.0);
+    let output = Command::new(env!("CARGO_BIN_EXE_cult"))
+        .args(["bench", "-n", &count])
+        .output()
+        .expect("cult bench runs");
+    assert!(output.status.success(), "cult bench failed");
+    let text = String::from_utf8(output.stdout).unwrap();
+
+    let baseline_path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("bench-baseline.txt");
+    let baseline = std::fs::read_to_string(&baseline_path)
+        .map(|text| results(&text))
+        .unwrap_or_default();
+    let now = results(&text);
+    let mut regressed = false;
+    let mut lines = text.lines();
+    println!("{}  change", lines.next().unwrap_or_default());
+    for line in lines {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let key = (fields[0].to_string(), fields[1].to_string());
+        let change = match (now.get(&key), baseline.get(&key)) {
+            (Some(now), Some(before)) => {
+                let change = 100.0 * (now - before) / before;
+                if change < -threshold {
+                    regressed = true;
+                    format!("{:+.1}%  regressed", change)
+                } else {
+                    format!("{:+.1}%", change)
+                }
+            }
+            _ => "new".to_string(),
+        };
+        println!("{}  {}", line, change);
+    }
+    // Keep comparing against the old baseline until the regression is fixed
+    if regressed {
+        eprintln!("MIPS fell more than {}% since the last run", threshold);
+        std::process::exit(1);
+    }
+    std::fs::write(&baseline_path, &text).unwrap();
+}
@@ -0,0 +1,295 @@
+// Tokens of UM assembly. Comments run from ';' to the end of the line and
+// every newline is a token, since statements are line oriented.
+
+use super::{AsmError, Span};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Ident(String),
+    Directive(String),
+    Number(u64),
+    Str(String),
+    Comma,
+    Colon,
+    Plus,
+    Minus,
+    Newline,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lexeme {
+    pub token: Token,
+    pub span: Span,
+}
+
+struct Lexer {
+    chars: Vec<char>,
+    file: usize,
+    next: usize,
+    line: usize,
+    column: usize,
+}
+
+impl Lexer {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.next).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.next += 1;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn span_from(&self, line: usize, column: usize) -> Span {
+        let length = if self.line == line {
+            self.column - column
+        } else {
+            1
+        };
+        Span {
+            file: self.file,
+            line,
+            column,
+            length,
+        }
+    }
+
+    fn word(&mut self) -> String {
+        let mut word = String::new();
+        while let Some(c) = self.peek() {
+            if c.is_alphanumeric() || c == '_' || c == '.' {
+                word.push(c);
+                self.bump();
+            } else {
+                break;
+            }
+        }
+        word
+    }
+
+    // The character after a backslash, or a plain character
+    fn escaped(&mut self, line: usize, column: usize) -> Result<char, AsmError> {
+        let c = match self.bump() {
+            Some('\\') => match self.bump() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('r') => '\r',
+                Some('0') => '\0',
+                Some('\\') => '\\',
+                Some('\'') => '\'',
+                Some('"') => '"',
+                Some(other) => {
+                    return Err(AsmError::new(
+                        self.span_from(line, column),
+                        format!("unknown escape '\\{}'", other),
+                    ))
+                }
+                None => '\\',
+            },
+            Some('\n') | None => {
+                return Err(AsmError::new(
+                    self.span_from(line, column),
+                    "unterminated literal".to_string(),
+                ))
+            }
+            Some(c) => c,
+        };
+        Ok(c)
+    }
+
+    fn number(&mut self, line: usize, column: usize) -> Result<u64, AsmError> {
+        let text = self.word();
+        let (digits, radix) = if let Some(hex) = text.strip_prefix("0x") {
+            (hex, 16)
+        } else if let Some(binary) = text.strip_prefix("0b") {
+            (binary, 2)
+        } else {
+            (text.as_str(), 10)
+        };
+        let digits = digits.replace('_', "");
+        u64::from_str_radix(&digits, radix)
+            .ok()
+            .filter(|n| *n <= u32::MAX as u64)
+            .ok_or_else(|| {
+                AsmError::new(
+                    self.span_from(line, column),
+                    format!("invalid 32 bit number '{}'", text),
+                )
+            })
+    }
+
+    fn token(&mut self) -> Result<Option<Lexeme>, AsmError> {
+        // Skip blanks and comments
+        loop {
+            match self.peek() {
+                Some(';') => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.bump();
+                    }
+                }
+                Some(c) if c != '\n' && c.is_whitespace() => {
+                    self.bump();
+                }
+                _ => break,
+            }
+        }
+        let (line, column) = (self.line, self.column);
+        let c = match self.peek() {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+        let token = match c {
+            '\n' => {
+                self.bump();
+                Token::Newline
+            }
+            ',' | ':' | '+' | '-' => {
+                self.bump();
+                match c {
+                    ',' => Token::Comma,
+                    ':' => Token::Colon,
+                    '+' => Token::Plus,
+                    _ => Token::Minus,
+                }
+            }
+            '0'..='9' => Token::Number(self.number(line, column)?),
+            '\'' => {
+                self.bump();
+                let value = self.escaped(line, column)?;
+                if self.bump() != Some('\'') {
+                    return Err(AsmError::new(
+                        self.span_from(line, column),
+                        "character literal must hold exactly one character".to_string(),
+                    ));
+                }
+                Token::Number(value as u64)
+            }
+            '"' => {
+                self.bump();
+                let mut text = String::new();
+                while self.peek() != Some('"') {
+                    text.push(self.escaped(line, column)?);
+                }
+                self.bump();
+                Token::Str(text)
+            }
+            '.' => Token::Directive(self.word()),
+            c if c.is_alphabetic() || c == '_' => Token::Ident(self.word()),
+            c => {
+                self.bump();
+                return Err(AsmError::new(
+                    self.span_from(line, column),
+                    format!("unexpected character '{}'", c),
+                ));
+            }
+        };
+        let span = self.span_from(line, column);
+        Ok(Some(Lexeme { token, span }))
+    }
+}
+
+/// The tokens of `source`, which is file number `file` of an assembly
+pub fn tokenize(source: &str, file: usize) -> Result<Vec<Lexeme>, AsmError> {
+    let mut lexer = Lexer {
+        chars: source.chars().collect(),
+        file,
+        next: 0,
+        line: 1,
+        column: 1,
+    };
+    let mut lexemes = Vec::new();
+    while let Some(lexeme) = lexer.token()? {
+        lexemes.push(lexeme);
+    }
+    Ok(lexemes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(source: &str) -> Vec<Token> {
+        tokenize(source, 0)
+            .unwrap()
+            .into_iter()
+            .map(|l| l.token)
+            .collect()
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(
+            tokens("12 0x1F 0b101 'A' '\\n' \"a\\tb\""),
+            vec![
+                Token::Number(12),
+                Token::Number(31),
+                Token::Number(5),
+                Token::Number(65),
+                Token::Number(10),
+                Token::Str("a\tb".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn statements_and_comments() {
+        assert_eq!(
+            tokens("loop: add r0, r1, r2 ; comment\n.word loop+1"),
+            vec![
+                Token::Ident("loop".to_string()),
+                Token::Colon,
+                Token::Ident("add".to_string()),
+                Token::Ident("r0".to_string()),
+                Token::Comma,
+                Token::Ident("r1".to_string()),
+                Token::Comma,
+                Token::Ident("r2".to_string()),
+                Token::Newline,
+                Token::Directive(".word".to_string()),
+                Token::Ident("loop".to_string()),
+                Token::Plus,
+                Token::Number(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn spans() {
+        let lexemes = tokenize("  halt\n\tout r7", 2).unwrap();
+        assert_eq!(
+            lexemes[0].span,
+            Span {
+                file: 2,
+                line: 1,
+                column: 3,
+                length: 4
+            }
+        );
+        assert_eq!(
+            lexemes[3].span,
+            Span {
+                file: 2,
+                line: 2,
+                column: 6,
+                length: 2
+            }
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let error = tokenize("add r0 @", 0).unwrap_err();
+        assert_eq!((error.span.line, error.span.column), (1, 8));
+        assert!(tokenize("'ab'", 0).is_err());
+        assert!(tokenize("\"open\nhalt", 0).is_err());
+        assert!(tokenize("0x100000000", 0).is_err());
+        assert!(tokenize("0xZZ", 0).is_err());
+    }
+}
@@ -0,0 +1,310 @@
+// Linking: objects are laid out one after another in the order given, so
+// execution starts at the first word of the first object. Each relocation
+// adds (or subtracts) its module's start or the address of a global symbol
+// to the word; orth values must still fit in 25 bits afterwards.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use super::object::Object;
+use crate::symbols::Symbols;
+use crate::Data;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LinkError {
+    Undefined {
+        symbol: String,
+        object: String,
+    },
+    Duplicate {
+        symbol: String,
+        first: String,
+        second: String,
+    },
+    /// A global with no label behind it
+    Unlabelled {
+        symbol: String,
+        object: String,
+    },
+    Overflow {
+        object: String,
+        offset: Data,
+    },
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinkError::Undefined { symbol, object } => {
+                write!(f, "undefined symbol '{}' used in {}", symbol, object)
+            }
+            LinkError::Duplicate {
+                symbol,
+                first,
+                second,
+            } => write!(
+                f,
+                "duplicate symbol '{}' in {} and {}",
+                symbol, first, second
+            ),
+            LinkError::Unlabelled { symbol, object } => {
+                write!(f, "global '{}' of {} is not a label", symbol, object)
+            }
+            LinkError::Overflow { object, offset } => write!(
+                f,
+                "orth value at offset {} of {} does not fit in 25 bits once linked",
+                offset, object
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Linked {
+    pub words: Vec<Data>,
+    /// Where each object starts
+    pub bases: Vec<Data>,
+    pub globals: BTreeMap<String, Data>,
+}
+
+impl Linked {
+    /// A symbol map for the program linked from `objects`: every label and
+    /// source line moved to where its object went. Globals keep their names;
+    /// a local label whose name is already taken is left out.
+    pub fn symbols(&self, objects: &[(String, Object)]) -> Symbols {
+        let mut files: Vec<String> = Vec::new();
+        let mut labels = self.globals.clone();
+        let mut lines = BTreeMap::new();
+        for ((_, object), base) in objects.iter().zip(&self.bases) {
+            for (name, offset) in &object.symbols {
+                labels.entry(name.clone()).or_insert(base + offset);
+            }
+            let indices: Vec<usize> = object
+                .files
+                .iter()
+                .map(|file| match files.iter().position(|f| f == file) {
+                    Some(i) => i,
+                    None => {
+                        files.push(file.clone());
+                        files.len() - 1
+                    }
+                })
+                .collect();
+            for (offset, (length, file, line)) in &object.lines {
+                lines.insert(base + offset, (*length, indices[*file], *line));
+            }
+        }
+        Symbols::new(files, labels, lines)
+    }
+}
+
+/// Link named objects into one program, reporting every error found
+pub fn link(objects: &[(String, Object)]) -> Result<Linked, Vec<LinkError>> {
+    let mut errors = Vec::new();
+    let mut bases = Vec::with_capacity(objects.len());
+    let mut globals: BTreeMap<String, (Data, &str)> = BTreeMap::new();
+    let mut next: Data = 0;
+    for (name, object) in objects {
+        bases.push(next);
+        for symbol in &object.globals {
+            let address = match object.symbols.get(symbol) {
+                Some(offset) => next + offset,
+                None => {
+                    errors.push(LinkError::Unlabelled {
+                        symbol: symbol.clone(),
+                        object: name.clone(),
+                    });
+                    continue;
+                }
+            };
+            match globals.get(symbol) {
+                Some((_, first)) => errors.push(LinkError::Duplicate {
+                    symbol: symbol.clone(),
+                    first: first.to_string(),
+                    second: name.clone(),
+                }),
+                None => {
+                    globals.insert(symbol.clone(), (address, name));
+                }
+            }
+        }
+        next += object.words.len() as Data;
+    }
+
+    let mut words = Vec::with_capacity(next as usize);
+    for ((name, object), base) in objects.iter().zip(&bases) {
+        // Sum the adjustments to each word before checking orth ranges,
+        // since `end - start` only fits once both are applied
+        let mut deltas: BTreeMap<Data, (i64, bool)> = BTreeMap::new();
+        for relocation in &object.relocations {
+            let target = match &relocation.symbol {
+                None => *base,
+                Some(symbol) => match globals.get(symbol) {
+                    Some((address, _)) => *address,
+                    None => {
+                        let error = LinkError::Undefined {
+                            symbol: symbol.clone(),
+                            object: name.clone(),
+                        };
+                        if !errors.contains(&error) {
+                            errors.push(error);
+                        }
+                        continue;
+                    }
+                },
+            };
+            let delta = if relocation.negated {
+                -(target as i64)
+            } else {
+                target as i64
+            };
+            let entry = deltas
+                .entry(relocation.offset)
+                .or_insert((0, relocation.orth));
+            entry.0 += delta;
+        }
+        let start = words.len();
+        words.extend_from_slice(&object.words);
+        for (offset, (delta, orth)) in deltas {
+            let word = &mut words[start + offset as usize];
+            if orth {
+                let value = (*word & 0x1FFFFFF) as i64 + delta;
+                if !(0..=0x1FFFFFF).contains(&value) {
+                    errors.push(LinkError::Overflow {
+                        object: name.clone(),
+                        offset,
+                    });
+                    continue;
+                }
+                *word = (*word & !0x1FFFFFF) | value as Data;
+            } else {
+                *word = word.wrapping_add(delta as Data);
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(Linked {
+        words,
+        bases,
+        globals: globals
+            .into_iter()
+            .map(|(symbol, (address, _))| (symbol, address))
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::build;
+    use crate::run_buffered;
+
+    fn object(source: &str) -> Object {
+        Object::from(&build(source).unwrap())
+    }
+
+    #[test]
+    fn two_modules() {
+        let main = object(
+            "       .extern print, greeting
+                    orth r1, greeting
+                    jmp print
+            .global done
+            done:   halt",
+        );
+        let library = object(
+            "       .global print, greeting
+                    .extern done
+            greeting: .string \"hi\"
+            print:  load r2, r0, r1     ; r0 is 0, the program
+                    jz r2, finished
+                    out r2
+                    orth r3, 1
+                    add r1, r1, r3
+                    jmp print
+            finished: jmp done",
+        );
+        let linked = link(&[("main".to_string(), main), ("lib".to_string(), library)]).unwrap();
+        assert_eq!(linked.bases, vec![0, 5]);
+        assert_eq!(linked.globals["greeting"], 5);
+        assert_eq!(linked.globals["print"], 8);
+
+        assert_eq!(run_buffered(linked.words, b""), b"hi");
+    }
+
+    #[test]
+    fn linked_symbols() {
+        let main = Object::from(
+            &crate::asm::build_with(
+                "main.uma",
+                ".extern print\nloop: orth r1, 1\n\njmp print",
+                &crate::asm::read_file,
+            )
+            .unwrap(),
+        );
+        let library = Object::from(
+            &crate::asm::build_with(
+                "lib.uma",
+                ".global print\nloop: halt\nprint: halt",
+                &crate::asm::read_file,
+            )
+            .unwrap(),
+        );
+        let objects = [("main".to_string(), main), ("lib".to_string(), library)];
+        let linked = link(&objects).unwrap();
+        let symbols = linked.symbols(&objects);
+        let base = linked.bases[1];
+        assert_eq!(symbols.resolve("print"), Some(base + 1));
+        assert_eq!(symbols.resolve("loop"), Some(0));
+        assert_eq!(symbols.resolve("main.uma:4"), Some(1));
+        assert_eq!(symbols.resolve("lib.uma:3"), Some(base + 1));
+        assert_eq!(symbols.line(base), Some(("lib.uma", 2)));
+    }
+
+    #[test]
+    fn errors() {
+        let a = object(".global x, y\n.extern z\nx: y: orth r1, z\n.word w\n.extern w");
+        let b = object(".global x\nx: halt");
+        let errors = link(&[("a".to_string(), a), ("b".to_string(), b)]).unwrap_err();
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "duplicate symbol 'x' in a and b",
+                "undefined symbol 'z' used in a",
+                "undefined symbol 'w' used in a",
+            ]
+        );
+    }
+
+    #[test]
+    fn globals_need_labels() {
+        let mut a = object(".global x\nx: halt");
+        a.globals.insert("y".to_string());
+        let errors = link(&[("a".to_string(), a)]).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![LinkError::Unlabelled {
+                symbol: "y".to_string(),
+                object: "a".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn orth_overflow() {
+        let big = object(".global top\n.zero 0x1FFFFFF\ntop:");
+        let user = object(".extern top\north r1, top + 1");
+        let errors = link(&[("big".to_string(), big), ("user".to_string(), user)]).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![LinkError::Overflow {
+                object: "user".to_string(),
+                offset: 0
+            }]
+        );
+    }
+}
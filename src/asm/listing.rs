@@ -0,0 +1,102 @@
+// Source lines beside the words they produced:
+//
+//      4        2  d2000041  start:  orth r1, 'A'
+//      5        3                    mov r2, r1
+//               3  60000089      nand r2, r1, r1
+//               4  60000092      nand r2, r2, r2
+//
+// Pseudo-instructions are followed by their expansions, disassembled.
+// Long data directives show their first few words. Only the main file is
+// listed, not included ones.
+
+use super::Assembly;
+use crate::disasm;
+
+const DATA_LINES: usize = 4;
+
+pub fn listing(source: &str, assembly: &Assembly) -> Vec<String> {
+    let mut placements = assembly
+        .placements
+        .iter()
+        .filter(|p| p.span.file == 0)
+        .peekable();
+    let mut lines = Vec::new();
+    for (number, text) in source.lines().enumerate().map(|(n, t)| (n + 1, t)) {
+        let placement = match placements.peek() {
+            Some(p) if p.span.line == number => placements.next(),
+            _ => None,
+        };
+        let placement = match placement {
+            Some(p) if p.length > 0 => p,
+            _ => {
+                lines.push(format!("{:>5}  {:>8}  {:8}  {}", number, "", "", text));
+                continue;
+            }
+        };
+        let words = &assembly.words[placement.offset as usize..][..placement.length as usize];
+        if placement.expanded {
+            lines.push(format!(
+                "{:>5}  {:>8}  {:8}  {}",
+                number, placement.offset, "", text
+            ));
+            for (i, word) in words.iter().enumerate() {
+                lines.push(format!(
+                    "{:>5}  {:>8}  {:08x}      {}",
+                    "",
+                    placement.offset as usize + i,
+                    word,
+                    disasm::text(*word)
+                ));
+            }
+            continue;
+        }
+        lines.push(format!(
+            "{:>5}  {:>8}  {:08x}  {}",
+            number, placement.offset, words[0], text
+        ));
+        for (i, word) in words.iter().enumerate().skip(1).take(DATA_LINES - 1) {
+            lines.push(format!(
+                "{:>5}  {:>8}  {:08x}",
+                "",
+                placement.offset as usize + i,
+                word
+            ));
+        }
+        if words.len() > DATA_LINES {
+            lines.push(format!(
+                "{:>5}  {:>8}  ... {} more",
+                "",
+                "",
+                words.len() - DATA_LINES
+            ));
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::build;
+
+    #[test]
+    fn expansions_and_data() {
+        let source = "start:  orth r1, 'A'\n        mov r2, r1\n.zero 6\nhalt";
+        let assembly = build(source).unwrap();
+        assert_eq!(
+            listing(source, &assembly),
+            vec![
+                "    1         0  d2000041  start:  orth r1, 'A'",
+                "    2         1                    mov r2, r1",
+                "              1  60000089      nand r2, r1, r1",
+                "              2  60000092      nand r2, r2, r2",
+                "    3         3  00000000  .zero 6",
+                "              4  00000000",
+                "              5  00000000",
+                "              6  00000000",
+                "                 ... 2 more",
+                "    4         9  70000000  halt",
+            ]
+        );
+    }
+}
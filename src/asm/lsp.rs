@@ -0,0 +1,668 @@
+// Language Server Protocol server for UM assembly.
+//
+// Speaks LSP over a pair of streams (stdin/stdout for `cult lsp`). Open
+// documents are assembled on every change and the first error is published
+// as a diagnostic. Labels can be followed to their definition and their
+// references found, across included files; hovering over a line shows the
+// words it assembled to, with pseudo-instructions disassembled, and over a
+// label its offset. Completion offers mnemonics and directives at the start
+// of a statement and registers and labels after it.
+//
+// Includes are read from the open documents first, then from disk. Columns
+// are counted in characters, which is what LSP's UTF-16 units are for the
+// ASCII that assembly is written in.
+
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+
+use super::parser::{Kind, Operand};
+use super::{pseudo, statements_with, stdlib, AsmError, Assembly, Span};
+use crate::json::{self, read_message, Json};
+use crate::{disasm, OpCode};
+
+const DIRECTIVES: [&str; 8] = [
+    ".word", ".string", ".zero", ".scratch", ".stack", ".include", ".global", ".extern",
+];
+
+// CompletionItemKind
+const KEYWORD: u64 = 14;
+const VARIABLE: u64 = 6;
+const CONSTANT: u64 = 21;
+
+pub struct Server<'a> {
+    input: &'a mut dyn BufRead,
+    output: &'a mut dyn Write,
+    /// Open documents by URI
+    documents: BTreeMap<String, String>,
+}
+
+/// Definitions and uses of every label, across a document and its includes
+#[derive(Default)]
+struct Index {
+    files: Vec<String>,
+    definitions: BTreeMap<String, Span>,
+    references: Vec<(String, Span)>,
+}
+
+fn path(uri: &str) -> String {
+    let path = uri.strip_prefix("file://").unwrap_or(uri);
+    let mut bytes = Vec::new();
+    let mut rest = path.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        let hex = tail.get(..2).and_then(|h| std::str::from_utf8(h).ok());
+        match hex.map(|h| u8::from_str_radix(h, 16)) {
+            Some(Ok(decoded)) if b == b'%' => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(b);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn uri(path: &str) -> String {
+    format!("file://{}", path.replace('%', "%25").replace(' ', "%20"))
+}
+
+fn position(line: usize, character: usize) -> Json {
+    Json::object(vec![("line", line.into()), ("character", character.into())])
+}
+
+fn range(span: Span) -> Json {
+    Json::object(vec![
+        ("start", position(span.line - 1, span.column - 1)),
+        (
+            "end",
+            position(span.line - 1, span.column - 1 + span.length),
+        ),
+    ])
+}
+
+fn contains(span: Span, line: usize, character: usize) -> bool {
+    span.file == 0
+        && span.line == line + 1
+        && span.column <= character + 1
+        && character < span.column + span.length
+}
+
+fn diagnostic(error: &AsmError, main: &str) -> Json {
+    // Errors in included files are shown at the top of the document
+    let (range, message) = if error.file == main {
+        (range(error.span), error.message.clone())
+    } else {
+        let span = Span {
+            file: 0,
+            line: 1,
+            column: 1,
+            length: 0,
+        };
+        (range(span), format!("{}:{}", error.file, error))
+    };
+    Json::object(vec![
+        ("range", range),
+        ("severity", 1u64.into()),
+        ("source", "cult".into()),
+        ("message", message.into()),
+    ])
+}
+
+fn completion(label: &str, kind: u64, detail: &str) -> Json {
+    Json::object(vec![
+        ("label", label.into()),
+        ("kind", kind.into()),
+        ("detail", detail.into()),
+    ])
+}
+
+/// Markdown for the words `line` of the main file assembled to
+fn words(assembly: &Assembly, line: usize) -> Option<(Span, String)> {
+    let placement = assembly
+        .placements
+        .iter()
+        .find(|p| p.span.file == 0 && p.span.line == line + 1 && p.length > 0)?;
+    let start = placement.offset as usize;
+    let words = &assembly.words[start..start + placement.length as usize];
+    let mut text = String::from("```\n");
+    if placement.expanded {
+        for (i, word) in words.iter().enumerate() {
+            let line = format!("{:>8}  {:08x}  {}\n", start + i, word, disasm::text(*word));
+            text.push_str(&line);
+        }
+    } else {
+        for (i, word) in words.iter().enumerate().take(8) {
+            text.push_str(&format!("{:>8}  {:08x}\n", start + i, word));
+        }
+        if words.len() > 8 {
+            text.push_str(&format!("          ... {} words\n", words.len()));
+        }
+    }
+    text.push_str("```");
+    Some((placement.span, text))
+}
+
+impl<'a> Server<'a> {
+    pub fn new(input: &'a mut dyn BufRead, output: &'a mut dyn Write) -> Self {
+        Server {
+            input,
+            output,
+            documents: BTreeMap::new(),
+        }
+    }
+
+    fn send(&mut self, mut message: Vec<(&str, Json)>) -> io::Result<()> {
+        message.push(("jsonrpc", "2.0".into()));
+        json::write_message(self.output, &Json::object(message))
+    }
+
+    fn notify(&mut self, method: &str, params: Json) -> io::Result<()> {
+        self.send(vec![("method", method.into()), ("params", params)])
+    }
+
+    /// Read an include, preferring an open document to the file on disk
+    fn read(&self, file: &str) -> io::Result<String> {
+        match self.documents.get(&uri(file)) {
+            Some(text) => Ok(text.clone()),
+            None => std::fs::read_to_string(file),
+        }
+    }
+
+    fn text(&self, uri: &str) -> Result<&str, String> {
+        self.documents
+            .get(uri)
+            .map(String::as_str)
+            .ok_or_else(|| format!("'{}' is not open", uri))
+    }
+
+    fn assemble(&self, uri: &str) -> Result<Assembly, AsmError> {
+        let text = self.documents.get(uri).map_or("", String::as_str);
+        super::build_with(&path(uri), text, &|file| self.read(file))
+    }
+
+    fn index(&self, uri: &str) -> Index {
+        let text = self.documents.get(uri).map_or("", String::as_str);
+        let (files, statements) = match statements_with(&path(uri), text, &|f| self.read(f)) {
+            Ok(parsed) => parsed,
+            Err(_) => return Index::default(),
+        };
+        let mut index = Index {
+            files,
+            ..Index::default()
+        };
+        for statement in statements {
+            let args = match statement.kind {
+                Kind::Label(name) => {
+                    index.definitions.entry(name).or_insert(statement.span);
+                    continue;
+                }
+                Kind::Instruction { args, .. } | Kind::Directive { args, .. } => args,
+            };
+            for arg in args {
+                if let Operand::Value(expr) = &arg.operand {
+                    for (name, span) in expr.symbols() {
+                        index.references.push((name.to_string(), span));
+                    }
+                }
+            }
+        }
+        index
+    }
+
+    fn location(&self, index: &Index, span: Span) -> Option<Json> {
+        let file = &index.files[span.file];
+        // The bundled library has no file to open
+        if stdlib::source(file).is_some() {
+            return None;
+        }
+        Some(Json::object(vec![
+            ("uri", uri(file).into()),
+            ("range", range(span)),
+        ]))
+    }
+
+    fn publish(&mut self, uri: &str) -> io::Result<()> {
+        let diagnostics = match self.assemble(uri) {
+            Ok(_) => vec![],
+            Err(error) => vec![diagnostic(&error, &path(uri))],
+        };
+        let params = Json::object(vec![
+            ("uri", uri.into()),
+            ("diagnostics", diagnostics.into()),
+        ]);
+        self.notify("textDocument/publishDiagnostics", params)
+    }
+
+    /// The label at a position in the document, and the index it is from
+    fn symbol(&self, params: &Json) -> Result<Option<(String, Index)>, String> {
+        let (uri, line, character) = document_position(params)?;
+        self.text(uri)?;
+        let index = self.index(uri);
+        let definitions = index.definitions.iter().map(|(n, s)| (n, *s));
+        let references = index.references.iter().map(|(n, s)| (n, *s));
+        let found = definitions
+            .chain(references)
+            .find(|(_, span)| contains(*span, line, character))
+            .map(|(name, _)| name.clone());
+        Ok(found.map(|name| (name, index)))
+    }
+
+    fn definition(&self, params: &Json) -> Result<Json, String> {
+        let (name, index) = match self.symbol(params)? {
+            Some(found) => found,
+            None => return Ok(Json::Null),
+        };
+        let span = index.definitions.get(&name);
+        Ok(span
+            .and_then(|span| self.location(&index, *span))
+            .unwrap_or(Json::Null))
+    }
+
+    fn references(&self, params: &Json) -> Result<Json, String> {
+        let (name, index) = match self.symbol(params)? {
+            Some(found) => found,
+            None => return Ok(Json::Null),
+        };
+        let declaration = params
+            .get("context")
+            .and_then(|c| c.get("includeDeclaration"))
+            .and_then(Json::as_bool)
+            == Some(true);
+        let mut spans = Vec::new();
+        if declaration {
+            spans.extend(index.definitions.get(&name).copied());
+        }
+        let uses = index.references.iter().filter(|(n, _)| *n == name);
+        spans.extend(uses.map(|(_, span)| *span));
+        let locations: Vec<Json> = spans
+            .into_iter()
+            .filter_map(|span| self.location(&index, span))
+            .collect();
+        Ok(locations.into())
+    }
+
+    fn hover(&self, params: &Json) -> Result<Json, String> {
+        let (uri, line, character) = document_position(params)?;
+        self.text(uri)?;
+        let assembly = match self.assemble(uri) {
+            Ok(assembly) => assembly,
+            Err(_) => return Ok(Json::Null),
+        };
+        let (span, text) = match self.symbol(params)? {
+            Some((name, index)) => {
+                let span = index
+                    .definitions
+                    .get(&name)
+                    .filter(|s| contains(**s, line, character))
+                    .or_else(|| {
+                        let uses = index.references.iter();
+                        uses.map(|(_, s)| s)
+                            .find(|s| contains(**s, line, character))
+                    })
+                    .copied()
+                    .unwrap();
+                let text = match assembly.symbols.get(&name) {
+                    Some(offset) => format!("`{}` = {} (0x{:x})", name, offset, offset),
+                    None if assembly.externs.contains_key(&name) => {
+                        format!("`{}` is external", name)
+                    }
+                    None => return Ok(Json::Null),
+                };
+                (span, text)
+            }
+            None => match words(&assembly, line) {
+                Some(found) => found,
+                None => return Ok(Json::Null),
+            },
+        };
+        let contents = Json::object(vec![("kind", "markdown".into()), ("value", text.into())]);
+        Ok(Json::object(vec![
+            ("contents", contents),
+            ("range", range(span)),
+        ]))
+    }
+
+    fn completion(&self, params: &Json) -> Result<Json, String> {
+        let (uri, line, character) = document_position(params)?;
+        let text = self.text(uri)?.lines().nth(line).unwrap_or("");
+        let before: String = text.chars().take(character).collect();
+        let before = before.split(';').next().unwrap_or("");
+        // Labels end in ':', so what follows the last one is the statement
+        let statement = before.rsplit(':').next().unwrap_or("").trim_start();
+        let mut items = Vec::new();
+        if !statement.contains(char::is_whitespace) {
+            for op in (0..=0xD).map(OpCode::from_byte) {
+                items.push(completion(op.mnemonic(), KEYWORD, "instruction"));
+            }
+            for mnemonic in pseudo::MNEMONICS.iter() {
+                items.push(completion(mnemonic, KEYWORD, "pseudo-instruction"));
+            }
+            for directive in DIRECTIVES.iter() {
+                items.push(completion(directive, KEYWORD, "directive"));
+            }
+        } else {
+            for register in 0..8 {
+                items.push(completion(&format!("r{}", register), VARIABLE, "register"));
+            }
+            for name in self.index(uri).definitions.keys() {
+                items.push(completion(name, CONSTANT, "label"));
+            }
+        }
+        Ok(items.into())
+    }
+
+    /// Handle messages until the client sends `exit`
+    pub fn run(&mut self) -> io::Result<()> {
+        while let Some(message) = read_message(self.input)? {
+            let method = message.get("method").and_then(Json::as_str).unwrap_or("");
+            let params = message.get("params").cloned().unwrap_or(Json::Null);
+            let document = params.get("textDocument");
+            let uri = document
+                .and_then(|d| d.get("uri"))
+                .and_then(Json::as_str)
+                .unwrap_or("")
+                .to_string();
+            let result = match method {
+                "initialize" => Ok(Json::object(vec![
+                    (
+                        "capabilities",
+                        Json::object(vec![
+                            ("textDocumentSync", 1u64.into()),
+                            ("definitionProvider", true.into()),
+                            ("referencesProvider", true.into()),
+                            ("hoverProvider", true.into()),
+                            ("completionProvider", Json::object(vec![])),
+                        ]),
+                    ),
+                    ("serverInfo", Json::object(vec![("name", "cult".into())])),
+                ])),
+                "textDocument/didOpen" | "textDocument/didChange" => {
+                    let text = match method {
+                        "textDocument/didOpen" => document.and_then(|d| d.get("text")),
+                        _ => params
+                            .get("contentChanges")
+                            .and_then(Json::as_array)
+                            .and_then(|changes| changes.last())
+                            .and_then(|change| change.get("text")),
+                    };
+                    let text = text.and_then(Json::as_str).unwrap_or("").to_string();
+                    self.documents.insert(uri.clone(), text);
+                    self.publish(&uri)?;
+                    continue;
+                }
+                "textDocument/didClose" => {
+                    self.documents.remove(&uri);
+                    let params = Json::object(vec![
+                        ("uri", uri.into()),
+                        ("diagnostics", Json::Array(vec![])),
+                    ]);
+                    self.notify("textDocument/publishDiagnostics", params)?;
+                    continue;
+                }
+                "textDocument/definition" => self.definition(&params),
+                "textDocument/references" => self.references(&params),
+                "textDocument/hover" => self.hover(&params),
+                "textDocument/completion" => self.completion(&params),
+                "shutdown" => Ok(Json::Null),
+                "exit" => return Ok(()),
+                _ => Err(format!("unsupported method '{}'", method)),
+            };
+            // Notifications get no response
+            let id = match message.get("id") {
+                Some(id) => id.clone(),
+                None => continue,
+            };
+            let reply = match result {
+                Ok(result) => ("result", result),
+                Err(e) => (
+                    "error",
+                    Json::object(vec![
+                        // MethodNotFound, or InvalidParams
+                        (
+                            "code",
+                            Json::Number(if e.starts_with("unsupported") {
+                                -32601.0
+                            } else {
+                                -32602.0
+                            }),
+                        ),
+                        ("message", e.into()),
+                    ]),
+                ),
+            };
+            self.send(vec![("id", id), reply])?;
+        }
+        Ok(())
+    }
+}
+
+/// The document URI and 0-based line and character of a position request
+fn document_position(params: &Json) -> Result<(&str, usize, usize), String> {
+    let uri = params
+        .get("textDocument")
+        .and_then(|d| d.get("uri"))
+        .and_then(Json::as_str);
+    let position = params.get("position");
+    let line = position.and_then(|p| p.get("line")).and_then(Json::as_u64);
+    let character = position
+        .and_then(|p| p.get("character"))
+        .and_then(Json::as_u64);
+    match (uri, line, character) {
+        (Some(uri), Some(line), Some(character)) => Ok((uri, line as usize, character as usize)),
+        _ => Err("expected a text document and position".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAIN: &str = "file:///work/main.uma";
+    const LIB: &str = "file:///work/lib.uma";
+
+    // A client that sends `messages` in order, numbering the requests, and
+    // returns everything the server sent back
+    fn session(messages: &[(&str, &str)]) -> Vec<Json> {
+        let mut input: Vec<u8> = Vec::new();
+        for (id, (method, params)) in messages.iter().enumerate() {
+            let id = if method.starts_with("textDocument/did") || *method == "exit" {
+                String::new()
+            } else {
+                format!("\"id\":{},", id)
+            };
+            let text = format!(
+                "{{\"jsonrpc\":\"2.0\",{}\"method\":\"{}\",\"params\":{}}}",
+                id, method, params
+            );
+            input.extend(format!("Content-Length: {}\r\n\r\n{}", text.len(), text).bytes());
+        }
+        let mut output = Vec::new();
+        Server::new(&mut input.as_slice(), &mut output)
+            .run()
+            .unwrap();
+        let mut reader: &[u8] = &output;
+        let mut replies = Vec::new();
+        while let Some(message) = read_message(&mut reader).unwrap() {
+            replies.push(message);
+        }
+        replies
+    }
+
+    fn open(uri: &str, text: &str) -> String {
+        let document = Json::object(vec![
+            ("uri", uri.into()),
+            ("languageId", "uma".into()),
+            ("version", 1u64.into()),
+            ("text", text.into()),
+        ]);
+        Json::object(vec![("textDocument", document)]).to_string()
+    }
+
+    fn at(uri: &str, line: u64, character: u64) -> String {
+        format!(
+            "{{\"textDocument\":{{\"uri\":\"{}\"}},\"position\":{{\"line\":{},\"character\":{}}},\
+             \"context\":{{\"includeDeclaration\":true}}}}",
+            uri, line, character
+        )
+    }
+
+    fn result(replies: &[Json], id: u64) -> &Json {
+        replies
+            .iter()
+            .find(|r| r.get("id").and_then(Json::as_u64) == Some(id))
+            .and_then(|r| r.get("result").or_else(|| r.get("error")))
+            .unwrap()
+    }
+
+    fn diagnostics(replies: &[Json]) -> Vec<(String, Vec<String>)> {
+        replies
+            .iter()
+            .filter(|r| r.get("method").is_some())
+            .map(|r| {
+                let params = r.get("params").unwrap();
+                let messages = params.get("diagnostics").unwrap().as_array().unwrap();
+                let messages = messages.iter().map(|d| {
+                    let start = d.get("range").unwrap().get("start").unwrap();
+                    format!(
+                        "{}:{}: {}",
+                        start.get("line").unwrap().as_u64().unwrap(),
+                        start.get("character").unwrap().as_u64().unwrap(),
+                        d.get("message").unwrap().as_str().unwrap()
+                    )
+                });
+                let uri = params.get("uri").unwrap().as_str().unwrap();
+                (uri.to_string(), messages.collect())
+            })
+            .collect()
+    }
+
+    // (uri, line, character) of each location
+    fn locations(json: &Json) -> Vec<(String, u64, u64)> {
+        json.as_array()
+            .map(|a| a.to_vec())
+            .unwrap_or_else(|| vec![json.clone()])
+            .iter()
+            .map(|l| {
+                let start = l.get("range").unwrap().get("start").unwrap();
+                (
+                    l.get("uri").unwrap().as_str().unwrap().to_string(),
+                    start.get("line").unwrap().as_u64().unwrap(),
+                    start.get("character").unwrap().as_u64().unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn diagnostics_follow_edits() {
+        let change = |text: &str| {
+            let document = Json::object(vec![("uri", MAIN.into()), ("version", 2u64.into())]);
+            let change = Json::object(vec![("text", text.into())]);
+            Json::object(vec![
+                ("textDocument", document),
+                ("contentChanges", vec![change].into()),
+            ])
+            .to_string()
+        };
+        let replies = session(&[
+            ("initialize", "{}"),
+            (
+                "textDocument/didOpen",
+                &open(MAIN, "        orth r1, 1\n        ad r1, r1, r1\n"),
+            ),
+            (
+                "textDocument/didChange",
+                &change("        orth r1, 0x2000000\n"),
+            ),
+            (
+                "textDocument/didChange",
+                &change("        .include \"gone.uma\"\n"),
+            ),
+            ("textDocument/didChange", &change("        halt\n")),
+            ("shutdown", "null"),
+            ("exit", "null"),
+        ]);
+        let capabilities = result(&replies, 0).get("capabilities").unwrap();
+        assert_eq!(capabilities.get("hoverProvider"), Some(&Json::Bool(true)));
+        let published = diagnostics(&replies);
+        assert!(published.iter().all(|(uri, _)| uri == MAIN));
+        let messages: Vec<_> = published.into_iter().map(|(_, m)| m).collect();
+        assert_eq!(messages[0], vec!["1:8: unknown mnemonic 'ad'"]);
+        assert_eq!(
+            messages[1],
+            vec!["0:17: value 33554432 does not fit in 25 bits"]
+        );
+        assert!(messages[2][0].contains("cannot read '/work/gone.uma'"));
+        assert!(messages[3].is_empty());
+        assert_eq!(result(&replies, 5), &Json::Null);
+    }
+
+    #[test]
+    fn definitions_references_hover_and_completion() {
+        let main = "start:  li r1, 0xdeadbeef\n\
+                    \x20       jmp start\n\
+                    \x20       orth r2, double\n\
+                    \x20       .include \"lib.uma\"\n";
+        let lib = "double: add r1, r1, r1\n        orth r0, start\n";
+        let replies = session(&[
+            ("initialize", "{}"),
+            ("textDocument/didOpen", &open(MAIN, main)),
+            ("textDocument/didOpen", &open(LIB, lib)),
+            ("textDocument/definition", &at(MAIN, 2, 18)),
+            ("textDocument/references", &at(MAIN, 1, 13)),
+            ("textDocument/hover", &at(MAIN, 0, 9)),
+            ("textDocument/hover", &at(MAIN, 2, 18)),
+            ("textDocument/completion", &at(MAIN, 1, 9)),
+            ("textDocument/completion", &at(MAIN, 1, 12)),
+            ("textDocument/hover", &at("file:///nowhere.uma", 0, 0)),
+            ("textDocument/formatting", "{}"),
+            ("exit", "null"),
+        ]);
+        let lib_uri = LIB.to_string();
+        let main_uri = MAIN.to_string();
+        assert_eq!(
+            locations(result(&replies, 3)),
+            vec![(lib_uri.clone(), 0, 0)]
+        );
+        assert_eq!(
+            locations(result(&replies, 4)),
+            vec![
+                (main_uri.clone(), 0, 0),
+                (main_uri, 1, 12),
+                (lib_uri, 1, 17)
+            ]
+        );
+
+        let hover = |id| {
+            let contents = result(&replies, id).get("contents").unwrap();
+            contents.get("value").unwrap().as_str().unwrap().to_string()
+        };
+        let expansion = hover(5);
+        assert!(expansion.contains("       0  d3bd5b7d  orth r1, 0x1bd5b7d"));
+        assert!(expansion.contains("       2  4000004e  mul r1, r1, r6"));
+        assert_eq!(hover(6), "`double` = 9 (0x9)");
+
+        let labels = |id| -> Vec<String> {
+            let items = result(&replies, id).as_array().unwrap();
+            let labels = items
+                .iter()
+                .map(|i| i.get("label").unwrap().as_str().unwrap());
+            labels.map(str::to_string).collect()
+        };
+        let statement = labels(7);
+        assert!(statement.contains(&"store".to_string()));
+        assert!(statement.contains(&"jsr".to_string()));
+        assert!(statement.contains(&".include".to_string()));
+        let operands = labels(8);
+        assert!(operands.contains(&"r7".to_string()));
+        assert!(operands.contains(&"double".to_string()));
+        assert!(!operands.contains(&"store".to_string()));
+
+        let error = result(&replies, 9).get("message").unwrap();
+        assert_eq!(error.as_str(), Some("'file:///nowhere.uma' is not open"));
+        let code = result(&replies, 10).get("code").unwrap();
+        assert_eq!(code, &Json::Number(-32601.0));
+    }
+}
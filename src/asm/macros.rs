@@ -0,0 +1,132 @@
+// Assembly inside Rust source, checked by the compiler:
+//
+//     let program: Vec<Data> = um_asm! { alloc r7, r0; amend r7, r1, r2; halt };
+//
+// Statements are separated by `;` and use the assembler's mnemonics, with
+// `index` and `amend` accepted for `load` and `store`. Orth values must be
+// constant expressions. An unknown mnemonic, a bad register, the wrong
+// number of operands or a value wider than 25 bits fails to compile.
+
+#[allow(unused_macros)] // only tests use it so far
+macro_rules! um_asm {
+    // Accumulate words until the input is used up
+    (@words [$($word:expr,)*]) => {
+        vec![$($word),*]
+    };
+    (@words [$($word:expr,)*] halt $(; $($rest:tt)*)?) => {
+        um_asm!(@words [$($word,)* $crate::Instruction::halt().encode(),] $($($rest)*)?)
+    };
+    (@words [$($word:expr,)*] orth $a:ident, $value:expr $(; $($rest:tt)*)?) => {
+        um_asm!(@words [$($word,)* um_asm!(@word orth(um_asm!(@reg $a), $value)),] $($($rest)*)?)
+    };
+    (@words [$($word:expr,)*] $op:ident $a:ident, $b:ident, $c:ident $(; $($rest:tt)*)?) => {
+        um_asm!(@words [$($word,)* um_asm!(@three $op $a $b $c),] $($($rest)*)?)
+    };
+    (@words [$($word:expr,)*] $op:ident $b:ident, $c:ident $(; $($rest:tt)*)?) => {
+        um_asm!(@words [$($word,)* um_asm!(@two $op $b $c),] $($($rest)*)?)
+    };
+    (@words [$($word:expr,)*] $op:ident $c:ident $(; $($rest:tt)*)?) => {
+        um_asm!(@words [$($word,)* um_asm!(@one $op $c),] $($($rest)*)?)
+    };
+    (@words [$($word:expr,)*] $($rest:tt)*) => {
+        compile_error!(concat!("cannot assemble `", stringify!($($rest)*), "`"))
+    };
+
+    (@three cmov $a:ident $b:ident $c:ident) => { um_asm!(@word cmov(um_asm!(@reg $a), um_asm!(@reg $b), um_asm!(@reg $c))) };
+    (@three load $a:ident $b:ident $c:ident) => { um_asm!(@word load(um_asm!(@reg $a), um_asm!(@reg $b), um_asm!(@reg $c))) };
+    (@three index $a:ident $b:ident $c:ident) => { um_asm!(@three load $a $b $c) };
+    (@three store $a:ident $b:ident $c:ident) => { um_asm!(@word store(um_asm!(@reg $a), um_asm!(@reg $b), um_asm!(@reg $c))) };
+    (@three amend $a:ident $b:ident $c:ident) => { um_asm!(@three store $a $b $c) };
+    (@three add $a:ident $b:ident $c:ident) => { um_asm!(@word add(um_asm!(@reg $a), um_asm!(@reg $b), um_asm!(@reg $c))) };
+    (@three mul $a:ident $b:ident $c:ident) => { um_asm!(@word mul(um_asm!(@reg $a), um_asm!(@reg $b), um_asm!(@reg $c))) };
+    (@three div $a:ident $b:ident $c:ident) => { um_asm!(@word div(um_asm!(@reg $a), um_asm!(@reg $b), um_asm!(@reg $c))) };
+    (@three nand $a:ident $b:ident $c:ident) => { um_asm!(@word nand(um_asm!(@reg $a), um_asm!(@reg $b), um_asm!(@reg $c))) };
+    (@two alloc $b:ident $c:ident) => { um_asm!(@word alloc(um_asm!(@reg $b), um_asm!(@reg $c))) };
+    (@two call $b:ident $c:ident) => { um_asm!(@word call(um_asm!(@reg $b), um_asm!(@reg $c))) };
+    (@one free $c:ident) => { um_asm!(@word free(um_asm!(@reg $c))) };
+    (@one out $c:ident) => { um_asm!(@word out(um_asm!(@reg $c))) };
+    (@one in $c:ident) => { um_asm!(@word input(um_asm!(@reg $c))) };
+    (@three $op:ident $($operand:ident)*) => { um_asm!(@arity $op 3) };
+    (@two $op:ident $($operand:ident)*) => { um_asm!(@arity $op 2) };
+    (@one $op:ident $($operand:ident)*) => { um_asm!(@arity $op 1) };
+    (@arity $op:ident $count:literal) => {
+        compile_error!(concat!("no operator `", stringify!($op), "` with ", $count, " register operands"))
+    };
+
+    (@reg r0) => { 0 };
+    (@reg r1) => { 1 };
+    (@reg r2) => { 2 };
+    (@reg r3) => { 3 };
+    (@reg r4) => { 4 };
+    (@reg r5) => { 5 };
+    (@reg r6) => { 6 };
+    (@reg r7) => { 7 };
+    (@reg $other:ident) => {
+        compile_error!(concat!("expected one of r0-r7, found `", stringify!($other), "`"))
+    };
+
+    // Evaluated as a constant, so a bad operand stops the build
+    (@word $constructor:ident($($operand:expr),*)) => {{
+        const WORD: $crate::Data = match $crate::Instruction::$constructor($($operand),*) {
+            Ok(instruction) => instruction.encode(),
+            Err(_) => panic!("orth value does not fit in 25 bits"),
+        };
+        WORD
+    }};
+
+    ($($statements:tt)*) => {
+        um_asm!(@words [] $($statements)*)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{asm, Data};
+
+    #[test]
+    fn matches_the_assembler() {
+        let program: Vec<Data> = um_asm! {
+            cmov r0, r1, r2;
+            index r6, r7, r1;
+            amend r7, r1, r2;
+            add r1, r2, r3;
+            mul r4, r5, r6;
+            div r7, r0, r1;
+            nand r0, r1, r2;
+            alloc r7, r0;
+            free r1;
+            out r7;
+            in r2;
+            call r5, r1;
+            orth r7, 0x1234;
+            orth r0, 'A' as u32;
+            halt;
+        };
+        let source = "
+            cmov r0, r1, r2
+            load r6, r7, r1
+            store r7, r1, r2
+            add r1, r2, r3
+            mul r4, r5, r6
+            div r7, r0, r1
+            nand r0, r1, r2
+            alloc r7, r0
+            free r1
+            out r7
+            in r2
+            call r5, r1
+            orth r7, 0x1234
+            orth r0, 'A'
+            halt";
+        assert_eq!(program, asm::assemble(source).unwrap());
+    }
+
+    #[test]
+    fn constants() {
+        const BASE: u32 = 0x100;
+        let program: Vec<Data> = um_asm! { orth r1, BASE + 2 };
+        assert_eq!(program, vec![0xD2000102]);
+        let empty: Vec<Data> = um_asm! {};
+        assert!(empty.is_empty());
+    }
+}
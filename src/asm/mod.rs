@@ -0,0 +1,804 @@
+// ---------- ASSEMBLER -------------------------------------------------------
+//
+// Text to program images, in the syntax the disassembler prints:
+//
+//     ; print "hi" and stop
+//             orth r1, 'h'
+//             out r1
+//             orth r1, message     ; labels are platter offsets
+//             halt
+//     message: .string "hi"        ; one character per platter, then 0
+//
+// Directives are `.word VALUE, ...`, `.string "TEXT"` and `.zero COUNT`,
+// `.scratch` and `.stack` for the pseudo-instructions (see pseudo.rs), and
+// `.include "FILE"`, `.global NAME, ...` and `.extern NAME, ...` for modules
+// (see object.rs). Each file is included at most once, and `std/` names the
+// routines bundled with the assembler (see stdlib.rs).
+// Numbers are decimal, 0x hex, 0b binary or 'c' characters, and any value
+// may be a sum such as `table + 2 - start`.
+
+#[macro_use]
+mod macros;
+
+pub mod lexer;
+pub mod link;
+pub mod listing;
+pub mod lsp;
+pub mod object;
+pub mod parser;
+pub mod pseudo;
+pub mod stdlib;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::path::{Component, Path};
+
+use crate::{Data, Instruction, OpCode, Register};
+use parser::{Arg, Expr, Kind, Operand, Statement, Term};
+use pseudo::{Conventions, Step};
+
+/// A 1-based line and column and a length in characters, in the file with
+/// this index in `Assembly::files`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Span {
+    pub file: usize,
+    pub line: usize,
+    pub column: usize,
+    pub length: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmError {
+    pub span: Span,
+    pub message: String,
+    /// The name of the file the span is in
+    pub file: String,
+}
+
+impl AsmError {
+    pub fn new(span: Span, message: String) -> AsmError {
+        AsmError {
+            span,
+            message,
+            file: String::new(),
+        }
+    }
+
+    fn in_files(mut self, files: &[String]) -> AsmError {
+        self.file = files.get(self.span.file).cloned().unwrap_or_default();
+        self
+    }
+
+    /// The error followed by the offending line of `source`, underlined
+    pub fn render(&self, source: &str) -> String {
+        let text = source.lines().nth(self.span.line - 1).unwrap_or("");
+        let gutter = self.span.line.to_string().len();
+        format!(
+            "{}:{}\n{:gutter$} |\n{} | {}\n{:gutter$} | {}{}",
+            self.file,
+            self,
+            "",
+            self.span.line,
+            text,
+            "",
+            " ".repeat(self.span.column - 1),
+            "^".repeat(self.span.length.max(1)),
+            gutter = gutter
+        )
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: {}",
+            self.span.line, self.span.column, self.message
+        )
+    }
+}
+
+/// Where the words of a statement ended up
+#[derive(Debug, Clone, PartialEq)]
+pub struct Placement {
+    pub span: Span,
+    pub offset: Data,
+    pub length: Data,
+    /// Whether the words are the expansion of a pseudo-instruction
+    pub expanded: bool,
+}
+
+/// A word whose value includes a label, to be adjusted once the module's
+/// place in the program is known
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relocation {
+    pub offset: Data,
+    /// orth words keep their register and change in the low 25 bits
+    pub orth: bool,
+    /// An external symbol, or None for the start of this module
+    pub symbol: Option<String>,
+    pub negated: bool,
+}
+
+/// A program image, its labels and the placement of every statement
+#[derive(Debug, Clone, Default)]
+pub struct Assembly {
+    pub words: Vec<Data>,
+    pub symbols: BTreeMap<String, Data>,
+    pub placements: Vec<Placement>,
+    /// The main file first, then each included file
+    pub files: Vec<String>,
+    pub globals: BTreeSet<String>,
+    pub externs: BTreeMap<String, Span>,
+    pub relocations: Vec<Relocation>,
+}
+
+impl Assembly {
+    /// The words as a whole program, which must not need other modules
+    pub fn image(&self) -> Result<Vec<Data>, AsmError> {
+        for relocation in &self.relocations {
+            if let Some(name) = &relocation.symbol {
+                let error = AsmError::new(
+                    self.externs[name],
+                    format!("'{}' is external; assemble with -c and link", name),
+                );
+                return Err(error.in_files(&self.files));
+            }
+        }
+        Ok(self.words.clone())
+    }
+}
+
+fn register(arg: &Arg) -> Result<Register, AsmError> {
+    match arg.operand {
+        Operand::Register(r) => Ok(r),
+        _ => Err(AsmError::new(arg.span, "expected a register".to_string())),
+    }
+}
+
+/// Symbol names given as operands, as to `.global` and `.extern`
+fn names(args: &[Arg]) -> Option<Vec<(&str, Span)>> {
+    args.iter()
+        .map(|arg| match &arg.operand {
+            Operand::Value(expr) if expr.terms.len() == 1 && !expr.terms[0].0 => {
+                expr.symbols().next()
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+struct Assembler {
+    symbols: BTreeMap<String, (Data, Span)>,
+    conventions: Conventions,
+    globals: BTreeMap<String, Span>,
+    externs: BTreeMap<String, Span>,
+    /// Labels in the value of the word being emitted
+    references: Vec<(bool, Option<String>)>,
+    relocations: Vec<Relocation>,
+}
+
+impl Assembler {
+    fn value(&mut self, expr: &Expr) -> Result<i64, AsmError> {
+        let mut sum: i64 = 0;
+        for (negated, term) in &expr.terms {
+            let n = match term {
+                Term::Number(n) => *n as i64,
+                Term::Symbol(name, span) => {
+                    if let Some((offset, _)) = self.symbols.get(name) {
+                        self.references.push((*negated, None));
+                        *offset as i64
+                    } else if self.externs.contains_key(name) {
+                        self.references.push((*negated, Some(name.clone())));
+                        0
+                    } else {
+                        return Err(AsmError::new(*span, format!("undefined label '{}'", name)));
+                    }
+                }
+            };
+            sum += if *negated { -n } else { n };
+        }
+        Ok(sum)
+    }
+
+    /// Record relocations for the labels in the word just emitted
+    fn relocate(&mut self, out: &[Data], orth: bool) {
+        let offset = out.len() as Data - 1;
+        for (negated, symbol) in self.references.drain(..) {
+            self.relocations.push(Relocation {
+                offset,
+                orth,
+                symbol,
+                negated,
+            });
+        }
+    }
+
+    /// A value in `min..=max`
+    fn bounded(&mut self, arg: &Arg, min: i64, max: i64, what: &str) -> Result<i64, AsmError> {
+        let value = match &arg.operand {
+            Operand::Value(expr) => self.value(expr)?,
+            _ => return Err(AsmError::new(arg.span, "expected a value".to_string())),
+        };
+        if value < min || value > max {
+            return Err(AsmError::new(
+                arg.span,
+                format!("value {} does not fit in {}", value, what),
+            ));
+        }
+        Ok(value)
+    }
+
+    fn word(&mut self, arg: &Arg) -> Result<Data, AsmError> {
+        self.bounded(arg, i32::MIN as i64, u32::MAX as i64, "32 bits")
+            .map(|v| v as Data)
+    }
+
+    fn instruction(
+        &mut self,
+        statement: &Statement,
+        op: OpCode,
+        args: &[Arg],
+    ) -> Result<Data, AsmError> {
+        let (arity, usage) = match op {
+            OpCode::HALT => (0, "no operands"),
+            OpCode::ALLOC | OpCode::CALL => (2, "2 registers"),
+            OpCode::FREE | OpCode::OUT | OpCode::IN => (1, "1 register"),
+            OpCode::CONST => (2, "a register and a value"),
+            _ => (3, "3 registers"),
+        };
+        if args.len() != arity {
+            return Err(AsmError::new(
+                args.get(arity).map_or(statement.span, |a| a.span),
+                format!("'{}' takes {}, found {}", op, usage, args.len()),
+            ));
+        }
+        let registers =
+            |n| -> Result<Vec<Register>, AsmError> { args[..n].iter().map(register).collect() };
+        let instruction = match op {
+            OpCode::HALT => Ok(Instruction::halt()),
+            OpCode::ALLOC | OpCode::CALL | OpCode::FREE | OpCode::OUT | OpCode::IN => {
+                let r = registers(arity)?;
+                match op {
+                    OpCode::ALLOC => Instruction::alloc(r[0], r[1]),
+                    OpCode::CALL => Instruction::call(r[0], r[1]),
+                    OpCode::FREE => Instruction::free(r[0]),
+                    OpCode::OUT => Instruction::out(r[0]),
+                    _ => Instruction::input(r[0]),
+                }
+            }
+            OpCode::CONST => {
+                let value = self.bounded(&args[1], 0, 0x1FFFFFF, "25 bits")?;
+                Instruction::orth(registers(1)?[0], value as Data)
+            }
+            _ => {
+                let r = registers(3)?;
+                let constructor = match op {
+                    OpCode::CMOV => Instruction::cmov,
+                    OpCode::LOAD => Instruction::load,
+                    OpCode::STORE => Instruction::store,
+                    OpCode::ADD => Instruction::add,
+                    OpCode::MUL => Instruction::mul,
+                    OpCode::DIV => Instruction::div,
+                    _ => Instruction::nand,
+                };
+                constructor(r[0], r[1], r[2])
+            }
+        };
+        // The parser only accepts r0-r7, so this is an orth value out of range
+        instruction
+            .map(|i| i.encode())
+            .map_err(|e| AsmError::new(args[arity - 1].span, e.to_string()))
+    }
+
+    /// Platters a statement occupies
+    fn size(&mut self, statement: &Statement) -> Result<Data, AsmError> {
+        match &statement.kind {
+            Kind::Label(_) => Ok(0),
+            Kind::Instruction { .. } => Ok(1),
+            Kind::Directive { name, args } => match name.as_str() {
+                ".scratch" | ".stack" | ".global" | ".extern" => Ok(0),
+                ".word" => Ok(args.len() as Data),
+                ".string" => match args.as_slice() {
+                    [Arg {
+                        operand: Operand::Str(text),
+                        ..
+                    }] => Ok(text.chars().count() as Data + 1),
+                    _ => Err(AsmError::new(
+                        statement.span,
+                        "'.string' takes one string".to_string(),
+                    )),
+                },
+                ".zero" => match args.as_slice() {
+                    [arg @ Arg {
+                        operand: Operand::Value(expr),
+                        ..
+                    }] if expr.symbols().next().is_none() => {
+                        Ok(self.bounded(arg, 0, u32::MAX as i64, "32 bits")? as Data)
+                    }
+                    _ => Err(AsmError::new(
+                        statement.span,
+                        "'.zero' takes one constant count".to_string(),
+                    )),
+                },
+                _ => Err(AsmError::new(
+                    statement.span,
+                    format!("unknown directive '{}'", name),
+                )),
+            },
+        }
+    }
+
+    /// Apply `.scratch` and `.stack`, and expand pseudo-instructions
+    fn expansion(&mut self, statement: &Statement) -> Result<Option<Vec<Step>>, AsmError> {
+        let registers = |args: &[Arg]| -> Option<Vec<Register>> {
+            args.iter()
+                .map(|a| match a.operand {
+                    Operand::Register(r) => Some(r),
+                    _ => None,
+                })
+                .collect()
+        };
+        match &statement.kind {
+            Kind::Instruction { mnemonic, args } => {
+                pseudo::expand(mnemonic, args, statement.span, self.conventions).transpose()
+            }
+            Kind::Directive { name, args } if name == ".scratch" => {
+                self.conventions.scratch = match registers(args).as_deref() {
+                    Some([]) => None,
+                    Some([s1, s2]) if s1 != s2 => Some((*s1, *s2)),
+                    _ => {
+                        return Err(AsmError::new(
+                            statement.span,
+                            "'.scratch' takes two different registers, or none".to_string(),
+                        ))
+                    }
+                };
+                Ok(None)
+            }
+            Kind::Directive { name, args } if name == ".global" || name == ".extern" => {
+                let names = names(args).ok_or_else(|| {
+                    AsmError::new(statement.span, format!("'{}' takes label names", name))
+                })?;
+                let table = if name == ".global" {
+                    &mut self.globals
+                } else {
+                    &mut self.externs
+                };
+                for (symbol, span) in names {
+                    table.entry(symbol.to_string()).or_insert(span);
+                }
+                Ok(None)
+            }
+            Kind::Directive { name, args } if name == ".stack" => {
+                match registers(args).as_deref() {
+                    Some([k]) => self.conventions.stack = Some(*k),
+                    _ => {
+                        return Err(AsmError::new(
+                            statement.span,
+                            "'.stack' takes one register".to_string(),
+                        ))
+                    }
+                }
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn step(&mut self, step: &Step, start: Data, span: Span) -> Result<Data, AsmError> {
+        match step {
+            Step::Word(instruction) => Ok(instruction.encode()),
+            Step::Orth(a, expr, span) => {
+                let value = self.value(expr)?;
+                let value = u32::try_from(value)
+                    .map_err(|_| AsmError::new(*span, format!("value {} is negative", value)))?;
+                Instruction::orth(*a, value)
+                    .map(|i| i.encode())
+                    .map_err(|e| AsmError::new(*span, e.to_string()))
+            }
+            Step::Relative(a, n) => {
+                self.references.push((false, None));
+                Ok(Instruction::orth(*a, start + n)
+                    .map_err(|e| AsmError::new(span, e.to_string()))?
+                    .encode())
+            }
+        }
+    }
+
+    fn emit(
+        &mut self,
+        statement: &Statement,
+        expansion: Option<&[Step]>,
+        out: &mut Vec<Data>,
+    ) -> Result<(), AsmError> {
+        if let Some(steps) = expansion {
+            let start = out.len() as Data;
+            for step in steps {
+                out.push(self.step(step, start, statement.span)?);
+                self.relocate(out, true);
+            }
+            return Ok(());
+        }
+        match &statement.kind {
+            Kind::Label(_) => {}
+            Kind::Instruction { mnemonic, args } => match OpCode::from_mnemonic(mnemonic) {
+                Some(op) => {
+                    out.push(self.instruction(statement, op, args)?);
+                    self.relocate(out, op == OpCode::CONST);
+                }
+                None => {
+                    return Err(AsmError::new(
+                        statement.span,
+                        format!("unknown mnemonic '{}'", mnemonic),
+                    ))
+                }
+            },
+            Kind::Directive { name, args } => match name.as_str() {
+                ".word" => {
+                    for arg in args {
+                        out.push(self.word(arg)?);
+                        self.relocate(out, false);
+                    }
+                }
+                ".string" => {
+                    if let Operand::Str(text) = &args[0].operand {
+                        out.extend(text.chars().map(|c| c as Data));
+                        out.push(0);
+                    }
+                }
+                _ => {
+                    let count = self.size(statement)?;
+                    out.extend(std::iter::repeat_n(0, count as usize));
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Reads a file named by `.include`
+pub type Loader<'a> = &'a dyn Fn(&str) -> io::Result<String>;
+
+pub fn read_file(path: &str) -> io::Result<String> {
+    std::fs::read_to_string(path)
+}
+
+struct Sources<'a> {
+    files: Vec<String>,
+    load: Loader<'a>,
+}
+
+impl<'a> Sources<'a> {
+    /// The statements of `source`, with included files spliced in
+    fn statements(
+        &mut self,
+        file: usize,
+        source: &str,
+        out: &mut Vec<Statement>,
+    ) -> Result<(), AsmError> {
+        for statement in parser::parse(source, file)? {
+            let args = match &statement.kind {
+                Kind::Directive { name, args } if name == ".include" => args,
+                _ => {
+                    out.push(statement);
+                    continue;
+                }
+            };
+            let included = match args.as_slice() {
+                [Arg {
+                    operand: Operand::Str(path),
+                    ..
+                }] => path,
+                _ => {
+                    return Err(AsmError::new(
+                        statement.span,
+                        "'.include' takes one file name".to_string(),
+                    ))
+                }
+            };
+            if let Some(text) = stdlib::source(included) {
+                if !self.files.iter().any(|f| f == included) {
+                    self.files.push(included.clone());
+                    self.statements(self.files.len() - 1, text, out)?;
+                }
+                continue;
+            }
+            // Relative to the including file, with ./ and dir/.. folded away
+            // so one file reached two ways is still included once
+            let mut path = std::path::PathBuf::new();
+            let joined = Path::new(&self.files[file])
+                .parent()
+                .unwrap_or_else(|| Path::new(""))
+                .join(included);
+            for component in joined.components() {
+                match component {
+                    Component::CurDir => {}
+                    Component::ParentDir
+                        if matches!(path.components().next_back(), Some(Component::Normal(_))) =>
+                    {
+                        path.pop();
+                    }
+                    _ => path.push(component),
+                }
+            }
+            let path = path.to_string_lossy().into_owned();
+            if self.files.contains(&path) {
+                continue;
+            }
+            let text = (self.load)(&path).map_err(|e| {
+                AsmError::new(statement.span, format!("cannot read '{}': {}", path, e))
+            })?;
+            self.files.push(path);
+            self.statements(self.files.len() - 1, &text, out)?;
+        }
+        Ok(())
+    }
+}
+
+fn assemble_statements(statements: &[Statement]) -> Result<Assembly, AsmError> {
+    let mut assembler = Assembler {
+        symbols: BTreeMap::new(),
+        conventions: Conventions::default(),
+        globals: BTreeMap::new(),
+        externs: BTreeMap::new(),
+        references: Vec::new(),
+        relocations: Vec::new(),
+    };
+    let mut expansions = Vec::with_capacity(statements.len());
+    let mut offset: Data = 0;
+    for statement in statements {
+        if let Kind::Label(name) = &statement.kind {
+            if let Some((_, first)) = assembler.symbols.get(name) {
+                return Err(AsmError::new(
+                    statement.span,
+                    format!(
+                        "duplicate label '{}', first defined on line {}",
+                        name, first.line
+                    ),
+                ));
+            }
+            assembler
+                .symbols
+                .insert(name.clone(), (offset, statement.span));
+        }
+        let expansion = assembler.expansion(statement)?;
+        let size = match &expansion {
+            Some(steps) => steps.len() as Data,
+            None => assembler.size(statement)?,
+        };
+        expansions.push(expansion);
+        offset = offset
+            .checked_add(size)
+            .ok_or_else(|| AsmError::new(statement.span, "program too large".to_string()))?;
+    }
+    for (name, span) in &assembler.externs {
+        if assembler.symbols.contains_key(name) {
+            return Err(AsmError::new(
+                *span,
+                format!("'{}' is declared .extern but defined here too", name),
+            ));
+        }
+    }
+    for (name, span) in &assembler.globals {
+        if !assembler.symbols.contains_key(name) {
+            return Err(AsmError::new(
+                *span,
+                format!("'{}' is declared .global but never defined", name),
+            ));
+        }
+    }
+    let mut assembly = Assembly {
+        words: Vec::with_capacity(offset as usize),
+        ..Assembly::default()
+    };
+    for (statement, expansion) in statements.iter().zip(&expansions) {
+        let start = assembly.words.len() as Data;
+        assembler.emit(statement, expansion.as_deref(), &mut assembly.words)?;
+        if let Kind::Label(_) = statement.kind {
+            continue;
+        }
+        assembly.placements.push(Placement {
+            span: statement.span,
+            offset: start,
+            length: assembly.words.len() as Data - start,
+            expanded: expansion.is_some(),
+        });
+    }
+    assembly.symbols = assembler
+        .symbols
+        .into_iter()
+        .map(|(name, (offset, _))| (name, offset))
+        .collect();
+    assembly.globals = assembler.globals.into_keys().collect();
+    assembly.externs = assembler.externs;
+    assembly.relocations = assembler.relocations;
+    Ok(assembly)
+}
+
+/// The statements of the file `name`, with included files spliced in, and
+/// the files their spans refer to
+pub fn statements_with(
+    name: &str,
+    source: &str,
+    load: Loader,
+) -> Result<(Vec<String>, Vec<Statement>), AsmError> {
+    let mut sources = Sources {
+        files: vec![name.to_string()],
+        load,
+    };
+    let mut statements = Vec::new();
+    match sources.statements(0, source, &mut statements) {
+        Ok(()) => Ok((sources.files, statements)),
+        Err(error) => Err(error.in_files(&sources.files)),
+    }
+}
+
+/// Assemble the file `name` with contents `source`, reading includes with `load`
+pub fn build_with(name: &str, source: &str, load: Loader) -> Result<Assembly, AsmError> {
+    let (files, statements) = statements_with(name, source, load)?;
+    match assemble_statements(&statements) {
+        Ok(assembly) => Ok(Assembly { files, ..assembly }),
+        Err(error) => Err(error.in_files(&files)),
+    }
+}
+
+/// Assemble `source`, keeping labels and placements for listings and tools
+pub fn build(source: &str) -> Result<Assembly, AsmError> {
+    build_with("<input>", source, &read_file)
+}
+
+/// Assemble `source` into a program image
+pub fn assemble(source: &str) -> Result<Vec<Data>, AsmError> {
+    build(source)?.image()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm;
+
+    #[test]
+    fn every_operator() {
+        let source = "
+            cmov r0, r1, r2
+            load r6, r7, r1
+            store r7, r1, r2
+            add r1, r2, r3
+            mul r4, r5, r6
+            div r7, r0, r1
+            nand r0, r1, r2
+            halt
+            alloc r7, r0
+            free r1
+            out r7
+            in r2
+            call r5, r1
+            orth r7, 0x1234
+        ";
+        let program = assemble(source).unwrap();
+        let expected: Vec<String> = source
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .map(String::from)
+            .collect();
+        let listed: Vec<String> = program.iter().map(|w| disasm::text(*w)).collect();
+        assert_eq!(listed, expected);
+    }
+
+    #[test]
+    fn labels_and_data() {
+        let program = assemble(
+            "start:  orth r1, message   ; forward reference
+                    orth r2, end - start
+                    halt
+            message: .string \"a\\n\"
+                    .word 'x', -1, start + 7
+                    .zero 2
+            end:",
+        )
+        .unwrap();
+        assert_eq!(
+            program,
+            vec![0xD2000003, 0xD400000B, 0x70000000, 97, 10, 0, 120, 0xFFFFFFFF, 7, 0, 0]
+        );
+    }
+
+    #[test]
+    fn runs_on_the_cpu() {
+        let program = assemble(
+            "       orth r0, 3
+                    orth r1, 4
+                    mul r2, r0, r1      ; 12
+                    orth r3, '0'
+                    add r2, r2, r3
+                    halt",
+        )
+        .unwrap();
+        let mut cpu = crate::CPU::new(program);
+        cpu.interpret();
+        assert_eq!(cpu.register_file[2], 12 + 48);
+    }
+
+    #[test]
+    fn errors() {
+        let error = |source| assemble(source).unwrap_err().to_string();
+        assert_eq!(error("  ad r0, r1, r2"), "1:3: unknown mnemonic 'ad'");
+        assert_eq!(error("add r0, r1"), "1:1: 'add' takes 3 registers, found 2");
+        assert_eq!(error("out r1, r2"), "1:9: 'out' takes 1 register, found 2");
+        assert_eq!(error("\nadd r0, 1, r2"), "2:9: expected a register");
+        assert_eq!(
+            error("orth r0, 0x2000000"),
+            "1:10: value 33554432 does not fit in 25 bits"
+        );
+        assert_eq!(
+            error("orth r0, -1"),
+            "1:10: value -1 does not fit in 25 bits"
+        );
+        assert_eq!(error("orth r0, missing"), "1:10: undefined label 'missing'");
+        assert_eq!(
+            error("a: halt\na: halt"),
+            "2:1: duplicate label 'a', first defined on line 1"
+        );
+        assert_eq!(
+            error(".zero n\nn:"),
+            "1:1: '.zero' takes one constant count"
+        );
+        assert_eq!(error(".byte 1"), "1:1: unknown directive '.byte'");
+    }
+
+    #[test]
+    fn rendered_errors() {
+        let source = "halt\n  orth r1, 0x2000000\n";
+        let error = build_with("t.uma", source, &read_file).unwrap_err();
+        assert_eq!(
+            error.render(source),
+            "t.uma:2:12: value 33554432 does not fit in 25 bits\n  |\n2 |   orth r1, 0x2000000\n  |            ^^^^^^^^^"
+        );
+    }
+
+    #[test]
+    fn includes() {
+        let load = |path: &str| -> io::Result<String> {
+            match path {
+                "lib/util.uma" => {
+                    Ok(".include \"../lib/./consts.uma\"\nnine: orth r1, NINE\n".into())
+                }
+                "lib/consts.uma" => Ok(".include \"util.uma\"\nNINE: halt\n".into()),
+                "lib/bad.uma" => Ok("halt\n  hlat\n".into()),
+                _ => Err(io::Error::new(io::ErrorKind::NotFound, "not found")),
+            }
+        };
+        // Each file once, so the cycle between util and consts is harmless
+        let source = "jmp nine\n.include \"lib/util.uma\"\n.include \"lib/consts.uma\"\n";
+        let assembly = build_with("main.uma", source, &load).unwrap();
+        assert_eq!(
+            assembly.files,
+            vec!["main.uma", "lib/util.uma", "lib/consts.uma"]
+        );
+        assert_eq!(assembly.symbols["nine"], assembly.symbols["NINE"] + 1);
+
+        let error = build_with("main.uma", ".include \"lib/bad.uma\"", &load).unwrap_err();
+        assert_eq!((error.file.as_str(), error.span.line), ("lib/bad.uma", 2));
+        let error = build_with("main.uma", "\n.include \"gone.uma\"", &load).unwrap_err();
+        assert_eq!(error.to_string(), "2:1: cannot read 'gone.uma': not found");
+    }
+
+    #[test]
+    fn externs_need_linking() {
+        let error = assemble(".extern f\njmp f").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "1:9: 'f' is external; assemble with -c and link"
+        );
+        assert!(assemble(".extern f\nhalt").is_ok());
+        let error = assemble(".global g\nhalt").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "1:9: 'g' is declared .global but never defined"
+        );
+    }
+}
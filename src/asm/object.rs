@@ -0,0 +1,261 @@
+// Object files: an assembled module that still needs linking, as JSON:
+//
+//     {"object":1,
+//      "words":[3489660928, ...],
+//      "symbols":{"main":0,"loop":4},
+//      "globals":["main"],
+//      "externs":["print"],
+//      "relocations":[{"offset":1,"orth":true,"symbol":"print","negated":false}],
+//      "files":["main.uma"],
+//      "lines":[[0,1,0,3], ...]}
+//
+// `.global NAME` exports a label to other modules and `.extern NAME` lets
+// a module use one exported elsewhere. Every word whose value involves a
+// label gets a relocation: a null symbol means the start of the module,
+// since every label moves with it. "files" and "lines" are as in a symbol
+// map, so a linked program can have one; objects without them link too.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+
+use super::{Assembly, Relocation};
+use crate::json::Json;
+use crate::symbols::{self, Symbols};
+use crate::Data;
+
+const VERSION: u64 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Object {
+    pub words: Vec<Data>,
+    pub symbols: BTreeMap<String, Data>,
+    pub globals: BTreeSet<String>,
+    pub externs: BTreeSet<String>,
+    pub relocations: Vec<Relocation>,
+    pub files: Vec<String>,
+    /// Source lines as in `Symbols::lines`
+    pub lines: BTreeMap<Data, (Data, usize, usize)>,
+}
+
+impl From<&Assembly> for Object {
+    fn from(assembly: &Assembly) -> Object {
+        let Symbols { files, lines, .. } = Symbols::from(assembly);
+        Object {
+            words: assembly.words.clone(),
+            symbols: assembly.symbols.clone(),
+            globals: assembly.globals.clone(),
+            externs: assembly.externs.keys().cloned().collect(),
+            relocations: assembly.relocations.clone(),
+            files,
+            lines,
+        }
+    }
+}
+
+fn words(value: Option<&Json>) -> Option<Vec<Data>> {
+    value?
+        .as_array()?
+        .iter()
+        .map(|w| w.as_u64().and_then(|w| Data::try_from(w).ok()))
+        .collect()
+}
+
+fn names(value: Option<&Json>) -> Option<BTreeSet<String>> {
+    value?
+        .as_array()?
+        .iter()
+        .map(|n| n.as_str().map(String::from))
+        .collect()
+}
+
+impl Object {
+    pub fn to_json(&self) -> Json {
+        let names = |set: &BTreeSet<String>| {
+            Json::from(set.iter().map(|n| n.as_str().into()).collect::<Vec<_>>())
+        };
+        Json::object(vec![
+            ("object", VERSION.into()),
+            (
+                "words",
+                self.words
+                    .iter()
+                    .map(|w| (*w).into())
+                    .collect::<Vec<_>>()
+                    .into(),
+            ),
+            (
+                "symbols",
+                Json::Object(
+                    self.symbols
+                        .iter()
+                        .map(|(name, offset)| (name.clone(), (*offset).into()))
+                        .collect(),
+                ),
+            ),
+            ("globals", names(&self.globals)),
+            ("externs", names(&self.externs)),
+            (
+                "relocations",
+                self.relocations
+                    .iter()
+                    .map(|r| {
+                        Json::object(vec![
+                            ("offset", r.offset.into()),
+                            ("orth", r.orth.into()),
+                            ("symbol", r.symbol.as_deref().map_or(Json::Null, Json::from)),
+                            ("negated", r.negated.into()),
+                        ])
+                    })
+                    .collect::<Vec<_>>()
+                    .into(),
+            ),
+            (
+                "files",
+                Json::from(
+                    self.files
+                        .iter()
+                        .map(|f| f.as_str().into())
+                        .collect::<Vec<_>>(),
+                ),
+            ),
+            ("lines", symbols::lines_to_json(&self.lines)),
+        ])
+    }
+
+    pub fn from_json(json: &Json) -> Result<Object, String> {
+        if json.get("object").and_then(Json::as_u64) != Some(VERSION) {
+            return Err("not a version 1 object file".to_string());
+        }
+        let bad = |what: &str| format!("malformed {}", what);
+        let symbols = match json.get("symbols") {
+            Some(Json::Object(map)) => map
+                .iter()
+                .map(|(name, offset)| {
+                    let offset = offset.as_u64().and_then(|o| Data::try_from(o).ok());
+                    offset.map(|o| (name.clone(), o))
+                })
+                .collect::<Option<_>>()
+                .ok_or_else(|| bad("symbols"))?,
+            _ => return Err(bad("symbols")),
+        };
+        let relocations = json
+            .get("relocations")
+            .and_then(Json::as_array)
+            .ok_or_else(|| bad("relocations"))?
+            .iter()
+            .map(|r| {
+                Some(Relocation {
+                    offset: Data::try_from(r.get("offset")?.as_u64()?).ok()?,
+                    orth: r.get("orth")?.as_bool()?,
+                    symbol: match r.get("symbol")? {
+                        Json::Null => None,
+                        name => Some(name.as_str()?.to_string()),
+                    },
+                    negated: r.get("negated")?.as_bool()?,
+                })
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| bad("relocations"))?;
+        let files = match json.get("files") {
+            None => Vec::new(),
+            Some(files) => files
+                .as_array()
+                .and_then(|files| files.iter().map(|f| f.as_str().map(String::from)).collect())
+                .ok_or_else(|| bad("files"))?,
+        };
+        let lines = match json.get("lines") {
+            None => BTreeMap::new(),
+            Some(lines) => {
+                symbols::lines_from_json(lines, files.len()).ok_or_else(|| bad("lines"))?
+            }
+        };
+        let object = Object {
+            words: words(json.get("words")).ok_or_else(|| bad("words"))?,
+            symbols,
+            globals: names(json.get("globals")).ok_or_else(|| bad("globals"))?,
+            externs: names(json.get("externs")).ok_or_else(|| bad("externs"))?,
+            relocations,
+            files,
+            lines,
+        };
+        if object
+            .relocations
+            .iter()
+            .any(|r| r.offset as usize >= object.words.len())
+        {
+            return Err(bad("relocations"));
+        }
+        if let Some(global) = object
+            .globals
+            .iter()
+            .find(|g| !object.symbols.contains_key(*g))
+        {
+            return Err(format!("global '{}' has no symbol", global));
+        }
+        Ok(object)
+    }
+
+    pub fn load(path: &str) -> Result<Object, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        Object::from_json(&crate::json::parse(&text)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::build;
+    use crate::json;
+
+    #[test]
+    fn relocations_and_round_trip() {
+        let assembly = build(
+            "       .global main
+                    .extern print
+            main:   orth r1, message
+                    orth r2, print + 1
+                    orth r3, end - main
+                    halt
+            message: .word message, 7
+            end:",
+        )
+        .unwrap();
+        let object = Object::from(&assembly);
+        let relocation = |offset, symbol: Option<&str>, negated| Relocation {
+            offset,
+            orth: offset < 4,
+            symbol: symbol.map(String::from),
+            negated,
+        };
+        assert_eq!(
+            object.relocations,
+            vec![
+                relocation(0, None, false),
+                relocation(1, Some("print"), false),
+                relocation(2, None, false),
+                relocation(2, None, true),
+                relocation(4, None, false),
+            ]
+        );
+        assert_eq!(object.words[1], 0xD4000001);
+        let text = object.to_json().to_string();
+        assert_eq!(Object::from_json(&json::parse(&text).unwrap()), Ok(object));
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(Object::from_json(&json::parse("{\"object\":2}").unwrap()).is_err());
+        let bad = r#"{"object":1,"words":[1],"symbols":{},"globals":[],"externs":[],
+            "relocations":[{"offset":5,"orth":false,"symbol":null,"negated":false}]}"#;
+        assert_eq!(
+            Object::from_json(&json::parse(bad).unwrap()),
+            Err("malformed relocations".to_string())
+        );
+        let unlabelled = r#"{"object":1,"words":[],"symbols":{"main":0},
+            "globals":["main","print"],"externs":[],"relocations":[]}"#;
+        assert_eq!(
+            Object::from_json(&json::parse(unlabelled).unwrap()),
+            Err("global 'print' has no symbol".to_string())
+        );
+    }
+}
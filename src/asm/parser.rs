@@ -0,0 +1,312 @@
+// Statements of UM assembly, one line at a time:
+//
+//     [label:]... [mnemonic operand, ...]
+//     [label:]... [.directive argument, ...]
+//
+// Operands are registers r0-r7, strings, or sums of numbers and symbols.
+
+use super::lexer::{tokenize, Lexeme, Token};
+use super::{AsmError, Span};
+use crate::Register;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Term {
+    Number(u64),
+    Symbol(String, Span),
+}
+
+/// Terms added together, each possibly negated
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub terms: Vec<(bool, Term)>,
+}
+
+impl Expr {
+    pub fn symbols(&self) -> impl Iterator<Item = (&str, Span)> {
+        self.terms.iter().filter_map(|(_, term)| match term {
+            Term::Symbol(name, span) => Some((name.as_str(), *span)),
+            Term::Number(_) => None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Register(Register),
+    Value(Expr),
+    Str(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Arg {
+    pub operand: Operand,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Kind {
+    Label(String),
+    Instruction { mnemonic: String, args: Vec<Arg> },
+    Directive { name: String, args: Vec<Arg> },
+}
+
+/// A statement; the span covers its label, mnemonic or directive name
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement {
+    pub kind: Kind,
+    pub span: Span,
+}
+
+/// `r0` to `r7`
+pub fn register(name: &str) -> Option<Register> {
+    match name.strip_prefix('r')?.as_bytes() {
+        [digit @ b'0'..=b'7'] => Some(digit - b'0'),
+        _ => None,
+    }
+}
+
+struct Parser {
+    lexemes: Vec<Lexeme>,
+    next: usize,
+    end: Span,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.lexemes.get(self.next).map(|l| &l.token)
+    }
+
+    fn span(&self) -> Span {
+        self.lexemes.get(self.next).map_or(self.end, |l| l.span)
+    }
+
+    fn bump(&mut self) -> Option<Lexeme> {
+        let lexeme = self.lexemes.get(self.next).cloned();
+        self.next += 1;
+        lexeme
+    }
+
+    fn at_end_of_line(&self) -> bool {
+        matches!(self.peek(), None | Some(Token::Newline))
+    }
+
+    fn expected<T>(&self, what: &str) -> Result<T, AsmError> {
+        let found = match self.peek() {
+            None => "end of input".to_string(),
+            Some(Token::Newline) => "end of line".to_string(),
+            Some(Token::Ident(name)) | Some(Token::Directive(name)) => format!("'{}'", name),
+            Some(Token::Number(n)) => format!("'{}'", n),
+            Some(Token::Str(_)) => "a string".to_string(),
+            Some(Token::Comma) => "','".to_string(),
+            Some(Token::Colon) => "':'".to_string(),
+            Some(Token::Plus) => "'+'".to_string(),
+            Some(Token::Minus) => "'-'".to_string(),
+        };
+        Err(AsmError::new(
+            self.span(),
+            format!("expected {}, found {}", what, found),
+        ))
+    }
+
+    fn term(&mut self) -> Result<Term, AsmError> {
+        match self.peek() {
+            Some(Token::Number(n)) => {
+                let n = *n;
+                self.bump();
+                Ok(Term::Number(n))
+            }
+            Some(Token::Ident(name)) if register(name).is_none() => {
+                let name = name.clone();
+                let span = self.span();
+                self.bump();
+                Ok(Term::Symbol(name, span))
+            }
+            _ => self.expected("a number or label"),
+        }
+    }
+
+    fn arg(&mut self) -> Result<Arg, AsmError> {
+        let start = self.span();
+        let operand = match self.peek() {
+            Some(Token::Ident(name)) if register(name).is_some() => {
+                let r = register(name).unwrap();
+                self.bump();
+                Operand::Register(r)
+            }
+            Some(Token::Str(text)) => {
+                let text = text.clone();
+                self.bump();
+                Operand::Str(text)
+            }
+            _ => {
+                let mut terms = Vec::new();
+                let mut negated = false;
+                if self.peek() == Some(&Token::Minus) {
+                    self.bump();
+                    negated = true;
+                }
+                loop {
+                    terms.push((negated, self.term()?));
+                    negated = match self.peek() {
+                        Some(Token::Plus) => false,
+                        Some(Token::Minus) => true,
+                        _ => break,
+                    };
+                    self.bump();
+                }
+                Operand::Value(Expr { terms })
+            }
+        };
+        let last = self.lexemes[self.next - 1].span;
+        let span = Span {
+            length: last.column + last.length - start.column,
+            ..start
+        };
+        Ok(Arg { operand, span })
+    }
+
+    fn args(&mut self) -> Result<Vec<Arg>, AsmError> {
+        let mut args = Vec::new();
+        if self.at_end_of_line() {
+            return Ok(args);
+        }
+        loop {
+            args.push(self.arg()?);
+            if self.at_end_of_line() {
+                return Ok(args);
+            }
+            if self.peek() != Some(&Token::Comma) {
+                return self.expected("',' or end of line");
+            }
+            self.bump();
+        }
+    }
+
+    fn line(&mut self, statements: &mut Vec<Statement>) -> Result<(), AsmError> {
+        loop {
+            let span = self.span();
+            match self.peek().cloned() {
+                None => return Ok(()),
+                Some(Token::Newline) => {
+                    self.bump();
+                    return Ok(());
+                }
+                Some(Token::Ident(name)) => {
+                    self.bump();
+                    if self.peek() == Some(&Token::Colon) {
+                        self.bump();
+                        if register(&name).is_some() {
+                            return Err(AsmError::new(
+                                span,
+                                format!("'{}' is a register and cannot be a label", name),
+                            ));
+                        }
+                        statements.push(Statement {
+                            kind: Kind::Label(name),
+                            span,
+                        });
+                        continue;
+                    }
+                    let args = self.args()?;
+                    statements.push(Statement {
+                        kind: Kind::Instruction {
+                            mnemonic: name,
+                            args,
+                        },
+                        span,
+                    });
+                }
+                Some(Token::Directive(name)) => {
+                    self.bump();
+                    let args = self.args()?;
+                    statements.push(Statement {
+                        kind: Kind::Directive { name, args },
+                        span,
+                    });
+                }
+                Some(_) => return self.expected("a label, mnemonic or directive"),
+            }
+        }
+    }
+}
+
+pub fn parse(source: &str, file: usize) -> Result<Vec<Statement>, AsmError> {
+    let lexemes = tokenize(source, file)?;
+    let end = match lexemes.last() {
+        Some(last) => Span {
+            column: last.span.column + last.span.length,
+            length: 1,
+            ..last.span
+        },
+        None => Span {
+            file,
+            line: 1,
+            column: 1,
+            length: 1,
+        },
+    };
+    let mut parser = Parser {
+        lexemes,
+        next: 0,
+        end,
+    };
+    let mut statements = Vec::new();
+    while parser.peek().is_some() {
+        parser.line(&mut statements)?;
+    }
+    Ok(statements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_and_operands() {
+        let statements = parse("start: loop:\n  orth r1, end - start + 2\n", 0).unwrap();
+        assert_eq!(statements.len(), 3);
+        assert_eq!(statements[1].kind, Kind::Label("loop".to_string()));
+        match &statements[2].kind {
+            Kind::Instruction { mnemonic, args } => {
+                assert_eq!(mnemonic, "orth");
+                assert_eq!(args[0].operand, Operand::Register(1));
+                let expr = match &args[1].operand {
+                    Operand::Value(expr) => expr,
+                    other => panic!("{:?}", other),
+                };
+                assert_eq!(expr.terms.len(), 3);
+                assert!(expr.terms[1].0);
+                assert_eq!(expr.terms[2].1, Term::Number(2));
+                assert_eq!(args[1].span.column, 12);
+                assert_eq!(args[1].span.length, 15);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn directives() {
+        let statements = parse(".string \"hi\"\n.word 1, -1", 0).unwrap();
+        match &statements[1].kind {
+            Kind::Directive { name, args } => {
+                assert_eq!(name, ".word");
+                assert_eq!(args.len(), 2);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let error = parse("add r0 r1, r2", 0).unwrap_err();
+        assert_eq!(error.message, "expected ',' or end of line, found 'r1'");
+        assert_eq!(error.span.column, 8);
+        let error = parse("r3: halt", 0).unwrap_err();
+        assert_eq!(error.span.column, 1);
+        let error = parse("orth r1,", 0).unwrap_err();
+        assert_eq!(
+            error.message,
+            "expected a number or label, found end of input"
+        );
+    }
+}
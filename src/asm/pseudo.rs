@@ -0,0 +1,547 @@
+// Pseudo-instructions, expanded into real ones:
+//
+//     mov rA, rB          nand rA, rB, rB; nand rA, rA, rA
+//     not rA, rB          nand rA, rB, rB
+//     and rA, rB, rC      nand rA, rB, rC; nand rA, rA, rA
+//     or rA, rB, rC       3 words, uses S1
+//     xor rA, rB, rC      4 words, uses S1
+//     sub rA, rB, rC      rB + ~rC + 1, uses S1
+//     li rA, VALUE        orth when VALUE fits in 25 bits, else up to 5 words
+//     jmp LABEL | rC      call through array 0, uses S1 and S2
+//     jz rX, LABEL        jump when rX is 0, uses S1 and S2
+//     jnz rX, LABEL       jump when rX is not 0, uses S1 and S2
+//     push rX / pop rX    uses S1, S2 and the stack register
+//     jsr LABEL | rC      push the return offset and jump, like push
+//     ret                 pop an offset and jump to it, like pop
+//
+// S1 and S2 are the scratch registers, r6 and r7 unless changed with
+// `.scratch rA, rB` (or disabled with a bare `.scratch`). Any pseudo-
+// instruction may clobber them, so they cannot be its operands.
+//
+// The stack register, set with `.stack rK`, holds the id of an array whose
+// platter 0 counts the words pushed; the words follow from platter 1 on.
+// Pushing past the end of the array faults like any other store.
+
+use super::parser::{Arg, Expr, Operand, Term};
+use super::{AsmError, Span};
+use crate::{Data, Instruction, Register};
+
+pub const MNEMONICS: [&str; 14] = [
+    "mov", "not", "and", "or", "xor", "sub", "li", "jmp", "jz", "jnz", "push", "pop", "jsr", "ret",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Conventions {
+    pub scratch: Option<(Register, Register)>,
+    pub stack: Option<Register>,
+}
+
+impl Default for Conventions {
+    fn default() -> Conventions {
+        Conventions {
+            scratch: Some((6, 7)),
+            stack: None,
+        }
+    }
+}
+
+/// One word of an expansion
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    Word(Instruction),
+    /// orth of a value that may name labels
+    Orth(Register, Expr, Span),
+    /// orth of the offset this many platters past the start of the expansion
+    Relative(Register, Data),
+}
+
+fn word(instruction: Result<Instruction, crate::EncodeError>) -> Step {
+    // Registers are checked by the parser and constants below, so this holds
+    Step::Word(instruction.expect("operands were checked"))
+}
+
+fn orth(a: Register, value: Data) -> Step {
+    word(Instruction::orth(a, value))
+}
+
+fn nand(a: Register, b: Register, c: Register) -> Step {
+    word(Instruction::nand(a, b, c))
+}
+
+struct Expansion<'a> {
+    mnemonic: &'a str,
+    args: &'a [Arg],
+    span: Span,
+    conventions: Conventions,
+}
+
+impl<'a> Expansion<'a> {
+    fn error<T>(&self, span: Span, message: String) -> Result<T, AsmError> {
+        Err(AsmError::new(span, message))
+    }
+
+    /// Check the operand count and kinds, `r` for a register and `v` for a value
+    fn shape(&self, shape: &str) -> Result<(), AsmError> {
+        let usage = shape
+            .chars()
+            .map(|c| if c == 'r' { "rN" } else { "VALUE" })
+            .collect::<Vec<_>>()
+            .join(", ");
+        if self.args.len() != shape.len() {
+            return self.error(self.span, format!("'{}' takes {}", self.mnemonic, usage));
+        }
+        for (arg, kind) in self.args.iter().zip(shape.chars()) {
+            match (&arg.operand, kind) {
+                (Operand::Register(_), 'r') | (Operand::Value(_), 'v') => {}
+                (_, 'r') => return self.error(arg.span, "expected a register".to_string()),
+                _ => return self.error(arg.span, "expected a value".to_string()),
+            }
+        }
+        Ok(())
+    }
+
+    fn register(&self, n: usize) -> Register {
+        match self.args[n].operand {
+            Operand::Register(r) => r,
+            _ => unreachable!("checked by shape"),
+        }
+    }
+
+    fn value(&self, n: usize) -> (Expr, Span) {
+        match &self.args[n].operand {
+            Operand::Value(expr) => (expr.clone(), self.args[n].span),
+            _ => unreachable!("checked by shape"),
+        }
+    }
+
+    /// The scratch registers, which must not also be operands
+    fn scratch(&self) -> Result<(Register, Register), AsmError> {
+        let (s1, s2) = match self.conventions.scratch {
+            Some(scratch) => scratch,
+            None => {
+                return self.error(
+                    self.span,
+                    format!(
+                        "'{}' needs scratch registers; set them with .scratch",
+                        self.mnemonic
+                    ),
+                )
+            }
+        };
+        for arg in self.args {
+            if let Operand::Register(r) = arg.operand {
+                if r == s1 || r == s2 {
+                    return self.error(
+                        arg.span,
+                        format!(
+                            "r{} is a scratch register and '{}' may clobber it",
+                            r, self.mnemonic
+                        ),
+                    );
+                }
+            }
+        }
+        Ok((s1, s2))
+    }
+
+    fn stack(&self, s1: Register, s2: Register) -> Result<Register, AsmError> {
+        match self.conventions.stack {
+            Some(k) if k == s1 || k == s2 => self.error(
+                self.span,
+                format!("the stack register r{} is also a scratch register", k),
+            ),
+            Some(k) => Ok(k),
+            None => self.error(
+                self.span,
+                format!(
+                    "'{}' needs a stack register; set it with .stack",
+                    self.mnemonic
+                ),
+            ),
+        }
+    }
+
+    /// A value without labels, if it is one
+    fn constant(&self, expr: &Expr, span: Span) -> Result<Option<Data>, AsmError> {
+        let mut sum: i64 = 0;
+        for (negated, term) in &expr.terms {
+            match term {
+                Term::Number(n) => sum += if *negated { -(*n as i64) } else { *n as i64 },
+                Term::Symbol(..) => return Ok(None),
+            }
+        }
+        if sum < i32::MIN as i64 || sum > u32::MAX as i64 {
+            return self.error(span, format!("value {} does not fit in 32 bits", sum));
+        }
+        Ok(Some(sum as Data))
+    }
+
+    fn steps(&self) -> Result<Vec<Step>, AsmError> {
+        Ok(match self.mnemonic {
+            "mov" => {
+                self.shape("rr")?;
+                let (a, b) = (self.register(0), self.register(1));
+                vec![nand(a, b, b), nand(a, a, a)]
+            }
+            "not" => {
+                self.shape("rr")?;
+                vec![nand(self.register(0), self.register(1), self.register(1))]
+            }
+            "and" => {
+                self.shape("rrr")?;
+                let (a, b, c) = (self.register(0), self.register(1), self.register(2));
+                vec![nand(a, b, c), nand(a, a, a)]
+            }
+            "or" => {
+                self.shape("rrr")?;
+                let (s1, _) = self.scratch()?;
+                let (a, b, c) = (self.register(0), self.register(1), self.register(2));
+                vec![nand(s1, b, b), nand(a, c, c), nand(a, s1, a)]
+            }
+            "xor" => {
+                self.shape("rrr")?;
+                let (s1, _) = self.scratch()?;
+                let (a, b, c) = (self.register(0), self.register(1), self.register(2));
+                if b == c {
+                    vec![orth(a, 0)]
+                } else {
+                    // s1 = b nand c, then a = (b nand s1) nand (c nand s1),
+                    // computed in the order that reads b and c before a is written
+                    let (first, second) = if a == c { (c, b) } else { (b, c) };
+                    vec![
+                        nand(s1, b, c),
+                        nand(a, first, s1),
+                        nand(s1, second, s1),
+                        nand(a, a, s1),
+                    ]
+                }
+            }
+            "sub" => {
+                self.shape("rrr")?;
+                let (s1, _) = self.scratch()?;
+                let (a, b, c) = (self.register(0), self.register(1), self.register(2));
+                vec![
+                    nand(s1, c, c),
+                    word(Instruction::add(a, b, s1)),
+                    orth(s1, 1),
+                    word(Instruction::add(a, a, s1)),
+                ]
+            }
+            "li" => {
+                self.shape("rv")?;
+                let a = self.register(0);
+                let (expr, span) = self.value(1);
+                match self.constant(&expr, span)? {
+                    None => vec![Step::Orth(a, expr, span)],
+                    Some(v) if v <= 0x1FFFFFF => vec![orth(a, v)],
+                    Some(v) if !v <= 0x1FFFFFF => vec![orth(a, !v), nand(a, a, a)],
+                    Some(v) => {
+                        // (v >> 7) * 128 + (v & 127)
+                        let (s1, _) = self.scratch()?;
+                        vec![
+                            orth(a, v >> 7),
+                            orth(s1, 128),
+                            word(Instruction::mul(a, a, s1)),
+                            orth(s1, v & 0x7F),
+                            word(Instruction::add(a, a, s1)),
+                        ]
+                    }
+                }
+            }
+            "jmp" => {
+                if self.args.len() == 1 {
+                    if let Operand::Register(c) = self.args[0].operand {
+                        let (s1, _) = self.scratch()?;
+                        return Ok(vec![orth(s1, 0), word(Instruction::call(s1, c))]);
+                    }
+                }
+                self.shape("v")?;
+                let (s1, s2) = self.scratch()?;
+                let (expr, span) = self.value(0);
+                vec![
+                    orth(s1, 0),
+                    Step::Orth(s2, expr, span),
+                    word(Instruction::call(s1, s2)),
+                ]
+            }
+            "jz" | "jnz" => {
+                self.shape("rv")?;
+                let (s1, s2) = self.scratch()?;
+                let x = self.register(0);
+                let (expr, span) = self.value(1);
+                // s2 <- taken when x is 0, then s1 replaces it otherwise
+                let (when_zero, otherwise) = if self.mnemonic == "jz" {
+                    (Step::Orth(s2, expr, span), Step::Relative(s1, 5))
+                } else {
+                    (Step::Relative(s2, 5), Step::Orth(s1, expr, span))
+                };
+                vec![
+                    when_zero,
+                    otherwise,
+                    word(Instruction::cmov(s2, s1, x)),
+                    orth(s1, 0),
+                    word(Instruction::call(s1, s2)),
+                ]
+            }
+            "push" => {
+                self.shape("r")?;
+                let (s1, s2) = self.scratch()?;
+                let k = self.stack(s1, s2)?;
+                let x = self.register(0);
+                vec![
+                    orth(s1, 0),
+                    word(Instruction::load(s2, k, s1)),
+                    orth(s1, 1),
+                    word(Instruction::add(s2, s2, s1)),
+                    word(Instruction::store(k, s2, x)),
+                    orth(s1, 0),
+                    word(Instruction::store(k, s1, s2)),
+                ]
+            }
+            "pop" => {
+                self.shape("r")?;
+                let (s1, s2) = self.scratch()?;
+                let k = self.stack(s1, s2)?;
+                let x = self.register(0);
+                if x == k {
+                    return self.error(
+                        self.args[0].span,
+                        format!("cannot pop into the stack register r{}", k),
+                    );
+                }
+                vec![
+                    orth(s1, 0),
+                    word(Instruction::load(s2, k, s1)),
+                    word(Instruction::load(x, k, s2)),
+                    nand(s1, s1, s1),
+                    word(Instruction::add(s2, s2, s1)),
+                    orth(s1, 0),
+                    word(Instruction::store(k, s1, s2)),
+                ]
+            }
+            "jsr" => {
+                let target = match self.args {
+                    [Arg {
+                        operand: Operand::Register(c),
+                        ..
+                    }] => Ok(*c),
+                    _ => {
+                        self.shape("v")?;
+                        Err(self.value(0))
+                    }
+                };
+                let (s1, s2) = self.scratch()?;
+                let k = self.stack(s1, s2)?;
+                let length = if target.is_ok() { 9 } else { 10 };
+                let mut steps = vec![
+                    orth(s1, 0),
+                    word(Instruction::load(s2, k, s1)),
+                    orth(s1, 1),
+                    word(Instruction::add(s2, s2, s1)),
+                    Step::Relative(s1, length),
+                    word(Instruction::store(k, s2, s1)),
+                    orth(s1, 0),
+                    word(Instruction::store(k, s1, s2)),
+                ];
+                match target {
+                    Ok(c) => steps.push(word(Instruction::call(s1, c))),
+                    Err((expr, span)) => {
+                        steps.push(Step::Orth(s2, expr, span));
+                        steps.push(word(Instruction::call(s1, s2)));
+                    }
+                }
+                steps
+            }
+            "ret" => {
+                self.shape("")?;
+                let (s1, s2) = self.scratch()?;
+                let k = self.stack(s1, s2)?;
+                vec![
+                    orth(s1, 0),
+                    word(Instruction::load(s2, k, s1)),
+                    nand(s1, s1, s1),
+                    word(Instruction::add(s2, s2, s1)),
+                    orth(s1, 0),
+                    word(Instruction::store(k, s1, s2)),
+                    orth(s1, 1),
+                    word(Instruction::add(s2, s2, s1)),
+                    word(Instruction::load(s2, k, s2)),
+                    orth(s1, 0),
+                    word(Instruction::call(s1, s2)),
+                ]
+            }
+            _ => unreachable!("not a pseudo-instruction"),
+        })
+    }
+}
+
+/// The expansion of `mnemonic`, or None if it is not a pseudo-instruction
+pub fn expand(
+    mnemonic: &str,
+    args: &[Arg],
+    span: Span,
+    conventions: Conventions,
+) -> Option<Result<Vec<Step>, AsmError>> {
+    if !MNEMONICS.contains(&mnemonic) {
+        return None;
+    }
+    let expansion = Expansion {
+        mnemonic,
+        args,
+        span,
+        conventions,
+    };
+    Some(expansion.steps())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm::assemble;
+    use crate::disasm;
+    use crate::{Data, CPU};
+
+    fn listed(source: &str) -> Vec<String> {
+        assemble(source)
+            .unwrap()
+            .into_iter()
+            .map(disasm::text)
+            .collect()
+    }
+
+    // Run `source` with the given registers and return the final registers
+    fn run(source: &str, registers: &[(usize, Data)]) -> [Data; 8] {
+        let mut cpu = CPU::new(assemble(source).unwrap());
+        for (r, value) in registers {
+            cpu.register_file[*r] = *value;
+        }
+        cpu.interpret();
+        cpu.register_file
+    }
+
+    #[test]
+    fn expansions() {
+        assert_eq!(
+            listed("mov r1, r2"),
+            vec!["nand r1, r2, r2", "nand r1, r1, r1"]
+        );
+        assert_eq!(
+            listed("jmp end\nend: halt"),
+            vec!["orth r6, 0x0", "orth r7, 0x3", "call r6, r7", "halt"]
+        );
+        assert_eq!(listed("li r0, -1"), vec!["orth r0, 0x0", "nand r0, r0, r0"]);
+        assert_eq!(
+            listed(".scratch r4, r5\nnot r4, r6\nor r0, r1, r2")[1],
+            "nand r4, r1, r1"
+        );
+    }
+
+    #[test]
+    fn logic_and_arithmetic() {
+        let b: Data = 0xF0F0_1234;
+        let c = 0x0FF0_4321;
+        for &(op, expected) in &[
+            ("and", b & c),
+            ("or", b | c),
+            ("xor", b ^ c),
+            ("sub", b.wrapping_sub(c)),
+        ] {
+            // Every aliasing of the destination with the sources
+            for &(a, source) in &[(0, "r0, r1, r2"), (1, "r1, r1, r2"), (2, "r2, r1, r2")] {
+                let program = format!("{} {}\nhalt", op, source);
+                let registers = run(&program, &[(1, b), (2, c)]);
+                assert_eq!(registers[a], expected, "{}", program);
+            }
+        }
+        assert_eq!(run("xor r0, r1, r1\nhalt", &[(1, b)])[0], 0);
+        assert_eq!(
+            run("not r0, r1\nmov r2, r1\nhalt", &[(1, b)])[..3],
+            [!b, b, b]
+        );
+    }
+
+    #[test]
+    fn wide_constants() {
+        for &value in &[
+            0u32, 0x1FFFFFF, 0x2000000, 0xDEADBEEF, 0xFFFFFFFF, 0xFE000000,
+        ] {
+            let registers = run(&format!("li r3, {}\nhalt", value), &[]);
+            assert_eq!(registers[3], value);
+        }
+    }
+
+    #[test]
+    fn jumps() {
+        let source = "
+                jz r1, zero
+                orth r0, 'n'
+                halt
+        zero:   jnz r2, nonzero
+                orth r0, 'z'
+                halt
+        nonzero: orth r0, 'y'
+                halt";
+        assert_eq!(run(source, &[(1, 1)])[0], 'n' as Data);
+        assert_eq!(run(source, &[(1, 0), (2, 0)])[0], 'z' as Data);
+        assert_eq!(run(source, &[(1, 0), (2, 5)])[0], 'y' as Data);
+    }
+
+    #[test]
+    fn stack() {
+        let source = "
+                .stack r5
+                orth r0, 8
+                alloc r5, r0
+                orth r1, 11
+                orth r2, 22
+                push r1
+                push r2
+                pop r3
+                pop r4
+                halt";
+        let registers = run(source, &[]);
+        assert_eq!((registers[3], registers[4]), (22, 11));
+    }
+
+    #[test]
+    fn subroutines() {
+        let source = "
+                .stack r5
+                orth r0, 8
+                alloc r5, r0
+                jsr double
+                orth r2, double
+                jsr r2
+                halt
+        double: add r1, r1, r1
+                jsr increment
+                ret
+        increment:
+                orth r3, 1
+                add r1, r1, r3
+                ret";
+        let mut cpu = CPU::new(assemble(source).unwrap());
+        cpu.register_file[1] = 5;
+        cpu.interpret();
+        assert_eq!(cpu.register_file[1], 23);
+        assert_eq!(cpu.array(1).unwrap()[0], 0);
+    }
+
+    #[test]
+    fn errors() {
+        let error = |source| assemble(source).unwrap_err().to_string();
+        assert_eq!(
+            error("or r7, r1, r2"),
+            "1:4: r7 is a scratch register and 'or' may clobber it"
+        );
+        assert_eq!(
+            error(".scratch\njmp x\nx:"),
+            "2:1: 'jmp' needs scratch registers; set them with .scratch"
+        );
+        assert_eq!(
+            error("push r1"),
+            "1:1: 'push' needs a stack register; set it with .stack"
+        );
+        assert_eq!(error("jz r1"), "1:1: 'jz' takes rN, VALUE");
+        assert_eq!(error("mov r1, 2"), "1:9: expected a register");
+    }
+}
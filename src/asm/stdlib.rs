@@ -0,0 +1,237 @@
+// Assembly routines bundled with the assembler, included by name:
+//
+//             .include "std/start.uma"    ; first: sets up the stack, calls main
+//             .include "std/io.uma"
+//     main:   orth r0, 42
+//             jsr print_udec
+//             ret
+//
+// std/start.uma describes the calling convention. The files are
+//
+//     std/start.uma   stack set up, then `main`
+//     std/io.uma      print_string, print_unsigned, print_udec, print_dec,
+//                     print_hex, read_line
+//     std/mem.uma     memcpy, memset
+//     std/vec.uma     grow, vec_new, vec_push (includes std/mem.uma)
+//     std/int.uma     ult, slt, negative, negate, abs, sdiv, smod
+
+const FILES: [(&str, &str); 5] = [
+    ("std/start.uma", include_str!("std/start.uma")),
+    ("std/io.uma", include_str!("std/io.uma")),
+    ("std/mem.uma", include_str!("std/mem.uma")),
+    ("std/vec.uma", include_str!("std/vec.uma")),
+    ("std/int.uma", include_str!("std/int.uma")),
+];
+
+/// The text of a bundled file
+pub fn source(path: &str) -> Option<&'static str> {
+    FILES.iter().find(|(name, _)| *name == path).map(|f| f.1)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{asm, run_buffered};
+
+    // Assemble `main` after the start-up code and every library, run it
+    //  on `input` and return what it printed
+    fn run(main: &str, input: &str) -> String {
+        let source = format!(
+            ".include \"std/start.uma\"\n\
+             .include \"std/io.uma\"\n\
+             .include \"std/vec.uma\"\n\
+             .include \"std/int.uma\"\n\
+             {}",
+            main
+        );
+        let program = asm::assemble(&source).unwrap();
+        String::from_utf8(run_buffered(program, input.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn strings() {
+        let main = "
+            main:   orth r0, 0
+                    orth r1, greeting
+                    jsr print_string
+                    ret
+            greeting: .string \"hello, world\\n\"";
+        assert_eq!(run(main, ""), "hello, world\n");
+    }
+
+    #[test]
+    fn numbers() {
+        let main = "
+            main:   orth r0, 0
+                    jsr print_udec
+                    jsr space
+                    li r0, 4294967295
+                    jsr print_udec
+                    jsr space
+                    jsr print_dec
+                    jsr space
+                    li r0, 0x80000000
+                    jsr print_dec
+                    jsr space
+                    orth r0, 1234567
+                    jsr print_dec
+                    jsr space
+                    li r0, 0xdeadbeef
+                    jsr print_hex
+                    orth r1, 2
+                    orth r0, 5
+                    jsr space
+                    jsr print_unsigned
+                    orth r1, 36
+                    orth r0, 1280
+                    jsr space
+                    jsr print_unsigned
+                    jsr space
+                    jsr print_udec              ; r0 is kept
+                    ret
+            space:  push r0
+                    orth r0, ' '
+                    out r0
+                    pop r0
+                    ret";
+        let output = run(main, "");
+        assert_eq!(
+            output,
+            "0 4294967295 -1 -2147483648 1234567 deadbeef 101 zk 1280"
+        );
+    }
+
+    #[test]
+    fn lines() {
+        // Echo each line in brackets with its length, through a 6 platter buffer
+        let main = "
+            main:   orth r1, 6
+                    alloc r4, r1
+            main.line:
+                    mov r0, r4
+                    orth r1, 0
+                    orth r2, 6
+                    jsr read_line
+                    not r3, r0
+                    jz r3, main.done
+                    mov r2, r0
+                    orth r3, '['
+                    out r3
+                    mov r0, r4
+                    jsr print_string
+                    orth r3, ']'
+                    out r3
+                    mov r0, r2
+                    jsr print_udec
+                    jmp main.line
+            main.done:
+                    ret";
+        assert_eq!(
+            run(main, "ab\n\ntoo long\nlast"),
+            "[ab]2[]0[too l]5[ong]3[last]4"
+        );
+    }
+
+    #[test]
+    fn memory() {
+        // Fill 3 platters of a new array with 7, copy 4 from offset 0 of
+        // that to offset 1 of another and read them back
+        let main = "
+            main:   orth r0, 5
+                    alloc r1, r0
+                    alloc r4, r0
+                    mov r0, r1
+                    orth r1, 0
+                    orth r2, 7
+                    orth r3, 3
+                    jsr memset
+                    mov r2, r0
+                    orth r3, 0
+                    mov r0, r4
+                    orth r1, 1
+                    orth r4, 4
+                    jsr memcpy
+                    orth r4, 0
+            main.print:
+                    load r1, r0, r4
+                    orth r2, '0'
+                    add r1, r1, r2
+                    out r1
+                    orth r1, 1
+                    add r4, r4, r1
+                    orth r1, 5
+                    sub r1, r1, r4
+                    jnz r1, main.print
+                    ret";
+        assert_eq!(run(main, ""), "07770");
+    }
+
+    #[test]
+    fn vectors() {
+        // Push 'a' to 'z' and a NUL onto a vector with room for 1, then
+        // print it as a string, with its length and capacity
+        let main = "
+            main:   orth r0, 1
+                    jsr vec_new
+                    orth r1, 'a'
+            main.push:
+                    jsr vec_push
+                    orth r2, 1
+                    add r1, r1, r2
+                    orth r2, 'z' + 1
+                    sub r2, r2, r1
+                    jnz r2, main.push
+                    orth r1, 0
+                    jsr vec_push
+                    mov r4, r0
+                    orth r1, 2
+                    jsr print_string
+                    orth r0, ' '
+                    out r0
+                    orth r1, 0
+                    load r0, r4, r1
+                    jsr print_udec
+                    orth r0, ' '
+                    out r0
+                    orth r1, 1
+                    load r0, r4, r1
+                    jsr print_udec
+                    ret";
+        assert_eq!(run(main, ""), "abcdefghijklmnopqrstuvwxyz 27 31");
+    }
+
+    #[test]
+    fn integers() {
+        let cases: [(&str, i32, i32, i32); 14] = [
+            ("ult", 1, 2, 1),
+            ("ult", 2, 2, 0),
+            ("ult", -1, 2, 0),
+            ("ult", 0, -1, 1),
+            ("slt", -1, 2, 1),
+            ("slt", 2, -1, 0),
+            ("slt", i32::MIN, i32::MAX, 1),
+            ("slt", -3, -3, 0),
+            ("sdiv", 7, 2, 3),
+            ("sdiv", -7, 2, -3),
+            ("sdiv", 7, -2, -3),
+            ("sdiv", -7, -2, 3),
+            ("smod", -7, 2, -1),
+            ("smod", 7, -2, 1),
+        ];
+        for (routine, a, b, expected) in cases.iter() {
+            let main = format!(
+                "main: li r0, {}\nli r1, {}\njsr {}\njsr print_dec\nret",
+                *a as u32, *b as u32, routine
+            );
+            assert_eq!(
+                run(&main, ""),
+                expected.to_string(),
+                "{} {} {}",
+                routine,
+                a,
+                b
+            );
+        }
+        let main = "main: li r0, -5\njsr abs\njsr print_dec\njsr negate\njsr print_dec\nret";
+        assert_eq!(run(main, ""), "5-5");
+    }
+}
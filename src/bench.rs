@@ -0,0 +1,166 @@
+// ---------- BENCHMARKS ------------------------------------------------------
+//
+// Synthetic workloads, run for a fixed number of instructions on each way of
+// executing them, to measure millions of instructions per second:
+//
+//     alu        arithmetic and nand in a tight loop
+//     alloc      allocating and freeing small arrays
+//     call       loading the program from another array on every jump
+//     memory     loading and storing through a 1024-platter array
+//
+// The backends are `step`, the plain interpreter, and `trace`, which also
+// builds the event every observer (--trace, --profile and the rest) sees.
+// Images given on the command line run the same way, with the input given
+// and their output thrown away, until they halt or reach the count.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Instant;
+
+use crate::{asm, trace, BufferConsole, Data, Fault, CPU};
+
+pub const WORKLOADS: [(&str, &str); 4] = [
+    (
+        "alu",
+        "
+            li r1, 0x12345
+            orth r5, 7
+    loop:   add r2, r2, r1
+            mul r3, r2, r1
+            nand r4, r3, r2
+            div r4, r4, r5
+            jmp loop",
+    ),
+    (
+        "alloc",
+        "
+            orth r1, 16
+    loop:   alloc r2, r1
+            alloc r3, r1
+            free r2
+            free r3
+            jmp loop",
+    ),
+    (
+        "call",
+        "
+            ; copy the program to a new array, then keep loading it
+            orth r1, end
+            alloc r2, r1
+            orth r4, 1
+    copy:   load r5, r0, r3
+            store r2, r3, r5
+            add r3, r3, r4
+            sub r5, r1, r3
+            jnz r5, copy
+            orth r3, loop
+    loop:   add r5, r5, r4
+            call r2, r3
+    end:",
+    ),
+    (
+        "memory",
+        "
+            li r4, 1024
+            alloc r1, r4
+            orth r3, 1
+            li r4, 1023
+    loop:   load r5, r1, r2
+            add r5, r5, r3
+            store r1, r2, r5
+            add r2, r2, r3
+            and r2, r2, r4
+            jmp loop",
+    ),
+];
+
+pub const BACKENDS: [&str; 2] = ["step", "trace"];
+
+/// The program for a built-in workload
+pub fn workload(name: &str) -> Option<Vec<Data>> {
+    let (_, source) = WORKLOADS.iter().find(|(n, _)| *n == name)?;
+    Some(asm::assemble(source).expect("built-in workloads assemble"))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Run {
+    pub instructions: u64,
+    pub seconds: f64,
+}
+
+impl Run {
+    pub fn mips(&self) -> f64 {
+        self.instructions as f64 / self.seconds.max(1e-9) / 1e6
+    }
+}
+
+/// Run `program` on `backend` for up to `count` instructions, stopping
+/// early at a halt or fault
+pub fn run(program: Vec<Data>, input: &[u8], backend: &str, count: u64) -> Run {
+    let step: fn(&mut CPU) -> Result<(), Fault> = match backend {
+        "trace" => |cpu| trace::step(cpu).map(|_| ()),
+        _ => CPU::step,
+    };
+    let console = BufferConsole {
+        input: input.iter().copied().collect(),
+        output: Rc::new(RefCell::new(Vec::new())),
+    };
+    let mut cpu = CPU::with_console(program, Box::new(console));
+    let start = Instant::now();
+    let mut instructions = 0;
+    while instructions < count && cpu.status {
+        if step(&mut cpu).is_err() {
+            break;
+        }
+        instructions += 1;
+    }
+    Run {
+        instructions,
+        seconds: start.elapsed().as_secs_f64(),
+    }
+}
+
+pub fn header() -> String {
+    format!(
+        "{:<16} {:<8} {:>13} {:>9} {:>9}",
+        "workload", "backend", "instructions", "seconds", "MIPS"
+    )
+}
+
+pub fn line(workload: &str, backend: &str, run: &Run) -> String {
+    format!(
+        "{:<16} {:<8} {:>13} {:>9.3} {:>9.1}",
+        workload,
+        backend,
+        run.instructions,
+        run.seconds,
+        run.mips()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn workloads_run_without_halting() {
+        for (name, _) in WORKLOADS.iter() {
+            for backend in BACKENDS.iter() {
+                let run = run(workload(name).unwrap(), b"", backend, 20_000);
+                assert_eq!(run.instructions, 20_000, "{} on {}", name, backend);
+            }
+        }
+        assert_eq!(workload("nothing"), None);
+
+        let halts = asm::assemble("orth r1, 1\nhalt").unwrap();
+        assert_eq!(run(halts, b"", "step", 100).instructions, 2);
+        let run = Run {
+            instructions: 3_000_000,
+            seconds: 1.5,
+        };
+        assert_eq!(
+            line("alu", "step", &run),
+            "alu              step           3000000     1.500       2.0"
+        );
+    }
+}
@@ -0,0 +1,252 @@
+// ---------- BRAINFUCK -------------------------------------------------------
+//
+// Brainfuck source to assembly for the assembler. The tape is an array of
+// byte cells, zeroed, with the head starting at cell 0:
+//
+//     r0  the tape          r1  the head          r2  the current cell
+//     r3  a constant        r4  2^24, for wrapping cells to 8 bits
+//     r5  input
+//
+// Runs of `+`/`-` and of `>`/`<` become one addition each, and `[-]` or
+// `[+]` a store of 0. Moving the head off either end of the tape faults.
+// Characters other than the eight commands are comments.
+
+use crate::asm::{AsmError, Span};
+
+/// What `,` stores once the input has ended
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Eof {
+    Zero,
+    /// 255, the byte form of -1
+    MinusOne,
+    Unchanged,
+}
+
+impl std::str::FromStr for Eof {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Eof, String> {
+        match text {
+            "0" => Ok(Eof::Zero),
+            "-1" | "255" => Ok(Eof::MinusOne),
+            "unchanged" => Ok(Eof::Unchanged),
+            _ => Err(format!("'{}' is not 0, -1 or unchanged", text)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    /// Cells on the tape
+    pub tape: u32,
+    pub eof: Eof,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            tape: 30_000,
+            eof: Eof::Zero,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Add(u8),
+    Move(i64),
+    Clear,
+    Open(usize),
+    Close(usize),
+    Input,
+    Output,
+}
+
+fn span(line: usize, column: usize) -> Span {
+    Span {
+        file: 0,
+        line,
+        column,
+        length: 1,
+    }
+}
+
+fn parse(source: &str) -> Result<Vec<Op>, AsmError> {
+    let mut ops = Vec::new();
+    let mut open: Vec<(usize, Span)> = Vec::new();
+    let mut loops = 0;
+    for (i, text) in source.lines().enumerate() {
+        for (j, c) in text.chars().enumerate() {
+            let op = match c {
+                '+' => Op::Add(1),
+                '-' => Op::Add(255),
+                '>' => Op::Move(1),
+                '<' => Op::Move(-1),
+                ',' => Op::Input,
+                '.' => Op::Output,
+                '[' => {
+                    open.push((loops, span(i + 1, j + 1)));
+                    loops += 1;
+                    Op::Open(loops - 1)
+                }
+                ']' => match open.pop() {
+                    Some((id, _)) => Op::Close(id),
+                    None => {
+                        let message = "']' without a matching '['".to_string();
+                        return Err(AsmError::new(span(i + 1, j + 1), message));
+                    }
+                },
+                _ => continue,
+            };
+            match (ops.last_mut(), op) {
+                (Some(Op::Add(n)), Op::Add(m)) => *n = n.wrapping_add(m),
+                (Some(Op::Move(n)), Op::Move(m)) => *n += m,
+                (_, op) => ops.push(op),
+            }
+            // `[-]` and `[+]`
+            if let [.., Op::Open(_), Op::Add(1), Op::Close(_)]
+            | [.., Op::Open(_), Op::Add(255), Op::Close(_)] = ops.as_slice()
+            {
+                ops.truncate(ops.len() - 3);
+                ops.push(Op::Clear);
+            }
+        }
+    }
+    match open.pop() {
+        Some((_, span)) => Err(AsmError::new(span, "'[' is never closed".to_string())),
+        None => Ok(ops),
+    }
+}
+
+fn emit(lines: &mut Vec<String>, line: &str) {
+    lines.push(format!("        {}", line));
+}
+
+/// Assembly text for the Brainfuck program in `source`
+pub fn translate(source: &str, options: &Options) -> Result<String, AsmError> {
+    let mut lines = vec![
+        "; translated from Brainfuck".to_string(),
+        "        li r4, 0x1000000".to_string(),
+        format!("        li r3, {}", options.tape),
+        "        alloc r0, r3".to_string(),
+        "        orth r1, 0".to_string(),
+    ];
+    for op in parse(source)? {
+        match op {
+            Op::Add(0) | Op::Move(0) => {}
+            Op::Add(n) => {
+                emit(&mut lines, "load r2, r0, r1");
+                emit(&mut lines, &format!("orth r3, {}", n));
+                emit(&mut lines, "add r2, r2, r3");
+                emit(&mut lines, "mul r2, r2, r4");
+                emit(&mut lines, "div r2, r2, r4");
+                emit(&mut lines, "store r0, r1, r2");
+            }
+            Op::Move(n) => {
+                emit(&mut lines, &format!("li r3, {}", n as i32));
+                emit(&mut lines, "add r1, r1, r3");
+            }
+            Op::Clear => {
+                emit(&mut lines, "orth r2, 0");
+                emit(&mut lines, "store r0, r1, r2");
+            }
+            Op::Open(id) => {
+                lines.push(format!("loop.{}:", id));
+                emit(&mut lines, "load r2, r0, r1");
+                emit(&mut lines, &format!("jz r2, loop.{}.end", id));
+            }
+            Op::Close(id) => {
+                emit(&mut lines, "load r2, r0, r1");
+                emit(&mut lines, &format!("jnz r2, loop.{}", id));
+                lines.push(format!("loop.{}.end:", id));
+            }
+            Op::Input => {
+                // All ones at the end of the input, so r3 = r2 + 1 is 0
+                emit(&mut lines, "in r2");
+                emit(&mut lines, "orth r3, 1");
+                emit(&mut lines, "add r3, r2, r3");
+                match options.eof {
+                    Eof::Unchanged => {
+                        emit(&mut lines, "cmov r5, r2, r3");
+                        emit(&mut lines, "load r2, r0, r1");
+                        emit(&mut lines, "cmov r2, r5, r3");
+                    }
+                    Eof::Zero | Eof::MinusOne => {
+                        let value = if options.eof == Eof::Zero { 0 } else { 255 };
+                        emit(&mut lines, &format!("orth r5, {}", value));
+                        emit(&mut lines, "cmov r5, r2, r3");
+                        emit(&mut lines, "mov r2, r5");
+                    }
+                }
+                emit(&mut lines, "store r0, r1, r2");
+            }
+            Op::Output => {
+                emit(&mut lines, "load r2, r0, r1");
+                emit(&mut lines, "out r2");
+            }
+        }
+    }
+    lines.push("        halt".to_string());
+    let mut text = lines.join("\n");
+    text.push('\n');
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm, run_buffered};
+
+    fn run(source: &str, options: &Options, input: &[u8]) -> Vec<u8> {
+        let text = translate(source, options).unwrap();
+        run_buffered(asm::assemble(&text).unwrap(), input)
+    }
+
+    #[test]
+    fn hello() {
+        let source = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+        assert_eq!(run(source, &Options::default(), b""), b"Hello World!\n");
+    }
+
+    #[test]
+    fn cells_wrap() {
+        let options = Options::default();
+        assert_eq!(run("-.+.", &options, b""), vec![255, 0]);
+        assert_eq!(run("-[-]+[+]>.<.", &options, b""), vec![0, 0]);
+        // 256 increments leave 0, so the loop never runs
+        let source = format!("{}[.]+.", "+".repeat(256));
+        assert_eq!(run(&source, &options, b""), vec![1]);
+    }
+
+    #[test]
+    fn input() {
+        // Copy the input to the output, reversed
+        let reverse = ">,[>,]<[.<]";
+        assert_eq!(run(reverse, &Options::default(), b"abc"), b"cba");
+        let eof = |eof| {
+            let options = Options { tape: 10, eof };
+            run("+++,.", &options, b"")
+        };
+        assert_eq!(eof(Eof::Zero), vec![0]);
+        assert_eq!(eof(Eof::MinusOne), vec![255]);
+        assert_eq!(eof(Eof::Unchanged), vec![3]);
+        let options = Options {
+            tape: 10,
+            eof: Eof::Unchanged,
+        };
+        assert_eq!(run(",.", &options, b"x"), b"x");
+    }
+
+    #[test]
+    fn errors() {
+        let error = |source| {
+            translate(source, &Options::default())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(error("+[>\n[-]"), "1:2: '[' is never closed");
+        assert_eq!(error("+.\n ]"), "2:2: ']' without a matching '['");
+        assert_eq!("255".parse::<Eof>(), Ok(Eof::MinusOne));
+        assert!("eof".parse::<Eof>().is_err());
+    }
+}
@@ -0,0 +1,458 @@
+// ---------- CONTROL FLOW ----------------------------------------------------
+//
+// Basic blocks and the jumps between them, recovered from a program image
+// without running it. Registers start at 0 and are followed through ORTH
+// and arithmetic as small sets of possible constants, so that
+//
+//     orth r6, 0                ; array 0
+//     orth r7, done             ; the target when r1 is 0
+//     orth r5, loop
+//     cmov r7, r5, r1           ; or loop when it is not
+//     call r6, r7
+//
+// gives edges to both `loop` and `done`. A jump whose target is not a
+// constant is unresolved. A jump that follows a store of the offset after
+// it, as `jsr` does, is a call: the block after it is reached by a return
+// edge, since returns load their target and cannot be followed. A CALL of an
+// array that may not be 0 loads code and ends the graph there, as do HALT
+// and words that are not instructions. STOREs into array 0 are not followed.
+//
+// `dot` writes the graph for Graphviz, one box per block with its
+// disassembly, unresolved jumps in red and code loads in blue.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::symbols::Symbols;
+use crate::{disasm, Data, Instruction, OpCode};
+
+/// The most constants a register is followed as before it becomes unknown
+const MAX_VALUES: usize = 8;
+
+/// The constants a register may hold, or None if it may hold anything
+type Value = Option<BTreeSet<Data>>;
+
+/// Where an instruction goes, and how it exits if it does not fall through
+type Flow = (Vec<(Data, Kind)>, Option<Exit>);
+
+#[derive(Debug, Clone, PartialEq)]
+struct State {
+    registers: [Value; 8],
+    /// Constants stored since the last jump
+    stored: BTreeSet<Data>,
+}
+
+fn constant(value: Data) -> Value {
+    Some(std::iter::once(value).collect())
+}
+
+fn union(a: &Value, b: &Value) -> Value {
+    match (a, b) {
+        (Some(a), Some(b)) => {
+            let values: BTreeSet<Data> = a.union(b).copied().collect();
+            if values.len() <= MAX_VALUES {
+                Some(values)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+fn apply(a: &Value, b: &Value, f: impl Fn(Data, Data) -> Option<Data>) -> Value {
+    let (a, b) = (a.as_ref()?, b.as_ref()?);
+    if a.len() * b.len() > MAX_VALUES {
+        return None;
+    }
+    let mut values = BTreeSet::new();
+    for &x in a {
+        for &y in b {
+            values.insert(f(x, y)?);
+        }
+    }
+    Some(values)
+}
+
+impl State {
+    fn start() -> State {
+        State {
+            registers: Default::default(),
+            stored: BTreeSet::new(),
+        }
+        .cleared(Some(0))
+    }
+
+    /// Every register set to `value`
+    fn cleared(mut self, value: Option<Data>) -> State {
+        for register in self.registers.iter_mut() {
+            *register = value.and_then(constant);
+        }
+        self
+    }
+
+    fn merge(&self, other: &State) -> State {
+        let mut state = self.clone();
+        for (register, value) in state.registers.iter_mut().zip(other.registers.iter()) {
+            *register = union(register, value);
+        }
+        state.stored = self.stored.intersection(&other.stored).copied().collect();
+        state
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Kind {
+    /// Falling through to the next offset
+    Next,
+    Jump,
+    Call,
+    /// From a call to the offset after it
+    Return,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Next => "",
+            Kind::Jump => "jump",
+            Kind::Call => "call",
+            Kind::Return => "return",
+        }
+    }
+}
+
+/// How a block ends, other than by its edges
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Exit {
+    /// Falls through or jumps to known targets
+    Edges,
+    Halt,
+    /// A jump to an offset that is not a constant
+    Unresolved,
+    /// A CALL that may load another array as the program
+    Load,
+    /// A word that is not an instruction, or runs off the end
+    Fault,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub start: Data,
+    /// One past the last offset
+    pub end: Data,
+    pub exit: Exit,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Graph {
+    pub blocks: Vec<Block>,
+    /// From block start to block start
+    pub edges: Vec<(Data, Data, Kind)>,
+}
+
+impl Graph {
+    /// Counts of blocks, edges and exits, and of the `size` words of the
+    /// program no block reaches
+    pub fn summary(&self, size: usize) -> String {
+        let exits = |exit| self.blocks.iter().filter(|b| b.exit == exit).count();
+        let reached: usize = self.blocks.iter().map(|b| (b.end - b.start) as usize).sum();
+        format!(
+            "{} blocks, {} edges, {} unresolved jumps, {} code loads, {} of {} words unreached",
+            self.blocks.len(),
+            self.edges.len(),
+            exits(Exit::Unresolved),
+            exits(Exit::Load),
+            size - reached,
+            size
+        )
+    }
+}
+
+/// Where control goes after the instruction at `offset`, and how it exits
+fn successors(
+    program: &[Data],
+    offset: Data,
+    state: &mut State,
+) -> (Vec<(Data, Kind, State)>, Option<Exit>) {
+    let instruction = match Instruction::try_decode(program[offset as usize]) {
+        Some(instruction) => instruction,
+        None => return (Vec::new(), Some(Exit::Fault)),
+    };
+    let r = |register: u8| state.registers[register as usize].clone();
+    let (a, b, c) = (instruction.r_a, instruction.r_b, instruction.r_c);
+    let next = offset + 1;
+    // The register written and what it may hold
+    let write = match instruction.op_code {
+        OpCode::CMOV => Some((
+            a,
+            match r(c) {
+                Some(values) if values.iter().all(|&v| v == 0) => r(a),
+                Some(values) if values.iter().all(|&v| v != 0) => r(b),
+                _ => union(&r(a), &r(b)),
+            },
+        )),
+        OpCode::LOAD => Some((a, None)),
+        OpCode::IN => Some((c, None)),
+        OpCode::ADD => Some((a, apply(&r(b), &r(c), |x, y| Some(x.wrapping_add(y))))),
+        OpCode::MUL => Some((a, apply(&r(b), &r(c), |x, y| Some(x.wrapping_mul(y))))),
+        OpCode::DIV => Some((a, apply(&r(b), &r(c), |x, y| x.checked_div(y)))),
+        OpCode::NAND => Some((a, apply(&r(b), &r(c), |x, y| Some(!(x & y))))),
+        OpCode::CONST => Some((a, constant(instruction.value))),
+        OpCode::ALLOC => Some((b, None)),
+        OpCode::STORE => {
+            if let Some(values) = r(c) {
+                state.stored.extend(values);
+            }
+            None
+        }
+        OpCode::FREE | OpCode::OUT => None,
+        OpCode::HALT => return (Vec::new(), Some(Exit::Halt)),
+        OpCode::CALL => {
+            if r(b) != constant(0) {
+                return (Vec::new(), Some(Exit::Load));
+            }
+            let targets = match r(c) {
+                Some(targets) => targets,
+                None => return (Vec::new(), Some(Exit::Unresolved)),
+            };
+            let returns = state.registers.contains(&constant(next)) || state.stored.contains(&next);
+            let mut jumped = state.clone();
+            jumped.stored.clear();
+            let kind = if returns { Kind::Call } else { Kind::Jump };
+            let mut successors: Vec<_> = targets
+                .into_iter()
+                .filter(|&target| (target as usize) < program.len())
+                .map(|target| (target, kind, jumped.clone()))
+                .collect();
+            if returns && (next as usize) < program.len() {
+                // The callee may have changed anything
+                successors.push((next, Kind::Return, jumped.cleared(None)));
+            }
+            return (successors, Some(Exit::Edges));
+        }
+    };
+    if let Some((register, value)) = write {
+        state.registers[register as usize] = value;
+    }
+    if (next as usize) < program.len() {
+        (vec![(next, Kind::Next, state.clone())], None)
+    } else {
+        (Vec::new(), Some(Exit::Fault))
+    }
+}
+
+/// The control flow graph of `program`, starting at offset 0
+pub fn recover(program: &[Data]) -> Graph {
+    if program.is_empty() {
+        return Graph::default();
+    }
+    let mut states: BTreeMap<Data, State> = BTreeMap::new();
+    let mut work = vec![0];
+    states.insert(0, State::start());
+    let mut flow: BTreeMap<Data, Flow> = BTreeMap::new();
+    while let Some(offset) = work.pop() {
+        let mut state = states[&offset].clone();
+        let (successors, exit) = successors(program, offset, &mut state);
+        let mut targets = Vec::new();
+        for (target, kind, state) in successors {
+            targets.push((target, kind));
+            let merged = match states.get(&target) {
+                Some(old) => old.merge(&state),
+                None => state,
+            };
+            if states.get(&target) != Some(&merged) {
+                states.insert(target, merged);
+                work.push(target);
+            }
+        }
+        flow.insert(offset, (targets, exit));
+    }
+
+    // A block starts at 0, at every target other than by falling through,
+    // and after every instruction that does not fall through
+    let mut leaders: BTreeSet<Data> = BTreeSet::new();
+    leaders.insert(0);
+    for (offset, (targets, exit)) in &flow {
+        for (target, kind) in targets {
+            if *kind != Kind::Next {
+                leaders.insert(*target);
+            }
+        }
+        if exit.is_some() && flow.contains_key(&(offset + 1)) {
+            leaders.insert(offset + 1);
+        }
+    }
+    let mut graph = Graph::default();
+    for &start in &leaders {
+        let mut end = start;
+        loop {
+            let (targets, exit) = &flow[&end];
+            end += 1;
+            let next_leader = leaders.contains(&end) || !flow.contains_key(&end);
+            if exit.is_some() || next_leader {
+                for (target, kind) in targets {
+                    graph.edges.push((start, *target, *kind));
+                }
+                graph.blocks.push(Block {
+                    start,
+                    end,
+                    exit: exit.unwrap_or(Exit::Edges),
+                });
+                break;
+            }
+        }
+    }
+    graph.edges.sort();
+    graph.edges.dedup();
+    graph
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// `graph` in Graphviz's DOT language
+pub fn dot(graph: &Graph, program: &[Data], symbols: &Symbols) -> String {
+    let mut lines = vec![
+        "digraph program {".to_string(),
+        "    node [shape=box, fontname=\"monospace\"];".to_string(),
+    ];
+    for block in &graph.blocks {
+        let mut label = String::new();
+        if let Some(at) = symbols.locate(block.start) {
+            label.push_str(&format!("{}\\l", escape(&at)));
+        }
+        for offset in block.start..block.end {
+            let line = disasm::line(offset as usize, program[offset as usize]);
+            label.push_str(&format!("{}\\l", escape(line.trim_start())));
+        }
+        let (note, color) = match block.exit {
+            Exit::Edges => ("", ""),
+            Exit::Halt => ("", ""),
+            Exit::Unresolved => ("unresolved jump\\l", ", color=red"),
+            Exit::Load => ("loads code\\l", ", color=blue"),
+            Exit::Fault => ("faults\\l", ", color=red"),
+        };
+        lines.push(format!(
+            "    b{} [label=\"{}{}\"{}];",
+            block.start, label, note, color
+        ));
+    }
+    for (from, to, kind) in &graph.edges {
+        let attributes = match kind {
+            Kind::Next => String::new(),
+            Kind::Return => " [label=\"return\", style=dashed]".to_string(),
+            kind => format!(" [label=\"{}\"]", kind.name()),
+        };
+        lines.push(format!("    b{} -> b{}{};", from, to, attributes));
+    }
+    lines.push("}".to_string());
+    let mut text = lines.join("\n");
+    text.push('\n');
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+
+    fn blocks(graph: &Graph) -> Vec<(Data, Data, Exit)> {
+        graph
+            .blocks
+            .iter()
+            .map(|block| (block.start, block.end, block.exit))
+            .collect()
+    }
+
+    #[test]
+    fn loops_and_branches() {
+        // Count r1 down from 3 with jnz, which selects its target with cmov
+        let program = asm::assemble(
+            "
+                orth r1, 3
+                orth r2, 1
+        loop:   sub r1, r1, r2
+                jnz r1, loop
+                halt",
+        )
+        .unwrap();
+        let graph = recover(&program);
+        let end = program.len() as Data;
+        let halt = end - 1;
+        assert_eq!(
+            blocks(&graph),
+            vec![
+                (0, 2, Exit::Edges),
+                (2, halt, Exit::Edges),
+                (halt, end, Exit::Halt)
+            ]
+        );
+        assert_eq!(
+            graph.edges,
+            vec![
+                (0, 2, Kind::Next),
+                (2, 2, Kind::Jump),
+                (2, halt, Kind::Jump)
+            ]
+        );
+    }
+
+    #[test]
+    fn calls_and_unresolved_returns() {
+        let source = "
+                .stack r5
+                orth r0, 8
+                alloc r5, r0
+                orth r0, 0
+                jsr double
+                halt
+        double: add r1, r1, r1
+                ret
+                .word 0xe0000000";
+        let assembly = asm::build(source).unwrap();
+        let program = assembly.image().unwrap();
+        let graph = recover(&program);
+        let symbols = Symbols::from(&assembly);
+        let double = symbols.resolve("double").unwrap();
+        let blocks = blocks(&graph);
+        assert_eq!(
+            blocks,
+            vec![
+                (0, double - 1, Exit::Edges),
+                (double - 1, double, Exit::Halt),
+                // ret, ending before the data word, which is never reached
+                (double, program.len() as Data - 1, Exit::Unresolved),
+            ]
+        );
+        assert_eq!(
+            graph.edges,
+            vec![(0, double - 1, Kind::Return), (0, double, Kind::Call)]
+        );
+        assert_eq!(
+            graph.summary(program.len()),
+            "3 blocks, 2 edges, 1 unresolved jumps, 0 code loads, 1 of 27 words unreached"
+        );
+
+        let text = dot(&graph, &program, &symbols);
+        assert!(text.starts_with("digraph program {\n"));
+        assert!(text.contains(&format!("    b0 -> b{} [label=\"call\"];", double)));
+        assert!(text.contains(&format!(
+            "    b0 -> b{} [label=\"return\", style=dashed];",
+            double - 1
+        )));
+        assert!(text.contains("unresolved jump\\l\", color=red];"));
+        assert!(text.contains(&format!("label=\"double (<input>:8)\\l{}  ", double)));
+    }
+
+    #[test]
+    fn code_loads_and_faults() {
+        let program = asm::assemble("in r1\ncall r1, r0").unwrap();
+        assert_eq!(blocks(&recover(&program)), vec![(0, 2, Exit::Load)]);
+        let program = asm::assemble("orth r1, 1\n.word 0xe0000000").unwrap();
+        assert_eq!(blocks(&recover(&program)), vec![(0, 2, Exit::Fault)]);
+        let program = asm::assemble("orth r1, 1").unwrap();
+        assert_eq!(blocks(&recover(&program)), vec![(0, 1, Exit::Fault)]);
+        assert_eq!(recover(&[]), Graph::default());
+    }
+}
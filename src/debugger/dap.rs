@@ -0,0 +1,780 @@
+// Debug Adapter Protocol server.
+//
+// Speaks DAP over a pair of streams (stdin/stdout for `cult dap`) so an editor
+// can drive the Debugger. The program is shown as a generated disassembly,
+// one platter per line, so line N is offset N - 1. Registers and arrays appear as
+// variables, and console output is forwarded as output events. Console input
+// comes from the `input` string of the launch request. With a symbol map,
+// breakpoints can also be set on lines of the assembly source.
+//
+// Requests are read on their own thread, so a running program can be paused.
+
+use super::{Debugger, Stop};
+use crate::json::{self, read_message, Json};
+use crate::symbols::Symbols;
+use crate::{disasm, load_program, BufferConsole, Data, CPU};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+const LISTING_REFERENCE: u64 = 1;
+const REGISTERS_REFERENCE: u64 = 1;
+const ARRAYS_REFERENCE: u64 = 2;
+// Array N is variables reference ARRAY_BASE + N
+const ARRAY_BASE: u64 = 3;
+// Instructions run between checks for a pause request
+const CHUNK: u64 = 100_000;
+
+type Requests = Receiver<io::Result<Json>>;
+
+// What to do once a request has been answered
+enum After {
+    Nothing,
+    Stop(&'static str),
+    Run(Option<u64>),
+}
+
+pub struct Server<'a> {
+    input: Option<Box<dyn BufRead + Send>>,
+    output: &'a mut dyn Write,
+    seq: u64,
+    debugger: Option<Debugger>,
+    program_name: String,
+    printed: Rc<RefCell<Vec<u8>>>,
+    stop_on_entry: bool,
+    breakpoints: Vec<usize>,
+    // Requests that arrived while the program ran, answered once it stops
+    deferred: VecDeque<Json>,
+}
+
+impl<'a> Server<'a> {
+    pub fn new(input: Box<dyn BufRead + Send>, output: &'a mut dyn Write) -> Self {
+        Server {
+            input: Some(input),
+            output,
+            seq: 1,
+            debugger: None,
+            program_name: String::new(),
+            printed: Rc::new(RefCell::new(Vec::new())),
+            stop_on_entry: false,
+            breakpoints: Vec::new(),
+            deferred: VecDeque::new(),
+        }
+    }
+
+    fn send(&mut self, mut message: Vec<(&str, Json)>) -> io::Result<()> {
+        message.push(("seq", self.seq.into()));
+        self.seq += 1;
+        json::write_message(self.output, &Json::object(message))
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> io::Result<()> {
+        self.send(vec![
+            ("type", "event".into()),
+            ("event", event.into()),
+            ("body", body),
+        ])
+    }
+
+    fn respond(&mut self, request: &Json, result: Result<Json, String>) -> io::Result<()> {
+        let command = request.get("command").cloned().unwrap_or(Json::Null);
+        let request_seq = request.get("seq").cloned().unwrap_or(Json::Null);
+        let mut message = vec![
+            ("type", "response".into()),
+            ("request_seq", request_seq),
+            ("command", command),
+            ("success", result.is_ok().into()),
+        ];
+        match result {
+            Ok(body) => message.push(("body", body)),
+            Err(e) => message.push(("message", e.into())),
+        }
+        self.send(message)
+    }
+
+    fn flush_output(&mut self) -> io::Result<()> {
+        let printed: Vec<u8> = self.printed.borrow_mut().drain(..).collect();
+        if !printed.is_empty() {
+            let text = String::from_utf8_lossy(&printed).into_owned();
+            let body = Json::object(vec![("category", "stdout".into()), ("output", text.into())]);
+            self.event("output", body)?;
+        }
+        Ok(())
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<String>) -> io::Result<()> {
+        let mut body = vec![
+            ("reason", reason.into()),
+            ("threadId", 1u64.into()),
+            ("allThreadsStopped", true.into()),
+        ];
+        if let Some(text) = text {
+            body.push(("text", text.into()));
+        }
+        self.event("stopped", Json::object(body))
+    }
+
+    fn report(&mut self, stop: Stop) -> io::Result<()> {
+        self.flush_output()?;
+        match stop {
+            Stop::Halted => {
+                self.event("exited", Json::object(vec![("exitCode", 0u64.into())]))?;
+                self.event("terminated", Json::object(vec![]))
+            }
+            Stop::Stepped | Stop::Beginning => self.stopped("step", None),
+            Stop::Breakpoint(_) => self.stopped("breakpoint", None),
+            Stop::Watchpoint { .. } => self.stopped("data breakpoint", None),
+            Stop::ConditionError(id, e) => {
+                self.stopped("exception", Some(format!("condition of {}: {}", id, e)))
+            }
+            Stop::Fault(fault) => self.stopped("exception", Some(fault.to_string())),
+        }
+    }
+
+    fn debugger(&mut self) -> Result<&mut Debugger, String> {
+        self.debugger
+            .as_mut()
+            .ok_or_else(|| "no program launched".to_string())
+    }
+
+    fn launch(&mut self, args: &Json) -> Result<Json, String> {
+        let path = args
+            .get("program")
+            .and_then(Json::as_str)
+            .ok_or("launch needs a program")?;
+        let program = load_program(path).map_err(|e| format!("cannot load {}: {}", path, e))?;
+        let input = args.get("input").and_then(Json::as_str).unwrap_or("");
+        let console = BufferConsole {
+            input: input.bytes().collect(),
+            output: self.printed.clone(),
+        };
+        let cpu = CPU::with_console(program, Box::new(console));
+        let mut debugger = Debugger::new(cpu);
+        if let Some(symbols) = args.get("symbols").and_then(Json::as_str) {
+            debugger.symbols =
+                Symbols::load(symbols).map_err(|e| format!("cannot load {}: {}", symbols, e))?;
+        }
+        self.debugger = Some(debugger);
+        self.program_name = path.to_string();
+        self.stop_on_entry = args.get("stopOnEntry").and_then(Json::as_bool) == Some(true);
+        Ok(Json::Null)
+    }
+
+    fn listing(&mut self) -> Result<Json, String> {
+        let debugger = self.debugger()?;
+        let platter = &debugger.cpu.instruction_platter;
+        let mut content = String::new();
+        for line in disasm::listing(platter, 0, platter.len()) {
+            content.push_str(&line);
+            content.push('\n');
+        }
+        Ok(Json::object(vec![("content", content.into())]))
+    }
+
+    fn set_breakpoints(&mut self, args: &Json) -> Result<Json, String> {
+        let requested = args
+            .get("breakpoints")
+            .and_then(Json::as_array)
+            .unwrap_or(&[])
+            .to_vec();
+        let path = args
+            .get("source")
+            .and_then(|source| source.get("path"))
+            .and_then(Json::as_str)
+            .map(str::to_string);
+        let old = std::mem::take(&mut self.breakpoints);
+        let debugger = self.debugger()?;
+        for id in old {
+            debugger.delete(id);
+        }
+        // Lines of a file named in the symbol map, otherwise of the listing
+        let file = path.and_then(|path| source_file(&debugger.symbols, &path));
+        let mut verified = Vec::new();
+        let mut ids = Vec::new();
+        for breakpoint in requested.iter() {
+            let line = breakpoint.get("line").and_then(Json::as_u64).unwrap_or(0);
+            let condition = breakpoint.get("condition").and_then(Json::as_str);
+            let offset = match (line, file) {
+                (0, _) => Err("lines start at 1".to_string()),
+                (_, Some(file)) => code_at(&debugger.symbols, file, line as usize)
+                    .ok_or(format!("no code at line {}", line)),
+                (_, None) => Ok(line - 1),
+            };
+            let result = offset.and_then(|offset| {
+                debugger
+                    .add_breakpoint(offset, condition)
+                    .map_err(|e| format!("bad condition {}", e))
+            });
+            verified.push(match result {
+                Ok(id) => {
+                    ids.push(id);
+                    Json::object(vec![
+                        ("id", id.into()),
+                        ("verified", true.into()),
+                        ("line", line.into()),
+                    ])
+                }
+                Err(message) => Json::object(vec![
+                    ("verified", false.into()),
+                    ("line", line.into()),
+                    ("message", message.into()),
+                ]),
+            });
+        }
+        self.breakpoints = ids;
+        Ok(Json::object(vec![("breakpoints", verified.into())]))
+    }
+
+    fn source(&self) -> Json {
+        Json::object(vec![
+            ("name", self.program_name.as_str().into()),
+            ("sourceReference", LISTING_REFERENCE.into()),
+        ])
+    }
+
+    fn stack_trace(&mut self) -> Result<Json, String> {
+        let listing = self.source();
+        let debugger = self.debugger()?;
+        let pc = debugger.cpu.instruction_pointer;
+        let symbols = &debugger.symbols;
+        // The assembly source when the symbol map knows it, else the listing
+        let (name, source, line) = match symbols.line(pc as Data) {
+            Some((file, line)) => {
+                let name = std::path::Path::new(file)
+                    .file_name()
+                    .map_or(file.to_string(), |n| n.to_string_lossy().into_owned());
+                let source = Json::object(vec![("name", name.into()), ("path", file.into())]);
+                (
+                    symbols.locate(pc as Data).unwrap_or_default(),
+                    source,
+                    line as u64,
+                )
+            }
+            None => (format!("offset {}", pc), listing, pc + 1),
+        };
+        let frame = Json::object(vec![
+            ("id", 1u64.into()),
+            ("name", name.into()),
+            ("line", line.into()),
+            ("column", 1u64.into()),
+            ("source", source),
+        ]);
+        Ok(Json::object(vec![
+            ("stackFrames", vec![frame].into()),
+            ("totalFrames", 1u64.into()),
+        ]))
+    }
+
+    fn variables(&mut self, args: &Json) -> Result<Json, String> {
+        let reference = args
+            .get("variablesReference")
+            .and_then(Json::as_u64)
+            .ok_or("missing variablesReference")?;
+        let start = args.get("start").and_then(Json::as_u64).unwrap_or(0) as usize;
+        let count = args.get("count").and_then(Json::as_u64).unwrap_or(0) as usize;
+        let cpu = &self.debugger()?.cpu;
+        let variable = |name: String, value: String, reference: u64, indexed: Option<usize>| {
+            let mut fields = vec![
+                ("name", name.into()),
+                ("value", value.into()),
+                ("variablesReference", reference.into()),
+            ];
+            if let Some(n) = indexed {
+                fields.push(("indexedVariables", n.into()));
+            }
+            Json::object(fields)
+        };
+        let mut variables = Vec::new();
+        match reference {
+            REGISTERS_REFERENCE => {
+                for (i, r) in cpu.register_file.iter().enumerate() {
+                    variables.push(variable(format!("r{}", i), format!("0x{:08x}", r), 0, None));
+                }
+                variables.push(variable(
+                    "pc".into(),
+                    cpu.instruction_pointer.to_string(),
+                    0,
+                    None,
+                ));
+                variables.push(variable(
+                    "icount".into(),
+                    cpu.instruction_count.to_string(),
+                    0,
+                    None,
+                ));
+            }
+            ARRAYS_REFERENCE => {
+                let ids = std::iter::once(0).chain(cpu.memory.keys().copied());
+                for id in ids {
+                    let length = cpu.array(id).unwrap().len();
+                    let value = format!("[{} platters]", length);
+                    let reference = ARRAY_BASE + id as u64;
+                    variables.push(variable(
+                        format!("array {}", id),
+                        value,
+                        reference,
+                        Some(length),
+                    ));
+                }
+            }
+            _ => {
+                let id = reference
+                    .checked_sub(ARRAY_BASE)
+                    .ok_or(format!("no variables with reference {}", reference))?
+                    as Data;
+                let platter = cpu.array(id).ok_or(format!("array {} is not active", id))?;
+                let end = if count == 0 {
+                    platter.len()
+                } else {
+                    (start + count).min(platter.len())
+                };
+                for (offset, word) in platter.iter().enumerate().take(end).skip(start) {
+                    variables.push(variable(
+                        format!("[{}]", offset),
+                        format!("0x{:08x}", word),
+                        0,
+                        None,
+                    ));
+                }
+            }
+        }
+        Ok(Json::object(vec![("variables", variables.into())]))
+    }
+
+    fn evaluate(&mut self, args: &Json) -> Result<Json, String> {
+        let text = args.get("expression").and_then(Json::as_str).unwrap_or("");
+        let expr = super::expr::parse(text).map_err(|e| e.to_string())?;
+        let value = expr.eval(&self.debugger()?.cpu, 0)?;
+        Ok(Json::object(vec![
+            ("result", format!("{} (0x{:x})", value, value).into()),
+            ("variablesReference", 0u64.into()),
+        ]))
+    }
+
+    fn resume(&mut self, limit: Option<u64>) -> Result<Stop, String> {
+        Ok(self.debugger()?.resume(limit))
+    }
+
+    /// Handle requests until the client disconnects
+    pub fn run(&mut self) -> io::Result<()> {
+        let requests = self.listen();
+        while let Some(request) = self.next_request(&requests)? {
+            if !self.handle(&request, &requests)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // Read requests on a thread of their own, so they can be looked at
+    // between chunks of a running program
+    fn listen(&mut self) -> Requests {
+        let (sender, receiver) = mpsc::channel();
+        if let Some(mut input) = self.input.take() {
+            thread::spawn(move || {
+                while let Some(request) = read_message(&mut input).transpose() {
+                    let failed = request.is_err();
+                    if sender.send(request).is_err() || failed {
+                        break;
+                    }
+                }
+            });
+        }
+        receiver
+    }
+
+    fn next_request(&mut self, requests: &Requests) -> io::Result<Option<Json>> {
+        match self.deferred.pop_front() {
+            Some(request) => Ok(Some(request)),
+            None => requests.recv().ok().transpose(),
+        }
+    }
+
+    /// Answer one request, returning false on disconnect
+    fn handle(&mut self, request: &Json, requests: &Requests) -> io::Result<bool> {
+        let command = request.get("command").and_then(Json::as_str).unwrap_or("");
+        let args = request.get("arguments").cloned().unwrap_or(Json::Null);
+        let mut after = After::Nothing;
+        let result = match command {
+            "initialize" => Ok(Json::object(vec![
+                ("supportsConfigurationDoneRequest", true.into()),
+                ("supportsConditionalBreakpoints", true.into()),
+                ("supportsEvaluateForHovers", true.into()),
+            ])),
+            "launch" => self.launch(&args),
+            "setBreakpoints" => self.set_breakpoints(&args),
+            "configurationDone" => {
+                after = if self.stop_on_entry {
+                    After::Stop("entry")
+                } else {
+                    After::Run(None)
+                };
+                Ok(Json::Null)
+            }
+            "threads" => Ok(Json::object(vec![(
+                "threads",
+                vec![Json::object(vec![
+                    ("id", 1u64.into()),
+                    ("name", "UM".into()),
+                ])]
+                .into(),
+            )])),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(Json::object(vec![(
+                "scopes",
+                vec![
+                    Json::object(vec![
+                        ("name", "Registers".into()),
+                        ("variablesReference", REGISTERS_REFERENCE.into()),
+                        ("expensive", false.into()),
+                    ]),
+                    Json::object(vec![
+                        ("name", "Arrays".into()),
+                        ("variablesReference", ARRAYS_REFERENCE.into()),
+                        ("expensive", false.into()),
+                    ]),
+                ]
+                .into(),
+            )])),
+            "variables" => self.variables(&args),
+            "source" => self.listing(),
+            "evaluate" => self.evaluate(&args),
+            "continue" => {
+                after = After::Run(None);
+                Ok(Json::object(vec![("allThreadsContinued", true.into())]))
+            }
+            "next" | "stepIn" | "stepOut" => {
+                after = After::Run(Some(1));
+                Ok(Json::Null)
+            }
+            // Already stopped: just say so
+            "pause" => self.debugger().map(|_| {
+                after = After::Stop("pause");
+                Json::Null
+            }),
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(Json::Null))?;
+                return Ok(false);
+            }
+            _ => Err(format!("unsupported request '{}'", command)),
+        };
+        let launched = command == "launch" && result.is_ok();
+        self.respond(request, result)?;
+        if launched {
+            self.event("initialized", Json::object(vec![]))?;
+        }
+        match after {
+            After::Stop(reason) => self.stopped(reason, None)?,
+            After::Run(limit) => return self.run_program(limit, requests),
+            After::Nothing => {}
+        }
+        Ok(true)
+    }
+
+    // Run in chunks, stopping early for pause and disconnect requests and
+    // keeping any others for later. Returns false on disconnect.
+    fn run_program(&mut self, limit: Option<u64>, requests: &Requests) -> io::Result<bool> {
+        let mut remaining = limit;
+        loop {
+            let chunk = remaining.map_or(CHUNK, |r| r.min(CHUNK));
+            let stop = match self.resume(Some(chunk)) {
+                Ok(stop) => stop,
+                Err(e) => {
+                    self.event("output", Json::object(vec![("output", e.into())]))?;
+                    return Ok(true);
+                }
+            };
+            if let Some(r) = remaining.as_mut() {
+                *r -= chunk;
+            }
+            if stop != Stop::Stepped || remaining == Some(0) {
+                self.report(stop)?;
+                return Ok(true);
+            }
+            self.flush_output()?;
+            while let Ok(request) = requests.try_recv() {
+                let request = request?;
+                match request.get("command").and_then(Json::as_str) {
+                    Some("pause") => {
+                        self.respond(&request, Ok(Json::Null))?;
+                        self.stopped("pause", None)?;
+                        return Ok(true);
+                    }
+                    Some("disconnect") | Some("terminate") => {
+                        self.respond(&request, Ok(Json::Null))?;
+                        return Ok(false);
+                    }
+                    _ => self.deferred.push_back(request),
+                }
+            }
+        }
+    }
+}
+
+/// The index of the file in `symbols` that `path` names
+fn source_file(symbols: &Symbols, path: &str) -> Option<usize> {
+    symbols.files.iter().position(|file| {
+        path == file
+            || path.ends_with(&format!("/{}", file))
+            || file.ends_with(&format!("/{}", path))
+    })
+}
+
+/// The first offset assembled from `line` of a file, if it produced any words
+fn code_at(symbols: &Symbols, file: usize, line: usize) -> Option<u64> {
+    symbols
+        .lines
+        .iter()
+        .find(|(_, (_, f, l))| *f == file && *l == line)
+        .map(|(offset, _)| u64::from(*offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(message: &str) -> String {
+        format!("Content-Length: {}\r\n\r\n{}", message.len(), message)
+    }
+
+    fn session(requests: &[&str]) -> Vec<Json> {
+        let mut input: Vec<u8> = Vec::new();
+        for (seq, request) in requests.iter().enumerate() {
+            let message = format!("{{\"seq\":{},\"type\":\"request\",{}}}", seq + 1, request);
+            input.extend(frame(&message).bytes());
+        }
+        let mut output = Vec::new();
+        Server::new(Box::new(io::Cursor::new(input)), &mut output)
+            .run()
+            .unwrap();
+
+        let mut reader: &[u8] = &output;
+        let mut messages = Vec::new();
+        while let Some(message) = read_message(&mut reader).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    fn find<'a>(messages: &'a [Json], kind: &str, name: &str) -> Vec<&'a Json> {
+        let key = if kind == "event" { "event" } else { "command" };
+        messages
+            .iter()
+            .filter(|m| m.get("type").and_then(Json::as_str) == Some(kind))
+            .filter(|m| m.get(key).and_then(Json::as_str) == Some(name))
+            .collect()
+    }
+
+    fn write_image(name: &str, words: &[Data]) -> String {
+        let path = std::env::temp_dir().join(name);
+        let bytes: Vec<u8> = words
+            .iter()
+            .flat_map(|w| w.to_be_bytes().to_vec())
+            .collect();
+        std::fs::write(&path, bytes).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn launch_break_inspect_and_run() {
+        let path = write_image(
+            "cult_dap_test.um",
+            &[
+                0b10110000000000000000000000000001, // r1 <- IN
+                0b10100000000000000000000000000001, // OUT r1
+                0b11010000000000000000000001001000, // r0 <- 'H'
+                0b10100000000000000000000000000000, // OUT r0
+                0x70000000,
+            ],
+        );
+        let launch = format!(
+            "\"command\":\"launch\",\"arguments\":{{\"program\":\"{}\",\"input\":\"!\"}}",
+            path
+        );
+        let messages = session(&[
+            "\"command\":\"initialize\",\"arguments\":{}",
+            &launch,
+            "\"command\":\"setBreakpoints\",\"arguments\":{\"breakpoints\":[{\"line\":4,\"condition\":\"r0 == 72\"}]}",
+            "\"command\":\"configurationDone\"",
+            "\"command\":\"variables\",\"arguments\":{\"variablesReference\":1}",
+            "\"command\":\"variables\",\"arguments\":{\"variablesReference\":3,\"start\":4,\"count\":2}",
+            "\"command\":\"evaluate\",\"arguments\":{\"expression\":\"r1 + 1\"}",
+            "\"command\":\"next\"",
+            "\"command\":\"continue\"",
+            "\"command\":\"disconnect\"",
+        ]);
+
+        assert_eq!(find(&messages, "event", "initialized").len(), 1);
+        let breakpoints = &find(&messages, "response", "setBreakpoints")[0];
+        assert!(breakpoints.to_string().contains("\"verified\":true"));
+
+        let stops = find(&messages, "event", "stopped");
+        assert_eq!(
+            stops[0]
+                .get("body")
+                .unwrap()
+                .get("reason")
+                .unwrap()
+                .as_str(),
+            Some("breakpoint")
+        );
+        assert_eq!(
+            stops[1]
+                .get("body")
+                .unwrap()
+                .get("reason")
+                .unwrap()
+                .as_str(),
+            Some("step")
+        );
+
+        let variables = find(&messages, "response", "variables");
+        assert!(variables[0]
+            .to_string()
+            .contains("\"value\":\"0x00000021\""));
+        assert!(variables[1]
+            .to_string()
+            .contains("\"name\":\"[4]\",\"value\":\"0x70000000\""));
+        assert!(!variables[1].to_string().contains("[3]"));
+        assert!(find(&messages, "response", "evaluate")[0]
+            .to_string()
+            .contains("34 (0x22)"));
+
+        let output: String = find(&messages, "event", "output")
+            .iter()
+            .map(|m| {
+                m.get("body")
+                    .unwrap()
+                    .get("output")
+                    .unwrap()
+                    .as_str()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(output, "!H");
+        assert_eq!(find(&messages, "event", "terminated").len(), 1);
+    }
+
+    fn stop_reasons(messages: &[Json]) -> Vec<&str> {
+        find(messages, "event", "stopped")
+            .iter()
+            .filter_map(|m| m.get("body")?.get("reason")?.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn breakpoints_on_source_lines() {
+        let source = "\
+            orth r1, 1
+
+            add r2, r2, r1
+            halt";
+        let assembly = crate::asm::build_with("hello.uma", source, &crate::asm::read_file).unwrap();
+        let image = write_image("cult_dap_source.um", &assembly.image().unwrap());
+        let map = std::env::temp_dir().join("cult_dap_source.sym");
+        std::fs::write(&map, Symbols::from(&assembly).to_json().to_string()).unwrap();
+        let launch = format!(
+            "\"command\":\"launch\",\"arguments\":{{\"program\":\"{}\",\"symbols\":\"{}\"}}",
+            image,
+            map.display()
+        );
+        let messages = session(&[
+            &launch,
+            "\"command\":\"setBreakpoints\",\"arguments\":{\"source\":{\"path\":\"/work/hello.uma\"},\"breakpoints\":[{\"line\":3},{\"line\":2}]}",
+            "\"command\":\"configurationDone\"",
+            "\"command\":\"variables\",\"arguments\":{\"variablesReference\":1}",
+            "\"command\":\"variables\",\"arguments\":{\"variablesReference\":0}",
+            "\"command\":\"stackTrace\"",
+            "\"command\":\"disconnect\"",
+        ]);
+
+        let breakpoints = find(&messages, "response", "setBreakpoints")[0]
+            .get("body")
+            .and_then(|body| body.get("breakpoints"))
+            .and_then(Json::as_array)
+            .unwrap();
+        assert_eq!(breakpoints[0].get("verified"), Some(&Json::Bool(true)));
+        assert_eq!(breakpoints[1].get("verified"), Some(&Json::Bool(false)));
+        assert_eq!(
+            breakpoints[1].get("message").and_then(Json::as_str),
+            Some("no code at line 2")
+        );
+        assert_eq!(stop_reasons(&messages), ["breakpoint"]);
+        let variables = find(&messages, "response", "variables");
+        // Stopped before the add: pc is the offset of line 3
+        assert!(variables[0]
+            .to_string()
+            .contains("\"name\":\"pc\",\"value\":\"1\""));
+        assert_eq!(variables[1].get("success"), Some(&Json::Bool(false)));
+        // The frame is in the source, not the listing
+        let frame = find(&messages, "response", "stackTrace")[0]
+            .get("body")
+            .and_then(|body| body.get("stackFrames"))
+            .and_then(Json::as_array)
+            .unwrap()[0]
+            .clone();
+        assert_eq!(frame.get("line").and_then(Json::as_u64), Some(3));
+        assert_eq!(
+            frame.get("name").and_then(Json::as_str),
+            Some("(hello.uma:3)")
+        );
+        let source = frame.get("source").unwrap();
+        assert_eq!(source.get("path").and_then(Json::as_str), Some("hello.uma"));
+    }
+
+    #[test]
+    fn running_programs_can_be_paused() {
+        let path = write_image(
+            "cult_dap_loop.um",
+            &crate::asm::assemble("loop: jmp loop").unwrap(),
+        );
+        let launch = format!(
+            "\"command\":\"launch\",\"arguments\":{{\"program\":\"{}\"}}",
+            path
+        );
+        let messages = session(&[
+            &launch,
+            "\"command\":\"configurationDone\"",
+            "\"command\":\"threads\"",
+            "\"command\":\"pause\"",
+            "\"command\":\"continue\"",
+            "\"command\":\"disconnect\"",
+        ]);
+        assert_eq!(stop_reasons(&messages), ["pause"]);
+        // Requests that arrive while running are answered once it stops
+        let responses: Vec<&str> = messages
+            .iter()
+            .filter_map(|m| m.get("command")?.as_str())
+            .collect();
+        assert_eq!(
+            responses,
+            [
+                "launch",
+                "configurationDone",
+                "pause",
+                "threads",
+                "continue",
+                "disconnect"
+            ]
+        );
+    }
+
+    #[test]
+    fn launch_errors_are_reported() {
+        let messages = session(&[
+            "\"command\":\"launch\",\"arguments\":{\"program\":\"/nonexistent.um\"}",
+            "\"command\":\"stackTrace\"",
+            "\"command\":\"pause\"",
+        ]);
+        for response in find(&messages, "response", "launch")
+            .iter()
+            .chain(find(&messages, "response", "stackTrace").iter())
+            .chain(find(&messages, "response", "pause").iter())
+        {
+            assert_eq!(response.get("success"), Some(&Json::Bool(false)));
+        }
+    }
+}
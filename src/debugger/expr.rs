@@ -0,0 +1,408 @@
+// Small expression language for breakpoint and watchpoint conditions.
+//
+//  r0..r7          registers
+//  pc              the execution finger
+//  icount          instructions executed so far
+//  hits            hit count of the breakpoint being evaluated
+//  mem[A][O]       platter O of array A (array 0 is the program)
+//
+// Literals are decimal, hex (0x41) or characters ('A'). Operators follow C
+// precedence and all arithmetic wraps on 64 bits; comparisons give 0 or 1.
+
+use crate::CPU;
+use std::convert::TryFrom;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnOp {
+    Not,
+    Complement,
+    Negate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Mul,
+    Div,
+    Rem,
+    Add,
+    Sub,
+    Shl,
+    Shr,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    BitAnd,
+    BitXor,
+    BitOr,
+    And,
+    Or,
+}
+
+impl BinOp {
+    fn precedence(self) -> u8 {
+        match self {
+            BinOp::Mul | BinOp::Div | BinOp::Rem => 10,
+            BinOp::Add | BinOp::Sub => 9,
+            BinOp::Shl | BinOp::Shr => 8,
+            BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => 7,
+            BinOp::Eq | BinOp::Ne => 6,
+            BinOp::BitAnd => 5,
+            BinOp::BitXor => 4,
+            BinOp::BitOr => 3,
+            BinOp::And => 2,
+            BinOp::Or => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Literal(u64),
+    Register(u8),
+    Finger,
+    InstructionCount,
+    Hits,
+    Mem(Box<Expr>, Box<Expr>),
+    Unary(UnOp, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ExprError {
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "at column {}: {}", self.position + 1, self.message)
+    }
+}
+
+fn error<T>(position: usize, message: String) -> Result<T, ExprError> {
+    Err(ExprError { position, message })
+}
+
+// ---------- LEXING ----------------------------------------------------------
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(u64),
+    Ident(String),
+    Op(&'static str),
+    End,
+}
+
+const OPERATORS: [&str; 24] = [
+    "&&", "||", "==", "!=", "<=", ">=", "<<", ">>", "+", "-", "*", "/", "%", "<", ">", "&", "^",
+    "|", "!", "~", "(", ")", "[", "]",
+];
+
+fn tokenize(text: &str) -> Result<Vec<(usize, Token)>, ExprError> {
+    let bytes = text.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if c.is_ascii_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < bytes.len() && bytes[i].is_ascii_alphanumeric() {
+                i += 1;
+            }
+            let word = &text[start..i];
+            let parsed = if let Some(hex) = word.strip_prefix("0x") {
+                u64::from_str_radix(hex, 16)
+            } else {
+                word.parse::<u64>()
+            };
+            match parsed {
+                Ok(n) => tokens.push((start, Token::Number(n))),
+                Err(_) => return error(start, format!("invalid number '{}'", word)),
+            }
+        } else if c == b'\'' {
+            if i + 2 < bytes.len() && bytes[i + 2] == b'\'' {
+                tokens.push((i, Token::Number(bytes[i + 1] as u64)));
+                i += 3;
+            } else {
+                return error(i, "unterminated character literal".to_string());
+            }
+        } else if c.is_ascii_alphabetic() || c == b'_' {
+            let start = i;
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+            tokens.push((start, Token::Ident(text[start..i].to_string())));
+        } else {
+            match OPERATORS.iter().find(|op| text[i..].starts_with(*op)) {
+                Some(op) => {
+                    tokens.push((i, Token::Op(op)));
+                    i += op.len();
+                }
+                None => return error(i, format!("unexpected character '{}'", c as char)),
+            }
+        }
+    }
+    tokens.push((text.len(), Token::End));
+    Ok(tokens)
+}
+
+// ---------- PARSING ---------------------------------------------------------
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.next].1
+    }
+
+    fn position(&self) -> usize {
+        self.tokens[self.next].0
+    }
+
+    fn advance(&mut self) -> Token {
+        let token = self.tokens[self.next].1.clone();
+        if token != Token::End {
+            self.next += 1;
+        }
+        token
+    }
+
+    fn expect(&mut self, op: &'static str) -> Result<(), ExprError> {
+        if *self.peek() == Token::Op(op) {
+            self.advance();
+            Ok(())
+        } else {
+            error(self.position(), format!("expected '{}'", op))
+        }
+    }
+
+    fn binary_op(&self) -> Option<BinOp> {
+        let op = match self.peek() {
+            Token::Op(op) => *op,
+            _ => return None,
+        };
+        Some(match op {
+            "*" => BinOp::Mul,
+            "/" => BinOp::Div,
+            "%" => BinOp::Rem,
+            "+" => BinOp::Add,
+            "-" => BinOp::Sub,
+            "<<" => BinOp::Shl,
+            ">>" => BinOp::Shr,
+            "<" => BinOp::Lt,
+            "<=" => BinOp::Le,
+            ">" => BinOp::Gt,
+            ">=" => BinOp::Ge,
+            "==" => BinOp::Eq,
+            "!=" => BinOp::Ne,
+            "&" => BinOp::BitAnd,
+            "^" => BinOp::BitXor,
+            "|" => BinOp::BitOr,
+            "&&" => BinOp::And,
+            "||" => BinOp::Or,
+            _ => return None,
+        })
+    }
+
+    // Precedence climbing: every operator is left associative
+    fn expression(&mut self, min_precedence: u8) -> Result<Expr, ExprError> {
+        let mut lhs = self.unary()?;
+        while let Some(op) = self.binary_op() {
+            if op.precedence() < min_precedence {
+                break;
+            }
+            self.advance();
+            let rhs = self.expression(op.precedence() + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, ExprError> {
+        let op = match self.peek() {
+            Token::Op("!") => UnOp::Not,
+            Token::Op("~") => UnOp::Complement,
+            Token::Op("-") => UnOp::Negate,
+            _ => return self.primary(),
+        };
+        self.advance();
+        Ok(Expr::Unary(op, Box::new(self.unary()?)))
+    }
+
+    fn primary(&mut self) -> Result<Expr, ExprError> {
+        let position = self.position();
+        match self.advance() {
+            Token::Number(n) => Ok(Expr::Literal(n)),
+            Token::Op("(") => {
+                let inner = self.expression(0)?;
+                self.expect(")")?;
+                Ok(inner)
+            }
+            Token::Ident(name) => match name.as_str() {
+                "pc" => Ok(Expr::Finger),
+                "icount" => Ok(Expr::InstructionCount),
+                "hits" => Ok(Expr::Hits),
+                "mem" => {
+                    self.expect("[")?;
+                    let array = self.expression(0)?;
+                    self.expect("]")?;
+                    self.expect("[")?;
+                    let offset = self.expression(0)?;
+                    self.expect("]")?;
+                    Ok(Expr::Mem(Box::new(array), Box::new(offset)))
+                }
+                _ => match register_number(&name) {
+                    Some(r) => Ok(Expr::Register(r)),
+                    None => error(position, format!("unknown name '{}'", name)),
+                },
+            },
+            Token::Op(op) => error(position, format!("unexpected '{}'", op)),
+            Token::End => error(position, "unexpected end of expression".to_string()),
+        }
+    }
+}
+
+fn register_number(name: &str) -> Option<u8> {
+    match name.as_bytes() {
+        [b'r', d @ b'0'..=b'7'] => Some(d - b'0'),
+        _ => None,
+    }
+}
+
+pub fn parse(text: &str) -> Result<Expr, ExprError> {
+    let mut parser = Parser {
+        tokens: tokenize(text)?,
+        next: 0,
+    };
+    let expr = parser.expression(0)?;
+    match parser.peek() {
+        Token::End => Ok(expr),
+        _ => error(parser.position(), "unexpected trailing input".to_string()),
+    }
+}
+
+// ---------- EVALUATION ------------------------------------------------------
+
+impl Expr {
+    /// Evaluate against the machine state; `hits` is the hit count of the
+    ///  breakpoint or watchpoint that owns this expression
+    pub fn eval(&self, cpu: &CPU, hits: u64) -> Result<u64, String> {
+        Ok(match self {
+            Expr::Literal(n) => *n,
+            Expr::Register(r) => cpu.register_file[*r as usize] as u64,
+            Expr::Finger => cpu.instruction_pointer,
+            Expr::InstructionCount => cpu.instruction_count,
+            Expr::Hits => hits,
+            Expr::Mem(array, offset) => {
+                let a = array.eval(cpu, hits)?;
+                let o = offset.eval(cpu, hits)?;
+                let platter = u32::try_from(a)
+                    .ok()
+                    .and_then(|a| cpu.array(a))
+                    .ok_or(format!("array {} is not active", a))?;
+                *platter
+                    .get(o as usize)
+                    .ok_or(format!("offset {} is outside array {}", o, a))? as u64
+            }
+            Expr::Unary(op, e) => {
+                let v = e.eval(cpu, hits)?;
+                match op {
+                    UnOp::Not => (v == 0) as u64,
+                    UnOp::Complement => !v,
+                    UnOp::Negate => v.wrapping_neg(),
+                }
+            }
+            Expr::Binary(BinOp::And, l, r) => {
+                (l.eval(cpu, hits)? != 0 && r.eval(cpu, hits)? != 0) as u64
+            }
+            Expr::Binary(BinOp::Or, l, r) => {
+                (l.eval(cpu, hits)? != 0 || r.eval(cpu, hits)? != 0) as u64
+            }
+            Expr::Binary(op, l, r) => {
+                let a = l.eval(cpu, hits)?;
+                let b = r.eval(cpu, hits)?;
+                match op {
+                    BinOp::Mul => a.wrapping_mul(b),
+                    BinOp::Div => a.checked_div(b).ok_or("division by zero")?,
+                    BinOp::Rem => a.checked_rem(b).ok_or("division by zero")?,
+                    BinOp::Add => a.wrapping_add(b),
+                    BinOp::Sub => a.wrapping_sub(b),
+                    BinOp::Shl => a.checked_shl(b as u32).unwrap_or(0),
+                    BinOp::Shr => a.checked_shr(b as u32).unwrap_or(0),
+                    BinOp::Lt => (a < b) as u64,
+                    BinOp::Le => (a <= b) as u64,
+                    BinOp::Gt => (a > b) as u64,
+                    BinOp::Ge => (a >= b) as u64,
+                    BinOp::Eq => (a == b) as u64,
+                    BinOp::Ne => (a != b) as u64,
+                    BinOp::BitAnd => a & b,
+                    BinOp::BitXor => a ^ b,
+                    BinOp::BitOr => a | b,
+                    BinOp::And | BinOp::Or => unreachable!(),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(text: &str, cpu: &CPU) -> Result<u64, String> {
+        parse(text).unwrap().eval(cpu, 7)
+    }
+
+    #[test]
+    fn precedence() {
+        let cpu = CPU::new(vec![]);
+        assert_eq!(eval("1 + 2 * 3", &cpu), Ok(7));
+        assert_eq!(eval("(1 + 2) * 3", &cpu), Ok(9));
+        assert_eq!(eval("10 - 4 - 3", &cpu), Ok(3));
+        assert_eq!(eval("1 << 4 | 1", &cpu), Ok(17));
+        assert_eq!(eval("1 == 1 && 2 > 3 || 'A' == 0x41", &cpu), Ok(1));
+        assert_eq!(eval("!0 + ~0xFFFFFFFFFFFFFFFE", &cpu), Ok(2));
+    }
+
+    #[test]
+    fn machine_state() {
+        let mut cpu = CPU::new(vec![0x70000000, 0xDEAD]);
+        cpu.register_file[3] = 0x41;
+        cpu.register_file[0] = 101;
+        cpu.register_file[1] = 5;
+        cpu.memory.insert(5, vec![0, 0, 0, 0, 99]);
+        cpu.instruction_pointer = 1;
+        assert_eq!(eval("r3 == 0x41 && r0 > 100", &cpu), Ok(1));
+        assert_eq!(eval("mem[r1][4]", &cpu), Ok(99));
+        assert_eq!(eval("mem[0][pc]", &cpu), Ok(0xDEAD));
+        assert_eq!(eval("hits + icount", &cpu), Ok(7));
+    }
+
+    #[test]
+    fn evaluation_errors() {
+        let cpu = CPU::new(vec![0x70000000]);
+        assert!(eval("mem[3][0]", &cpu).is_err());
+        assert!(eval("mem[0][1]", &cpu).is_err());
+        assert!(eval("1 / (r0 - r0)", &cpu).is_err());
+        // Short-circuiting skips the failing side
+        assert_eq!(eval("0 && mem[3][0]", &cpu), Ok(0));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(parse("r8").unwrap_err().position, 0);
+        assert_eq!(parse("r1 +").unwrap_err().position, 4);
+        assert_eq!(parse("mem[1]").unwrap_err().position, 6);
+        assert_eq!(parse("r1 r2").unwrap_err().position, 3);
+        assert_eq!(parse("r1 @ 2").unwrap_err().position, 3);
+    }
+}
@@ -0,0 +1,406 @@
+// GDB remote serial protocol stub.
+//
+// Exposes a Debugger to a GDB compatible front end over TCP. The register set
+// is r0..r7 followed by the execution finger, and array 0 is presented as
+// byte addressed big-endian memory, so platter N lives at address 4 * N and
+// the finger is reported as a byte address too.
+
+use super::{Debugger, Stop};
+use crate::{Data, Fault};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+// Instructions to run between checks for an interrupt from the client
+const CHUNK: u64 = 1 << 16;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.boundvariable.um">
+    <reg name="r0" bitsize="32" type="uint32"/>
+    <reg name="r1" bitsize="32" type="uint32"/>
+    <reg name="r2" bitsize="32" type="uint32"/>
+    <reg name="r3" bitsize="32" type="uint32"/>
+    <reg name="r4" bitsize="32" type="uint32"/>
+    <reg name="r5" bitsize="32" type="uint32"/>
+    <reg name="r6" bitsize="32" type="uint32"/>
+    <reg name="r7" bitsize="32" type="uint32"/>
+    <reg name="pc" bitsize="32" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+fn hex_decode(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_hex(text: &str) -> Option<u64> {
+    u64::from_str_radix(text, 16).ok()
+}
+
+// "ADDR,LEN" as used by m, M, Z and z
+fn parse_range(text: &str) -> Option<(u64, u64)> {
+    let mut parts = text.splitn(2, ',');
+    Some((parse_hex(parts.next()?)?, parse_hex(parts.next()?)?))
+}
+
+fn stop_reply(stop: &Stop) -> String {
+    match stop {
+        Stop::Halted => "W00".to_string(),
+        Stop::Fault(Fault::InvalidInstruction(_)) => "S04".to_string(),
+        Stop::Fault(Fault::DivideByZero) => "S08".to_string(),
+        Stop::Fault(_) => "S0b".to_string(),
+        _ => "S05".to_string(),
+    }
+}
+
+enum Outcome {
+    Reply(String),
+    Detach(String),
+}
+
+pub struct Session {
+    debugger: Debugger,
+    // Debugger breakpoint ids by GDB byte address
+    breakpoints: BTreeMap<u64, usize>,
+}
+
+impl Session {
+    pub fn new(debugger: Debugger) -> Self {
+        Session {
+            debugger,
+            breakpoints: BTreeMap::new(),
+        }
+    }
+
+    fn registers(&self) -> [Data; 9] {
+        let cpu = &self.debugger.cpu;
+        let mut registers = [0; 9];
+        registers[..8].copy_from_slice(&cpu.register_file);
+        registers[8] = (cpu.instruction_pointer * 4) as Data;
+        registers
+    }
+
+    fn set_register(&mut self, n: usize, value: Data) -> bool {
+        let cpu = &mut self.debugger.cpu;
+        match n {
+            0..=7 => cpu.register_file[n] = value,
+            8 => cpu.instruction_pointer = (value / 4) as u64,
+            _ => return false,
+        }
+        true
+    }
+
+    fn read_memory(&self, address: u64, length: u64) -> String {
+        let platter = &self.debugger.cpu.instruction_platter;
+        let mut reply = String::new();
+        for a in address..address.saturating_add(length) {
+            match platter.get((a / 4) as usize) {
+                Some(word) => reply.push_str(&format!("{:02x}", word >> (24 - 8 * (a % 4)) & 0xFF)),
+                None => break,
+            }
+        }
+        if reply.is_empty() && length > 0 {
+            "E01".to_string()
+        } else {
+            reply
+        }
+    }
+
+    fn write_memory(&mut self, address: u64, bytes: &[u8]) -> String {
+        let platter = &mut self.debugger.cpu.instruction_platter;
+        match address.checked_add(bytes.len() as u64) {
+            Some(end) if end <= platter.len() as u64 * 4 => {}
+            _ => return "E01".to_string(),
+        }
+        for (a, byte) in (address..).zip(bytes) {
+            let shift = 24 - 8 * (a % 4);
+            let word = &mut platter[(a / 4) as usize];
+            *word = (*word & !(0xFF << shift)) | ((*byte as Data) << shift);
+        }
+        "OK".to_string()
+    }
+
+    fn insert_breakpoint(&mut self, address: u64) -> String {
+        if !self.breakpoints.contains_key(&address) {
+            let id = self.debugger.add_breakpoint(address / 4, None).unwrap();
+            self.breakpoints.insert(address, id);
+        }
+        "OK".to_string()
+    }
+
+    fn remove_breakpoint(&mut self, address: u64) -> String {
+        if let Some(id) = self.breakpoints.remove(&address) {
+            self.debugger.delete(id);
+        }
+        "OK".to_string()
+    }
+
+    // Run in chunks, giving the client a chance to interrupt with ^C
+    fn run(&mut self, stream: &mut TcpStream, limit: Option<u64>) -> io::Result<String> {
+        let mut remaining = limit;
+        loop {
+            let chunk = remaining.map_or(CHUNK, |r| r.min(CHUNK));
+            let stop = self.debugger.resume(Some(chunk));
+            match stop {
+                Stop::Stepped => {}
+                other => return Ok(stop_reply(&other)),
+            }
+            if let Some(r) = remaining.as_mut() {
+                *r -= chunk;
+                if *r == 0 {
+                    return Ok("S05".to_string());
+                }
+            }
+            stream.set_nonblocking(true)?;
+            let mut byte = [0u8];
+            let interrupted = match stream.read(&mut byte) {
+                Ok(1) => byte[0] == 0x03,
+                Ok(_) => false,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => false,
+                Err(e) => return Err(e),
+            };
+            stream.set_nonblocking(false)?;
+            if interrupted {
+                return Ok("S02".to_string());
+            }
+        }
+    }
+
+    fn handle(&mut self, packet: &str, stream: &mut TcpStream) -> io::Result<Outcome> {
+        let (command, args) = packet.split_at(packet.len().min(1));
+        let reply = match command {
+            "?" => "S05".to_string(),
+            "g" => self
+                .registers()
+                .iter()
+                .map(|r| format!("{:08x}", r))
+                .collect(),
+            "G" => match hex_decode(args) {
+                Some(bytes) if bytes.len() == 36 => {
+                    for (n, chunk) in bytes.chunks(4).enumerate() {
+                        let value = chunk.iter().fold(0, |w, b| (w << 8) | *b as Data);
+                        self.set_register(n, value);
+                    }
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            "p" => match parse_hex(args).and_then(|n| self.registers().get(n as usize).copied()) {
+                Some(value) => format!("{:08x}", value),
+                None => "E01".to_string(),
+            },
+            "P" => {
+                let mut parts = args.splitn(2, '=');
+                let n = parts.next().and_then(parse_hex);
+                let value = parts.next().and_then(parse_hex);
+                match (n, value) {
+                    (Some(n), Some(value)) if self.set_register(n as usize, value as Data) => {
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "m" => match parse_range(args) {
+                Some((address, length)) => self.read_memory(address, length),
+                None => "E01".to_string(),
+            },
+            "M" => {
+                let mut parts = args.splitn(2, ':');
+                let range = parts.next().and_then(parse_range);
+                match (range, parts.next().and_then(hex_decode)) {
+                    (Some((address, length)), Some(bytes)) if bytes.len() as u64 == length => {
+                        self.write_memory(address, &bytes)
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "Z" | "z" if args.starts_with("0,") => {
+                match args[2..].rsplit_once(',').and_then(|(a, _)| parse_hex(a)) {
+                    Some(address) if command == "Z" => self.insert_breakpoint(address),
+                    Some(address) => self.remove_breakpoint(address),
+                    None => "E01".to_string(),
+                }
+            }
+            "c" | "s" => {
+                if let Some(address) = parse_hex(args) {
+                    self.set_register(8, address as Data);
+                }
+                let limit = if command == "s" { Some(1) } else { None };
+                self.run(stream, limit)?
+            }
+            "H" => "OK".to_string(),
+            "k" => return Ok(Outcome::Detach(String::new())),
+            "D" => return Ok(Outcome::Detach("OK".to_string())),
+            _ => {
+                if packet.starts_with("qSupported") {
+                    "PacketSize=4000;qXfer:features:read+".to_string()
+                } else if let Some(annex) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+                    match parse_range(annex) {
+                        Some((offset, length)) => {
+                            let offset = (offset as usize).min(TARGET_XML.len());
+                            let end = (offset + length as usize).min(TARGET_XML.len());
+                            let more = if end < TARGET_XML.len() { "m" } else { "l" };
+                            format!("{}{}", more, &TARGET_XML[offset..end])
+                        }
+                        None => "E01".to_string(),
+                    }
+                } else if packet == "qAttached" {
+                    "1".to_string()
+                } else if packet == "qfThreadInfo" {
+                    "m1".to_string()
+                } else if packet == "qsThreadInfo" {
+                    "l".to_string()
+                } else if packet == "qC" {
+                    "QC1".to_string()
+                } else {
+                    String::new()
+                }
+            }
+        };
+        Ok(Outcome::Reply(reply))
+    }
+
+    /// Serve one client until it detaches, kills the session or hangs up
+    pub fn serve(&mut self, stream: &mut TcpStream) -> io::Result<()> {
+        loop {
+            let packet = match read_packet(stream)? {
+                Some(packet) => packet,
+                None => return Ok(()),
+            };
+            match self.handle(&packet, stream)? {
+                Outcome::Reply(reply) => write_packet(stream, &reply)?,
+                Outcome::Detach(reply) => {
+                    if !reply.is_empty() {
+                        write_packet(stream, &reply)?;
+                    }
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+// Read the next packet, acknowledging it. Stray acks and interrupts are
+//  skipped; None means the connection closed.
+fn read_packet<S: Read + Write>(stream: &mut S) -> io::Result<Option<String>> {
+    let mut byte = [0u8];
+    loop {
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'$' {
+                break;
+            }
+        }
+        let mut data = Vec::new();
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'#' {
+                break;
+            }
+            data.push(byte[0]);
+        }
+        let mut sum = [0u8; 2];
+        stream.read_exact(&mut sum)?;
+        let expected = std::str::from_utf8(&sum)
+            .ok()
+            .and_then(|s| u8::from_str_radix(s, 16).ok());
+        if expected == Some(checksum(&data)) {
+            stream.write_all(b"+")?;
+            return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+        }
+        stream.write_all(b"-")?;
+    }
+}
+
+fn write_packet(stream: &mut TcpStream, data: &str) -> io::Result<()> {
+    let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+    stream.write_all(packet.as_bytes())?;
+    stream.flush()
+}
+
+/// Accept a single GDB connection on `address` and serve it
+pub fn listen(address: &str, debugger: Debugger) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    eprintln!("Waiting for GDB on {}", listener.local_addr()?);
+    let (mut stream, peer) = listener.accept()?;
+    eprintln!("GDB connected from {}", peer);
+    Session::new(debugger).serve(&mut stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CPU;
+    use std::thread;
+
+    struct Client(TcpStream);
+
+    impl Client {
+        fn request(&mut self, data: &str) -> String {
+            let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+            self.0.write_all(packet.as_bytes()).unwrap();
+            let mut ack = [0u8];
+            self.0.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+');
+            read_packet(&mut self.0).unwrap().unwrap()
+        }
+    }
+
+    fn connect(program: Vec<Data>) -> (Client, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let debugger = Debugger::new(CPU::new(program));
+            Session::new(debugger).serve(&mut stream).unwrap();
+        });
+        (Client(TcpStream::connect(address).unwrap()), server)
+    }
+
+    #[test]
+    fn registers_memory_and_breakpoints() {
+        let (mut client, server) = connect(vec![
+            0b11010010000000000000000000000101, // r1 <- 5
+            0b00110000000000000000000000001001, // r0 <- r1 + r1
+            0b00110000000000000000000000001001, // r0 <- r1 + r1
+            0x70000000,
+        ]);
+        assert!(client.request("qSupported:swbreak+").contains("PacketSize"));
+        assert_eq!(client.request("?"), "S05");
+        assert_eq!(client.request("m0,6"), "d20000053000");
+        assert_eq!(client.request("Z0,8,4"), "OK");
+        assert_eq!(client.request("c"), "S05");
+        assert_eq!(client.request("p8"), "00000008");
+        let registers = format!("0000000a00000005{}00000008", "00000000".repeat(6));
+        assert_eq!(client.request("g"), registers);
+        assert_eq!(client.request("P1=00000007"), "OK");
+        assert_eq!(client.request("s"), "S05");
+        assert_eq!(client.request("p0"), "0000000e");
+        // Patch the HALT into an invalid instruction
+        assert_eq!(client.request("M10,1:00"), "E01");
+        assert_eq!(client.request("Mfffffffffffffffe,4:00000000"), "E01");
+        assert_eq!(client.request("Mc,1:f0"), "OK");
+        assert_eq!(client.request("z0,8,4"), "OK");
+        assert_eq!(client.request("c"), "S04");
+        assert_eq!(client.request("Mc,1:70"), "OK");
+        assert_eq!(client.request("c"), "W00");
+        assert_eq!(client.request("D"), "OK");
+        server.join().unwrap();
+    }
+}
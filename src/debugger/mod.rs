@@ -0,0 +1,751 @@
+// ---------- DEBUGGER --------------------------------------------------------
+//
+// Line oriented debugger around a CPU. Breakpoints stop before the
+// instruction at an offset of array 0 is executed, watchpoints stop after any
+// instruction that changes the value of an expression. Either kind may carry
+// a condition written in the expression language from `expr`. Execution can
+// also run backwards through the history kept by `reverse`. With a symbol
+// map, offsets are shown by label and source line, and breakpoints can be
+// set on either.
+
+pub mod dap;
+pub mod expr;
+pub mod gdb;
+pub mod reverse;
+
+use crate::symbols::Symbols;
+use crate::{disasm, Data, Fault, PlatterIndex, CPU};
+use expr::{Expr, ExprError};
+use reverse::History;
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+
+struct Condition {
+    text: String,
+    expr: Expr,
+}
+
+enum Kind {
+    Break(PlatterIndex),
+    Watch {
+        text: String,
+        expr: Expr,
+        last: Option<u64>,
+    },
+}
+
+struct Point {
+    kind: Kind,
+    condition: Option<Condition>,
+    hits: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Stop {
+    Halted,
+    Stepped,
+    Breakpoint(usize),
+    Watchpoint {
+        id: usize,
+        old: Option<u64>,
+        new: Option<u64>,
+    },
+    ConditionError(usize, String),
+    Fault(Fault),
+    Beginning,
+}
+
+pub struct Debugger {
+    pub cpu: CPU,
+    points: BTreeMap<usize, Point>,
+    next_point: usize,
+    history: History,
+    pub symbols: Symbols,
+    /// The instruction count at which breakpoints on the current finger
+    ///  were last checked, so resuming neither skips nor repeats them
+    checked: Option<u64>,
+}
+
+fn parse_condition(text: Option<&str>) -> Result<Option<Condition>, ExprError> {
+    match text {
+        None => Ok(None),
+        Some(text) => Ok(Some(Condition {
+            text: text.to_string(),
+            expr: expr::parse(text)?,
+        })),
+    }
+}
+
+fn parse_number(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+fn show(value: Option<u64>) -> String {
+    match value {
+        Some(v) => format!("{} (0x{:x})", v, v),
+        None => "<unavailable>".to_string(),
+    }
+}
+
+impl Debugger {
+    pub fn new(mut cpu: CPU) -> Self {
+        let history = History::attach(&mut cpu);
+        Debugger {
+            cpu,
+            points: BTreeMap::new(),
+            next_point: 1,
+            history,
+            symbols: Symbols::default(),
+            checked: None,
+        }
+    }
+
+    fn insert(&mut self, kind: Kind, condition: Option<Condition>) -> usize {
+        let id = self.next_point;
+        self.next_point += 1;
+        self.points.insert(
+            id,
+            Point {
+                kind,
+                condition,
+                hits: 0,
+            },
+        );
+        id
+    }
+
+    pub fn add_breakpoint(
+        &mut self,
+        offset: PlatterIndex,
+        condition: Option<&str>,
+    ) -> Result<usize, ExprError> {
+        let condition = parse_condition(condition)?;
+        Ok(self.insert(Kind::Break(offset), condition))
+    }
+
+    pub fn add_watchpoint(
+        &mut self,
+        text: &str,
+        condition: Option<&str>,
+    ) -> Result<usize, ExprError> {
+        let expr = expr::parse(text)?;
+        let condition = parse_condition(condition)?;
+        let last = expr.eval(&self.cpu, 0).ok();
+        let kind = Kind::Watch {
+            text: text.to_string(),
+            expr,
+            last,
+        };
+        Ok(self.insert(kind, condition))
+    }
+
+    /// Replace (or with `None`, remove) the condition of a point
+    pub fn set_condition(&mut self, id: usize, condition: Option<&str>) -> Result<bool, ExprError> {
+        let condition = parse_condition(condition)?;
+        match self.points.get_mut(&id) {
+            Some(point) => {
+                point.condition = condition;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    pub fn delete(&mut self, id: usize) -> bool {
+        self.points.remove(&id).is_some()
+    }
+
+    pub fn hits(&self, id: usize) -> Option<u64> {
+        self.points.get(&id).map(|p| p.hits)
+    }
+
+    fn holds(cpu: &CPU, point: &Point, hits: u64) -> Result<bool, String> {
+        match &point.condition {
+            None => Ok(true),
+            Some(c) => c.expr.eval(cpu, hits).map(|v| v != 0),
+        }
+    }
+
+    // Hits count every arrival, so a condition may refer to the current one
+    fn check(cpu: &CPU, id: usize, point: &mut Point) -> Option<Stop> {
+        point.hits += 1;
+        match Debugger::holds(cpu, point, point.hits) {
+            Ok(false) => None,
+            Ok(true) => Some(Stop::Breakpoint(id)),
+            Err(e) => Some(Stop::ConditionError(id, e)),
+        }
+    }
+
+    fn check_breakpoints(&mut self) -> Option<Stop> {
+        let cpu = &self.cpu;
+        for (id, point) in self.points.iter_mut() {
+            if let Kind::Break(offset) = point.kind {
+                if offset == cpu.instruction_pointer {
+                    if let Some(stop) = Debugger::check(cpu, *id, point) {
+                        return Some(stop);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn check_watchpoints(&mut self) -> Option<Stop> {
+        let cpu = &self.cpu;
+        let mut stop = None;
+        for (id, point) in self.points.iter_mut() {
+            let (old, new) = match &mut point.kind {
+                Kind::Watch { expr, last, .. } => {
+                    let new = expr.eval(cpu, point.hits).ok();
+                    if new == *last {
+                        continue;
+                    }
+                    (std::mem::replace(last, new), new)
+                }
+                Kind::Break(_) => continue,
+            };
+            // Keep going so every watched value stays current
+            if stop.is_none() {
+                stop = match Debugger::check(cpu, *id, point) {
+                    Some(Stop::Breakpoint(id)) => Some(Stop::Watchpoint { id, old, new }),
+                    other => other,
+                };
+            }
+        }
+        stop
+    }
+
+    /// Run until the machine halts, a point fires, or `limit` instructions
+    ///  have executed. A breakpoint on the starting finger fires only if it
+    ///  was not the last stop, so resuming from one makes progress.
+    pub fn resume(&mut self, limit: Option<u64>) -> Stop {
+        let mut steps = 0;
+        if self.cpu.status && self.checked != Some(self.cpu.instruction_count) {
+            self.checked = Some(self.cpu.instruction_count);
+            if let Some(stop) = self.check_breakpoints() {
+                return stop;
+            }
+        }
+        loop {
+            if !self.cpu.status {
+                return Stop::Halted;
+            }
+            if limit == Some(steps) {
+                return Stop::Stepped;
+            }
+            self.history.before_step(&self.cpu);
+            if let Err(fault) = self.cpu.step() {
+                return Stop::Fault(fault);
+            }
+            self.history.after_step(&self.cpu);
+            steps += 1;
+            if let Some(stop) = self.check_watchpoints() {
+                return stop;
+            }
+            self.checked = Some(self.cpu.instruction_count);
+            if self.cpu.status {
+                if let Some(stop) = self.check_breakpoints() {
+                    return stop;
+                }
+            }
+        }
+    }
+
+    /// Go back `count` instructions, ignoring breakpoints and watchpoints
+    pub fn reverse_step(&mut self, count: u64) -> Stop {
+        let now = self.cpu.instruction_count;
+        if now <= self.history.start() {
+            return Stop::Beginning;
+        }
+        let target = now.saturating_sub(count).max(self.history.start());
+        self.history.rewind(&mut self.cpu, target);
+        self.sync_watchpoints();
+        Stop::Stepped
+    }
+
+    // The values of every watchpoint, in the order of `points`
+    fn watched_values(&self) -> Vec<Option<u64>> {
+        let cpu = &self.cpu;
+        self.points
+            .values()
+            .map(|point| match &point.kind {
+                Kind::Watch { expr, .. } => expr.eval(cpu, point.hits).ok(),
+                Kind::Break(_) => None,
+            })
+            .collect()
+    }
+
+    // The last stop strictly between the current instruction count and
+    //  `end`, found by replaying forward. Hit counts are left untouched.
+    fn last_stop_before(&mut self, end: u64) -> Option<(u64, Stop)> {
+        let mut found = None;
+        let mut before = self.watched_values();
+        while self.cpu.instruction_count < end {
+            let count = self.cpu.instruction_count;
+            for (id, point) in self.points.iter() {
+                if let Kind::Break(offset) = point.kind {
+                    if offset == self.cpu.instruction_pointer {
+                        match Debugger::holds(&self.cpu, point, point.hits) {
+                            Ok(false) => {}
+                            Ok(true) => found = Some((count, Stop::Breakpoint(*id))),
+                            Err(e) => found = Some((count, Stop::ConditionError(*id, e))),
+                        }
+                    }
+                }
+            }
+            self.history.replay_step(&mut self.cpu);
+            let after = self.watched_values();
+            let changes = self.points.iter().zip(before.iter().zip(after.iter()));
+            for ((id, point), (old, new)) in changes {
+                if let Kind::Watch { .. } = point.kind {
+                    if old != new && Debugger::holds(&self.cpu, point, point.hits) == Ok(true) {
+                        let (id, old, new) = (*id, *old, *new);
+                        found = Some((count, Stop::Watchpoint { id, old, new }));
+                    }
+                }
+            }
+            before = after;
+        }
+        found
+    }
+
+    /// Run backwards to the most recent breakpoint hit or watched change.
+    ///  For a watchpoint the finger is left on the instruction that wrote it.
+    pub fn reverse_resume(&mut self) -> Stop {
+        let now = self.cpu.instruction_count;
+        let mut end = now;
+        while let Some(start) = self.history.restore_before(&mut self.cpu, end) {
+            if let Some((count, stop)) = self.last_stop_before(end) {
+                self.history.rewind(&mut self.cpu, count);
+                self.sync_watchpoints();
+                return stop;
+            }
+            end = start;
+        }
+        self.history.rewind(&mut self.cpu, self.history.start());
+        self.sync_watchpoints();
+        Stop::Beginning
+    }
+
+    // Watched values must describe the state we travelled to, and a
+    //  breakpoint where we stopped counts as reported
+    fn sync_watchpoints(&mut self) {
+        self.checked = Some(self.cpu.instruction_count);
+        let values = self.watched_values();
+        for (point, value) in self.points.values_mut().zip(values) {
+            if let Kind::Watch { last, .. } = &mut point.kind {
+                *last = value;
+            }
+        }
+    }
+
+    /// An offset or a location known to the symbol map
+    fn offset(&self, text: &str) -> Option<PlatterIndex> {
+        parse_number(text).or_else(|| self.symbols.resolve(text).map(PlatterIndex::from))
+    }
+
+    fn describe(&self, id: usize, point: &Point) -> String {
+        let mut line = match &point.kind {
+            Kind::Break(offset) => format!(
+                "{:<3} breakpoint at {}",
+                id,
+                self.symbols.describe(*offset as Data)
+            ),
+            Kind::Watch { text, .. } => format!("{:<3} watchpoint on {}", id, text),
+        };
+        if let Some(c) = &point.condition {
+            line.push_str(&format!(" if {}", c.text));
+        }
+        line.push_str(&format!(", hit {} times", point.hits));
+        line
+    }
+
+    fn report(&self, stop: &Stop, output: &mut dyn Write) -> std::io::Result<()> {
+        match stop {
+            Stop::Halted => writeln!(output, "Machine halted")?,
+            Stop::Stepped => {}
+            Stop::Breakpoint(id) => writeln!(
+                output,
+                "Breakpoint {}, hit {}",
+                id,
+                self.hits(*id).unwrap_or(0)
+            )?,
+            Stop::Watchpoint { id, old, new } => writeln!(
+                output,
+                "Watchpoint {}: {} -> {}",
+                id,
+                show(*old),
+                show(*new)
+            )?,
+            Stop::ConditionError(id, e) => {
+                writeln!(output, "Error evaluating condition of {}: {}", id, e)?
+            }
+            Stop::Fault(fault) => writeln!(output, "Fault: {}", fault)?,
+            Stop::Beginning => writeln!(output, "Reached the start of the recorded history")?,
+        }
+        writeln!(
+            output,
+            "pc={} icount={}",
+            self.symbols.describe(self.cpu.instruction_pointer as Data),
+            self.cpu.instruction_count
+        )?;
+        let pc = self.cpu.instruction_pointer as usize;
+        for line in disasm::annotated(&self.cpu.instruction_platter, pc, 1, &self.symbols) {
+            writeln!(output, "{}", line)?;
+        }
+        Ok(())
+    }
+
+    fn command(&mut self, line: &str, output: &mut dyn Write) -> std::io::Result<bool> {
+        let line = line.trim();
+        let (command, rest) = match line.find(' ') {
+            Some(i) => (&line[..i], line[i..].trim()),
+            None => (line, ""),
+        };
+        let (subject, condition) = match rest.find(" if ") {
+            Some(i) => (rest[..i].trim(), Some(rest[i + 4..].trim())),
+            None => (rest, None),
+        };
+
+        match command {
+            "" => {}
+            "b" | "break" => match self.offset(subject) {
+                Some(offset) => match self.add_breakpoint(offset, condition) {
+                    Ok(id) => writeln!(
+                        output,
+                        "Breakpoint {} at {}",
+                        id,
+                        self.symbols.describe(offset as Data)
+                    )?,
+                    Err(e) => writeln!(output, "Bad condition {}", e)?,
+                },
+                None => writeln!(
+                    output,
+                    "Usage: break OFFSET|LABEL[+N]|FILE:LINE [if CONDITION]"
+                )?,
+            },
+            "w" | "watch" => match self.add_watchpoint(subject, condition) {
+                Ok(id) => writeln!(output, "Watchpoint {} on {}", id, subject)?,
+                Err(e) => writeln!(output, "Bad expression {}", e)?,
+            },
+            "condition" => {
+                let (id, text) = match rest.find(' ') {
+                    Some(i) => (&rest[..i], Some(rest[i..].trim())),
+                    None => (rest, None),
+                };
+                match id.parse().map(|id| self.set_condition(id, text)) {
+                    Ok(Ok(true)) => {}
+                    Ok(Ok(false)) | Err(_) => writeln!(output, "No point {}", id)?,
+                    Ok(Err(e)) => writeln!(output, "Bad condition {}", e)?,
+                }
+            }
+            "d" | "delete" => {
+                if !rest.parse().map(|id| self.delete(id)).unwrap_or(false) {
+                    writeln!(output, "No point {}", rest)?;
+                }
+            }
+            "i" | "info" => {
+                for (id, point) in self.points.iter() {
+                    writeln!(output, "{}", self.describe(*id, point))?;
+                }
+            }
+            "c" | "continue" => {
+                let stop = self.resume(None);
+                self.report(&stop, output)?;
+            }
+            "s" | "step" => match if rest.is_empty() {
+                Some(1)
+            } else {
+                parse_number(rest)
+            } {
+                Some(n) => {
+                    let stop = self.resume(Some(n));
+                    self.report(&stop, output)?;
+                }
+                None => writeln!(output, "Usage: step [COUNT]")?,
+            },
+            "rs" | "reverse-step" => match if rest.is_empty() {
+                Some(1)
+            } else {
+                parse_number(rest)
+            } {
+                Some(n) => {
+                    let stop = self.reverse_step(n);
+                    self.report(&stop, output)?;
+                }
+                None => writeln!(output, "Usage: reverse-step [COUNT]")?,
+            },
+            "rc" | "reverse-continue" => {
+                let stop = self.reverse_resume();
+                self.report(&stop, output)?;
+            }
+            "r" | "regs" => {
+                for (i, r) in self.cpu.register_file.iter().enumerate() {
+                    writeln!(output, "r{} = 0x{:08x} ({})", i, r, r)?;
+                }
+                writeln!(output, "pc = {}", self.cpu.instruction_pointer)?;
+                writeln!(output, "icount = {}", self.cpu.instruction_count)?;
+            }
+            "p" | "print" => match expr::parse(rest).map(|e| e.eval(&self.cpu, 0)) {
+                Ok(value) => writeln!(output, "{}", show(value.ok()))?,
+                Err(e) => writeln!(output, "Bad expression {}", e)?,
+            },
+            "x" | "disasm" => {
+                let numbers: Option<Vec<u64>> = rest.split_whitespace().map(parse_number).collect();
+                let numbers = numbers.unwrap_or_default();
+                let array = numbers.first().copied().unwrap_or(0) as Data;
+                let pc = self.cpu.instruction_pointer;
+                let start = numbers
+                    .get(1)
+                    .copied()
+                    .unwrap_or(if array == 0 { pc } else { 0 });
+                let count = numbers.get(2).copied().unwrap_or(10);
+                match self.cpu.array(array) {
+                    Some(words) => {
+                        let lines = if array == 0 {
+                            disasm::annotated(words, start as usize, count as usize, &self.symbols)
+                        } else {
+                            disasm::listing(words, start as usize, count as usize)
+                        };
+                        for line in lines {
+                            writeln!(output, "{}", line)?;
+                        }
+                    }
+                    None => writeln!(output, "Array {} is not active", array)?,
+                }
+            }
+            "q" | "quit" => return Ok(false),
+            "h" | "help" => writeln!(
+                output,
+                "break OFFSET|LABEL[+N]|FILE:LINE [if COND] | watch EXPR [if COND]\n\
+                 condition N [COND] | delete N\n\
+                 info | continue | step [N] | reverse-continue | reverse-step [N]\n\
+                 regs | print EXPR | disasm [ARRAY [OFFSET [COUNT]]] | quit"
+            )?,
+            _ => writeln!(output, "Unknown command '{}', try help", command)?,
+        }
+        Ok(true)
+    }
+
+    pub fn repl(&mut self, input: &mut dyn BufRead, output: &mut dyn Write) {
+        let mut line = String::new();
+        loop {
+            write!(output, "(cult) ").unwrap();
+            output.flush().unwrap();
+            line.clear();
+            if input.read_line(&mut line).unwrap() == 0 {
+                break;
+            }
+            if !self.command(&line, output).unwrap() {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // r0 <- r0 + r1 in a loop: ADD, CALL(r2)[r3], with r2 = 0 and r3 = 0
+    fn counting_loop() -> CPU {
+        let program: Vec<Data> = vec![
+            0b00110000000000000000000000000001, // r0 <- r0 + r1
+            0b11000000000000000000000000010011, // CALL(r2)[r3]
+        ];
+        let mut cpu = CPU::new(program);
+        cpu.register_file[1] = 1;
+        cpu
+    }
+
+    #[test]
+    fn plain_breakpoint() {
+        let mut debugger = Debugger::new(counting_loop());
+        let id = debugger.add_breakpoint(1, None).unwrap();
+        assert_eq!(debugger.resume(None), Stop::Breakpoint(id));
+        assert_eq!(debugger.cpu.register_file[0], 1);
+        assert_eq!(debugger.resume(None), Stop::Breakpoint(id));
+        assert_eq!(debugger.cpu.register_file[0], 2);
+    }
+
+    #[test]
+    fn conditional_breakpoint() {
+        let mut debugger = Debugger::new(counting_loop());
+        let id = debugger.add_breakpoint(1, Some("r0 == 0x41")).unwrap();
+        assert_eq!(debugger.resume(None), Stop::Breakpoint(id));
+        assert_eq!(debugger.cpu.register_file[0], 0x41);
+        assert_eq!(debugger.hits(id), Some(0x41));
+    }
+
+    #[test]
+    fn hit_count_condition() {
+        let mut debugger = Debugger::new(counting_loop());
+        let id = debugger.add_breakpoint(0, Some("hits % 10 == 0")).unwrap();
+        // The first hit is at the start, before r0 is incremented
+        assert_eq!(debugger.resume(None), Stop::Breakpoint(id));
+        assert_eq!(debugger.cpu.register_file[0], 9);
+        assert_eq!(debugger.resume(None), Stop::Breakpoint(id));
+        assert_eq!(debugger.cpu.register_file[0], 19);
+    }
+
+    #[test]
+    fn breakpoint_on_starting_finger() {
+        let mut debugger = Debugger::new(counting_loop());
+        let id = debugger.add_breakpoint(0, None).unwrap();
+        assert_eq!(debugger.resume(None), Stop::Breakpoint(id));
+        assert_eq!(debugger.cpu.instruction_count, 0);
+        assert_eq!(debugger.resume(None), Stop::Breakpoint(id));
+        assert_eq!(debugger.cpu.register_file[0], 1);
+        // Once reported, going back to it and resuming moves on
+        debugger.reverse_step(2);
+        assert_eq!(debugger.resume(Some(1)), Stop::Stepped);
+    }
+
+    #[test]
+    fn watchpoint_on_memory() {
+        let program: Vec<Data> = vec![
+            0b10000000000000000000000000111000, // r7 <- ALLOC (r0)
+            0b00100000000000000000000111001010, // MEM(r7)[r1] <- r2
+            0b00100000000000000000000111001011, // MEM(r7)[r1] <- r3
+            0x70000000,
+        ];
+        let mut cpu = CPU::new(program);
+        cpu.register_file[0] = 8;
+        cpu.register_file[1] = 4;
+        cpu.register_file[2] = 5;
+        cpu.register_file[3] = 0x41;
+        let mut debugger = Debugger::new(cpu);
+        let id = debugger
+            .add_watchpoint("mem[1][4]", Some("mem[1][4] > 10"))
+            .unwrap();
+        // Allocation and the first store change the value but fail the condition
+        assert_eq!(
+            debugger.resume(None),
+            Stop::Watchpoint {
+                id,
+                old: Some(5),
+                new: Some(0x41)
+            }
+        );
+        assert_eq!(debugger.cpu.instruction_pointer, 3);
+        assert_eq!(debugger.resume(None), Stop::Halted);
+    }
+
+    #[test]
+    fn condition_error_stops() {
+        let mut debugger = Debugger::new(counting_loop());
+        let id = debugger.add_breakpoint(1, Some("mem[9][0]")).unwrap();
+        match debugger.resume(None) {
+            Stop::ConditionError(stopped, _) => assert_eq!(stopped, id),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reverse_to_last_write() {
+        let program: Vec<Data> = vec![
+            0b11010010000000000000000000000101, // r1 <- 5
+            0b00110000000000000000000000001001, // r0 <- r1 + r1
+            0b00110000000000000000000010010010, // r2 <- r2 + r2
+            0b00110000000000000000000000000001, // r0 <- r0 + r1
+            0b00110000000000000000000010010010, // r2 <- r2 + r2
+            0x70000000,
+        ];
+        let mut debugger = Debugger::new(CPU::new(program));
+        assert_eq!(debugger.resume(None), Stop::Halted);
+        let id = debugger.add_watchpoint("r0", None).unwrap();
+        let stop = debugger.reverse_resume();
+        assert_eq!(
+            stop,
+            Stop::Watchpoint {
+                id,
+                old: Some(10),
+                new: Some(15)
+            }
+        );
+        assert_eq!(debugger.cpu.instruction_pointer, 3);
+        assert_eq!(debugger.cpu.register_file[0], 10);
+        assert_eq!(
+            debugger.reverse_resume(),
+            Stop::Watchpoint {
+                id,
+                old: Some(0),
+                new: Some(10)
+            }
+        );
+        assert_eq!(debugger.cpu.instruction_pointer, 1);
+        assert_eq!(debugger.reverse_resume(), Stop::Beginning);
+        assert_eq!(debugger.cpu.instruction_count, 0);
+    }
+
+    #[test]
+    fn reverse_from_fault() {
+        let program: Vec<Data> = vec![
+            0b00110000000000000000000000000001, // r0 <- r0 + r1
+            0b11000000000000000000000000010011, // CALL(r2)[r3]
+        ];
+        let mut cpu = CPU::new(program);
+        cpu.register_file[1] = 1;
+        cpu.register_file[3] = 100;
+        let mut debugger = Debugger::new(cpu);
+        let id = debugger.add_breakpoint(0, Some("r0 == 1")).unwrap();
+        assert_eq!(debugger.resume(None), Stop::Fault(Fault::EndOfPlatter));
+        assert_eq!(debugger.cpu.instruction_count, 2);
+
+        assert_eq!(debugger.reverse_step(1), Stop::Stepped);
+        assert_eq!(debugger.cpu.instruction_pointer, 1);
+        assert_eq!(debugger.cpu.register_file[0], 1);
+
+        // Nothing before icount 1 satisfies the condition
+        assert_eq!(debugger.reverse_resume(), Stop::Beginning);
+        assert_eq!(debugger.resume(None), Stop::Fault(Fault::EndOfPlatter));
+        assert_eq!(debugger.cpu.register_file[0], 1);
+
+        assert_eq!(debugger.set_condition(id, None), Ok(true));
+        assert_eq!(debugger.reverse_resume(), Stop::Breakpoint(id));
+        assert_eq!(debugger.cpu.instruction_count, 0);
+    }
+
+    #[test]
+    fn repl_session() {
+        let mut debugger = Debugger::new(counting_loop());
+        let mut input: &[u8] =
+            b"break 1 if r0 == 3\nc\nprint r0 * 2\ncondition 1\nstep 2\ninfo\nx 0 0 2\nquit\n";
+        let mut output = Vec::new();
+        debugger.repl(&mut input, &mut output);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("Breakpoint 1 at 1"));
+        assert!(output.contains("Breakpoint 1, hit 3"));
+        assert!(output.contains("6 (0x6)"));
+        assert!(output.contains("1   breakpoint at 1, hit 4 times"));
+        assert!(output.contains("       1  c0000013  call r2, r3\n"));
+        assert!(output.contains("       0  30000001  add r0, r0, r1\n"));
+        assert_eq!(debugger.cpu.register_file[0], 4);
+    }
+
+    #[test]
+    fn symbolic_breakpoints() {
+        let source = "        orth r1, 1\n\
+                      loop:   add r0, r0, r1\n\
+                      \x20       call r2, r3\n";
+        let assembly = crate::asm::build_with("count.uma", source, &crate::asm::read_file).unwrap();
+        let mut debugger = Debugger::new(CPU::new(assembly.image().unwrap()));
+        debugger.symbols = Symbols::from(&assembly);
+        let mut input: &[u8] =
+            b"break count.uma:3 if r0 == 2\nbreak loop\nbreak nowhere\ninfo\nc\ndelete 2\nc\nx\n";
+        let mut output = Vec::new();
+        debugger.repl(&mut input, &mut output);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("Breakpoint 1 at 2 loop+1 (count.uma:3)"));
+        assert!(output.contains("2   breakpoint at 1 loop (count.uma:2), hit 0 times"));
+        assert!(output.contains("Usage: break OFFSET|LABEL[+N]|FILE:LINE"));
+        assert!(output.contains("Breakpoint 2, hit 1\npc=1 loop (count.uma:2) icount=1\n"));
+        assert!(output.contains("pc=2 loop+1 (count.uma:3) icount=5\n"));
+        assert!(
+            output.contains("       2  c0000013  call r2, r3             ; loop+1 (count.uma:3)")
+        );
+    }
+}
@@ -0,0 +1,198 @@
+// History for reverse execution.
+//
+// The machine is deterministic apart from IN, so the debugger keeps periodic
+// snapshots and a log of every input word. Going back to instruction count N
+// restores the last snapshot at or before N and re-executes forward, feeding
+// IN from the log and swallowing OUT until the furthest point ever reached.
+
+use crate::{Console, Data, Snapshot, CPU};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+const MAX_SNAPSHOTS: usize = 64;
+const FIRST_INTERVAL: u64 = 10_000;
+
+struct Recording {
+    inner: Box<dyn Console>,
+    log: Vec<Data>,
+    position: usize,
+    quiet: bool,
+}
+
+struct Recorder(Rc<RefCell<Recording>>);
+
+impl Console for Recorder {
+    fn input(&mut self) -> Data {
+        let mut recording = self.0.borrow_mut();
+        let position = recording.position;
+        recording.position += 1;
+        match recording.log.get(position) {
+            Some(word) => *word,
+            None => {
+                let word = recording.inner.input();
+                recording.log.push(word);
+                word
+            }
+        }
+    }
+
+    fn output(&mut self, c: u8) {
+        let mut recording = self.0.borrow_mut();
+        if !recording.quiet {
+            recording.inner.output(c);
+        }
+    }
+}
+
+pub struct History {
+    recording: Rc<RefCell<Recording>>,
+    // Each snapshot with the number of input words consumed before it
+    snapshots: Vec<(Snapshot, usize)>,
+    interval: u64,
+    frontier: u64,
+}
+
+impl History {
+    /// Start recording the machine from its current state
+    pub fn attach(cpu: &mut CPU) -> Self {
+        let inner = std::mem::replace(&mut cpu.console, Box::new(crate::StdConsole));
+        let recording = Rc::new(RefCell::new(Recording {
+            inner,
+            log: Vec::new(),
+            position: 0,
+            quiet: false,
+        }));
+        cpu.console = Box::new(Recorder(recording.clone()));
+        History {
+            recording,
+            snapshots: vec![(cpu.snapshot(), 0)],
+            interval: FIRST_INTERVAL,
+            frontier: cpu.instruction_count,
+        }
+    }
+
+    /// The earliest instruction count that can be returned to
+    pub fn start(&self) -> u64 {
+        self.snapshots[0].0.instruction_count
+    }
+
+    /// Call before every forward step so replayed output is not repeated
+    pub fn before_step(&self, cpu: &CPU) {
+        self.recording.borrow_mut().quiet = cpu.instruction_count < self.frontier;
+    }
+
+    /// Call after every successful forward step
+    pub fn after_step(&mut self, cpu: &CPU) {
+        if cpu.instruction_count > self.frontier {
+            self.frontier = cpu.instruction_count;
+        }
+        let last = self.snapshots.last().unwrap().0.instruction_count;
+        if cpu.instruction_count >= last + self.interval {
+            let position = self.recording.borrow().position;
+            self.snapshots.push((cpu.snapshot(), position));
+            if self.snapshots.len() > MAX_SNAPSHOTS {
+                // Thin out to every other snapshot, always keeping the first
+                let mut index = 0;
+                self.snapshots.retain(|_| {
+                    index += 1;
+                    index % 2 == 1
+                });
+                self.interval *= 2;
+            }
+        }
+    }
+
+    /// Restore the latest snapshot taken strictly before `count`, returning
+    ///  its instruction count, or None when `count` is at the start
+    pub fn restore_before(&self, cpu: &mut CPU, count: u64) -> Option<u64> {
+        let (snapshot, position) = self
+            .snapshots
+            .iter()
+            .rev()
+            .find(|(s, _)| s.instruction_count < count)?;
+        cpu.restore(snapshot);
+        self.recording.borrow_mut().position = *position;
+        Some(snapshot.instruction_count)
+    }
+
+    /// Put the machine into the state it had after `count` instructions
+    pub fn rewind(&self, cpu: &mut CPU, count: u64) {
+        self.restore_before(cpu, count + 1)
+            .expect("instruction count precedes the recorded history");
+        while cpu.instruction_count < count {
+            self.replay_step(cpu);
+        }
+    }
+
+    /// Re-execute one already recorded instruction
+    pub fn replay_step(&self, cpu: &mut CPU) {
+        self.recording.borrow_mut().quiet = true;
+        cpu.step().expect("fault while replaying recorded history");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Scripted(Vec<Data>, Rc<RefCell<Vec<u8>>>);
+
+    impl Console for Scripted {
+        fn input(&mut self) -> Data {
+            self.0.remove(0)
+        }
+
+        fn output(&mut self, c: u8) {
+            self.1.borrow_mut().push(c);
+        }
+    }
+
+    #[test]
+    fn replay_uses_logged_input() {
+        let program: Vec<Data> = vec![
+            0b10110000000000000000000000000001, // r1 <- IN
+            0b10100000000000000000000000000001, // OUT r1
+            0b10110000000000000000000000000010, // r2 <- IN
+            0x70000000,
+        ];
+        let printed = Rc::new(RefCell::new(Vec::new()));
+        let console = Scripted(vec![b'a' as Data, b'b' as Data], printed.clone());
+        let mut cpu = CPU::with_console(program, Box::new(console));
+        let mut history = History::attach(&mut cpu);
+        while cpu.status {
+            history.before_step(&cpu);
+            cpu.step().unwrap();
+            history.after_step(&cpu);
+        }
+
+        history.rewind(&mut cpu, 1);
+        assert_eq!(cpu.register_file[1], b'a' as Data);
+        assert_eq!(cpu.register_file[2], 0);
+        // The scripted console is empty, so these must come from the log
+        while cpu.status {
+            history.before_step(&cpu);
+            cpu.step().unwrap();
+            history.after_step(&cpu);
+        }
+        assert_eq!(cpu.register_file[2], b'b' as Data);
+        assert_eq!(*printed.borrow(), vec![b'a']);
+    }
+
+    #[test]
+    fn snapshots_are_thinned() {
+        // CALL(r0)[r0] forever
+        let mut cpu = CPU::new(vec![0b11000000000000000000000000000000]);
+        let mut history = History::attach(&mut cpu);
+        for _ in 0..(FIRST_INTERVAL * (MAX_SNAPSHOTS as u64 + 1)) {
+            history.before_step(&cpu);
+            cpu.step().unwrap();
+            history.after_step(&cpu);
+        }
+        assert!(history.snapshots.len() <= MAX_SNAPSHOTS);
+        assert_eq!(history.interval, FIRST_INTERVAL * 2);
+        assert_eq!(history.start(), 0);
+
+        history.rewind(&mut cpu, 12_345);
+        assert_eq!(cpu.instruction_count, 12_345);
+    }
+}
@@ -0,0 +1,109 @@
+// ---------- DISASSEMBLER ----------------------------------------------------
+//
+// Listings of program images or live arrays, one platter per line:
+//
+//        12  d2000005  orth r1, 0x5
+//        13  e0000000  .word 0xe0000000    ; data
+//
+// Words with operator 14 or 15 cannot be executed and are shown as data in
+// the assembler's `.word` syntax. With symbols each line ends in where it
+// came from:
+//
+//         4  60000092  nand r2, r2, r2         ; loop+3 (main.uma:42)
+
+use crate::symbols::Symbols;
+use crate::{Data, Instruction};
+
+/// The mnemonic form of a single word
+pub fn text(word: Data) -> String {
+    match Instruction::try_decode(word) {
+        Some(instruction) => instruction.to_string(),
+        None => format!(".word 0x{:08x}    ; data", word),
+    }
+}
+
+pub fn line(offset: usize, word: Data) -> String {
+    format!("{:>8}  {:08x}  {}", offset, word, text(word))
+}
+
+/// Up to `count` lines of `words` starting at `start`
+pub fn listing(words: &[Data], start: usize, count: usize) -> Vec<String> {
+    words
+        .iter()
+        .enumerate()
+        .skip(start)
+        .take(count)
+        .map(|(offset, word)| line(offset, *word))
+        .collect()
+}
+
+/// Like `listing`, with each word's location from `symbols`
+pub fn annotated(words: &[Data], start: usize, count: usize, symbols: &Symbols) -> Vec<String> {
+    listing(words, start, count)
+        .into_iter()
+        .enumerate()
+        .map(|(i, line)| {
+            let offset = start + i;
+            match symbols.locate(offset as Data) {
+                None => line,
+                Some(location) if Instruction::try_decode(words[offset]).is_none() => {
+                    format!("{}, {}", line, location)
+                }
+                Some(location) => format!("{:<44}; {}", line, location),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mnemonics() {
+        assert_eq!(text(0b00000000000000000000000000001010), "cmov r0, r1, r2");
+        assert_eq!(text(0b00010000000000000000000110111001), "load r6, r7, r1");
+        assert_eq!(text(0b00100000000000000000000111001010), "store r7, r1, r2");
+        assert_eq!(text(0b01100000000000000000000000001010), "nand r0, r1, r2");
+        assert_eq!(text(0x70000000), "halt");
+        assert_eq!(text(0b10000000000000000000000000111000), "alloc r7, r0");
+        assert_eq!(text(0b10010000000000000000000000000001), "free r1");
+        assert_eq!(text(0b10100000000000000000000000000111), "out r7");
+        assert_eq!(text(0b10110000000000000000000000000010), "in r2");
+        assert_eq!(text(0b11000000000000000000000000101001), "call r5, r1");
+        assert_eq!(text(0b11011110000000000001001000110100), "orth r7, 0x1234");
+    }
+
+    #[test]
+    fn undecodable_words_are_data() {
+        assert_eq!(text(0xE0000000), ".word 0xe0000000    ; data");
+        assert_eq!(text(0xFFFFFFFF), ".word 0xffffffff    ; data");
+    }
+
+    #[test]
+    fn listing_window() {
+        let words = vec![0xD2000005, 0x70000000, 0xF0000001];
+        assert_eq!(
+            listing(&words, 1, 5),
+            vec![
+                "       1  70000000  halt",
+                "       2  f0000001  .word 0xf0000001    ; data"
+            ]
+        );
+    }
+
+    #[test]
+    fn annotated_listing() {
+        let source = "start: halt\nmsg: .word 0xF0000001, 2";
+        let assembly = crate::asm::build_with("a.uma", source, &crate::asm::read_file).unwrap();
+        let symbols = Symbols::from(&assembly);
+        assert_eq!(
+            annotated(&assembly.words, 0, 3, &symbols),
+            vec![
+                "       0  70000000  halt                    ; start (a.uma:1)",
+                "       1  f0000001  .word 0xf0000001    ; data, msg (a.uma:2)",
+                "       2  00000002  cmov r0, r0, r2         ; msg+1 (a.uma:2)",
+            ]
+        );
+    }
+}
@@ -0,0 +1,559 @@
+// Syntax tree to UM assembly for the assembler, with the routines from
+// std/ doing the printing, comparisons and division.
+//
+// Expressions leave their value in r0, with r1-r3 as temporaries and
+// intermediate values pushed on the stack array in r5. r4 is the frame
+// pointer: the stack count just after the caller's r4 was pushed. For a
+// function of N parameters the frame, by offset from r4, is
+//
+//     -N-1 .. -2   arguments, pushed by the caller in order
+//     -1           return offset, pushed by jsr
+//      0           caller's r4
+//      1 ..        local variables
+
+use std::collections::BTreeMap;
+
+use super::parser::{Expr, ExprKind, Function, Program, Stmt};
+use crate::asm::{AsmError, Span};
+
+const BUILTINS: [(&str, usize); 6] = [
+    ("putc", 1),
+    ("getc", 0),
+    ("array", 1),
+    ("free", 1),
+    ("puts", 1),
+    // Any number of arguments
+    ("print", usize::MAX),
+];
+
+fn error<T>(span: Span, message: String) -> Result<T, AsmError> {
+    Err(AsmError::new(span, message))
+}
+
+fn arguments(count: usize) -> String {
+    match count {
+        1 => "1 argument".to_string(),
+        n => format!("{} arguments", n),
+    }
+}
+
+/// `text` in the assembler's string syntax, if it can be written that way
+fn quoted(text: &str) -> Option<String> {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            ' '..='~' => quoted.push(c),
+            _ => return None,
+        }
+    }
+    quoted.push('"');
+    Some(quoted)
+}
+
+#[derive(Default)]
+struct Codegen {
+    lines: Vec<String>,
+    functions: BTreeMap<String, usize>,
+    globals: BTreeMap<String, String>,
+    strings: Vec<String>,
+    /// Words to point at a copy of a string when the program starts
+    arrays: Vec<(String, usize)>,
+    labels: usize,
+    // The function being compiled
+    function: String,
+    scopes: Vec<BTreeMap<String, i64>>,
+    locals: i64,
+    loops: Vec<(String, String)>,
+}
+
+// Variables declared anywhere in a function body, each of which gets a slot
+fn count_locals(statements: &[Stmt]) -> i64 {
+    statements
+        .iter()
+        .map(|statement| match statement {
+            Stmt::Var(..) => 1,
+            Stmt::If(_, then, otherwise) => count_locals(then) + count_locals(otherwise),
+            Stmt::While(_, body) => count_locals(body),
+            _ => 0,
+        })
+        .sum()
+}
+
+impl Codegen {
+    fn emit(&mut self, line: &str) {
+        self.lines.push(format!("        {}", line));
+    }
+
+    fn label(&mut self, label: &str) {
+        self.lines.push(format!("{}:", label));
+    }
+
+    fn fresh(&mut self) -> String {
+        self.labels += 1;
+        format!("{}.{}", self.function, self.labels)
+    }
+
+    fn string(&mut self, text: &str) -> usize {
+        self.strings.push(text.to_string());
+        self.strings.len() - 1
+    }
+
+    // r1 <- the stack index of the frame slot at `offset`
+    fn slot(&mut self, offset: i64) {
+        self.emit(&format!("li r1, {}", offset));
+        self.emit("add r1, r4, r1");
+    }
+
+    fn local(&self, name: &str) -> Option<i64> {
+        self.scopes.iter().rev().find_map(|s| s.get(name).copied())
+    }
+
+    fn load(&mut self, name: &str, span: Span) -> Result<(), AsmError> {
+        if let Some(offset) = self.local(name) {
+            self.slot(offset);
+            self.emit("load r0, r5, r1");
+        } else if let Some(label) = self.globals.get(name).cloned() {
+            self.emit("orth r1, 0");
+            self.emit(&format!("orth r2, {}", label));
+            self.emit("load r0, r1, r2");
+        } else {
+            return error(span, format!("unknown variable '{}'", name));
+        }
+        Ok(())
+    }
+
+    // The variable gets r0
+    fn store(&mut self, name: &str, span: Span) -> Result<(), AsmError> {
+        if let Some(offset) = self.local(name) {
+            self.slot(offset);
+            self.emit("store r5, r1, r0");
+        } else if let Some(label) = self.globals.get(name).cloned() {
+            self.emit("orth r1, 0");
+            self.emit(&format!("orth r2, {}", label));
+            self.emit("store r1, r2, r0");
+        } else {
+            return error(span, format!("unknown variable '{}'", name));
+        }
+        Ok(())
+    }
+
+    // r0 <- 1 if r0 is 0, else 0
+    fn is_zero(&mut self) {
+        self.emit("orth r1, 1");
+        self.emit("orth r2, 0");
+        self.emit("cmov r1, r2, r0");
+        self.emit("mov r0, r1");
+    }
+
+    // r0 <- 0 if r0 is 0, else 1
+    fn truth(&mut self) {
+        self.emit("orth r1, 0");
+        self.emit("orth r2, 1");
+        self.emit("cmov r1, r2, r0");
+        self.emit("mov r0, r1");
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Result<(), AsmError> {
+        match &expr.kind {
+            ExprKind::Number(n) => self.emit(&format!("li r0, {}", n)),
+            ExprKind::Str(text) => {
+                let id = self.string(text);
+                let label = format!("string.{}.array", id);
+                self.arrays.push((label.clone(), id));
+                self.emit("orth r1, 0");
+                self.emit(&format!("orth r2, {}", label));
+                self.emit("load r0, r1, r2");
+            }
+            ExprKind::Var(name) => self.load(name, expr.span)?,
+            ExprKind::Index(array, index) => {
+                self.expr(array)?;
+                self.emit("push r0");
+                self.expr(index)?;
+                self.emit("mov r1, r0");
+                self.emit("pop r0");
+                self.emit("load r0, r0, r1");
+            }
+            ExprKind::Call(name, args) => self.call(name, args, expr.span)?,
+            ExprKind::Unary(op, operand) => {
+                self.expr(operand)?;
+                match *op {
+                    "-" => self.emit("jsr negate"),
+                    _ => self.is_zero(),
+                }
+            }
+            ExprKind::Binary(op @ "&&", left, right) | ExprKind::Binary(op @ "||", left, right) => {
+                let (short, end) = (self.fresh(), self.fresh());
+                self.expr(left)?;
+                let jump = if *op == "&&" { "jz" } else { "jnz" };
+                self.emit(&format!("{} r0, {}", jump, short));
+                self.expr(right)?;
+                self.truth();
+                self.emit(&format!("jmp {}", end));
+                self.label(&short);
+                self.emit(if *op == "&&" {
+                    "orth r0, 0"
+                } else {
+                    "orth r0, 1"
+                });
+                self.label(&end);
+            }
+            ExprKind::Binary(op, left, right) => {
+                self.expr(left)?;
+                self.emit("push r0");
+                self.expr(right)?;
+                self.emit("mov r1, r0");
+                self.emit("pop r0");
+                let swap = ["mov r2, r0", "mov r0, r1", "mov r1, r2"];
+                match *op {
+                    "+" => self.emit("add r0, r0, r1"),
+                    "-" => self.emit("sub r0, r0, r1"),
+                    "*" => self.emit("mul r0, r0, r1"),
+                    "/" => self.emit("jsr sdiv"),
+                    "%" => self.emit("jsr smod"),
+                    "==" | "!=" => {
+                        self.emit("sub r0, r0, r1");
+                        if *op == "==" {
+                            self.is_zero();
+                        } else {
+                            self.truth();
+                        }
+                    }
+                    "<" => self.emit("jsr slt"),
+                    ">=" => {
+                        self.emit("jsr slt");
+                        self.is_zero();
+                    }
+                    ">" | "<=" => {
+                        for line in &swap {
+                            self.emit(line);
+                        }
+                        self.emit("jsr slt");
+                        if *op == "<=" {
+                            self.is_zero();
+                        }
+                    }
+                    _ => unreachable!("not a binary operator"),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn call(&mut self, name: &str, args: &[Expr], span: Span) -> Result<(), AsmError> {
+        let builtin = BUILTINS.iter().find(|(n, _)| *n == name);
+        let arity = match (builtin, self.functions.get(name)) {
+            (Some((_, arity)), _) | (None, Some(arity)) => *arity,
+            (None, None) => return error(span, format!("unknown function '{}'", name)),
+        };
+        if arity != usize::MAX && args.len() != arity {
+            let message = format!("'{}' takes {}, not {}", name, arguments(arity), args.len());
+            return error(span, message);
+        }
+        if builtin.is_none() {
+            for arg in args {
+                self.expr(arg)?;
+                self.emit("push r0");
+            }
+            self.emit(&format!("jsr fn.{}", name));
+            if !args.is_empty() {
+                self.emit("orth r1, 0");
+                self.emit("load r2, r5, r1");
+                self.emit(&format!("li r3, {}", args.len()));
+                self.emit("sub r2, r2, r3");
+                self.emit("store r5, r1, r2");
+            }
+            return Ok(());
+        }
+        if name == "print" {
+            for arg in args {
+                match &arg.kind {
+                    ExprKind::Str(text) => {
+                        let id = self.string(text);
+                        self.emit("orth r0, 0");
+                        self.emit(&format!("orth r1, string.{}", id));
+                        self.emit("jsr print_string");
+                    }
+                    _ => {
+                        self.expr(arg)?;
+                        self.emit("jsr print_dec");
+                    }
+                }
+            }
+            self.emit("orth r0, 0");
+            return Ok(());
+        }
+        if let Some(arg) = args.first() {
+            self.expr(arg)?;
+        }
+        match name {
+            "putc" => self.emit("out r0"),
+            "getc" => self.emit("in r0"),
+            "array" => self.emit("alloc r0, r0"),
+            "free" => self.emit("free r0"),
+            _ => {
+                self.emit("orth r1, 0");
+                self.emit("jsr print_string");
+            }
+        }
+        if name == "putc" || name == "free" || name == "puts" {
+            self.emit("orth r0, 0");
+        }
+        Ok(())
+    }
+
+    fn block(&mut self, statements: &[Stmt]) -> Result<(), AsmError> {
+        self.scopes.push(BTreeMap::new());
+        for statement in statements {
+            self.statement(statement)?;
+        }
+        self.scopes.pop();
+        Ok(())
+    }
+
+    fn statement(&mut self, statement: &Stmt) -> Result<(), AsmError> {
+        match statement {
+            Stmt::Var(name, value, span) => {
+                if self.scopes.last().unwrap().contains_key(name) {
+                    return error(*span, format!("'{}' is already declared here", name));
+                }
+                match value {
+                    Some(value) => self.expr(value)?,
+                    None => self.emit("orth r0, 0"),
+                }
+                self.locals += 1;
+                let offset = self.locals;
+                self.scopes.last_mut().unwrap().insert(name.clone(), offset);
+                self.slot(offset);
+                self.emit("store r5, r1, r0");
+            }
+            Stmt::Assign(target, value) => match &target.kind {
+                ExprKind::Var(name) => {
+                    self.expr(value)?;
+                    self.store(name, target.span)?;
+                }
+                ExprKind::Index(array, index) => {
+                    self.expr(array)?;
+                    self.emit("push r0");
+                    self.expr(index)?;
+                    self.emit("push r0");
+                    self.expr(value)?;
+                    self.emit("pop r2");
+                    self.emit("pop r1");
+                    self.emit("store r1, r2, r0");
+                }
+                _ => unreachable!("checked by the parser"),
+            },
+            Stmt::Expr(expr) => self.expr(expr)?,
+            Stmt::If(condition, then, otherwise) => {
+                let (other, end) = (self.fresh(), self.fresh());
+                self.expr(condition)?;
+                self.emit(&format!("jz r0, {}", other));
+                self.block(then)?;
+                if !otherwise.is_empty() {
+                    self.emit(&format!("jmp {}", end));
+                }
+                self.label(&other);
+                self.block(otherwise)?;
+                self.label(&end);
+            }
+            Stmt::While(condition, body) => {
+                let (top, end) = (self.fresh(), self.fresh());
+                self.label(&top);
+                self.expr(condition)?;
+                self.emit(&format!("jz r0, {}", end));
+                self.loops.push((top.clone(), end.clone()));
+                self.block(body)?;
+                self.loops.pop();
+                self.emit(&format!("jmp {}", top));
+                self.label(&end);
+            }
+            Stmt::Return(value) => {
+                match value {
+                    Some(value) => self.expr(value)?,
+                    None => self.emit("orth r0, 0"),
+                }
+                self.emit(&format!("jmp {}.return", self.function));
+            }
+            Stmt::Break(span) | Stmt::Continue(span) => {
+                let (top, end) = match self.loops.last() {
+                    Some(labels) => labels.clone(),
+                    None => {
+                        let word = if let Stmt::Break(_) = statement {
+                            "break"
+                        } else {
+                            "continue"
+                        };
+                        return error(*span, format!("'{}' outside a loop", word));
+                    }
+                };
+                let target = if let Stmt::Break(_) = statement {
+                    end
+                } else {
+                    top
+                };
+                self.emit(&format!("jmp {}", target));
+            }
+        }
+        Ok(())
+    }
+
+    fn function(&mut self, function: &Function) -> Result<(), AsmError> {
+        self.function = format!("fn.{}", function.name);
+        self.locals = 0;
+        let count = function.params.len() as i64;
+        let mut params = BTreeMap::new();
+        for (i, (name, span)) in function.params.iter().enumerate() {
+            if params.insert(name.clone(), i as i64 - count - 1).is_some() {
+                return error(*span, format!("'{}' is already a parameter", name));
+            }
+        }
+        self.scopes = vec![params];
+
+        self.lines.push(String::new());
+        let label = self.function.clone();
+        self.label(&label);
+        self.emit("push r4");
+        self.emit("orth r1, 0");
+        self.emit("load r4, r5, r1");
+        let locals = count_locals(&function.body);
+        if locals > 0 {
+            self.emit(&format!("li r2, {}", locals));
+            self.emit("add r2, r4, r2");
+            self.emit("store r5, r1, r2");
+        }
+        self.block(&function.body)?;
+        self.emit("orth r0, 0");
+        self.label(&format!("{}.return", label));
+        self.emit("orth r1, 0");
+        self.emit("store r5, r1, r4");
+        self.emit("pop r4");
+        self.emit("ret");
+        Ok(())
+    }
+}
+
+/// Assembly text for `program`, to be assembled on its own
+pub fn generate(program: &Program) -> Result<String, AsmError> {
+    let mut codegen = Codegen::default();
+    for function in &program.functions {
+        if BUILTINS.iter().any(|(name, _)| *name == function.name) {
+            let message = format!("'{}' is built in", function.name);
+            return error(function.span, message);
+        }
+        let arity = function.params.len();
+        if codegen
+            .functions
+            .insert(function.name.clone(), arity)
+            .is_some()
+        {
+            let message = format!("function '{}' is defined twice", function.name);
+            return error(function.span, message);
+        }
+    }
+    match codegen.functions.get("main") {
+        Some(0) => {}
+        Some(_) => {
+            let main = program.functions.iter().find(|f| f.name == "main");
+            return error(main.unwrap().span, "'main' takes no arguments".to_string());
+        }
+        None => {
+            let span = Span {
+                file: 0,
+                line: 1,
+                column: 1,
+                length: 1,
+            };
+            return error(span, "there is no 'main' function".to_string());
+        }
+    }
+    let mut data = Vec::new();
+    for global in &program.globals {
+        let label = format!("global.{}", global.name);
+        if codegen
+            .globals
+            .insert(global.name.clone(), label.clone())
+            .is_some()
+        {
+            let message = format!("global '{}' is defined twice", global.name);
+            return error(global.span, message);
+        }
+        match &global.value.kind {
+            ExprKind::Number(n) => data.push(format!("{}: .word {}", label, n)),
+            ExprKind::Str(text) => {
+                let id = codegen.string(text);
+                codegen.arrays.push((label.clone(), id));
+                data.push(format!("{}: .word 0", label));
+            }
+            _ => unreachable!("checked by the parser"),
+        }
+    }
+
+    let mut body = Codegen {
+        functions: codegen.functions,
+        globals: codegen.globals,
+        strings: codegen.strings,
+        arrays: codegen.arrays,
+        ..Codegen::default()
+    };
+    for function in &program.functions {
+        body.function(function)?;
+    }
+
+    // Start-up: std/start.uma calls `main`, which copies strings into
+    // arrays and then runs the program's main
+    let mut start = Codegen::default();
+    start
+        .lines
+        .push("        .include \"std/start.uma\"".to_string());
+    start.label("main");
+    for (label, id) in &body.arrays {
+        let length = body.strings[*id].chars().count() + 1;
+        start.emit(&format!("li r4, {}", length));
+        start.emit("alloc r0, r4");
+        start.emit("orth r1, 0");
+        start.emit("orth r2, 0");
+        start.emit(&format!("orth r3, string.{}", id));
+        start.emit("jsr memcpy");
+        start.emit("orth r1, 0");
+        start.emit(&format!("orth r2, {}", label));
+        start.emit("store r1, r2, r0");
+    }
+    start.emit("jmp fn.main");
+    for file in &["io", "mem", "int"] {
+        start.emit(&format!(".include \"std/{}.uma\"", file));
+    }
+
+    let mut lines = start.lines;
+    lines.append(&mut body.lines);
+    lines.push(String::new());
+    lines.append(&mut data);
+    for (id, text) in body.strings.iter().enumerate() {
+        let words = match quoted(text) {
+            Some(quoted) => format!(".string {}", quoted),
+            None => {
+                let words: Vec<String> = text
+                    .chars()
+                    .chain(Some('\0'))
+                    .map(|c| (c as u32).to_string())
+                    .collect();
+                format!(".word {}", words.join(", "))
+            }
+        };
+        lines.push(format!("string.{}: {}", id, words));
+    }
+    for (label, _) in &body.arrays {
+        if !label.starts_with("global.") {
+            lines.push(format!("{}: .word 0", label));
+        }
+    }
+    let mut text = lines.join("\n");
+    text.push('\n');
+    Ok(text)
+}
@@ -0,0 +1,240 @@
+// Tokens of the language. Comments run from `//` to the end of the line and
+// whitespace, newlines included, only separates tokens.
+
+use crate::asm::{AsmError, Span};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Ident(String),
+    Keyword(&'static str),
+    Number(u32),
+    Str(String),
+    /// Punctuation and operators
+    Symbol(&'static str),
+    End,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lexeme {
+    pub token: Token,
+    pub span: Span,
+}
+
+pub const KEYWORDS: [&str; 8] = [
+    "fn", "var", "if", "else", "while", "return", "break", "continue",
+];
+
+// Longest first, so `<=` is not read as `<` then `=`
+const SYMBOLS: [&str; 23] = [
+    "==", "!=", "<=", ">=", "&&", "||", "(", ")", "{", "}", "[", "]", ",", ";", "=", "<", ">", "+",
+    "-", "*", "/", "%", "!",
+];
+
+fn error<T>(line: usize, column: usize, length: usize, message: String) -> Result<T, AsmError> {
+    let span = Span {
+        file: 0,
+        line,
+        column,
+        length,
+    };
+    Err(AsmError::new(span, message))
+}
+
+fn escape(c: char) -> Option<char> {
+    Some(match c {
+        'n' => '\n',
+        't' => '\t',
+        'r' => '\r',
+        '0' => '\0',
+        '\\' | '\'' | '"' => c,
+        _ => return None,
+    })
+}
+
+pub fn tokenize(source: &str) -> Result<Vec<Lexeme>, AsmError> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut lexemes = Vec::new();
+    let (mut i, mut line, mut column) = (0, 1, 1);
+    loop {
+        // Whitespace and comments
+        while i < chars.len() {
+            if chars[i] == '\n' {
+                line += 1;
+                column = 1;
+            } else if chars[i].is_whitespace() {
+                column += 1;
+            } else if chars[i] == '/' && chars.get(i + 1) == Some(&'/') {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+                continue;
+            } else {
+                break;
+            }
+            i += 1;
+        }
+        let start = i;
+        let c = match chars.get(i) {
+            Some(c) => *c,
+            None => {
+                let span = Span {
+                    file: 0,
+                    line,
+                    column,
+                    length: 1,
+                };
+                lexemes.push(Lexeme {
+                    token: Token::End,
+                    span,
+                });
+                return Ok(lexemes);
+            }
+        };
+        let token = if c.is_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            match KEYWORDS.iter().find(|k| **k == word) {
+                Some(keyword) => Token::Keyword(keyword),
+                None => Token::Ident(word),
+            }
+        } else if c.is_ascii_digit() {
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().filter(|c| **c != '_').collect();
+            let value = match text.strip_prefix("0x") {
+                Some(hex) => u32::from_str_radix(hex, 16),
+                None => text.parse(),
+            };
+            match value {
+                Ok(n) => Token::Number(n),
+                Err(_) => {
+                    let message = format!("'{}' is not a 32-bit number", text);
+                    return error(line, column, i - start, message);
+                }
+            }
+        } else if c == '"' || c == '\'' {
+            i += 1;
+            let mut text = String::new();
+            loop {
+                match chars.get(i) {
+                    None | Some('\n') => {
+                        return error(line, column, i - start, "unterminated literal".to_string())
+                    }
+                    Some(q) if *q == c => break,
+                    Some('\\') => {
+                        let escaped = chars.get(i + 1).copied().and_then(escape);
+                        match escaped {
+                            Some(e) => text.push(e),
+                            None => {
+                                let column = column + i - start;
+                                return error(line, column, 2, "unknown escape".to_string());
+                            }
+                        }
+                        i += 2;
+                    }
+                    Some(other) => {
+                        text.push(*other);
+                        i += 1;
+                    }
+                }
+            }
+            i += 1;
+            if c == '"' {
+                Token::Str(text)
+            } else {
+                let mut chars = text.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Token::Number(c as u32),
+                    _ => {
+                        let message = "a character literal holds one character".to_string();
+                        return error(line, column, i - start, message);
+                    }
+                }
+            }
+        } else {
+            let rest: String = chars[i..].iter().take(2).collect();
+            match SYMBOLS.iter().find(|s| rest.starts_with(*s)) {
+                Some(symbol) => {
+                    i += symbol.len();
+                    Token::Symbol(symbol)
+                }
+                None => return error(line, column, 1, format!("unexpected '{}'", c)),
+            }
+        };
+        let length = i - start;
+        lexemes.push(Lexeme {
+            token,
+            span: Span {
+                file: 0,
+                line,
+                column,
+                length,
+            },
+        });
+        column += length;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(source: &str) -> Vec<Token> {
+        tokenize(source)
+            .unwrap()
+            .into_iter()
+            .map(|l| l.token)
+            .collect()
+    }
+
+    #[test]
+    fn kinds() {
+        assert_eq!(
+            tokens("fn f(x) { // hi\n return x<=0x1_0 && 'a' != \"b\\n\"; }"),
+            vec![
+                Token::Keyword("fn"),
+                Token::Ident("f".to_string()),
+                Token::Symbol("("),
+                Token::Ident("x".to_string()),
+                Token::Symbol(")"),
+                Token::Symbol("{"),
+                Token::Keyword("return"),
+                Token::Ident("x".to_string()),
+                Token::Symbol("<="),
+                Token::Number(16),
+                Token::Symbol("&&"),
+                Token::Number(97),
+                Token::Symbol("!="),
+                Token::Str("b\n".to_string()),
+                Token::Symbol(";"),
+                Token::Symbol("}"),
+                Token::End,
+            ]
+        );
+        let spans: Vec<_> = tokenize("a\n  bc")
+            .unwrap()
+            .into_iter()
+            .map(|l| (l.span.line, l.span.column, l.span.length))
+            .collect();
+        assert_eq!(spans, vec![(1, 1, 1), (2, 3, 2), (2, 5, 1)]);
+    }
+
+    #[test]
+    fn errors() {
+        let error = |source| tokenize(source).unwrap_err().to_string();
+        assert_eq!(
+            error("x = 4294967296;"),
+            "1:5: '4294967296' is not a 32-bit number"
+        );
+        assert_eq!(error("\"abc"), "1:1: unterminated literal");
+        assert_eq!(
+            error("'ab'"),
+            "1:1: a character literal holds one character"
+        );
+        assert_eq!(error("  \"a\\q\""), "1:5: unknown escape");
+        assert_eq!(error("x # y"), "1:3: unexpected '#'");
+    }
+}
@@ -0,0 +1,175 @@
+// ---------- LANGUAGE --------------------------------------------------------
+//
+// A small structured language, compiled to assembly for the assembler:
+//
+//     var greeting = "hello";          // globals: a number or a string
+//
+//     fn fact(n) {
+//         if n <= 1 { return 1; }
+//         return n * fact(n - 1);
+//     }
+//
+//     fn main() {
+//         var i = 0;
+//         while i < 5 {
+//             print(greeting, " ", i, "! = ", fact(i), "\n");
+//             i = i + 1;
+//         }
+//     }
+//
+// Every value is a 32-bit word, used as a signed integer by the comparison,
+// division and printing operators. A string literal is an array holding its
+// characters and a 0, made when the program starts; `array(n)` makes an
+// array of n zeroes and `a[i]` reads or writes its elements. Statements are
+// `var`, assignment, `if`/`else`, `while` with `break` and `continue`, and
+// `return`; `&&` and `||` only evaluate their right side when they must.
+//
+// Built in functions:
+//
+//     print(x, ...)   string literals as text, anything else in decimal
+//     putc(c)         write one character
+//     getc()          read one character, -1 at the end of the input
+//     puts(a)         write the string in array a
+//     array(n)        a new array of n words
+//     free(a)         release an array
+//
+// Execution starts at `main`, which takes no arguments.
+
+mod codegen;
+pub mod lexer;
+pub mod parser;
+
+use crate::asm::AsmError;
+use crate::Data;
+
+/// Assembly text for the program in `source`
+pub fn compile(source: &str) -> Result<String, AsmError> {
+    codegen::generate(&parser::parse(source)?)
+}
+
+/// The program image for `source`; errors name `path`
+pub fn build(path: &str, source: &str) -> Result<Vec<Data>, AsmError> {
+    let text = compile(source).map_err(|mut error| {
+        error.file = path.to_string();
+        error
+    })?;
+    let assembly = crate::asm::build(&text).expect("generated assembly is valid");
+    Ok(assembly.image().expect("generated assembly links"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run_buffered;
+
+    fn run(source: &str, input: &str) -> String {
+        let program = build("test.cult", source).unwrap();
+        String::from_utf8(run_buffered(program, input.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn recursion() {
+        let source = "
+            fn fact(n) {
+                if n <= 1 { return 1; }
+                return n * fact(n - 1);
+            }
+            fn fib(n) {
+                if n < 2 { return n; }
+                return fib(n - 1) + fib(n - 2);
+            }
+            fn main() {
+                print(fact(10), \" \", fib(15), \"\\n\");
+            }";
+        assert_eq!(run(source, ""), "3628800 610\n");
+    }
+
+    #[test]
+    fn arithmetic() {
+        let source = "
+            fn main() {
+                print(7 / -2, \" \", -7 % 3, \" \", 0x7fffffff + 1, \" \", 3 - 5);
+                print(\" \", 1 < 2, 2 < 1, -1 < 0, 3 >= 3, 3 > 3, 2 <= 1, 4 == 4, 4 != 4);
+                print(\" \", !0, !5, 1 && 2, 0 && f(), 0 || 3, 1 || f());
+            }
+            fn f() { print(\"evaluated\"); return 1; }";
+        assert_eq!(run(source, ""), "-3 -1 -2147483648 -2 10110010 101011");
+    }
+
+    #[test]
+    fn arrays_and_strings() {
+        let source = "
+            var greeting = \"hello\";
+            var size = 10;
+            fn length(s) {
+                var n = 0;
+                while s[n] { n = n + 1; }
+                return n;
+            }
+            fn main() {
+                var squares = array(size);
+                var i = 0;
+                while i < size {
+                    squares[i] = i * i;
+                    i = i + 1;
+                }
+                print(squares[9], \" \", length(greeting), \" \");
+                greeting[0] = 'j';
+                puts(greeting);
+                puts(\"!\\n\");
+                free(squares);
+            }";
+        assert_eq!(run(source, ""), "81 5 jello!\n");
+    }
+
+    #[test]
+    fn loops_and_input() {
+        // Echo the input upper-cased, stopping at '.', skipping spaces
+        let source = "
+            fn main() {
+                var count = 0;
+                while 1 {
+                    var c = getc();
+                    if c == -1 || c == '.' { break; }
+                    if c == ' ' { continue; }
+                    if c >= 'a' && c <= 'z' { c = c - 32; }
+                    putc(c);
+                    count = count + 1;
+                }
+                print(\" \", count);
+            }";
+        assert_eq!(run(source, "to be. or not"), "TOBE 4");
+        assert_eq!(run(source, "a b"), "AB 2");
+    }
+
+    #[test]
+    fn errors() {
+        let error = |source| compile(source).unwrap_err().to_string();
+        assert_eq!(error("fn f() {}"), "1:1: there is no 'main' function");
+        assert_eq!(error("fn main(x) {}"), "1:4: 'main' takes no arguments");
+        assert_eq!(error("fn main() { x = 1; }"), "1:13: unknown variable 'x'");
+        assert_eq!(error("fn main() { g(); }"), "1:13: unknown function 'g'");
+        assert_eq!(
+            error("fn f(a) {} fn main() { f(); }"),
+            "1:24: 'f' takes 1 argument, not 0"
+        );
+        assert_eq!(
+            error("fn main() { break; }"),
+            "1:13: 'break' outside a loop"
+        );
+        assert_eq!(
+            error("fn main() { var a; var a; }"),
+            "1:24: 'a' is already declared here"
+        );
+        assert_eq!(
+            error("fn print() {} fn main() {}"),
+            "1:4: 'print' is built in"
+        );
+        assert_eq!(
+            error("fn main() {} fn main() {}"),
+            "1:17: function 'main' is defined twice"
+        );
+        let error = build("bad.cult", "fn main() { y; }").unwrap_err();
+        assert_eq!(error.file, "bad.cult");
+    }
+}
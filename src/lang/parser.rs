@@ -0,0 +1,443 @@
+// Syntax tree of the language, by recursive descent. Operators bind, from
+// loosest to tightest:
+//
+//     ||    &&    == !=    < <= > >=    + -    * / %    unary - !
+//
+// followed by calls `f(x, y)` and indexing `a[i]`.
+
+use super::lexer::{tokenize, Lexeme, Token};
+use crate::asm::{AsmError, Span};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Number(u32),
+    Str(String),
+    Var(String),
+    Index(Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stmt {
+    Var(String, Option<Expr>, Span),
+    /// To a variable or an array element
+    Assign(Expr, Expr),
+    Expr(Expr),
+    If(Expr, Vec<Stmt>, Vec<Stmt>),
+    While(Expr, Vec<Stmt>),
+    Return(Option<Expr>),
+    Break(Span),
+    Continue(Span),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub params: Vec<(String, Span)>,
+    pub body: Vec<Stmt>,
+    pub span: Span,
+}
+
+/// A variable outside any function, set to a number or string before
+/// `main` runs
+#[derive(Debug, Clone, PartialEq)]
+pub struct Global {
+    pub name: String,
+    pub value: Expr,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Program {
+    pub globals: Vec<Global>,
+    pub functions: Vec<Function>,
+}
+
+const LEVELS: [&[&str]; 6] = [
+    &["||"],
+    &["&&"],
+    &["==", "!="],
+    &["<", "<=", ">", ">="],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+struct Parser {
+    lexemes: Vec<Lexeme>,
+    next: usize,
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Ident(name) => format!("'{}'", name),
+        Token::Keyword(k) | Token::Symbol(k) => format!("'{}'", k),
+        Token::Number(n) => n.to_string(),
+        Token::Str(_) => "a string".to_string(),
+        Token::End => "the end of the file".to_string(),
+    }
+}
+
+impl Parser {
+    fn peek(&self) -> &Lexeme {
+        &self.lexemes[self.next]
+    }
+
+    fn bump(&mut self) -> Lexeme {
+        let lexeme = self.lexemes[self.next].clone();
+        if lexeme.token != Token::End {
+            self.next += 1;
+        }
+        lexeme
+    }
+
+    fn at(&self, symbol: &str) -> bool {
+        match &self.peek().token {
+            Token::Symbol(s) | Token::Keyword(s) => *s == symbol,
+            _ => false,
+        }
+    }
+
+    fn eat(&mut self, symbol: &str) -> bool {
+        let found = self.at(symbol);
+        if found {
+            self.next += 1;
+        }
+        found
+    }
+
+    fn unexpected<T>(&self, wanted: &str) -> Result<T, AsmError> {
+        let lexeme = self.peek();
+        let message = format!("expected {}, found {}", wanted, describe(&lexeme.token));
+        Err(AsmError::new(lexeme.span, message))
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<Span, AsmError> {
+        if self.at(symbol) {
+            Ok(self.bump().span)
+        } else {
+            self.unexpected(&format!("'{}'", symbol))
+        }
+    }
+
+    fn name(&mut self) -> Result<(String, Span), AsmError> {
+        match self.peek().token.clone() {
+            Token::Ident(name) => Ok((name, self.bump().span)),
+            _ => self.unexpected("a name"),
+        }
+    }
+
+    fn program(&mut self) -> Result<Program, AsmError> {
+        let mut program = Program::default();
+        loop {
+            if self.eat("fn") {
+                program.functions.push(self.function()?);
+            } else if self.eat("var") {
+                let (name, span) = self.name()?;
+                self.expect("=")?;
+                let value = self.primary()?;
+                match value.kind {
+                    ExprKind::Number(_) | ExprKind::Str(_) => {}
+                    _ => {
+                        let message = "a global is set to a number or a string".to_string();
+                        return Err(AsmError::new(value.span, message));
+                    }
+                }
+                self.expect(";")?;
+                program.globals.push(Global { name, value, span });
+            } else if self.peek().token == Token::End {
+                return Ok(program);
+            } else {
+                return self.unexpected("'fn' or 'var'");
+            }
+        }
+    }
+
+    fn function(&mut self) -> Result<Function, AsmError> {
+        let (name, span) = self.name()?;
+        self.expect("(")?;
+        let mut params = Vec::new();
+        if !self.eat(")") {
+            loop {
+                params.push(self.name()?);
+                if self.eat(")") {
+                    break;
+                }
+                self.expect(",")?;
+            }
+        }
+        let body = self.block()?;
+        Ok(Function {
+            name,
+            params,
+            body,
+            span,
+        })
+    }
+
+    fn block(&mut self) -> Result<Vec<Stmt>, AsmError> {
+        self.expect("{")?;
+        let mut statements = Vec::new();
+        while !self.eat("}") {
+            statements.push(self.statement()?);
+        }
+        Ok(statements)
+    }
+
+    fn statement(&mut self) -> Result<Stmt, AsmError> {
+        let span = self.peek().span;
+        if self.eat("var") {
+            let (name, span) = self.name()?;
+            let value = if self.eat("=") {
+                Some(self.expr()?)
+            } else {
+                None
+            };
+            self.expect(";")?;
+            return Ok(Stmt::Var(name, value, span));
+        }
+        if self.eat("if") {
+            return self.if_rest();
+        }
+        if self.eat("while") {
+            let condition = self.expr()?;
+            return Ok(Stmt::While(condition, self.block()?));
+        }
+        if self.eat("return") {
+            let value = if self.at(";") {
+                None
+            } else {
+                Some(self.expr()?)
+            };
+            self.expect(";")?;
+            return Ok(Stmt::Return(value));
+        }
+        if self.eat("break") {
+            self.expect(";")?;
+            return Ok(Stmt::Break(span));
+        }
+        if self.eat("continue") {
+            self.expect(";")?;
+            return Ok(Stmt::Continue(span));
+        }
+        let expr = self.expr()?;
+        let statement = if self.eat("=") {
+            match expr.kind {
+                ExprKind::Var(_) | ExprKind::Index(..) => Stmt::Assign(expr, self.expr()?),
+                _ => {
+                    let message = "only a variable or an element can be assigned".to_string();
+                    return Err(AsmError::new(expr.span, message));
+                }
+            }
+        } else {
+            Stmt::Expr(expr)
+        };
+        self.expect(";")?;
+        Ok(statement)
+    }
+
+    // After `if`; `else if` nests another one in the else branch
+    fn if_rest(&mut self) -> Result<Stmt, AsmError> {
+        let condition = self.expr()?;
+        let then = self.block()?;
+        let otherwise = if !self.eat("else") {
+            Vec::new()
+        } else if self.eat("if") {
+            vec![self.if_rest()?]
+        } else {
+            self.block()?
+        };
+        Ok(Stmt::If(condition, then, otherwise))
+    }
+
+    fn expr(&mut self) -> Result<Expr, AsmError> {
+        self.binary(0)
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, AsmError> {
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        loop {
+            let op = match &self.peek().token {
+                Token::Symbol(s) if LEVELS[level].contains(s) => *s,
+                _ => return Ok(left),
+            };
+            let span = self.bump().span;
+            let right = self.binary(level + 1)?;
+            left = Expr {
+                kind: ExprKind::Binary(op, Box::new(left), Box::new(right)),
+                span,
+            };
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, AsmError> {
+        for op in &["-", "!"] {
+            if self.at(op) {
+                let span = self.bump().span;
+                let operand = self.unary()?;
+                return Ok(Expr {
+                    kind: ExprKind::Unary(op, Box::new(operand)),
+                    span,
+                });
+            }
+        }
+        let mut expr = self.primary()?;
+        while self.at("[") {
+            let span = self.bump().span;
+            let index = self.expr()?;
+            self.expect("]")?;
+            expr = Expr {
+                kind: ExprKind::Index(Box::new(expr), Box::new(index)),
+                span,
+            };
+        }
+        Ok(expr)
+    }
+
+    fn primary(&mut self) -> Result<Expr, AsmError> {
+        let lexeme = self.peek().clone();
+        let kind = match lexeme.token {
+            Token::Number(n) => ExprKind::Number(n),
+            Token::Str(text) => ExprKind::Str(text),
+            Token::Ident(name) => {
+                self.bump();
+                if !self.eat("(") {
+                    return Ok(Expr {
+                        kind: ExprKind::Var(name),
+                        span: lexeme.span,
+                    });
+                }
+                let mut args = Vec::new();
+                if !self.eat(")") {
+                    loop {
+                        args.push(self.expr()?);
+                        if self.eat(")") {
+                            break;
+                        }
+                        self.expect(",")?;
+                    }
+                }
+                return Ok(Expr {
+                    kind: ExprKind::Call(name, args),
+                    span: lexeme.span,
+                });
+            }
+            Token::Symbol("(") => {
+                self.bump();
+                let expr = self.expr()?;
+                self.expect(")")?;
+                return Ok(expr);
+            }
+            _ => return self.unexpected("an expression"),
+        };
+        self.bump();
+        Ok(Expr {
+            kind,
+            span: lexeme.span,
+        })
+    }
+}
+
+pub fn parse(source: &str) -> Result<Program, AsmError> {
+    let mut parser = Parser {
+        lexemes: tokenize(source)?,
+        next: 0,
+    };
+    parser.program()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The expression as fully parenthesised text
+    fn shape(expr: &Expr) -> String {
+        match &expr.kind {
+            ExprKind::Number(n) => n.to_string(),
+            ExprKind::Str(s) => format!("{:?}", s),
+            ExprKind::Var(name) => name.clone(),
+            ExprKind::Index(a, i) => format!("{}[{}]", shape(a), shape(i)),
+            ExprKind::Call(f, args) => {
+                let args: Vec<String> = args.iter().map(shape).collect();
+                format!("{}({})", f, args.join(", "))
+            }
+            ExprKind::Unary(op, e) => format!("({}{})", op, shape(e)),
+            ExprKind::Binary(op, l, r) => format!("({} {} {})", shape(l), op, shape(r)),
+        }
+    }
+
+    fn expression(source: &str) -> String {
+        let program = parse(&format!("fn main() {{ {}; }}", source)).unwrap();
+        match &program.functions[0].body[0] {
+            Stmt::Expr(e) => shape(e),
+            other => panic!("not an expression: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(expression("1 + 2 * 3 - 4"), "((1 + (2 * 3)) - 4)");
+        assert_eq!(
+            expression("a || b && c == d < e"),
+            "(a || (b && (c == (d < e))))"
+        );
+        assert_eq!(expression("-f(x, 1)[2] * !(y)"), "((-f(x, 1)[2]) * (!y))");
+        assert_eq!(expression("a[i][j]"), "a[i][j]");
+    }
+
+    #[test]
+    fn statements() {
+        let program = parse(
+            "var count = 3;
+             var name = \"x\";
+             fn f(a, b) {
+                 var t;
+                 if a { return; } else if b { t = 1; } else { while 1 { break; continue; } }
+                 t[0] = 2;
+             }",
+        )
+        .unwrap();
+        assert_eq!(program.globals.len(), 2);
+        assert_eq!(program.globals[1].value.kind, ExprKind::Str("x".into()));
+        let f = &program.functions[0];
+        assert_eq!(f.params.len(), 2);
+        assert_eq!(f.body.len(), 3);
+        match &f.body[1] {
+            Stmt::If(_, then, otherwise) => {
+                assert_eq!(then, &vec![Stmt::Return(None)]);
+                assert!(matches!(otherwise[0], Stmt::If(..)));
+            }
+            other => panic!("not an if: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let error = |source| parse(source).unwrap_err().to_string();
+        assert_eq!(error("fn f( { }"), "1:7: expected a name, found '{'");
+        assert_eq!(error("fn f() { x = 1 }"), "1:16: expected ';', found '}'");
+        assert_eq!(
+            error("fn f() { 1 + 2 = 3; }"),
+            "1:12: only a variable or an element can be assigned"
+        );
+        assert_eq!(
+            error("var g = h;"),
+            "1:9: a global is set to a number or a string"
+        );
+        assert_eq!(error("x"), "1:1: expected 'fn' or 'var', found 'x'");
+        assert_eq!(
+            error("fn f() {"),
+            "1:9: expected an expression, found the end of the file"
+        );
+    }
+}
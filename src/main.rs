@@ -1,11 +1,28 @@
-use std::collections::BTreeMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
 use std::num::Wrapping;
+use std::rc::Rc;
 use text_io::read;
 
+#[macro_use]
+mod asm;
+mod bench;
+mod bf;
+mod cfg;
+mod debugger;
+mod disasm;
+mod json;
+mod lang;
+mod profile;
+mod symbols;
+mod trace;
+mod tui;
 
 
 // ---------- INSTRUCTIONS ----------------------------------------------------
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum OpCode {
     CMOV = 0x0,
     LOAD = 0x1,
@@ -25,7 +42,17 @@ pub enum OpCode {
 
 impl OpCode {
     pub fn from_byte(b: u8) -> OpCode {
-        match b {
+        match OpCode::try_from_byte(b) {
+            Some(op) => op,
+            None => {
+                panic!("Encountered invalid instruction!");
+            }
+        }
+    }
+
+    /// None for the unassigned operators 14 and 15
+    pub fn try_from_byte(b: u8) -> Option<OpCode> {
+        Some(match b {
             0x0 => OpCode::CMOV,
             0x1 => OpCode::LOAD,
             0x2 => OpCode::STORE,
@@ -40,11 +67,46 @@ impl OpCode {
             0xB => OpCode::IN,
             0xC => OpCode::CALL,
             0xD => OpCode::CONST,
-            _ => {
-                panic!("Encountered invalid instruction!");
-            }
+            _ => return None,
+        })
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            OpCode::CMOV => "cmov",
+            OpCode::LOAD => "load",
+            OpCode::STORE => "store",
+            OpCode::ADD => "add",
+            OpCode::MUL => "mul",
+            OpCode::DIV => "div",
+            OpCode::NAND => "nand",
+            OpCode::HALT => "halt",
+            OpCode::ALLOC => "alloc",
+            OpCode::FREE => "free",
+            OpCode::OUT => "out",
+            OpCode::IN => "in",
+            OpCode::CALL => "call",
+            OpCode::CONST => "orth",
         }
     }
+
+    /// Also accepts the specification's `index` and `amend`
+    pub fn from_mnemonic(mnemonic: &str) -> Option<OpCode> {
+        match mnemonic {
+            "index" => return Some(OpCode::LOAD),
+            "amend" => return Some(OpCode::STORE),
+            _ => {}
+        }
+        (0..=0xD)
+            .map(OpCode::from_byte)
+            .find(|op| op.mnemonic() == mnemonic)
+    }
+}
+
+impl fmt::Display for OpCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.mnemonic())
+    }
 }
 
 type Register = u8; // 4 bit number
@@ -81,6 +143,7 @@ fn parse_r_c(data: Data) -> Register {
     (data & 0b111) as Register
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Instruction {
     op_code: OpCode,
     r_a: Register,
@@ -122,6 +185,258 @@ impl Instruction {
             },
         }
     }
+
+    /// None for words whose operator is 14 or 15
+    pub fn try_decode(data: Data) -> Option<Self> {
+        OpCode::try_from_byte(upper_byte(data)).map(|_| Instruction::decode(data))
+    }
+
+    /// The word `decode` maps back to this instruction
+    pub const fn encode(&self) -> Data {
+        match self.op_code {
+            OpCode::CONST => {
+                ((OpCode::CONST as Data) << 28) | ((self.r_a as Data) << 25) | self.value
+            }
+            op => {
+                ((op as Data) << 28)
+                    | ((self.r_a as Data) << 6)
+                    | ((self.r_b as Data) << 3)
+                    | (self.r_c as Data)
+            }
+        }
+    }
+
+    const fn registers(
+        op_code: OpCode,
+        r_a: Register,
+        r_b: Register,
+        r_c: Register,
+    ) -> Result<Self, EncodeError> {
+        if r_a > 7 {
+            Err(EncodeError::Register(r_a))
+        } else if r_b > 7 {
+            Err(EncodeError::Register(r_b))
+        } else if r_c > 7 {
+            Err(EncodeError::Register(r_c))
+        } else {
+            Ok(Instruction {
+                op_code,
+                r_a,
+                r_b,
+                r_c,
+                value: 0,
+            })
+        }
+    }
+
+    /// rA <- rB unless rC is 0
+    pub const fn cmov(a: Register, b: Register, c: Register) -> Result<Self, EncodeError> {
+        Instruction::registers(OpCode::CMOV, a, b, c)
+    }
+
+    /// rA <- array rB at offset rC
+    pub const fn load(a: Register, b: Register, c: Register) -> Result<Self, EncodeError> {
+        Instruction::registers(OpCode::LOAD, a, b, c)
+    }
+
+    /// Array rA at offset rB <- rC
+    pub const fn store(a: Register, b: Register, c: Register) -> Result<Self, EncodeError> {
+        Instruction::registers(OpCode::STORE, a, b, c)
+    }
+
+    pub const fn add(a: Register, b: Register, c: Register) -> Result<Self, EncodeError> {
+        Instruction::registers(OpCode::ADD, a, b, c)
+    }
+
+    pub const fn mul(a: Register, b: Register, c: Register) -> Result<Self, EncodeError> {
+        Instruction::registers(OpCode::MUL, a, b, c)
+    }
+
+    pub const fn div(a: Register, b: Register, c: Register) -> Result<Self, EncodeError> {
+        Instruction::registers(OpCode::DIV, a, b, c)
+    }
+
+    pub const fn nand(a: Register, b: Register, c: Register) -> Result<Self, EncodeError> {
+        Instruction::registers(OpCode::NAND, a, b, c)
+    }
+
+    pub const fn halt() -> Self {
+        Instruction {
+            op_code: OpCode::HALT,
+            r_a: 0,
+            r_b: 0,
+            r_c: 0,
+            value: 0,
+        }
+    }
+
+    /// rB <- a new array of rC platters
+    pub const fn alloc(b: Register, c: Register) -> Result<Self, EncodeError> {
+        Instruction::registers(OpCode::ALLOC, 0, b, c)
+    }
+
+    pub const fn free(c: Register) -> Result<Self, EncodeError> {
+        Instruction::registers(OpCode::FREE, 0, 0, c)
+    }
+
+    pub const fn out(c: Register) -> Result<Self, EncodeError> {
+        Instruction::registers(OpCode::OUT, 0, 0, c)
+    }
+
+    /// `in`, which is a keyword
+    pub const fn input(c: Register) -> Result<Self, EncodeError> {
+        Instruction::registers(OpCode::IN, 0, 0, c)
+    }
+
+    /// Load array rB as the program and jump to offset rC
+    pub const fn call(b: Register, c: Register) -> Result<Self, EncodeError> {
+        Instruction::registers(OpCode::CALL, 0, b, c)
+    }
+
+    pub const fn orth(a: Register, value: Data) -> Result<Self, EncodeError> {
+        if a > 7 {
+            Err(EncodeError::Register(a))
+        } else if value > 0x1FFFFFF {
+            Err(EncodeError::Value(value))
+        } else {
+            Ok(Instruction {
+                op_code: OpCode::CONST,
+                r_a: a,
+                r_b: 0,
+                r_c: 0,
+                value,
+            })
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EncodeError {
+    Register(Register),
+    Value(Data),
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncodeError::Register(r) => write!(f, "register {} is not one of r0-r7", r),
+            EncodeError::Value(v) => write!(f, "value {} does not fit in 25 bits", v),
+        }
+    }
+}
+
+// Only the registers an operator actually uses are shown
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (a, b, c) = (self.r_a, self.r_b, self.r_c);
+        match self.op_code {
+            OpCode::CMOV
+            | OpCode::LOAD
+            | OpCode::STORE
+            | OpCode::ADD
+            | OpCode::MUL
+            | OpCode::DIV
+            | OpCode::NAND => write!(f, "{} r{}, r{}, r{}", self.op_code, a, b, c),
+            OpCode::HALT => write!(f, "{}", self.op_code),
+            OpCode::ALLOC | OpCode::CALL => write!(f, "{} r{}, r{}", self.op_code, b, c),
+            OpCode::FREE | OpCode::OUT | OpCode::IN => write!(f, "{} r{}", self.op_code, c),
+            OpCode::CONST => write!(f, "{} r{}, 0x{:x}", self.op_code, a, self.value),
+        }
+    }
+}
+
+// ---------- CONSOLE ---------------------------------------------------------
+
+/// Where IN reads from and OUT writes to
+pub trait Console {
+    fn input(&mut self) -> Data;
+    fn output(&mut self, c: u8);
+}
+
+impl fmt::Debug for dyn Console {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Console")
+    }
+}
+
+pub struct StdConsole;
+
+impl Console for StdConsole {
+    fn input(&mut self) -> Data {
+        let x: u8 = read!();
+        let xn: i8 = x as i8;
+        (xn as i32) as Data
+    }
+
+    fn output(&mut self, c: u8) {
+        print!("{}", c as char);
+    }
+}
+
+/// Reads from a buffer, all ones once it runs out, and keeps what is printed
+pub struct BufferConsole {
+    pub input: VecDeque<u8>,
+    pub output: Rc<RefCell<Vec<u8>>>,
+}
+
+impl Console for BufferConsole {
+    fn input(&mut self) -> Data {
+        self.input.pop_front().map_or(Data::MAX, |b| b as Data)
+    }
+
+    fn output(&mut self, c: u8) {
+        self.output.borrow_mut().push(c);
+    }
+}
+
+/// Run `program` to the end on `input`, returning what it printed
+#[cfg(test)]
+pub fn run_buffered(program: Vec<Data>, input: &[u8]) -> Vec<u8> {
+    let output = Rc::new(RefCell::new(Vec::new()));
+    let console = BufferConsole {
+        input: input.iter().copied().collect(),
+        output: output.clone(),
+    };
+    CPU::with_console(program, Box::new(console)).interpret();
+    let bytes = output.borrow().clone();
+    bytes
+}
+
+// ---------- FAULTS ----------------------------------------------------------
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    InvalidInstruction(Data),
+    EndOfPlatter,
+    LoadInactive(Data),
+    StoreInactive(Data),
+    OutOfBounds(Data, Data),
+    OutOfSpace,
+    FreeProgram,
+    DoubleFree(Data),
+    DivideByZero,
+    OutputOutOfBounds(Data),
+    CallInactive(Data),
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::InvalidInstruction(w) => {
+                write!(f, "Encountered invalid instruction 0x{:08x}", w)
+            }
+            Fault::EndOfPlatter => write!(f, "Execution finger ran off the platter"),
+            Fault::LoadInactive(a) => write!(f, "Loaded inactive array {}", a),
+            Fault::StoreInactive(a) => write!(f, "Stored to inactive array {}", a),
+            Fault::OutOfBounds(a, o) => write!(f, "Offset {} is outside array {}", o, a),
+            Fault::OutOfSpace => write!(f, "Problem in allocating new platter. Out of space?"),
+            Fault::FreeProgram => write!(f, "Cannot free program data"),
+            Fault::DoubleFree(a) => write!(f, "Double free of array {}", a),
+            Fault::DivideByZero => write!(f, "Division by zero"),
+            Fault::OutputOutOfBounds(c) => write!(f, "Printed character {} out of bounds", c),
+            Fault::CallInactive(a) => write!(f, "Called inactive array {}", a),
+        }
+    }
 }
 
 // ---------- CPU EMULATION ---------------------------------------------------
@@ -136,10 +451,28 @@ pub struct CPU {
     instruction_platter: Vec<Data>,
     memory: BTreeMap<Data, Platter>,
     next_allocate: Data,
+    instruction_count: u64,
+    console: Box<dyn Console>,
+}
+
+/// Everything about a CPU except its console
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    status: bool,
+    register_file: [Data; 8],
+    instruction_pointer: PlatterIndex,
+    instruction_platter: Vec<Data>,
+    memory: BTreeMap<Data, Platter>,
+    next_allocate: Data,
+    instruction_count: u64,
 }
 
 impl CPU {
     pub fn new(program: Vec<Data>) -> Self {
+        CPU::with_console(program, Box::new(StdConsole))
+    }
+
+    pub fn with_console(program: Vec<Data>, console: Box<dyn Console>) -> Self {
         CPU {
             status: true,
             register_file: [0; 8],
@@ -147,29 +480,81 @@ impl CPU {
             instruction_platter: program, // always platter 0, always active
             memory: BTreeMap::new(),
             next_allocate: 1,
+            instruction_count: 0,
+            console,
         }
     }
 
     pub fn interpret(&mut self) {
-        loop {
-            // 1. Fetch Decode
-            if !self.status {
-                break;
+        while self.status {
+            if let Err(fault) = self.step() {
+                panic!("{}", fault);
             }
-            let instruction = Instruction::decode(CPU::fetch_instruction(self));
+        }
+    }
+
+    /// Execute exactly one instruction at the execution finger. On a fault
+    ///  the machine is left as it was, with the finger on the culprit.
+    pub fn step(&mut self) -> Result<(), Fault> {
+        // 1. Fetch Decode
+        let finger = self.instruction_pointer;
+        let data = self.fetch_instruction()?;
+        if upper_byte(data) > OpCode::CONST as u8 {
+            self.instruction_pointer = finger;
+            return Err(Fault::InvalidInstruction(data));
+        }
+        let instruction = Instruction::decode(data);
 
-            // 2. Regsiter/Execute
-            self.execute_instruction(instruction);
+        // 2. Regsiter/Execute
+        if let Err(fault) = self.execute_instruction(instruction) {
+            self.instruction_pointer = finger;
+            return Err(fault);
         }
+        self.instruction_count += 1;
+        Ok(())
     }
 
-    fn fetch_instruction(&mut self) -> Data {
-        let data = self.instruction_platter[self.instruction_pointer as usize];
+    /// The contents of an active array, with 0 being the instruction platter
+    pub fn array(&self, id: Data) -> Option<&[Data]> {
+        if id == 0 {
+            Some(&self.instruction_platter)
+        } else {
+            self.memory.get(&id).map(|p| p.as_slice())
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            status: self.status,
+            register_file: self.register_file,
+            instruction_pointer: self.instruction_pointer,
+            instruction_platter: self.instruction_platter.clone(),
+            memory: self.memory.clone(),
+            next_allocate: self.next_allocate,
+            instruction_count: self.instruction_count,
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.status = snapshot.status;
+        self.register_file = snapshot.register_file;
+        self.instruction_pointer = snapshot.instruction_pointer;
+        self.instruction_platter = snapshot.instruction_platter.clone();
+        self.memory = snapshot.memory.clone();
+        self.next_allocate = snapshot.next_allocate;
+        self.instruction_count = snapshot.instruction_count;
+    }
+
+    fn fetch_instruction(&mut self) -> Result<Data, Fault> {
+        let data = *self
+            .instruction_platter
+            .get(self.instruction_pointer as usize)
+            .ok_or(Fault::EndOfPlatter)?;
         self.instruction_pointer += 1;
-        data
+        Ok(data)
     }
 
-    fn execute_instruction(&mut self, inst: Instruction) {
+    fn execute_instruction(&mut self, inst: Instruction) -> Result<(), Fault> {
         match inst.op_code {
             OpCode::CMOV => {
                 if self.register_file[inst.r_c as usize] != 0 {
@@ -179,36 +564,26 @@ impl CPU {
             OpCode::LOAD => {
                 let b = self.register_file[inst.r_b as usize];
                 let c = self.register_file[inst.r_c as usize];
-                let loaded: Data = if b == 0 {
-                    self.instruction_platter[c as usize]
+                let platter = if b == 0 {
+                    &self.instruction_platter
                 } else {
-                    let found = self.memory.get(&b);
-                    match found {
-                        None => {
-                            panic!("Loaded inactive array");
-                        }
-                        Some(p) => p[c as usize],
-                    }
+                    self.memory.get(&b).ok_or(Fault::LoadInactive(b))?
                 };
+                let loaded: Data = *platter.get(c as usize).ok_or(Fault::OutOfBounds(b, c))?;
                 self.register_file[inst.r_a as usize] = loaded;
             }
             OpCode::STORE => {
                 let a = self.register_file[inst.r_a as usize];
                 let b = self.register_file[inst.r_b as usize];
                 let c = self.register_file[inst.r_c as usize];
-                if a == 0 {
-                    self.instruction_platter[b as usize] = c;
+                let platter = if a == 0 {
+                    &mut self.instruction_platter
                 } else {
-                    let found = self.memory.get_mut(&a);
-                    match found {
-                        None => {
-                            panic!("Stored to inactive array");
-                        }
-                        Some(p) => {
-                            p[b as usize] = c;
-                        }
-                    }
-                }
+                    self.memory.get_mut(&a).ok_or(Fault::StoreInactive(a))?
+                };
+                *platter
+                    .get_mut(b as usize)
+                    .ok_or(Fault::OutOfBounds(a, b))? = c;
             }
             OpCode::ADD => {
                 let b = Wrapping(self.register_file[inst.r_b as usize]);
@@ -221,9 +596,10 @@ impl CPU {
                 self.register_file[inst.r_a as usize] = (b * c).0;
             }
             OpCode::DIV => {
-                let b = Wrapping(self.register_file[inst.r_b as usize]);
-                let c = Wrapping(self.register_file[inst.r_c as usize]);
-                self.register_file[inst.r_a as usize] = (b / c).0;
+                let b = self.register_file[inst.r_b as usize];
+                let c = self.register_file[inst.r_c as usize];
+                self.register_file[inst.r_a as usize] =
+                    b.checked_div(c).ok_or(Fault::DivideByZero)?;
             }
             OpCode::NAND => {
                 let b = self.register_file[inst.r_b as usize];
@@ -236,10 +612,10 @@ impl CPU {
             OpCode::ALLOC => {
                 let c = self.register_file[inst.r_c as usize];
 
-                let insert_result = self.memory.insert(self.next_allocate, vec![0; c as usize]);
-                if let Some(_) = insert_result {
-                    panic!("Problem in allocating new platter. Out of space?");
+                if self.memory.contains_key(&self.next_allocate) {
+                    return Err(Fault::OutOfSpace);
                 }
+                self.memory.insert(self.next_allocate, vec![0; c as usize]);
                 self.register_file[inst.r_b as usize] = self.next_allocate;
 
                 // TODO: Slow. Refactor to be efficient and to handle out of space and 0
@@ -251,25 +627,23 @@ impl CPU {
             OpCode::FREE => {
                 let c = self.register_file[inst.r_c as usize];
                 if c == 0 {
-                    panic!("Cannot free program data");
+                    return Err(Fault::FreeProgram);
                 }
 
                 let remove_result = self.memory.remove(&c);
-                if let None = remove_result {
-                    panic!("Double free");
+                if remove_result.is_none() {
+                    return Err(Fault::DoubleFree(c));
                 }
             }
             OpCode::OUT => {
                 let c = self.register_file[inst.r_c as usize];
                 if c > 255 {
-                    panic!("Printed character out of bounds");
+                    return Err(Fault::OutputOutOfBounds(c));
                 }
-                print!("{}", (c as u8) as char);
+                self.console.output(c as u8);
             }
             OpCode::IN => {
-                let x: u8 = read!();
-                let xn: i8 = x as i8;
-                self.register_file[inst.r_c as usize] = (xn as i32) as Data;
+                self.register_file[inst.r_c as usize] = self.console.input();
             }
             OpCode::CALL => {
                 let b = self.register_file[inst.r_b as usize];
@@ -279,7 +653,7 @@ impl CPU {
                     let found = self.memory.get(&b);
                     match found {
                         None => {
-                            panic!("Called inactive array");
+                            return Err(Fault::CallInactive(b));
                         }
                         Some(p) => {
                             self.instruction_platter = p.clone();
@@ -292,6 +666,498 @@ impl CPU {
                 self.register_file[inst.r_a as usize] = inst.value;
             }
         }
+        Ok(())
+    }
+}
+
+// ---------- PROGRAM LOADING -------------------------------------------------
+fn u8x4_to_u32_big_endian(u8s: &[u8]) -> u32{
+    ((u8s[0] as u32) << 24) 
+        + ((u8s[1] as u32) << 16) 
+        + ((u8s[2] as u32) << 8)
+        + (u8s[3] as u32)
+}
+
+/// The platters of an image, which must be a whole number of them
+pub fn load_program(path: &str) -> Result<Vec<Data>, String> {
+    let raw: Vec<u8> = std::fs::read(path).map_err(|e| e.to_string())?;
+    if !raw.len().is_multiple_of(4) {
+        return Err(format!("{} bytes is not a whole number of platters", raw.len()));
+    }
+    Ok(raw.chunks(4)
+        .map(u8x4_to_u32_big_endian)
+        .collect::<Vec<u32>>())
+}
+
+/// Load the image at `path`, or say why not and exit
+fn load_or_exit(path: &str) -> Vec<Data> {
+    load_program(path).unwrap_or_else(|error| {
+        eprintln!("{}: {}", path, error);
+        std::process::exit(1);
+    })
+}
+
+pub fn save_program(path: &str, program: &[Data]) -> std::io::Result<()> {
+    let raw: Vec<u8> = program.iter().flat_map(|w| w.to_be_bytes()).collect();
+    std::fs::write(path, raw)
+}
+
+/// Remove `-s SYMBOLS` from `args` and load the map it names
+fn symbols_option(args: &mut Vec<String>) -> symbols::Symbols {
+    let i = match args.iter().position(|a| a == "-s") {
+        Some(i) if i + 1 < args.len() => i,
+        _ => return symbols::Symbols::default(),
+    };
+    let path = args.remove(i + 1);
+    args.remove(i);
+    symbols::Symbols::load(&path).unwrap_or_else(|error| {
+        eprintln!("{}: {}", path, error);
+        std::process::exit(1);
+    })
+}
+
+/// Remove the `--trace FILE` options from `args` and make the tracer they
+/// describe. FILE ending in .json or .jsonl gets JSON lines, else binary.
+fn trace_options(args: &mut Vec<String>, symbols: &symbols::Symbols) -> Option<trace::Tracer> {
+    let mut option = |name: &str| {
+        let i = args.iter().position(|a| a == name)?;
+        let value = args.get(i + 1).cloned();
+        args.drain(i..(i + 2).min(args.len()));
+        Some(value.unwrap_or_else(|| {
+            eprintln!("{} needs a value", name);
+            std::process::exit(1);
+        }))
+    };
+    let path = option("--trace");
+    let offsets = option("--trace-offsets");
+    let operators = option("--trace-ops");
+    let window = option("--trace-window");
+    let path = path?;
+    let fail = |error: String| -> ! {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    };
+    let offset = |text: &str| match text.strip_prefix("0x") {
+        Some(hex) => Data::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok().or_else(|| symbols.resolve(text)),
+    };
+    let mut filter = trace::Filter::default();
+    if let Some(offsets) = offsets {
+        filter.offsets = Some(trace::range(&offsets, Data::MAX, offset).unwrap_or_else(|e| fail(e)));
+    }
+    if let Some(operators) = operators {
+        let names = operators.split(',').map(|name| {
+            OpCode::from_mnemonic(name).unwrap_or_else(|| fail(format!("unknown operator '{}'", name)))
+        });
+        filter.operators = Some(names.collect());
+    }
+    if let Some(window) = window {
+        let count = |text: &str| text.parse().ok();
+        filter.window = Some(trace::range(&window, u64::MAX, count).unwrap_or_else(|e| fail(e)));
+    }
+    let format = if path.ends_with(".json") || path.ends_with(".jsonl") {
+        trace::Format::Json
+    } else {
+        trace::Format::Binary
+    };
+    let file = std::fs::File::create(&path).unwrap_or_else(|e| fail(format!("{}: {}", path, e)));
+    let output = Box::new(std::io::BufWriter::new(file));
+    let mut tracer = trace::Tracer::new(output, format, symbols.clone()).unwrap();
+    tracer.filter = filter;
+    Some(tracer)
+}
+
+/// Remove `--profile`, `--memory-profile`, `--memory-csv FILE`,
+/// `--alloc-profile`, `--coverage FILE`, `--flamegraph FILE` and
+/// `--profile-top N` from `args` and add the profiles they ask for
+fn profile_options(
+    args: &mut Vec<String>,
+    symbols: &symbols::Symbols,
+    observers: &mut Vec<Box<dyn trace::Observer>>,
+) {
+    let mut flag = |name: &str| args.iter().position(|a| a == name).map(|i| args.remove(i));
+    let hot = flag("--profile").is_some();
+    let memory = flag("--memory-profile").is_some();
+    let alloc = flag("--alloc-profile").is_some();
+    let mut option = |name: &str| {
+        let i = args.iter().position(|a| a == name)?;
+        let value = args.get(i + 1).cloned();
+        args.drain(i..(i + 2).min(args.len()));
+        Some(value.unwrap_or_else(|| {
+            eprintln!("{} needs a value", name);
+            std::process::exit(1);
+        }))
+    };
+    let csv = option("--memory-csv");
+    let coverage = option("--coverage");
+    let flamegraph = option("--flamegraph");
+    let top = option("--profile-top").map(|n| {
+        n.parse().unwrap_or_else(|_| {
+            eprintln!("--profile-top needs a number, not '{}'", n);
+            std::process::exit(1);
+        })
+    });
+    let create = |path: &str| -> Box<dyn std::io::Write> {
+        let file = std::fs::File::create(path).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(1);
+        });
+        Box::new(std::io::BufWriter::new(file))
+    };
+    if hot {
+        let mut profile = profile::Profile::new(symbols.clone());
+        profile.top = top.unwrap_or(profile.top);
+        observers.push(Box::new(profile));
+    }
+    if memory || csv.is_some() {
+        let mut profile = profile::memory::Memory::new(symbols.clone());
+        profile.top = top.unwrap_or(profile.top);
+        profile.summary = memory;
+        profile.csv = csv.map(|path| create(&path));
+        observers.push(Box::new(profile));
+    }
+    if alloc {
+        let mut profile = profile::alloc::Allocations::new(symbols.clone());
+        profile.top = top.unwrap_or(profile.top);
+        observers.push(Box::new(profile));
+    }
+    if let Some(path) = coverage {
+        observers.push(Box::new(profile::coverage::Recorder::new(create(&path))));
+    }
+    if let Some(path) = flamegraph {
+        let flame = profile::flame::Flame::new(symbols.clone(), create(&path));
+        observers.push(Box::new(flame));
+    }
+}
+
+pub fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("debug") => {
+            let symbols = symbols_option(&mut args);
+            let path = args.get(1).map(String::as_str).unwrap_or("./codex.umz");
+            let mut debugger = debugger::Debugger::new(CPU::new(load_or_exit(path)));
+            debugger.symbols = symbols;
+            let stdin = std::io::stdin();
+            debugger.repl(&mut stdin.lock(), &mut std::io::stdout());
+        }
+        Some("disasm") => {
+            let symbols = symbols_option(&mut args);
+            let path = args.get(1).expect("Usage: cult disasm IMAGE [START [COUNT]] [-s SYMBOLS]");
+            let program = load_or_exit(path);
+            let start = args.get(2).map_or(0, |n| n.parse().unwrap());
+            let count = args.get(3).map_or(program.len(), |n| n.parse().unwrap());
+            for line in disasm::annotated(&program, start, count, &symbols) {
+                println!("{}", line);
+            }
+        }
+        Some("asm") => {
+            let usage = "Usage: cult asm SOURCE [-c] [-o OUTPUT] [-l LISTING] [-s SYMBOLS]";
+            let path = args.get(1).expect(usage);
+            let stem = path.strip_suffix(".uma").unwrap_or(path);
+            let (mut object, mut output, mut listing, mut map) = (false, None, None, None);
+            let mut options = args[2..].iter();
+            while let Some(option) = options.next() {
+                match option.as_str() {
+                    "-c" => object = true,
+                    "-o" => output = Some(options.next().expect(usage).clone()),
+                    "-l" => listing = Some(options.next().expect(usage).clone()),
+                    "-s" => map = Some(options.next().expect(usage).clone()),
+                    _ => panic!("{}", usage),
+                }
+            }
+            let source = std::fs::read_to_string(path).unwrap();
+            let result = asm::build_with(path, &source, &asm::read_file).and_then(|assembly| {
+                if object {
+                    let output = output.unwrap_or(format!("{}.umo", stem));
+                    let json = asm::object::Object::from(&assembly).to_json();
+                    std::fs::write(output, json.to_string()).unwrap();
+                } else {
+                    let output = output.unwrap_or(format!("{}.um", stem));
+                    save_program(&output, &assembly.image()?).unwrap();
+                }
+                if let Some(listing) = listing {
+                    let mut text = asm::listing::listing(&source, &assembly).join("\n");
+                    text.push('\n');
+                    std::fs::write(listing, text).unwrap();
+                }
+                if let Some(map) = map {
+                    let json = symbols::Symbols::from(&assembly).to_json();
+                    std::fs::write(map, json.to_string()).unwrap();
+                }
+                Ok(())
+            });
+            if let Err(error) = result {
+                let text = match asm::stdlib::source(&error.file) {
+                    Some(text) => text.to_string(),
+                    None => std::fs::read_to_string(&error.file).unwrap_or_default(),
+                };
+                eprintln!("{}", error.render(&text));
+                std::process::exit(1);
+            }
+        }
+        Some("compile") => {
+            let usage = "Usage: cult compile SOURCE [-S] [-o OUTPUT]";
+            let path = args.get(1).expect(usage);
+            let stem = path.strip_suffix(".cult").unwrap_or(path);
+            let (mut assembly, mut output) = (false, None);
+            let mut options = args[2..].iter();
+            while let Some(option) = options.next() {
+                match option.as_str() {
+                    "-S" => assembly = true,
+                    "-o" => output = Some(options.next().expect(usage).clone()),
+                    _ => panic!("{}", usage),
+                }
+            }
+            let source = std::fs::read_to_string(path).unwrap();
+            let result = if assembly {
+                lang::compile(&source).map(|text| {
+                    let output = output.unwrap_or(format!("{}.uma", stem));
+                    std::fs::write(output, text).unwrap();
+                })
+            } else {
+                lang::build(path, &source).map(|program| {
+                    let output = output.unwrap_or(format!("{}.um", stem));
+                    save_program(&output, &program).unwrap();
+                })
+            };
+            if let Err(error) = result {
+                eprintln!("{}", error.render(&source));
+                std::process::exit(1);
+            }
+        }
+        Some("bf2um") => {
+            let usage = "Usage: cult bf2um SOURCE [-S] [-o OUTPUT] [--tape CELLS] [--eof 0|-1|unchanged]";
+            let path = args.get(1).expect(usage);
+            let stem = path.strip_suffix(".bf").unwrap_or(path);
+            let (mut assembly, mut output) = (false, None);
+            let mut options = bf::Options::default();
+            let mut rest = args[2..].iter();
+            while let Some(option) = rest.next() {
+                match option.as_str() {
+                    "-S" => assembly = true,
+                    "-o" => output = Some(rest.next().expect(usage).clone()),
+                    "--tape" => options.tape = rest.next().expect(usage).parse().expect(usage),
+                    "--eof" => options.eof = rest.next().expect(usage).parse().expect(usage),
+                    _ => panic!("{}", usage),
+                }
+            }
+            let source = std::fs::read_to_string(path).unwrap();
+            match bf::translate(&source, &options) {
+                Ok(text) if assembly => {
+                    std::fs::write(output.unwrap_or(format!("{}.uma", stem)), text).unwrap()
+                }
+                Ok(text) => {
+                    let program = asm::assemble(&text).expect("translated assembly is valid");
+                    save_program(&output.unwrap_or(format!("{}.um", stem)), &program).unwrap();
+                }
+                Err(mut error) => {
+                    error.file = path.to_string();
+                    eprintln!("{}", error.render(&source));
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some("link") => {
+            let usage = "Usage: cult link OBJECT... [-o IMAGE] [-s SYMBOLS]";
+            let mut output = "a.um".to_string();
+            let mut map = None;
+            let mut objects = Vec::new();
+            let mut options = args[1..].iter();
+            while let Some(option) = options.next() {
+                match option.as_str() {
+                    "-o" => output = options.next().expect(usage).clone(),
+                    "-s" => map = Some(options.next().expect(usage).clone()),
+                    path => match asm::object::Object::load(path) {
+                        Ok(object) => objects.push((path.to_string(), object)),
+                        Err(error) => {
+                            eprintln!("{}: {}", path, error);
+                            std::process::exit(1);
+                        }
+                    },
+                }
+            }
+            if objects.is_empty() {
+                panic!("{}", usage);
+            }
+            match asm::link::link(&objects) {
+                Ok(linked) => {
+                    save_program(&output, &linked.words).unwrap();
+                    if let Some(map) = map {
+                        let json = linked.symbols(&objects).to_json();
+                        std::fs::write(map, json.to_string()).unwrap();
+                    }
+                }
+                Err(errors) => {
+                    for error in errors {
+                        eprintln!("{}", error);
+                    }
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some("trace-diff") => {
+            let symbols = symbols_option(&mut args);
+            let usage = "Usage: cult trace-diff A B [-C CONTEXT] [-s SYMBOLS]";
+            let context = match args.iter().position(|a| a == "-C") {
+                Some(i) => {
+                    let n = args.get(i + 1).and_then(|n| n.parse().ok()).expect(usage);
+                    args.drain(i..i + 2);
+                    n
+                }
+                None => 5,
+            };
+            let (a, b) = match &args[1..] {
+                [a, b] => (a, b),
+                _ => panic!("{}", usage),
+            };
+            let read = |path: &str| {
+                let bytes = std::fs::read(path).map_err(|e| e.to_string());
+                bytes.and_then(|bytes| trace::read(&bytes)).unwrap_or_else(|error| {
+                    eprintln!("{}: {}", path, error);
+                    std::process::exit(2);
+                })
+            };
+            let (events_a, events_b) = (read(a), read(b));
+            match trace::diff::report((a, b), &events_a, &events_b, &symbols, context) {
+                Some(report) => {
+                    println!("{}", report);
+                    std::process::exit(1);
+                }
+                None => println!("traces match ({} events)", events_a.len()),
+            }
+        }
+        Some("bench") => {
+            let usage = "Usage: cult bench [IMAGE...] [-n COUNT] [--input FILE]";
+            let mut option = |name: &str| {
+                let i = args.iter().position(|a| a == name)?;
+                let value = args.get(i + 1).cloned().expect(usage);
+                args.drain(i..i + 2);
+                Some(value)
+            };
+            let count = option("-n").map_or(10_000_000, |n| n.parse().expect(usage));
+            let input = option("--input").map_or(Vec::new(), |path| std::fs::read(path).unwrap());
+            let mut workloads: Vec<(String, Vec<Data>)> = bench::WORKLOADS
+                .iter()
+                .map(|(name, _)| (name.to_string(), bench::workload(name).unwrap()))
+                .collect();
+            for path in &args[1..] {
+                workloads.push((path.clone(), load_or_exit(path)));
+            }
+            println!("{}", bench::header());
+            for (name, program) in &workloads {
+                for backend in bench::BACKENDS.iter() {
+                    let run = bench::run(program.clone(), &input, backend, count);
+                    println!("{}", bench::line(name, backend, &run));
+                }
+            }
+        }
+        Some("cfg") => {
+            let symbols = symbols_option(&mut args);
+            let usage = "Usage: cult cfg IMAGE [-s SYMBOLS] [-o OUTPUT]";
+            let output = match args.iter().position(|a| a == "-o") {
+                Some(i) => {
+                    let path = args.get(i + 1).cloned().expect(usage);
+                    args.drain(i..i + 2);
+                    Some(path)
+                }
+                None => None,
+            };
+            let program = load_or_exit(args.get(1).expect(usage));
+            let graph = cfg::recover(&program);
+            let text = cfg::dot(&graph, &program, &symbols);
+            match output {
+                Some(path) => std::fs::write(&path, text).unwrap(),
+                None => print!("{}", text),
+            }
+            eprintln!("{}", graph.summary(program.len()));
+        }
+        Some("coverage") => {
+            let symbols = symbols_option(&mut args);
+            let usage = "Usage: cult coverage FILE... [-o OUTPUT] [--annotate IMAGE] [-s SYMBOLS]";
+            let mut option = |name: &str| {
+                let i = args.iter().position(|a| a == name)?;
+                let value = args.get(i + 1).cloned().expect(usage);
+                args.drain(i..i + 2);
+                Some(value)
+            };
+            let output = option("-o");
+            let image = option("--annotate");
+            if args.len() < 2 {
+                panic!("{}", usage);
+            }
+            let mut coverage = profile::coverage::Coverage::default();
+            for path in &args[1..] {
+                let text = std::fs::read_to_string(path).map_err(|e| e.to_string());
+                let file = text.and_then(|text| profile::coverage::Coverage::parse(&text));
+                if let Err(error) = file.and_then(|file| coverage.merge(&file)) {
+                    eprintln!("{}: {}", path, error);
+                    std::process::exit(1);
+                }
+            }
+            if let Some(path) = output {
+                std::fs::write(&path, coverage.to_text()).unwrap();
+            }
+            if let Some(path) = image {
+                let program = load_or_exit(&path);
+                let hash = profile::coverage::image_hash(&program);
+                if coverage.image.is_some_and(|image| image != hash) {
+                    eprintln!("{}: not the image the coverage was recorded for", path);
+                    std::process::exit(1);
+                }
+                coverage.size = program.len() as Data;
+                println!("{}", coverage.annotate(&program, &symbols));
+            }
+            println!("{}", coverage.summary());
+        }
+        Some("tui") => {
+            let path = args.get(1).map(String::as_str).unwrap_or("./codex.umz");
+            tui::Tui::new(load_or_exit(path)).run().unwrap();
+        }
+        Some("lsp") => {
+            let stdin = std::io::stdin();
+            let mut stdout = std::io::stdout();
+            asm::lsp::Server::new(&mut stdin.lock(), &mut stdout).run().unwrap();
+        }
+        Some("dap") => {
+            let stdin = Box::new(std::io::BufReader::new(std::io::stdin()));
+            let mut stdout = std::io::stdout();
+            debugger::dap::Server::new(stdin, &mut stdout).run().unwrap();
+        }
+        Some("--gdb-listen") => {
+            let address = args.get(1).expect("Usage: cult --gdb-listen ADDRESS:PORT [IMAGE]");
+            let path = args.get(2).map(String::as_str).unwrap_or("./codex.umz");
+            let debugger = debugger::Debugger::new(CPU::new(load_or_exit(path)));
+            debugger::gdb::listen(address, debugger).unwrap();
+        }
+        _ => {
+            let symbols = symbols_option(&mut args);
+            let mut observers: Vec<Box<dyn trace::Observer>> = Vec::new();
+            if let Some(tracer) = trace_options(&mut args, &symbols) {
+                observers.push(Box::new(tracer));
+            }
+            profile_options(&mut args, &symbols, &mut observers);
+            let path = args.first().map(String::as_str).unwrap_or("./codex.umz");
+            let mut cpu = CPU::new(load_or_exit(path));
+            for observer in observers.iter_mut() {
+                observer.start(&cpu);
+            }
+            let mut fault = None;
+            while cpu.status && fault.is_none() {
+                fault = trace::step_observed(&mut cpu, &mut observers).err();
+            }
+            for observer in observers.iter_mut() {
+                if let Err(error) = observer.finish(&cpu) {
+                    eprintln!("{}", error);
+                    std::process::exit(1);
+                }
+            }
+            if let Some(fault) = fault {
+                let finger = symbols.describe(cpu.instruction_pointer as Data);
+                eprintln!("{} at {}", fault, finger);
+                std::process::exit(1);
+            }
+        }
     }
 }
 
@@ -299,9 +1165,23 @@ impl CPU {
 mod tests {
     use super::*;
 
+    #[test]
+    fn truncated_images_are_refused() {
+        let path = std::env::temp_dir().join("cult_truncated.um");
+        std::fs::write(&path, [0x70, 0, 0, 0, 0xd2]).unwrap();
+        let path = path.to_str().unwrap();
+        assert_eq!(
+            load_program(path),
+            Err("5 bytes is not a whole number of platters".to_string())
+        );
+        std::fs::write(path, [0x70, 0, 0, 0]).unwrap();
+        assert_eq!(load_program(path), Ok(vec![0x70000000]));
+        assert!(load_program("/nonexistent.um").is_err());
+    }
+
     #[test]
     fn create_and_halt() {
-        let program: Vec<Data> = vec![0x70000000];
+        let program: Vec<Data> = um_asm! { halt };
         let mut cpu = CPU::new(program);
         cpu.interpret();
         assert!(cpu.instruction_pointer == 1);
@@ -329,7 +1209,7 @@ mod tests {
 
     #[test]
     fn cmov_no_move() {
-        let program: Vec<Data> = vec![0b00000000000000000000000000001010, 0x70000000];
+        let program: Vec<Data> = um_asm! { cmov r0, r1, r2; halt };
         let mut cpu = CPU::new(program);
         cpu.register_file[0] = 0xDEADBEEF;
         cpu.register_file[1] = 0xDECAF000;
@@ -343,7 +1223,7 @@ mod tests {
 
     #[test]
     fn cmov_move() {
-        let program: Vec<Data> = vec![0b00000000000000000000000000001010, 0x70000000];
+        let program: Vec<Data> = um_asm! { cmov r0, r1, r2; halt };
         let mut cpu = CPU::new(program);
         cpu.register_file[0] = 0xDEADBEEF;
         cpu.register_file[1] = 0xDECAF000;
@@ -357,7 +1237,7 @@ mod tests {
 
     #[test]
     fn cmov_move_to_self() {
-        let program: Vec<Data> = vec![0b00000000000000000000000011011010, 0x70000000];
+        let program: Vec<Data> = um_asm! { cmov r3, r3, r2; halt };
         let mut cpu = CPU::new(program);
         cpu.register_file[3] = 0xDEADBEEF;
         cpu.register_file[2] = 0x1;
@@ -369,7 +1249,7 @@ mod tests {
 
     #[test]
     fn add_no_wrap() {
-        let program: Vec<Data> = vec![0b00110000000000000000000000001010, 0x70000000];
+        let program: Vec<Data> = um_asm! { add r0, r1, r2; halt };
         let mut cpu = CPU::new(program);
         cpu.register_file[0] = 0;
         cpu.register_file[1] = 0x1;
@@ -383,7 +1263,7 @@ mod tests {
 
     #[test]
     fn add_wrap() {
-        let program: Vec<Data> = vec![0b00110000000000000000000000001010, 0x70000000];
+        let program: Vec<Data> = um_asm! { add r0, r1, r2; halt };
         let mut cpu = CPU::new(program);
         cpu.register_file[0] = 0;
         cpu.register_file[1] = 0x1;
@@ -397,7 +1277,7 @@ mod tests {
 
     #[test]
     fn mul_no_wrap() {
-        let program: Vec<Data> = vec![0b01000000000000000000000000001010, 0x70000000];
+        let program: Vec<Data> = um_asm! { mul r0, r1, r2; halt };
         let mut cpu = CPU::new(program);
         cpu.register_file[0] = 0;
         cpu.register_file[1] = 2;
@@ -411,7 +1291,7 @@ mod tests {
 
     #[test]
     fn mul_wrap() {
-        let program: Vec<Data> = vec![0b01000000000000000000000000001010, 0x70000000];
+        let program: Vec<Data> = um_asm! { mul r0, r1, r2; halt };
         let mut cpu = CPU::new(program);
         cpu.register_file[0] = 0;
         cpu.register_file[1] = u32::MAX;
@@ -425,7 +1305,7 @@ mod tests {
 
     #[test]
     fn div_even() {
-        let program: Vec<Data> = vec![0b01010000000000000000000000001010, 0x70000000];
+        let program: Vec<Data> = um_asm! { div r0, r1, r2; halt };
         let mut cpu = CPU::new(program);
         cpu.register_file[0] = 0;
         cpu.register_file[1] = 8;
@@ -439,7 +1319,7 @@ mod tests {
 
     #[test]
     fn div_round() {
-        let program: Vec<Data> = vec![0b01010000000000000000000000001010, 0x70000000];
+        let program: Vec<Data> = um_asm! { div r0, r1, r2; halt };
         let mut cpu = CPU::new(program);
         cpu.register_file[0] = 0;
         cpu.register_file[1] = 8;
@@ -454,7 +1334,7 @@ mod tests {
     #[test]
     #[should_panic]
     fn div_0() {
-        let program: Vec<Data> = vec![0b01010000000000000000000000001010, 0x70000000];
+        let program: Vec<Data> = um_asm! { div r0, r1, r2; halt };
         let mut cpu = CPU::new(program);
         cpu.register_file[0] = 1;
         cpu.register_file[1] = 8;
@@ -464,7 +1344,7 @@ mod tests {
 
     #[test]
     fn single_allocate() {
-        let program: Vec<Data> = vec![0b10000000000000000000000000000001, 0x70000000];
+        let program: Vec<Data> = um_asm! { alloc r0, r1; halt };
         let mut cpu = CPU::new(program);
         cpu.register_file[1] = 17;
         cpu.interpret();
@@ -482,12 +1362,12 @@ mod tests {
 
     #[test]
     fn two_allocate() {
-        let program: Vec<Data> = vec![
-            0b10000000000000000000000000000001,
-            0b00000000000000000000000111000001, // MOVE r2 -> r7
-            0b10000000000000000000000000000010,
-            0x70000000,
-        ];
+        let program: Vec<Data> = um_asm! {
+            alloc r0, r1;
+            cmov r7, r0, r1;    // MOVE r2 -> r7
+            alloc r0, r2;
+            halt;
+        };
         let mut cpu = CPU::new(program);
         cpu.register_file[1] = 12;
         cpu.register_file[2] = 17;
@@ -516,12 +1396,13 @@ mod tests {
         }
     }
     #[test]
+    #[allow(clippy::len_zero, clippy::redundant_pattern_matching)]
     fn allocate_then_free() {
-        let program: Vec<Data> = vec![
-            0b10000000000000000000000000001010,
-            0b10010000000000000000000000000001,
-            0x70000000,
-        ];
+        let program: Vec<Data> = um_asm! {
+            alloc r1, r2;
+            free r1;
+            halt;
+        };
         let mut cpu = CPU::new(program);
         cpu.register_file[2] = 17;
         cpu.interpret();
@@ -534,7 +1415,7 @@ mod tests {
 
     #[test]
     fn test_nand() {
-        let program: Vec<Data> = vec![0b01100000000000000000000000001010, 0x70000000];
+        let program: Vec<Data> = um_asm! { nand r0, r1, r2; halt };
         let mut cpu = CPU::new(program);
         cpu.register_file[0] = 0x0;
         cpu.register_file[1] = 0xFFFF00FF;
@@ -545,12 +1426,12 @@ mod tests {
 
     #[test]
     fn test_alloc_store_load() {
-        let program: Vec<Data> = vec![
-            0b10000000000000000000000000111000, // r7 <- ALLOC (r0)
-            0b00100000000000000000000111001010, // MEM(r7)[r1] <- r2
-            0b00010000000000000000000110111001, // r6 <- MEM(r7)[r1]
-            0x70000000,
-        ];
+        let program: Vec<Data> = um_asm! {
+            alloc r7, r0;       // r7 <- ALLOC (r0)
+            store r7, r1, r2;   // MEM(r7)[r1] <- r2
+            load r6, r7, r1;    // r6 <- MEM(r7)[r1]
+            halt;
+        };
         let mut cpu = CPU::new(program);
 
         cpu.register_file[0] = 20;
@@ -564,10 +1445,10 @@ mod tests {
     #[test]
     #[should_panic]
     fn test_store_unallocated_space() {
-        let program: Vec<Data> = vec![
-            0b00100000000000000000000111001010, // MEM(r7)[r1] <- r2
-            0x70000000,
-        ];
+        let program: Vec<Data> = um_asm! {
+            store r7, r1, r2;   // MEM(r7)[r1] <- r2
+            halt;
+        };
         let mut cpu = CPU::new(program);
         cpu.register_file[7] = 100;
         cpu.interpret();
@@ -575,11 +1456,11 @@ mod tests {
 
     #[test]
     fn test_store_program_memory() {
-        let program: Vec<Data> = vec![
-            0b00100000000000000000000111001010, // MEM(r7)[r1] <- r2
-            0b00010000000000000000000110111001, // r6 <- MEM(r7)[r1]
-            0x70000000,
-        ];
+        let program: Vec<Data> = um_asm! {
+            store r7, r1, r2;   // MEM(r7)[r1] <- r2
+            load r6, r7, r1;    // r6 <- MEM(r7)[r1]
+            halt;
+        };
         let mut cpu = CPU::new(program);
         cpu.register_file[1] = 0;
         cpu.register_file[7] = 0;
@@ -591,10 +1472,10 @@ mod tests {
     #[test]
     #[should_panic]
     fn test_load_unallocated_space() {
-        let program: Vec<Data> = vec![
-            0b00010000000000000000000110111001, // r6 <- MEM(r7)[r1]
-            0x70000000,
-        ];
+        let program: Vec<Data> = um_asm! {
+            load r6, r7, r1;    // r6 <- MEM(r7)[r1]
+            halt;
+        };
         let mut cpu = CPU::new(program);
         cpu.register_file[7] = 100;
         cpu.interpret();
@@ -602,7 +1483,7 @@ mod tests {
 
     #[test]
     fn print_c() {
-        let program: Vec<Data> = vec![0b10100000000000000000000000000111, 0x70000000];
+        let program: Vec<Data> = um_asm! { out r7; halt };
         let mut cpu = CPU::new(program);
         cpu.register_file[7] = 67;
         cpu.interpret();
@@ -610,7 +1491,7 @@ mod tests {
 
     #[test]
     fn print_0() {
-        let program: Vec<Data> = vec![0b10100000000000000000000000000111, 0x70000000];
+        let program: Vec<Data> = um_asm! { out r7; halt };
         let mut cpu = CPU::new(program);
         cpu.register_file[7] = 0;
         cpu.interpret();
@@ -618,7 +1499,7 @@ mod tests {
 
     #[test]
     fn print_255() {
-        let program: Vec<Data> = vec![0b10100000000000000000000000000111, 0x70000000];
+        let program: Vec<Data> = um_asm! { out r7; halt };
         let mut cpu = CPU::new(program);
         cpu.register_file[7] = 255;
         cpu.interpret();
@@ -627,7 +1508,7 @@ mod tests {
     #[test]
     #[should_panic]
     fn print_out_of_bounds() {
-        let program: Vec<Data> = vec![0b10100000000000000000000000000111, 0x70000000];
+        let program: Vec<Data> = um_asm! { out r7; halt };
         let mut cpu = CPU::new(program);
         cpu.register_file[7] = 256;
         cpu.interpret();
@@ -635,7 +1516,7 @@ mod tests {
 
     #[test]
     fn constant_load() {
-        let program: Vec<Data> = vec![0b11011110101010101101001001010101, 0x70000000];
+        let program: Vec<Data> = um_asm! { orth r7, 0xaad255; halt };
         let mut cpu = CPU::new(program);
         cpu.interpret();
         assert!(cpu.register_file[7] == 0b0101010101101001001010101);
@@ -644,10 +1525,11 @@ mod tests {
 
     #[test]
     fn call_array() {
-        let program: Vec<Data> = vec![
-            0b10000000000000000000000000101000,     // r5 <- ALLOC(r0)   size 5
-            0b00100000000000000000000101001010,     // MEM(r5)[r1] = r2   (halt)
-            0b11000000000000000000000000101001];    // CALL(r5)[r1]
+        let program: Vec<Data> = um_asm! {
+            alloc r5, r0;       // r5 <- ALLOC(r0)   size 5
+            store r5, r1, r2;   // MEM(r5)[r1] = r2   (halt)
+            call r5, r1;        // CALL(r5)[r1]
+        };
         let mut cpu = CPU::new(program);
         cpu.register_file[0] = 5;           // Size of new array
         cpu.register_file[1] = 3;           // Index in new array to jump to
@@ -661,8 +1543,9 @@ mod tests {
     #[test]
     #[should_panic]
     fn call_inactive_array() {
-        let program: Vec<Data> = vec![
-            0b11000000000000000000000000101001];    // CALL(r5)[r1]
+        let program: Vec<Data> = um_asm! {
+            call r5, r1;        // CALL(r5)[r1]
+        };
         let mut cpu = CPU::new(program);
         cpu.register_file[0] = 5;           // Size of new array
         cpu.register_file[1] = 3;           // Index in new array to jump to
@@ -686,6 +1569,86 @@ mod tests {
         cpu.interpret();
     }
 
+    // xorshift32, so the properties below see the same words every run
+    fn words(mut state: u32, count: usize) -> Vec<Data> {
+        (0..count)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state
+            })
+            .collect()
+    }
+
+    // Operators other than orth only use the top 4 and bottom 9 bits
+    fn is_canonical(word: Data) -> bool {
+        match OpCode::try_from_byte(upper_byte(word)) {
+            Some(OpCode::CONST) => true,
+            Some(_) => word & 0x0FFFFE00 == 0,
+            None => false,
+        }
+    }
+
+    #[test]
+    fn encode_inverts_decode() {
+        // Every register operand of every other operator
+        for op in 0..0xD {
+            for registers in 0..512 {
+                let word = (op << 28) | registers;
+                assert_eq!(Instruction::decode(word).encode(), word);
+            }
+        }
+        let edges = vec![0xD0000000, 0xD1FFFFFF, 0xDE000000, 0xDFFFFFFF];
+        for word in edges.into_iter().chain(words(0x2006, 100_000)) {
+            let word = if is_canonical(word) { word } else { word & 0xF00001FF };
+            if let Some(instruction) = Instruction::try_decode(word) {
+                assert_eq!(instruction.encode(), word, "{:08x}", word);
+            }
+        }
+    }
+
+    #[test]
+    fn decode_inverts_encode() {
+        let random = words(0xC0DE, 30_000);
+        for chunk in random.chunks(3) {
+            let a = (chunk[0] % 8) as Register;
+            let b = (chunk[1] % 8) as Register;
+            let c = (chunk[2] % 8) as Register;
+            let value = chunk[0] & 0x1FFFFFF;
+            let instructions = vec![
+                Instruction::cmov(a, b, c),
+                Instruction::load(a, b, c),
+                Instruction::store(a, b, c),
+                Instruction::add(a, b, c),
+                Instruction::mul(a, b, c),
+                Instruction::div(a, b, c),
+                Instruction::nand(a, b, c),
+                Ok(Instruction::halt()),
+                Instruction::alloc(b, c),
+                Instruction::free(c),
+                Instruction::out(c),
+                Instruction::input(c),
+                Instruction::call(b, c),
+                Instruction::orth(a, value),
+            ];
+            for instruction in instructions {
+                let instruction = instruction.unwrap();
+                assert_eq!(Instruction::decode(instruction.encode()), instruction);
+            }
+        }
+    }
+
+    #[test]
+    fn constructors_validate_operands() {
+        assert_eq!(Instruction::add(8, 0, 0), Err(EncodeError::Register(8)));
+        assert_eq!(Instruction::alloc(1, 9), Err(EncodeError::Register(9)));
+        assert_eq!(Instruction::orth(7, 0x2000000), Err(EncodeError::Value(0x2000000)));
+        assert_eq!(Instruction::orth(7, 0x1FFFFFF).unwrap().encode(), 0xDFFFFFFF);
+        assert_eq!(Instruction::alloc(7, 0).unwrap().encode(), 0b10000000000000000000000000111000);
+        assert_eq!(Instruction::halt().encode(), 0x70000000);
+    }
+
     // #[test]
     // fn input_test() {
     //     let program: Vec<Data> = vec![0b10110000000000000000000111010111, 0x70000000];
@@ -704,23 +1667,3 @@ mod tests {
     //     );
     // }
 }
-
-
-fn u8x4_to_u32_big_endian(u8s: &[u8]) -> u32{
-    ((u8s[0] as u32) << 24) 
-        + ((u8s[1] as u32) << 16) 
-        + ((u8s[2] as u32) << 8)
-        + (u8s[3] as u32)
-}
-
-pub fn main() {
-    let codex_raw: Vec<u8> = std::fs::read("./codex.umz").unwrap();
-    let codex: Vec<u32> 
-        = codex_raw
-            .chunks(4)
-            .map(|u8s|u8x4_to_u32_big_endian(u8s))
-            .collect::<Vec<u32>>();
-    
-    let mut cpu = CPU::new(codex);
-    cpu.interpret();
-}
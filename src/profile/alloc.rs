@@ -0,0 +1,260 @@
+// Where arrays come from and which ones are never freed:
+//
+//     1523 allocs of 48200 platters, 1400 frees of 40100 platters,
+//     3 program loads of 9000 platters
+//     peak 130 live arrays at instruction 5000
+//     peak 12000 live platters at instruction 5100
+//
+//     allocation sizes
+//           size      allocs  live at halt
+//              1          12             0
+//            2-3         840            20
+//
+//     123 arrays of 8100 platters live at halt
+//          arrays    platters  allocated at
+//             100        6400  12 new+3 (mem.uma:40)
+//
+// Arrays are grouped by the offset of the ALLOC that made them. Array 0 is
+// the program, not an allocation, so it is left out of the live counts;
+// a program load is a CALL that copies another array over it.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+
+use crate::symbols::Symbols;
+use crate::trace::{Access, Event, Observer};
+use crate::{Data, OpCode, CPU};
+
+pub struct Allocations {
+    /// Live arrays to the offset that allocated them and their size
+    live: HashMap<Data, (Data, Data)>,
+    platters: u64,
+    /// Operations and the platters they moved
+    allocs: (u64, u64),
+    frees: (u64, u64),
+    loads: (u64, u64),
+    /// Peak and the instruction count it was first reached at
+    peak_arrays: (usize, u64),
+    peak_platters: (u64, u64),
+    /// Allocations per size bucket
+    sizes: BTreeMap<u32, u64>,
+    /// Allocation sites to list
+    pub top: usize,
+    symbols: Symbols,
+}
+
+/// 0 for empty arrays, otherwise k for sizes 2^(k-1) to 2^k - 1
+fn bucket(size: Data) -> u32 {
+    32 - size.leading_zeros()
+}
+
+fn bucket_name(k: u32) -> String {
+    if k == 0 {
+        return "0".to_string();
+    }
+    let (low, high) = (1u64 << (k - 1), (1u64 << k) - 1);
+    if low == high {
+        low.to_string()
+    } else {
+        format!("{}-{}", low, high)
+    }
+}
+
+impl Allocations {
+    pub fn new(symbols: Symbols) -> Self {
+        Allocations {
+            live: HashMap::new(),
+            platters: 0,
+            allocs: (0, 0),
+            frees: (0, 0),
+            loads: (0, 0),
+            peak_arrays: (0, 0),
+            peak_platters: (0, 0),
+            sizes: BTreeMap::new(),
+            top: 20,
+            symbols,
+        }
+    }
+
+    /// Arrays live at the halt by allocation site: site, arrays and
+    /// platters, most platters first
+    pub fn leaks(&self) -> Vec<(Data, u64, u64)> {
+        let mut sites: HashMap<Data, (u64, u64)> = HashMap::new();
+        for (site, size) in self.live.values() {
+            let entry = sites.entry(*site).or_default();
+            entry.0 += 1;
+            entry.1 += *size as u64;
+        }
+        let mut sites: Vec<_> = sites
+            .into_iter()
+            .map(|(site, (arrays, platters))| (site, arrays, platters))
+            .collect();
+        sites.sort_by(|a, b| b.2.cmp(&a.2).then(b.1.cmp(&a.1)).then(a.0.cmp(&b.0)));
+        sites
+    }
+
+    pub fn report(&self) -> String {
+        let mut lines = vec![
+            format!(
+                "{} allocs of {} platters, {} frees of {} platters,",
+                self.allocs.0, self.allocs.1, self.frees.0, self.frees.1
+            ),
+            format!(
+                "{} program loads of {} platters",
+                self.loads.0, self.loads.1
+            ),
+            format!(
+                "peak {} live arrays at instruction {}",
+                self.peak_arrays.0, self.peak_arrays.1
+            ),
+            format!(
+                "peak {} live platters at instruction {}",
+                self.peak_platters.0, self.peak_platters.1
+            ),
+            String::new(),
+            "allocation sizes".to_string(),
+            format!("{:>15} {:>11}  live at halt", "size", "allocs"),
+        ];
+        let mut live: BTreeMap<u32, u64> = BTreeMap::new();
+        for (_, size) in self.live.values() {
+            *live.entry(bucket(*size)).or_default() += 1;
+        }
+        for (k, allocs) in &self.sizes {
+            lines.push(format!(
+                "{:>15} {:>11} {:>13}",
+                bucket_name(*k),
+                allocs,
+                live.get(k).copied().unwrap_or(0)
+            ));
+        }
+        lines.push(String::new());
+        lines.push(format!(
+            "{} arrays of {} platters live at halt",
+            self.live.len(),
+            self.platters
+        ));
+        let leaks = self.leaks();
+        if !leaks.is_empty() {
+            lines.push(format!("{:>12} {:>11}  allocated at", "arrays", "platters"));
+            for (site, arrays, platters) in leaks.into_iter().take(self.top) {
+                lines.push(format!(
+                    "{:>12} {:>11}  {}",
+                    arrays,
+                    platters,
+                    self.symbols.describe(site)
+                ));
+            }
+        }
+        lines.join("\n")
+    }
+}
+
+impl Observer for Allocations {
+    fn observe(&mut self, event: &Event) {
+        match event.access {
+            Some(Access::Alloc { array, size }) => {
+                self.live.insert(array, (event.finger, size));
+                self.platters += size as u64;
+                self.allocs.0 += 1;
+                self.allocs.1 += size as u64;
+                *self.sizes.entry(bucket(size)).or_default() += 1;
+                if self.live.len() > self.peak_arrays.0 {
+                    self.peak_arrays = (self.live.len(), event.count);
+                }
+                if self.platters > self.peak_platters.0 {
+                    self.peak_platters = (self.platters, event.count);
+                }
+            }
+            Some(Access::Free { array }) => {
+                if let Some((_, size)) = self.live.remove(&array) {
+                    self.platters -= size as u64;
+                    self.frees.0 += 1;
+                    self.frees.1 += size as u64;
+                }
+            }
+            _ => {}
+        }
+        let instruction = event.instruction();
+        if instruction.op_code == OpCode::CALL {
+            let array = event.before[instruction.r_b as usize];
+            if let Some((_, size)) = self.live.get(&array) {
+                self.loads.0 += 1;
+                self.loads.1 += *size as u64;
+            }
+        }
+    }
+
+    fn finish(&mut self, _cpu: &CPU) -> io::Result<()> {
+        eprintln!("{}", self.report());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::step;
+
+    #[test]
+    fn sizes_peaks_and_leaks() {
+        assert_eq!(
+            (0..=5).map(bucket_name).collect::<Vec<_>>(),
+            vec!["0", "1", "2-3", "4-7", "8-15", "16-31"]
+        );
+        assert_eq!(bucket(Data::MAX), 32);
+
+        // Three arrays of 5 from the ALLOC at offset 4, the last freed,
+        // then arrays of 0 and 1, loading the second as the program
+        let program = um_asm! {
+            orth r1, 5;
+            orth r5, 3;
+            orth r4, 0;
+            nand r4, r4, r4;
+            alloc r2, r1;
+            add r5, r5, r4;
+            orth r3, 10;
+            orth r7, 4;
+            cmov r3, r7, r5;
+            call r0, r3;
+            free r2;
+            alloc r6, r0;
+            orth r7, 1;
+            alloc r6, r7;
+            orth r7, 0;
+            call r6, r7
+        };
+        let mut cpu = CPU::new(program);
+        let mut allocations = Allocations::new(Symbols::default());
+        // The new program runs off its end
+        while let Ok(event) = step(&mut cpu) {
+            allocations.observe(&event);
+        }
+        assert_eq!(allocations.allocs, (5, 16));
+        assert_eq!(allocations.frees, (1, 5));
+        assert_eq!(allocations.loads, (1, 1));
+        assert_eq!(allocations.peak_arrays, (4, 25));
+        assert_eq!(allocations.peak_platters, (15, 16));
+
+        assert_eq!(
+            allocations.leaks(),
+            vec![(4, 2, 10), (13, 1, 1), (11, 1, 0)]
+        );
+        let report = allocations.report();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(
+            lines[..4],
+            [
+                "5 allocs of 16 platters, 1 frees of 5 platters,",
+                "1 program loads of 1 platters",
+                "peak 4 live arrays at instruction 25",
+                "peak 15 live platters at instruction 16",
+            ]
+        );
+        assert_eq!(lines[7], "              0           1             1");
+        assert_eq!(lines[8], "              1           1             1");
+        assert_eq!(lines[9], "            4-7           3             2");
+        assert_eq!(lines[11], "4 arrays of 11 platters live at halt");
+        assert_eq!(lines[13], "           2          10  4");
+        assert_eq!(lines.len(), 16);
+    }
+}
@@ -0,0 +1,359 @@
+// Which offsets of the program image ran, and which way each CMOV went.
+// Recording stops when the program loads code from another array, since
+// offsets after that belong to a different program.
+//
+// The coverage file is text: a hash of the image, then one line per offset
+// that ran, with the times a CMOV moved and did not move after its count:
+//
+//     cult coverage 1
+//     image 9c3a5b1e07d24f61
+//     size 12
+//     0 1
+//     6 3 2 1
+//
+// Files of the same image are merged by adding the counts. An annotated disassembly marks
+// offsets that never ran with `#####` and CMOVs that only ever went one
+// way with `!`:
+//
+//          count    offset  word      instruction
+//              3         6  00000119  cmov r4, r3, r1 ; moved 2, kept 1
+//          #####        11  70000000  halt
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use crate::symbols::Symbols;
+use crate::trace::{Event, Observer};
+use crate::{disasm, Data, OpCode, CPU};
+
+const HEADER: &str = "cult coverage 1";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Coverage {
+    /// Hash of the program image, from `image_hash`
+    pub image: Option<u64>,
+    /// Words in the program
+    pub size: Data,
+    /// Offset to times executed
+    hits: BTreeMap<Data, u64>,
+    /// CMOV offset to times it moved and times it did not
+    branches: BTreeMap<Data, (u64, u64)>,
+}
+
+/// FNV-1a over the words of `program`
+pub fn image_hash(program: &[Data]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in program.iter().flat_map(|word| word.to_be_bytes().to_vec()) {
+        hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+impl Coverage {
+    /// Empty coverage of `program`
+    pub fn of(program: &[Data]) -> Coverage {
+        Coverage {
+            image: Some(image_hash(program)),
+            size: program.len() as Data,
+            ..Coverage::default()
+        }
+    }
+
+    pub fn record(&mut self, event: &Event) {
+        *self.hits.entry(event.finger).or_default() += 1;
+        let instruction = event.instruction();
+        if instruction.op_code == OpCode::CMOV {
+            let branch = self.branches.entry(event.finger).or_default();
+            if event.before[instruction.r_c as usize] != 0 {
+                branch.0 += 1;
+            } else {
+                branch.1 += 1;
+            }
+        }
+    }
+
+    /// Add the counts of `other`, which must be of the same image
+    pub fn merge(&mut self, other: &Coverage) -> Result<(), String> {
+        if let (Some(a), Some(b)) = (self.image, other.image) {
+            if a != b {
+                return Err(format!("coverage of image {:016x}, not {:016x}", b, a));
+            }
+        }
+        self.image = self.image.or(other.image);
+        self.size = self.size.max(other.size);
+        for (offset, count) in &other.hits {
+            *self.hits.entry(*offset).or_default() += count;
+        }
+        for (offset, (moved, kept)) in &other.branches {
+            let branch = self.branches.entry(*offset).or_default();
+            branch.0 += moved;
+            branch.1 += kept;
+        }
+        Ok(())
+    }
+
+    pub fn parse(text: &str) -> Result<Coverage, String> {
+        let mut lines = text.lines().enumerate();
+        if lines.next().map(|(_, line)| line) != Some(HEADER) {
+            return Err(format!("not a coverage file (no '{}')", HEADER));
+        }
+        let mut coverage = Coverage::default();
+        for (i, line) in lines {
+            let error = || format!("line {}: bad coverage line '{}'", i + 1, line);
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                ["image", hash] => {
+                    coverage.image = Some(u64::from_str_radix(hash, 16).map_err(|_| error())?);
+                    continue;
+                }
+                ["size", size] => {
+                    coverage.size = size.parse().map_err(|_| error())?;
+                    continue;
+                }
+                _ => {}
+            }
+            let numbers: Vec<u64> = fields
+                .iter()
+                .map(|field| field.parse().map_err(|_| error()))
+                .collect::<Result<_, _>>()?;
+            match numbers.as_slice() {
+                [] => {}
+                [offset, count] => {
+                    coverage.hits.insert(*offset as Data, *count);
+                }
+                [offset, count, moved, kept] => {
+                    coverage.hits.insert(*offset as Data, *count);
+                    coverage.branches.insert(*offset as Data, (*moved, *kept));
+                }
+                _ => return Err(error()),
+            }
+        }
+        Ok(coverage)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut lines = vec![HEADER.to_string()];
+        if let Some(image) = self.image {
+            lines.push(format!("image {:016x}", image));
+        }
+        lines.push(format!("size {}", self.size));
+        for (offset, count) in &self.hits {
+            lines.push(match self.branches.get(offset) {
+                Some((moved, kept)) => format!("{} {} {} {}", offset, count, moved, kept),
+                None => format!("{} {}", offset, count),
+            });
+        }
+        let mut text = lines.join("\n");
+        text.push('\n');
+        text
+    }
+
+    pub fn summary(&self) -> String {
+        let covered = self
+            .hits
+            .keys()
+            .filter(|&&offset| offset < self.size)
+            .count();
+        let both = self
+            .branches
+            .values()
+            .filter(|(moved, kept)| *moved > 0 && *kept > 0)
+            .count();
+        format!(
+            "{} of {} offsets covered ({:.1}%), {} of {} cmovs went both ways",
+            covered,
+            self.size,
+            100.0 * covered as f64 / self.size.max(1) as f64,
+            both,
+            self.branches.len()
+        )
+    }
+
+    /// `program` disassembled with the counts
+    pub fn annotate(&self, program: &[Data], symbols: &Symbols) -> String {
+        let mut lines = vec![format!(
+            "{:>10} {:>9}  {:<8}  instruction",
+            "count", "offset", "word"
+        )];
+        for (offset, word) in program.iter().enumerate() {
+            let offset = offset as Data;
+            let count = match self.hits.get(&offset) {
+                Some(count) => count.to_string(),
+                None => "#####".to_string(),
+            };
+            let mut notes = Vec::new();
+            if let Some((moved, kept)) = self.branches.get(&offset) {
+                let one_way = if *moved == 0 || *kept == 0 { "!" } else { "" };
+                notes.push(format!("{}moved {}, kept {}", one_way, moved, kept));
+            }
+            if let Some(at) = symbols.locate(offset) {
+                notes.push(at);
+            }
+            let mut line = format!(
+                "{:>10} {:>9}  {:08x}  {}",
+                count,
+                offset,
+                word,
+                disasm::text(*word)
+            );
+            if !notes.is_empty() {
+                line = format!("{} ; {}", line, notes.join(", "));
+            }
+            lines.push(line);
+        }
+        lines.join("\n")
+    }
+}
+
+/// Records coverage of the program a run starts with and writes it out when
+/// the machine stops
+pub struct Recorder {
+    pub coverage: Coverage,
+    /// Cleared once code is loaded from another array
+    recording: bool,
+    output: Box<dyn Write>,
+}
+
+impl Recorder {
+    pub fn new(output: Box<dyn Write>) -> Self {
+        Recorder {
+            coverage: Coverage::default(),
+            recording: true,
+            output,
+        }
+    }
+}
+
+impl Observer for Recorder {
+    fn start(&mut self, cpu: &CPU) {
+        self.coverage = Coverage::of(cpu.array(0).unwrap_or(&[]));
+    }
+
+    fn observe(&mut self, event: &Event) {
+        if !self.recording {
+            return;
+        }
+        self.coverage.record(event);
+        let instruction = event.instruction();
+        if instruction.op_code == OpCode::CALL && event.before[instruction.r_b as usize] != 0 {
+            self.recording = false;
+        }
+    }
+
+    fn wants(&self, _count: u64) -> bool {
+        self.recording
+    }
+
+    fn finish(&mut self, _cpu: &CPU) -> io::Result<()> {
+        eprintln!("{}", self.coverage.summary());
+        self.output.write_all(self.coverage.to_text().as_bytes())?;
+        self.output.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::step;
+
+    #[test]
+    fn recording_stops_at_a_code_load() {
+        // Load a one-word program, halt, from array 1
+        let program = crate::asm::assemble(
+            "       orth r1, 1
+                    alloc r2, r1
+                    li r3, 0x70000000
+                    store r2, r0, r3
+                    call r2, r0",
+        )
+        .unwrap();
+        let mut recorder = Recorder::new(Box::new(io::sink()));
+        let mut cpu = CPU::new(program.clone());
+        recorder.start(&cpu);
+        while cpu.status {
+            let event = step(&mut cpu).unwrap();
+            if recorder.wants(event.count) {
+                recorder.observe(&event);
+            }
+        }
+        let hits: u64 = recorder.coverage.hits.values().sum();
+        assert_eq!(hits, cpu.instruction_count - 1);
+        assert!(recorder.coverage.hits.values().all(|count| *count == 1));
+        assert_eq!(recorder.coverage.size, program.len() as Data);
+    }
+
+    #[test]
+    fn records_merges_and_annotates() {
+        // Run offsets 4 to 7 twice, the cmov at 6 moving once, and never
+        // reach offset 9
+        let program = um_asm! {
+            orth r1, 2;
+            orth r2, 0;
+            nand r2, r2, r2;
+            orth r3, 4;
+            add r1, r1, r2;
+            orth r4, 8;
+            cmov r4, r3, r1;
+            call r0, r4;
+            halt;
+            orth r5, 1
+        };
+        let mut cpu = CPU::new(program.clone());
+        let mut coverage = Coverage::default();
+        while cpu.status {
+            coverage.record(&step(&mut cpu).unwrap());
+        }
+        coverage.size = program.len() as Data;
+        assert_eq!(coverage.hits[&0], 1);
+        assert_eq!(coverage.hits[&4], 2);
+        assert_eq!(coverage.hits.get(&9), None);
+        assert_eq!(coverage.branches[&6], (1, 1));
+
+        let text = coverage.to_text();
+        assert!(
+            text.starts_with("cult coverage 1\nsize 10\n0 1\n"),
+            "{}",
+            text
+        );
+        assert!(text.contains("\n6 2 1 1\n"), "{}", text);
+        let parsed = Coverage::parse(&text).unwrap();
+        assert_eq!(parsed, coverage);
+        assert_eq!(
+            coverage.summary(),
+            "9 of 10 offsets covered (90.0%), 1 of 1 cmovs went both ways"
+        );
+
+        let mut partial = Coverage::parse("cult coverage 1\nsize 12\n0 1\n6 1 1 0\n").unwrap();
+        assert_eq!(
+            partial.summary(),
+            "2 of 12 offsets covered (16.7%), 0 of 1 cmovs went both ways"
+        );
+        let listing = partial.annotate(&program, &Symbols::default());
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines[0], "     count    offset  word      instruction");
+        assert_eq!(lines[1], "         1         0  d2000002  orth r1, 0x2");
+        assert!(lines[2].starts_with("     #####         1  "));
+        assert!(lines[7].ends_with(" ; !moved 1, kept 0"), "{}", lines[7]);
+
+        partial.merge(&coverage).unwrap();
+        assert_eq!(partial.size, 12);
+        assert_eq!(partial.hits[&0], 2);
+        assert_eq!(partial.branches[&6], (2, 1));
+
+        let mut other = Coverage::of(&program);
+        let text = other.to_text();
+        assert!(text.starts_with(&format!(
+            "cult coverage 1\nimage {:016x}\nsize 10\n",
+            image_hash(&program)
+        )));
+        assert_eq!(Coverage::parse(&text), Ok(other.clone()));
+        other.merge(&coverage).unwrap();
+        assert!(other.merge(&Coverage::of(&program[1..])).is_err());
+
+        assert!(Coverage::parse("0 1\n").is_err());
+        assert_eq!(
+            Coverage::parse("cult coverage 1\n0 x\n").unwrap_err(),
+            "line 2: bad coverage line '0 x'"
+        );
+    }
+}
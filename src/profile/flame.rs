@@ -0,0 +1,209 @@
+// Folded stacks for flame graph tools, one line per stack with the
+// instructions executed in it:
+//
+//     [array 0];main;print_udec 1204
+//     [array 0];main;print_udec;divide 5120
+//
+// The machine has no call instruction, so calls are guessed from jumps: a
+// CALL of array 0 is a call if the offset after it is in a register or was
+// stored since the last CALL, as `jsr` leaves it on the stack; a jump to
+// the return offset of a frame on the stack returns from it and from every
+// frame above. Other jumps stay in the current frame. Loading a program
+// from another array starts a new stack, `[array N]`. Frames are named by
+// label when there are symbols, otherwise by offset. Calls nested deeper
+// than MAX_DEPTH are counted together under a `[truncated]` frame.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::symbols::Symbols;
+use crate::trace::{Access, Event, Observer};
+use crate::{Data, OpCode, CPU};
+
+const MAX_DEPTH: usize = 256;
+const TRUNCATED: &str = "[truncated]";
+
+struct Frame {
+    name: String,
+    /// The offset a return jumps to
+    ret: Data,
+}
+
+pub struct Flame {
+    stack: Vec<Frame>,
+    root: String,
+    /// Every stack seen, as folded text, with its count
+    paths: Vec<(String, u64)>,
+    ids: HashMap<String, usize>,
+    /// The id of the current stack, which only changes at a CALL
+    path: usize,
+    /// Values stored since the last CALL
+    stored: Vec<Data>,
+    symbols: Symbols,
+    output: Box<dyn Write>,
+}
+
+impl Flame {
+    pub fn new(symbols: Symbols, output: Box<dyn Write>) -> Self {
+        let root = "[array 0]".to_string();
+        Flame {
+            stack: Vec::new(),
+            paths: vec![(root.clone(), 0)],
+            ids: vec![(root.clone(), 0)].into_iter().collect(),
+            path: 0,
+            root,
+            stored: Vec::new(),
+            symbols,
+            output,
+        }
+    }
+
+    /// The id of the current stack, interning it if it is new
+    fn intern(&mut self) -> usize {
+        let mut path = self.root.clone();
+        for frame in &self.stack {
+            path.push(';');
+            path.push_str(&frame.name);
+        }
+        let paths = &mut self.paths;
+        *self.ids.entry(path).or_insert_with_key(|path| {
+            paths.push((path.clone(), 0));
+            paths.len() - 1
+        })
+    }
+
+    fn name(&self, offset: Data) -> String {
+        match self.symbols.label(offset) {
+            Some((name, 0)) => name.to_string(),
+            Some((name, distance)) => format!("{}+{}", name, distance),
+            None => offset.to_string(),
+        }
+    }
+
+    /// The folded stacks, sorted
+    pub fn folded(&self) -> String {
+        let mut lines: Vec<String> = self
+            .paths
+            .iter()
+            .filter(|(_, count)| *count > 0)
+            .map(|(path, count)| format!("{} {}", path, count))
+            .collect();
+        lines.sort();
+        lines.join("\n")
+    }
+}
+
+impl Observer for Flame {
+    fn observe(&mut self, event: &Event) {
+        self.paths[self.path].1 += 1;
+        if let Some(Access::Store { value, .. }) = event.access {
+            self.stored.push(value);
+        }
+        let instruction = event.instruction();
+        if instruction.op_code != OpCode::CALL {
+            return;
+        }
+        let array = event.before[instruction.r_b as usize];
+        let target = event.before[instruction.r_c as usize];
+        let next = event.finger.wrapping_add(1);
+        if array != 0 {
+            self.stack.clear();
+            self.root = format!("[array {}]", array);
+        } else if event.before.contains(&next) || self.stored.contains(&next) {
+            // Past the limit, calls stay in the one truncated frame
+            if self.stack.len() < MAX_DEPTH {
+                let name = self.name(target);
+                self.stack.push(Frame { name, ret: next });
+            } else if self.stack.len() == MAX_DEPTH {
+                let name = TRUNCATED.to_string();
+                self.stack.push(Frame { name, ret: next });
+            }
+        } else if let Some(i) = self.stack.iter().rposition(|frame| frame.ret == target) {
+            self.stack.truncate(i);
+        }
+        self.stored.clear();
+        self.path = self.intern();
+    }
+
+    fn finish(&mut self, _cpu: &CPU) -> io::Result<()> {
+        let mut text = self.folded();
+        text.push('\n');
+        self.output.write_all(text.as_bytes())?;
+        self.output.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::trace::step;
+
+    #[test]
+    fn folds_subroutine_calls() {
+        let source = "
+                .stack r5
+                orth r0, 8
+                alloc r5, r0
+                jsr double
+                orth r2, double
+                jsr r2
+                halt
+        double: add r1, r1, r1
+                jsr increment
+                ret
+        increment:
+                orth r3, 1
+                add r1, r1, r3
+                ret";
+        let assembly = asm::build(source).unwrap();
+        let mut cpu = CPU::new(assembly.image().unwrap());
+        let mut flame = Flame::new(Symbols::from(&assembly), Box::new(io::sink()));
+        let mut total = 0;
+        while cpu.status {
+            flame.observe(&step(&mut cpu).unwrap());
+            total += 1;
+        }
+        let folded = flame.folded();
+        let lines: Vec<(&str, u64)> = folded
+            .lines()
+            .map(|line| {
+                let (path, count) = line.rsplit_once(' ').unwrap();
+                (path, count.parse().unwrap())
+            })
+            .collect();
+        let paths: Vec<&str> = lines.iter().map(|(path, _)| *path).collect();
+        assert_eq!(
+            paths,
+            [
+                "[array 0]",
+                "[array 0];double",
+                "[array 0];double;increment"
+            ]
+        );
+        assert_eq!(lines.iter().map(|(_, count)| count).sum::<u64>(), total);
+        // orth, add and the 11 words of ret, twice
+        assert_eq!(lines[2].1, 2 * 13);
+    }
+
+    #[test]
+    fn deep_recursion_is_truncated() {
+        // Recurse, each call a new frame, until the stack array runs out
+        let source = "
+                .stack r5
+                li r0, 4096
+                alloc r5, r0
+        down:   jsr down";
+        let assembly = asm::build(source).unwrap();
+        let mut cpu = CPU::new(assembly.image().unwrap());
+        let mut flame = Flame::new(Symbols::from(&assembly), Box::new(io::sink()));
+        while let Ok(event) = step(&mut cpu) {
+            flame.observe(&event);
+        }
+        assert_eq!(flame.stack.len(), MAX_DEPTH + 1);
+        let folded = flame.folded();
+        let deepest = folded.lines().last().unwrap();
+        assert_eq!(deepest.matches(";down").count(), MAX_DEPTH);
+        assert!(deepest.contains(";down;[truncated] "), "{}", deepest);
+    }
+}
@@ -0,0 +1,283 @@
+// Load and store traffic per array, from its ALLOC to its FREE, and per
+// offset of the instruction that issued it:
+//
+//     12 arrays, 4056 loads, 1210 stores
+//
+//     busiest arrays
+//        array      size   allocated       freed    lifetime       loads      stores
+//            1      1024           3           -        5291        3010        1002
+//
+//     busiest offsets
+//            loads      stores  offset
+//             1000           0  145 loop+2 (h.uma:9)
+//
+// An array id may be reused once freed, so each ALLOC starts a new row. Array 0
+// is the program, live from the start; arrays still live at the halt have no
+// FREE and a lifetime up to it.
+//
+// The CSV form has a row per array lifetime and per offset:
+//
+//     kind,id,size,allocated,freed,lifetime,loads,stores
+//     array,1,1024,3,,5291,3010,1002
+//     offset,145,,,,,1000,0
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::symbols::Symbols;
+use crate::trace::{Access, Event, Observer};
+use crate::{Data, CPU};
+
+#[derive(Debug, Clone, PartialEq)]
+struct Lifetime {
+    array: Data,
+    size: Data,
+    allocated: u64,
+    freed: Option<u64>,
+    loads: u64,
+    stores: u64,
+}
+
+pub struct Memory {
+    lifetimes: Vec<Lifetime>,
+    /// Live array ids to their index in `lifetimes`
+    live: HashMap<Data, usize>,
+    /// Issuing offset to its loads and stores
+    offsets: HashMap<Data, (u64, u64)>,
+    /// Instructions executed
+    end: u64,
+    /// Arrays and offsets to list in the summary
+    pub top: usize,
+    /// Print the summary to stderr when the machine stops
+    pub summary: bool,
+    pub csv: Option<Box<dyn Write>>,
+    symbols: Symbols,
+}
+
+impl Memory {
+    pub fn new(symbols: Symbols) -> Self {
+        let program = Lifetime {
+            array: 0,
+            size: 0,
+            allocated: 0,
+            freed: None,
+            loads: 0,
+            stores: 0,
+        };
+        Memory {
+            lifetimes: vec![program],
+            live: vec![(0, 0)].into_iter().collect(),
+            offsets: HashMap::new(),
+            end: 0,
+            top: 20,
+            summary: true,
+            csv: None,
+            symbols,
+        }
+    }
+
+    fn lifetime(&mut self, array: Data) -> &mut Lifetime {
+        let i = self.live[&array];
+        &mut self.lifetimes[i]
+    }
+
+    fn span(&self, lifetime: &Lifetime) -> u64 {
+        lifetime.freed.unwrap_or(self.end) - lifetime.allocated
+    }
+
+    pub fn report(&self) -> String {
+        let loads: u64 = self.lifetimes.iter().map(|l| l.loads).sum();
+        let stores: u64 = self.lifetimes.iter().map(|l| l.stores).sum();
+        let mut lines = vec![
+            format!(
+                "{} arrays, {} loads, {} stores",
+                self.lifetimes.len(),
+                loads,
+                stores
+            ),
+            String::new(),
+            "busiest arrays".to_string(),
+            format!(
+                "{:>8} {:>9} {:>11} {:>11} {:>11} {:>11} {:>11}",
+                "array", "size", "allocated", "freed", "lifetime", "loads", "stores"
+            ),
+        ];
+        let mut lifetimes: Vec<&Lifetime> = self.lifetimes.iter().collect();
+        // Stable, so ties stay in allocation order
+        lifetimes.sort_by_key(|l| std::cmp::Reverse(l.loads + l.stores));
+        for lifetime in lifetimes.into_iter().take(self.top) {
+            let freed = match lifetime.freed {
+                Some(count) => count.to_string(),
+                None => "-".to_string(),
+            };
+            lines.push(format!(
+                "{:>8} {:>9} {:>11} {:>11} {:>11} {:>11} {:>11}",
+                lifetime.array,
+                lifetime.size,
+                lifetime.allocated,
+                freed,
+                self.span(lifetime),
+                lifetime.loads,
+                lifetime.stores
+            ));
+        }
+        lines.push(String::new());
+        lines.push("busiest offsets".to_string());
+        lines.push(format!("{:>12} {:>11}  offset", "loads", "stores"));
+        let mut offsets: Vec<(Data, u64, u64)> = self
+            .offsets
+            .iter()
+            .map(|(offset, (loads, stores))| (*offset, *loads, *stores))
+            .collect();
+        offsets.sort_by(|a, b| (b.1 + b.2).cmp(&(a.1 + a.2)).then(a.0.cmp(&b.0)));
+        for (offset, loads, stores) in offsets.into_iter().take(self.top) {
+            lines.push(format!(
+                "{:>12} {:>11}  {}",
+                loads,
+                stores,
+                self.symbols.describe(offset)
+            ));
+        }
+        lines.join("\n")
+    }
+
+    pub fn write_csv(&self, output: &mut dyn Write) -> io::Result<()> {
+        writeln!(output, "kind,id,size,allocated,freed,lifetime,loads,stores")?;
+        for lifetime in &self.lifetimes {
+            let freed = lifetime.freed.map(|c| c.to_string()).unwrap_or_default();
+            writeln!(
+                output,
+                "array,{},{},{},{},{},{},{}",
+                lifetime.array,
+                lifetime.size,
+                lifetime.allocated,
+                freed,
+                self.span(lifetime),
+                lifetime.loads,
+                lifetime.stores
+            )?;
+        }
+        let mut offsets: Vec<_> = self.offsets.iter().collect();
+        offsets.sort();
+        for (offset, (loads, stores)) in offsets {
+            writeln!(output, "offset,{},,,,,{},{}", offset, loads, stores)?;
+        }
+        Ok(())
+    }
+}
+
+impl Observer for Memory {
+    fn observe(&mut self, event: &Event) {
+        self.end = event.count + 1;
+        match event.access {
+            Some(Access::Load { array, .. }) => {
+                self.lifetime(array).loads += 1;
+                self.offsets.entry(event.finger).or_default().0 += 1;
+            }
+            Some(Access::Store { array, .. }) => {
+                self.lifetime(array).stores += 1;
+                self.offsets.entry(event.finger).or_default().1 += 1;
+            }
+            Some(Access::Alloc { array, size }) => {
+                self.live.insert(array, self.lifetimes.len());
+                self.lifetimes.push(Lifetime {
+                    array,
+                    size,
+                    allocated: event.count,
+                    freed: None,
+                    loads: 0,
+                    stores: 0,
+                });
+            }
+            Some(Access::Free { array }) => {
+                self.lifetime(array).freed = Some(event.count);
+                self.live.remove(&array);
+            }
+            None => {}
+        }
+    }
+
+    fn finish(&mut self, cpu: &CPU) -> io::Result<()> {
+        // The program may have been replaced since, so size it last
+        if let Some(program) = cpu.array(0) {
+            self.lifetime(0).size = program.len() as Data;
+        }
+        if self.summary {
+            eprintln!("{}", self.report());
+        }
+        if let Some(mut output) = self.csv.take() {
+            let mut text = Vec::new();
+            self.write_csv(&mut text)?;
+            output.write_all(&text)?;
+            output.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::step;
+
+    #[test]
+    fn counts_per_array_and_offset() {
+        let program = um_asm! {
+            orth r1, 4;
+            alloc r2, r1;
+            orth r3, 7;
+            store r2, r0, r3;
+            load r4, r2, r0;
+            load r5, r0, r0;
+            free r2;
+            alloc r2, r1;
+            load r4, r2, r0;
+            halt
+        };
+        let mut cpu = CPU::new(program);
+        let mut memory = Memory::new(Symbols::default());
+        memory.summary = false;
+        while cpu.status {
+            memory.observe(&step(&mut cpu).unwrap());
+        }
+        memory.finish(&cpu).unwrap();
+        let arrays: Vec<_> = memory
+            .lifetimes
+            .iter()
+            .map(|l| (l.array, l.size, l.allocated, l.freed, l.loads, l.stores))
+            .collect();
+        assert_eq!(
+            arrays,
+            vec![
+                (0, 10, 0, None, 1, 0),
+                (1, 4, 1, Some(6), 1, 1),
+                (2, 4, 7, None, 1, 0),
+            ]
+        );
+        assert_eq!(memory.offsets[&3], (0, 1));
+        assert_eq!(memory.offsets[&5], (1, 0));
+
+        let mut csv = Vec::new();
+        memory.write_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            lines[0],
+            "kind,id,size,allocated,freed,lifetime,loads,stores"
+        );
+        assert_eq!(lines[1], "array,0,10,0,,10,1,0");
+        assert_eq!(lines[2], "array,1,4,1,6,5,1,1");
+        assert_eq!(lines[3], "array,2,4,7,,3,1,0");
+        assert_eq!(lines[4], "offset,3,,,,,0,1");
+        assert_eq!(lines.len(), 1 + 3 + 4);
+
+        memory.top = 2;
+        let report = memory.report();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "3 arrays, 3 loads, 1 stores");
+        assert!(lines[4].starts_with("       1         4           1           6"));
+        assert!(lines[5].starts_with("       0        10           0           -"));
+        assert_eq!(lines.len(), 4 + 2 + 3 + 2);
+        assert_eq!(lines[9], "           0           1  3");
+    }
+}
@@ -0,0 +1,261 @@
+// ---------- SYMBOLS ---------------------------------------------------------
+//
+// Names for offsets of array 0, written by `cult asm -s` as JSON:
+//
+//     {"symbols":1,
+//      "files":["main.uma","lib/print.uma"],
+//      "labels":{"loop":4,"print":20},
+//      "lines":[[0,1,0,3],[1,2,0,4], ...]}
+//
+// Each entry of "lines" is [offset, length, file, line]: the words a source
+// line assembled to. Offsets are described as the nearest label at or before
+// them plus the distance, and the line they came from: `loop+3 (main.uma:42)`.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+use crate::asm::Assembly;
+use crate::json::Json;
+use crate::Data;
+
+const VERSION: u64 = 1;
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Symbols {
+    pub files: Vec<String>,
+    pub labels: BTreeMap<String, Data>,
+    /// Keyed by the first offset of each line: (length, file, line)
+    pub lines: BTreeMap<Data, (Data, usize, usize)>,
+    // The label chosen for each offset that has one
+    names: BTreeMap<Data, String>,
+}
+
+impl From<&Assembly> for Symbols {
+    fn from(assembly: &Assembly) -> Symbols {
+        let lines = assembly
+            .placements
+            .iter()
+            .filter(|p| p.length > 0)
+            .map(|p| (p.offset, (p.length, p.span.file, p.span.line)))
+            .collect();
+        Symbols::new(assembly.files.clone(), assembly.symbols.clone(), lines)
+    }
+}
+
+impl Symbols {
+    pub fn new(
+        files: Vec<String>,
+        labels: BTreeMap<String, Data>,
+        lines: BTreeMap<Data, (Data, usize, usize)>,
+    ) -> Symbols {
+        let mut names = BTreeMap::new();
+        for (name, offset) in &labels {
+            names.entry(*offset).or_insert_with(|| name.clone());
+        }
+        Symbols {
+            files,
+            labels,
+            lines,
+            names,
+        }
+    }
+
+    /// The nearest label at or before `offset`, and the distance from it
+    pub fn label(&self, offset: Data) -> Option<(&str, Data)> {
+        let (start, name) = self.names.range(..=offset).next_back()?;
+        Some((name, offset - start))
+    }
+
+    /// The file and line that produced the word at `offset`
+    pub fn line(&self, offset: Data) -> Option<(&str, usize)> {
+        let (start, (length, file, line)) = self.lines.range(..=offset).next_back()?;
+        if offset - start >= *length {
+            return None;
+        }
+        Some((self.files.get(*file)?, *line))
+    }
+
+    /// `loop+3 (main.uma:42)`, or as much of it as is known
+    pub fn locate(&self, offset: Data) -> Option<String> {
+        let label = self.label(offset).map(|(name, distance)| match distance {
+            0 => name.to_string(),
+            _ => format!("{}+{}", name, distance),
+        });
+        let line = self
+            .line(offset)
+            .map(|(file, line)| format!("({}:{})", file, line));
+        match (label, line) {
+            (Some(label), Some(line)) => Some(format!("{} {}", label, line)),
+            (label, line) => label.or(line),
+        }
+    }
+
+    /// An offset followed by its location, if there is one
+    pub fn describe(&self, offset: Data) -> String {
+        match self.locate(offset) {
+            Some(location) => format!("{} {}", offset, location),
+            None => offset.to_string(),
+        }
+    }
+
+    /// The offset named by `LABEL`, `LABEL+N` or `FILE:LINE`. A line that
+    ///  produced no words stands for the next one in the file that did.
+    pub fn resolve(&self, text: &str) -> Option<Data> {
+        if let Some((file, line)) = text.rsplit_once(':') {
+            let line: usize = line.parse().ok()?;
+            let matches = |name: &str| name == file || name.ends_with(&format!("/{}", file));
+            return self
+                .lines
+                .iter()
+                .filter(|(_, (_, f, l))| {
+                    *l >= line && self.files.get(*f).is_some_and(|n| matches(n))
+                })
+                .min_by_key(|(offset, (_, _, l))| (*l, **offset))
+                .map(|(offset, _)| *offset);
+        }
+        let (name, distance) = match text.split_once('+') {
+            Some((name, distance)) => (name.trim(), distance.trim().parse().ok()?),
+            None => (text, 0),
+        };
+        self.labels.get(name)?.checked_add(distance)
+    }
+
+    pub fn to_json(&self) -> Json {
+        let files: Vec<Json> = self.files.iter().map(|f| f.as_str().into()).collect();
+        let labels = self
+            .labels
+            .iter()
+            .map(|(name, offset)| (name.clone(), (*offset).into()))
+            .collect();
+        Json::object(vec![
+            ("symbols", VERSION.into()),
+            ("files", files.into()),
+            ("labels", Json::Object(labels)),
+            ("lines", lines_to_json(&self.lines)),
+        ])
+    }
+
+    pub fn from_json(json: &Json) -> Result<Symbols, String> {
+        if json.get("symbols").and_then(Json::as_u64) != Some(VERSION) {
+            return Err("not a version 1 symbol file".to_string());
+        }
+        let bad = |what: &str| format!("malformed {}", what);
+        let data = |value: &Json| value.as_u64().and_then(|v| Data::try_from(v).ok());
+        let files = json
+            .get("files")
+            .and_then(Json::as_array)
+            .and_then(|files| {
+                files
+                    .iter()
+                    .map(|f| f.as_str().map(String::from))
+                    .collect::<Option<Vec<_>>>()
+            })
+            .ok_or_else(|| bad("files"))?;
+        let labels = match json.get("labels") {
+            Some(Json::Object(map)) => map
+                .iter()
+                .map(|(name, offset)| data(offset).map(|o| (name.clone(), o)))
+                .collect::<Option<_>>()
+                .ok_or_else(|| bad("labels"))?,
+            _ => return Err(bad("labels")),
+        };
+        let lines = json
+            .get("lines")
+            .and_then(|lines| lines_from_json(lines, files.len()))
+            .ok_or_else(|| bad("lines"))?;
+        Ok(Symbols::new(files, labels, lines))
+    }
+
+    pub fn load(path: &str) -> Result<Symbols, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        Symbols::from_json(&crate::json::parse(&text)?)
+    }
+}
+
+/// The "lines" entries, shared with object files
+pub fn lines_to_json(lines: &BTreeMap<Data, (Data, usize, usize)>) -> Json {
+    lines
+        .iter()
+        .map(|(offset, (length, file, line))| {
+            Json::from(vec![
+                (*offset).into(),
+                (*length).into(),
+                (*file).into(),
+                (*line).into(),
+            ])
+        })
+        .collect::<Vec<_>>()
+        .into()
+}
+
+/// Parse "lines" entries whose file indices are below `files`
+pub fn lines_from_json(json: &Json, files: usize) -> Option<BTreeMap<Data, (Data, usize, usize)>> {
+    let data = |value: &Json| value.as_u64().and_then(|v| Data::try_from(v).ok());
+    json.as_array()?
+        .iter()
+        .map(|entry| match entry.as_array()? {
+            [offset, length, file, line] => {
+                let file = file.as_u64()? as usize;
+                if file >= files {
+                    return None;
+                }
+                let line = line.as_u64()? as usize;
+                Some((data(offset)?, (data(length)?, file, line)))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm, json};
+
+    fn symbols() -> Symbols {
+        let source = "start:  orth r1, 1\n\n\
+                      loop:   mov r2, r1\n\
+                      \x20       add r1, r1, r2\n\
+                      table:  .zero 3\n\
+                      end:";
+        Symbols::from(&asm::build_with("main.uma", source, &asm::read_file).unwrap())
+    }
+
+    #[test]
+    fn locations() {
+        let symbols = symbols();
+        assert_eq!(symbols.describe(0), "0 start (main.uma:1)");
+        assert_eq!(symbols.describe(2), "2 loop+1 (main.uma:3)");
+        assert_eq!(symbols.describe(6), "6 table+2 (main.uma:5)");
+        assert_eq!(symbols.describe(7), "7 end");
+        assert_eq!(Symbols::default().describe(7), "7");
+    }
+
+    #[test]
+    fn resolving() {
+        let symbols = symbols();
+        assert_eq!(symbols.resolve("loop"), Some(1));
+        assert_eq!(symbols.resolve("table + 1"), Some(5));
+        assert_eq!(symbols.resolve("main.uma:4"), Some(3));
+        assert_eq!(symbols.resolve("src/main.uma:4"), None);
+        // Line 2 is blank, so it means line 3
+        assert_eq!(symbols.resolve("main.uma:2"), Some(1));
+        assert_eq!(symbols.resolve("main.uma:9"), None);
+        assert_eq!(symbols.resolve("nowhere"), None);
+    }
+
+    #[test]
+    fn round_trip() {
+        let symbols = symbols();
+        let text = symbols.to_json().to_string();
+        assert_eq!(
+            Symbols::from_json(&json::parse(&text).unwrap()),
+            Ok(symbols)
+        );
+        let bad = r#"{"symbols":1,"files":[],"labels":{},"lines":[[0,1,0,1]]}"#;
+        assert_eq!(
+            Symbols::from_json(&json::parse(bad).unwrap()),
+            Err("malformed lines".to_string())
+        );
+    }
+}
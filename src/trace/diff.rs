@@ -0,0 +1,196 @@
+// The first point where two traces part ways, with the instructions leading
+// up to it from each:
+//
+//     first divergence at event 2 (instruction 2 in a.trace, 2 in b.trace)
+//       r1 after: 0x2 vs 0x3
+//     a.trace
+//              1  1 start+1 (m.uma:2)      orth r2, 0x1     ...
+//     >        2  2 start+2 (m.uma:3)      add r1, r1, r2   ...
+//     b.trace
+//     ...
+//
+// Events are compared in order, so both traces should be made with the same
+// filters. Two events differ if their finger, word, registers or memory
+// access do.
+
+use super::Event;
+use crate::symbols::Symbols;
+
+/// The index of the first event that differs, or where the shorter trace
+/// ends; None if they are the same
+pub fn divergence(a: &[Event], b: &[Event]) -> Option<usize> {
+    match a.iter().zip(b).position(|(a, b)| !same(a, b)) {
+        Some(i) => Some(i),
+        None if a.len() != b.len() => Some(a.len().min(b.len())),
+        None => None,
+    }
+}
+
+/// Equal apart from their counts, which differ when the traces start at
+/// different points
+fn same(a: &Event, b: &Event) -> bool {
+    a.finger == b.finger
+        && a.word == b.word
+        && a.before == b.before
+        && a.after == b.after
+        && a.access == b.access
+}
+
+/// How `a` and `b` differ, one line each
+pub fn differences(a: &Event, b: &Event, symbols: &Symbols) -> Vec<String> {
+    let mut lines = Vec::new();
+    if a.finger != b.finger {
+        lines.push(format!(
+            "finger: {} vs {}",
+            symbols.describe(a.finger),
+            symbols.describe(b.finger)
+        ));
+    }
+    if a.word != b.word {
+        lines.push(format!(
+            "instruction: {} vs {}",
+            a.instruction(),
+            b.instruction()
+        ));
+    }
+    for (when, x, y) in [
+        ("before", &a.before, &b.before),
+        ("after", &a.after, &b.after),
+    ]
+    .iter()
+    {
+        for r in 0..8 {
+            if x[r] != y[r] {
+                lines.push(format!("r{} {}: 0x{:x} vs 0x{:x}", r, when, x[r], y[r]));
+            }
+        }
+    }
+    if a.access != b.access {
+        lines.push(format!("access: {:?} vs {:?}", a.access, b.access));
+    }
+    lines
+}
+
+/// One event as a line of context
+pub fn line(event: &Event, symbols: &Symbols) -> String {
+    let registers: Vec<String> = event.after.iter().map(|r| format!("{:x}", r)).collect();
+    format!(
+        "{:>10}  {:<28} {:<20} {}",
+        event.count,
+        symbols.describe(event.finger),
+        event.instruction().to_string(),
+        registers.join(" ")
+    )
+}
+
+/// A report of the first divergence between traces `a` and `b`, with up
+/// to `context` events before it, or None if they are the same
+pub fn report(
+    names: (&str, &str),
+    a: &[Event],
+    b: &[Event],
+    symbols: &Symbols,
+    context: usize,
+) -> Option<String> {
+    let i = divergence(a, b)?;
+    let count = |events: &[Event]| match events.get(i) {
+        Some(event) => event.count.to_string(),
+        None => "the end".to_string(),
+    };
+    let mut lines = vec![format!(
+        "first divergence at event {} (instruction {} in {}, {} in {})",
+        i,
+        count(a),
+        names.0,
+        count(b),
+        names.1
+    )];
+    match (a.get(i), b.get(i)) {
+        (Some(x), Some(y)) => {
+            for difference in differences(x, y, symbols) {
+                lines.push(format!("  {}", difference));
+            }
+        }
+        (Some(_), None) => lines.push(format!("  {} ends after {} events", names.1, i)),
+        _ => lines.push(format!("  {} ends after {} events", names.0, i)),
+    }
+    for (name, events) in [(names.0, a), (names.1, b)].iter() {
+        lines.push(name.to_string());
+        let start = i.saturating_sub(context);
+        for (j, event) in events.iter().enumerate().take(i + 1).skip(start) {
+            let marker = if j == i { ">" } else { " " };
+            lines.push(format!("{}{}", marker, line(event, symbols)));
+        }
+    }
+    Some(lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::step;
+    use crate::{Data, CPU};
+
+    fn events(program: Vec<Data>) -> Vec<Event> {
+        let mut cpu = CPU::new(program);
+        let mut events = Vec::new();
+        while cpu.status {
+            events.push(step(&mut cpu).unwrap());
+        }
+        events
+    }
+
+    #[test]
+    fn first_divergence() {
+        let a = events(um_asm! { orth r1, 1; orth r2, 1; add r1, r1, r2; orth r3, 9; halt });
+        let b = events(um_asm! { orth r1, 2; orth r2, 1; add r1, r1, r2; orth r3, 9; halt });
+        let c = events(um_asm! { orth r1, 1; orth r2, 1; add r1, r1, r2; halt });
+        assert_eq!(divergence(&a, &a), None);
+        assert_eq!(divergence(&a, &b), Some(0));
+        assert_eq!(divergence(&a, &c), Some(3));
+        assert_eq!(divergence(&a[..3], &a), Some(3));
+        let later: Vec<Event> = a
+            .iter()
+            .map(|e| Event {
+                count: e.count + 100,
+                ..e.clone()
+            })
+            .collect();
+        assert_eq!(divergence(&a, &later), None);
+
+        let symbols = Symbols::new(
+            vec!["m.uma".to_string()],
+            vec![("start".to_string(), 0)].into_iter().collect(),
+            (0..5)
+                .map(|offset| (offset, (1, 0, offset as usize + 1)))
+                .collect(),
+        );
+        assert_eq!(
+            differences(&a[0], &b[0], &symbols),
+            vec![
+                "instruction: orth r1, 0x1 vs orth r1, 0x2",
+                "r1 after: 0x1 vs 0x2"
+            ]
+        );
+        let text = report(("a", "c"), &a, &c, &symbols, 2).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines[..4],
+            [
+                "first divergence at event 3 (instruction 3 in a, 3 in c)",
+                "  instruction: orth r3, 0x9 vs halt",
+                "  r3 after: 0x9 vs 0x0",
+                "a",
+            ]
+        );
+        assert_eq!(lines.len(), 4 + 3 + 1 + 3);
+        assert!(lines[4].starts_with("          1  1 start+1 (m.uma:2)"));
+        assert!(lines[6].starts_with(">         3  3 start+3 (m.uma:4)"));
+        assert!(lines[6].contains("orth r3, 0x9"));
+        assert_eq!(lines[7], "c");
+        assert!(lines[10].contains("halt"));
+
+        let short = report(("a", "a3"), &a, &a[..3], &symbols, 0).unwrap();
+        assert!(short.contains("  a3 ends after 3 events"), "{}", short);
+    }
+}
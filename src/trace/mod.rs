@@ -0,0 +1,641 @@
+// ---------- TRACING ---------------------------------------------------------
+//
+// A record of every instruction executed, written as JSON lines:
+//
+//     {"after":[0,5,...],"before":[0,0,...],"count":41,"finger":12,
+//      "text":"load r1, r2, r3","word":268435609,
+//      "access":{"kind":"load","array":3,"offset":0,"value":5}}
+//
+// or in a compact binary form: the header "UMTRACE" 1, then per instruction
+// little-endian
+//
+//     count u64, finger u32, word u32, before u32 x 8,
+//     mask u8 of the registers that changed, their new values u32 each,
+//     access u8 (0 none, 1 load, 2 store, 3 alloc, 4 free) and its
+//     fields u32 each: array, offset, value / array, size / array
+//
+// Filters keep only instructions in an offset range, with given operators
+// or within a window of instruction counts. With symbols, JSON lines also
+// carry the source location of the finger as "at".
+
+pub mod diff;
+
+use std::io::{self, Write};
+
+use crate::json::{self, Json};
+use crate::symbols::Symbols;
+use crate::{Data, Fault, Instruction, OpCode, CPU};
+
+const MAGIC: &[u8; 8] = b"UMTRACE\x01";
+
+/// What an instruction did to memory
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Load {
+        array: Data,
+        offset: Data,
+        value: Data,
+    },
+    Store {
+        array: Data,
+        offset: Data,
+        value: Data,
+    },
+    Alloc {
+        array: Data,
+        size: Data,
+    },
+    Free {
+        array: Data,
+    },
+}
+
+/// One executed instruction
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    /// Instructions executed before this one
+    pub count: u64,
+    pub finger: Data,
+    pub word: Data,
+    pub before: [Data; 8],
+    pub after: [Data; 8],
+    pub access: Option<Access>,
+}
+
+impl Event {
+    pub fn instruction(&self) -> Instruction {
+        Instruction::decode(self.word)
+    }
+
+    pub fn to_json(&self, symbols: &Symbols) -> Json {
+        let registers = |r: &[Data; 8]| Json::Array(r.iter().map(|&v| v.into()).collect());
+        let mut pairs = vec![
+            ("count", Json::Number(self.count as f64)),
+            ("finger", self.finger.into()),
+            ("word", self.word.into()),
+            ("text", self.instruction().to_string().into()),
+            ("before", registers(&self.before)),
+            ("after", registers(&self.after)),
+        ];
+        if let Some(at) = symbols.locate(self.finger) {
+            pairs.push(("at", at.into()));
+        }
+        if let Some(access) = self.access {
+            let fields: Vec<(&str, Json)> = match access {
+                Access::Load {
+                    array,
+                    offset,
+                    value,
+                } => vec![
+                    ("kind", "load".into()),
+                    ("array", array.into()),
+                    ("offset", offset.into()),
+                    ("value", value.into()),
+                ],
+                Access::Store {
+                    array,
+                    offset,
+                    value,
+                } => vec![
+                    ("kind", "store".into()),
+                    ("array", array.into()),
+                    ("offset", offset.into()),
+                    ("value", value.into()),
+                ],
+                Access::Alloc { array, size } => vec![
+                    ("kind", "alloc".into()),
+                    ("array", array.into()),
+                    ("size", size.into()),
+                ],
+                Access::Free { array } => vec![("kind", "free".into()), ("array", array.into())],
+            };
+            pairs.push(("access", Json::object(fields)));
+        }
+        Json::object(pairs)
+    }
+
+    pub fn from_json(json: &Json) -> Option<Event> {
+        let number = |key| json.get(key).and_then(Json::as_u64);
+        let registers = |key| -> Option<[Data; 8]> {
+            let values = json.get(key)?.as_array()?;
+            let mut registers = [0; 8];
+            if values.len() != 8 {
+                return None;
+            }
+            for (r, v) in registers.iter_mut().zip(values) {
+                *r = v.as_u64()? as Data;
+            }
+            Some(registers)
+        };
+        let access = match json.get("access") {
+            None => None,
+            Some(access) => {
+                let field = |key| access.get(key).and_then(Json::as_u64).map(|v| v as Data);
+                Some(match access.get("kind")?.as_str()? {
+                    "load" => Access::Load {
+                        array: field("array")?,
+                        offset: field("offset")?,
+                        value: field("value")?,
+                    },
+                    "store" => Access::Store {
+                        array: field("array")?,
+                        offset: field("offset")?,
+                        value: field("value")?,
+                    },
+                    "alloc" => Access::Alloc {
+                        array: field("array")?,
+                        size: field("size")?,
+                    },
+                    "free" => Access::Free {
+                        array: field("array")?,
+                    },
+                    _ => return None,
+                })
+            }
+        };
+        Some(Event {
+            count: number("count")?,
+            finger: number("finger")? as Data,
+            word: number("word")? as Data,
+            before: registers("before")?,
+            after: registers("after")?,
+            access,
+        })
+    }
+
+    pub fn write_binary(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.count.to_le_bytes());
+        for value in [self.finger, self.word].iter().chain(&self.before) {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        let changed: Vec<usize> = (0..8)
+            .filter(|&r| self.before[r] != self.after[r])
+            .collect();
+        out.push(changed.iter().fold(0, |mask, r| mask | 1 << r));
+        for r in changed {
+            out.extend_from_slice(&self.after[r].to_le_bytes());
+        }
+        let (tag, fields) = match self.access {
+            None => (0, vec![]),
+            Some(Access::Load {
+                array,
+                offset,
+                value,
+            }) => (1, vec![array, offset, value]),
+            Some(Access::Store {
+                array,
+                offset,
+                value,
+            }) => (2, vec![array, offset, value]),
+            Some(Access::Alloc { array, size }) => (3, vec![array, size]),
+            Some(Access::Free { array }) => (4, vec![array]),
+        };
+        out.push(tag);
+        for field in fields {
+            out.extend_from_slice(&field.to_le_bytes());
+        }
+    }
+
+    /// The event at the start of `bytes`, and the bytes after it
+    pub fn read_binary(mut bytes: &[u8]) -> Option<(Event, &[u8])> {
+        let rest = &mut bytes;
+        let count = u64::from(word(rest)?) | u64::from(word(rest)?) << 32;
+        let finger = word(rest)?;
+        let instruction = word(rest)?;
+        let mut before = [0; 8];
+        for r in before.iter_mut() {
+            *r = word(rest)?;
+        }
+        let mask = byte(rest)?;
+        let mut after = before;
+        for (r, value) in after.iter_mut().enumerate() {
+            if mask & 1 << r != 0 {
+                *value = word(rest)?;
+            }
+        }
+        let access = match byte(rest)? {
+            0 => None,
+            1 => Some(Access::Load {
+                array: word(rest)?,
+                offset: word(rest)?,
+                value: word(rest)?,
+            }),
+            2 => Some(Access::Store {
+                array: word(rest)?,
+                offset: word(rest)?,
+                value: word(rest)?,
+            }),
+            3 => Some(Access::Alloc {
+                array: word(rest)?,
+                size: word(rest)?,
+            }),
+            4 => Some(Access::Free { array: word(rest)? }),
+            _ => return None,
+        };
+        let event = Event {
+            count,
+            finger,
+            word: instruction,
+            before,
+            after,
+            access,
+        };
+        Some((event, bytes))
+    }
+}
+
+fn byte(bytes: &mut &[u8]) -> Option<u8> {
+    let (first, rest) = bytes.split_first()?;
+    *bytes = rest;
+    Some(*first)
+}
+
+fn word(bytes: &mut &[u8]) -> Option<Data> {
+    if bytes.len() < 4 {
+        return None;
+    }
+    let (word, rest) = bytes.split_at(4);
+    *bytes = rest;
+    Some(Data::from_le_bytes([word[0], word[1], word[2], word[3]]))
+}
+
+/// Execute one instruction, reporting what it did
+pub fn step(cpu: &mut CPU) -> Result<Event, Fault> {
+    let count = cpu.instruction_count;
+    let finger = cpu.instruction_pointer as Data;
+    let word = cpu
+        .instruction_platter
+        .get(finger as usize)
+        .copied()
+        .unwrap_or(0);
+    let before = cpu.register_file;
+    cpu.step()?;
+    let after = cpu.register_file;
+    let i = Instruction::decode(word);
+    let (a, b, c) = (i.r_a as usize, i.r_b as usize, i.r_c as usize);
+    let access = match i.op_code {
+        OpCode::LOAD => Some(Access::Load {
+            array: before[b],
+            offset: before[c],
+            value: after[a],
+        }),
+        OpCode::STORE => Some(Access::Store {
+            array: before[a],
+            offset: before[b],
+            value: before[c],
+        }),
+        OpCode::ALLOC => Some(Access::Alloc {
+            array: after[b],
+            size: before[c],
+        }),
+        OpCode::FREE => Some(Access::Free { array: before[c] }),
+        _ => None,
+    };
+    Ok(Event {
+        count,
+        finger,
+        word,
+        before,
+        after,
+        access,
+    })
+}
+
+/// Watches each instruction as it runs, and the machine once it stops
+pub trait Observer {
+    fn observe(&mut self, event: &Event);
+
+    /// Whether to see instruction `count`; when no observer does, it runs
+    /// without building an event
+    fn wants(&self, _count: u64) -> bool {
+        true
+    }
+
+    /// Called once, before the first instruction
+    fn start(&mut self, _cpu: &CPU) {}
+
+    /// Called once, after the machine halts or faults
+    fn finish(&mut self, _cpu: &CPU) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Execute one instruction, showing what it did to every observer
+pub fn step_observed(cpu: &mut CPU, observers: &mut [Box<dyn Observer>]) -> Result<(), Fault> {
+    let count = cpu.instruction_count;
+    if !observers.iter().any(|observer| observer.wants(count)) {
+        return cpu.step();
+    }
+    let event = step(cpu)?;
+    for observer in observers
+        .iter_mut()
+        .filter(|observer| observer.wants(count))
+    {
+        observer.observe(&event);
+    }
+    Ok(())
+}
+
+/// Which instructions to keep
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Filter {
+    /// Execution finger, from inclusive to exclusive
+    pub offsets: Option<(Data, Data)>,
+    pub operators: Option<Vec<OpCode>>,
+    /// Instruction counts, from inclusive to exclusive
+    pub window: Option<(u64, u64)>,
+}
+
+impl Filter {
+    pub fn in_window(&self, count: u64) -> bool {
+        self.window
+            .is_none_or(|(from, to)| from <= count && count < to)
+    }
+
+    pub fn matches(&self, event: &Event) -> bool {
+        let op = event.instruction().op_code;
+        self.in_window(event.count)
+            && self
+                .offsets
+                .is_none_or(|(from, to)| from <= event.finger && event.finger < to)
+            && self.operators.as_ref().is_none_or(|ops| ops.contains(&op))
+    }
+}
+
+/// `FROM..TO` with either end left out, each end parsed by `parse`
+pub fn range<T>(text: &str, max: T, parse: impl Fn(&str) -> Option<T>) -> Result<(T, T), String>
+where
+    T: Default,
+{
+    let (from, to) = text
+        .split_once("..")
+        .ok_or_else(|| format!("'{}' is not a range FROM..TO", text))?;
+    let end = |text: &str, default| match text {
+        "" => Ok(default),
+        text => parse(text).ok_or_else(|| format!("cannot read '{}'", text)),
+    };
+    Ok((end(from, T::default())?, end(to, max)?))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Binary,
+}
+
+/// Runs a CPU, writing each instruction the filter keeps
+pub struct Tracer {
+    output: Box<dyn Write>,
+    format: Format,
+    pub filter: Filter,
+    symbols: Symbols,
+    buffer: Vec<u8>,
+    /// The first failed write, after which nothing more is written
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn new(mut output: Box<dyn Write>, format: Format, symbols: Symbols) -> io::Result<Self> {
+        if format == Format::Binary {
+            output.write_all(MAGIC)?;
+        }
+        Ok(Tracer {
+            output,
+            format,
+            filter: Filter::default(),
+            symbols,
+            buffer: Vec::new(),
+            error: None,
+        })
+    }
+}
+
+impl Observer for Tracer {
+    fn observe(&mut self, event: &Event) {
+        if self.error.is_some() || !self.filter.matches(event) {
+            return;
+        }
+        let written = match self.format {
+            Format::Json => writeln!(self.output, "{}", event.to_json(&self.symbols)),
+            Format::Binary => {
+                self.buffer.clear();
+                event.write_binary(&mut self.buffer);
+                self.output.write_all(&self.buffer)
+            }
+        };
+        self.error = written.err();
+    }
+
+    fn wants(&self, count: u64) -> bool {
+        self.filter.in_window(count)
+    }
+
+    fn finish(&mut self, _cpu: &CPU) -> io::Result<()> {
+        match self.error.take() {
+            Some(error) => Err(io::Error::new(
+                error.kind(),
+                format!("cannot write the trace: {}", error),
+            )),
+            None => self.output.flush(),
+        }
+    }
+}
+
+/// The events of a trace in either format
+pub fn read(bytes: &[u8]) -> Result<Vec<Event>, String> {
+    let mut events = Vec::new();
+    if let Some(mut rest) = bytes.strip_prefix(&MAGIC[..]) {
+        while !rest.is_empty() {
+            let (event, tail) = Event::read_binary(rest)
+                .ok_or_else(|| format!("truncated record after {} events", events.len()))?;
+            events.push(event);
+            rest = tail;
+        }
+        return Ok(events);
+    }
+    let text = String::from_utf8_lossy(bytes);
+    for (n, line) in text
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty())
+    {
+        let event = json::parse(line)
+            .ok()
+            .as_ref()
+            .and_then(Event::from_json)
+            .ok_or_else(|| format!("line {} is not a trace event", n + 1))?;
+        events.push(event);
+    }
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BufferConsole, Data};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn program() -> Vec<Data> {
+        um_asm! {
+            orth r1, 3;
+            alloc r2, r1;
+            orth r3, 7;
+            store r2, r0, r3;
+            load r4, r2, r0;
+            free r2;
+            halt
+        }
+    }
+
+    fn events() -> Vec<Event> {
+        let console = BufferConsole {
+            input: Default::default(),
+            output: Rc::new(RefCell::new(Vec::new())),
+        };
+        let mut cpu = CPU::with_console(program(), Box::new(console));
+        let mut events = Vec::new();
+        while cpu.status {
+            events.push(step(&mut cpu).unwrap());
+        }
+        events
+    }
+
+    #[test]
+    fn accesses() {
+        let accesses: Vec<_> = events().iter().filter_map(|e| e.access).collect();
+        assert_eq!(
+            accesses,
+            vec![
+                Access::Alloc { array: 1, size: 3 },
+                Access::Store {
+                    array: 1,
+                    offset: 0,
+                    value: 7
+                },
+                Access::Load {
+                    array: 1,
+                    offset: 0,
+                    value: 7
+                },
+                Access::Free { array: 1 },
+            ]
+        );
+        let events = events();
+        assert_eq!((events[2].count, events[2].finger), (2, 2));
+        assert_eq!(events[2].before[3], 0);
+        assert_eq!(events[2].after[3], 7);
+    }
+
+    #[test]
+    fn formats_round_trip() {
+        let events = events();
+        let mut binary = MAGIC.to_vec();
+        let mut text = String::new();
+        for event in &events {
+            event.write_binary(&mut binary);
+            text.push_str(&event.to_json(&Symbols::default()).to_string());
+            text.push('\n');
+        }
+        assert_eq!(read(&binary).unwrap(), events);
+        assert_eq!(read(text.as_bytes()).unwrap(), events);
+        assert!(read(&binary[..binary.len() - 1]).is_err());
+        assert_eq!(
+            read(b"{\"count\":1}\n").unwrap_err(),
+            "line 1 is not a trace event"
+        );
+        let line = events[4].to_json(&Symbols::default()).to_string();
+        assert!(line.contains("\"text\":\"load r4, r2, r0\""), "{}", line);
+        assert!(
+            line.contains("\"access\":{\"array\":1,\"kind\":\"load\""),
+            "{}",
+            line
+        );
+    }
+
+    #[test]
+    fn filters() {
+        let events = events();
+        let kept = |filter: Filter| -> Vec<u64> {
+            let kept = events.iter().filter(|e| filter.matches(e));
+            kept.map(|e| e.count).collect()
+        };
+        assert_eq!(kept(Filter::default()).len(), 7);
+        let offsets = Filter {
+            offsets: Some((1, 3)),
+            ..Filter::default()
+        };
+        assert_eq!(kept(offsets), vec![1, 2]);
+        let operators = Filter {
+            operators: Some(vec![OpCode::LOAD, OpCode::STORE]),
+            ..Filter::default()
+        };
+        assert_eq!(kept(operators), vec![3, 4]);
+        let window = Filter {
+            window: Some((5, u64::MAX)),
+            ..Filter::default()
+        };
+        assert_eq!(kept(window), vec![5, 6]);
+
+        let number = |t: &str| t.parse().ok();
+        assert_eq!(range("5..", u64::MAX, number), Ok((5, u64::MAX)));
+        assert_eq!(range("..9", u64::MAX, number), Ok((0, 9)));
+        assert!(range("5", u64::MAX, number).is_err());
+        assert!(range("x..", u64::MAX, number).is_err());
+    }
+
+    #[test]
+    fn tracer_writes_the_kept_instructions() {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        struct Shared(Rc<RefCell<Vec<u8>>>);
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.borrow_mut().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let mut tracer = Tracer::new(
+            Box::new(Shared(output.clone())),
+            Format::Binary,
+            Symbols::default(),
+        )
+        .unwrap();
+        tracer.filter.window = Some((2, 4));
+        let mut observers: Vec<Box<dyn Observer>> = vec![Box::new(tracer)];
+        let mut cpu = CPU::new(program());
+        while cpu.status {
+            step_observed(&mut cpu, &mut observers).unwrap();
+        }
+        observers[0].finish(&cpu).unwrap();
+        let counts: Vec<u64> = read(&output.borrow())
+            .unwrap()
+            .iter()
+            .map(|e| e.count)
+            .collect();
+        assert_eq!(counts, vec![2, 3]);
+    }
+
+    #[test]
+    fn write_errors_wait_for_finish() {
+        struct Full;
+        impl Write for Full {
+            fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+                Err(io::Error::other("disk full"))
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let tracer = Tracer::new(Box::new(Full), Format::Json, Symbols::default()).unwrap();
+        let mut observers: Vec<Box<dyn Observer>> = vec![Box::new(tracer)];
+        let mut cpu = CPU::new(program());
+        while cpu.status {
+            step_observed(&mut cpu, &mut observers).unwrap();
+        }
+        let error = observers[0].finish(&cpu).unwrap_err();
+        assert_eq!(error.to_string(), "cannot write the trace: disk full");
+    }
+}
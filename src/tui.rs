@@ -0,0 +1,523 @@
+// ---------- TERMINAL UI -----------------------------------------------------
+//
+// Full screen view of a running machine drawn with ANSI escapes. The
+// terminal is put into non-canonical mode with `stty`, so this only works on
+// Unix-like systems. When the next instruction is IN and no input is queued,
+// the UI collects a line from the keyboard before executing it.
+//
+//  space  run / pause      s  step      n  step 1000      q  quit
+//  [ ]    select array     j k  scroll array view
+
+use crate::{disasm, upper_byte, Console, Data, OpCode, CPU};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+// Instructions executed between redraws while running
+const BATCH: u64 = 50_000;
+const FRAME: Duration = Duration::from_millis(33);
+// How often to ask the terminal for its size
+const RESIZE: Duration = Duration::from_millis(500);
+// The smallest screen the panes fit on
+const MIN_WIDTH: usize = 48;
+const MIN_HEIGHT: usize = 22;
+
+struct Shared {
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+    end_of_input: bool,
+}
+
+struct TuiConsole(Rc<RefCell<Shared>>);
+
+impl Console for TuiConsole {
+    fn input(&mut self) -> Data {
+        self.0
+            .borrow_mut()
+            .input
+            .pop_front()
+            .map_or(Data::MAX, |b| b as Data)
+    }
+
+    fn output(&mut self, c: u8) {
+        self.0.borrow_mut().output.push(c);
+    }
+}
+
+#[derive(PartialEq)]
+enum Mode {
+    Paused,
+    Running,
+    // Collecting a line for IN, then resuming in the given mode
+    Input(bool),
+    Stopped(String),
+}
+
+pub struct Tui {
+    cpu: CPU,
+    shared: Rc<RefCell<Shared>>,
+    mode: Mode,
+    line: String,
+    selected: Data,
+    scroll: usize,
+}
+
+fn pad(text: &str, width: usize) -> String {
+    let mut text: String = text.chars().take(width).collect();
+    while text.chars().count() < width {
+        text.push(' ');
+    }
+    text
+}
+
+fn boxed(title: &str, lines: &[String], width: usize, height: usize) -> Vec<String> {
+    let inner = width.saturating_sub(2);
+    let mut top: String = format!("- {} ", title).chars().take(inner).collect();
+    while top.chars().count() < inner {
+        top.push('-');
+    }
+    let mut out = vec![format!("+{}+", top)];
+    for i in 0..height.saturating_sub(2) {
+        let line = lines.get(i).map(String::as_str).unwrap_or("");
+        out.push(format!("|{}|", pad(line, inner)));
+    }
+    out.push(format!("+{}+", "-".repeat(inner)));
+    out
+}
+
+fn printable(byte: u8) -> char {
+    if (0x20..0x7F).contains(&byte) {
+        byte as char
+    } else {
+        '.'
+    }
+}
+
+impl Tui {
+    pub fn new(program: Vec<Data>) -> Self {
+        let shared = Rc::new(RefCell::new(Shared {
+            input: VecDeque::new(),
+            output: Vec::new(),
+            end_of_input: false,
+        }));
+        let cpu = CPU::with_console(program, Box::new(TuiConsole(shared.clone())));
+        Tui {
+            cpu,
+            shared,
+            mode: Mode::Paused,
+            line: String::new(),
+            selected: 0,
+            scroll: 0,
+        }
+    }
+
+    fn registers(&self) -> Vec<String> {
+        let cpu = &self.cpu;
+        let mut lines: Vec<String> = cpu
+            .register_file
+            .iter()
+            .enumerate()
+            .map(|(i, r)| format!("r{} {:08x} {:>10}", i, r, r))
+            .collect();
+        lines.push(format!("pc {:>8}", cpu.instruction_pointer));
+        lines.push(format!("icount {}", cpu.instruction_count));
+        lines
+    }
+
+    fn disassembly(&self, rows: usize) -> Vec<String> {
+        let cpu = &self.cpu;
+        let pc = cpu.instruction_pointer as usize;
+        let start = pc.saturating_sub(rows / 3);
+        (start..start + rows)
+            .filter_map(|offset| {
+                let word = cpu.instruction_platter.get(offset)?;
+                let marker = if offset == pc { '>' } else { ' ' };
+                Some(format!("{}{}", marker, disasm::line(offset, *word)))
+            })
+            .collect()
+    }
+
+    fn arrays(&self) -> Vec<String> {
+        let cpu = &self.cpu;
+        std::iter::once(0)
+            .chain(cpu.memory.keys().copied())
+            .map(|id| {
+                let marker = if id == self.selected { '>' } else { ' ' };
+                format!("{}{:>10} {:>10}", marker, id, cpu.array(id).unwrap().len())
+            })
+            .collect()
+    }
+
+    // As many words per row as fit in `columns`
+    fn hex_view(&self, rows: usize, columns: usize) -> Vec<String> {
+        let platter = match self.cpu.array(self.selected) {
+            Some(p) => p,
+            None => return vec!["(inactive)".to_string()],
+        };
+        let per_row = (columns.saturating_sub(12) / 13).max(1);
+        (self.scroll..self.scroll + rows)
+            .map(|row| row * per_row)
+            .filter(|offset| *offset < platter.len())
+            .map(|offset| {
+                let words = &platter[offset..(offset + per_row).min(platter.len())];
+                let hex: Vec<String> = words.iter().map(|w| format!("{:08x}", w)).collect();
+                let ascii: String = words
+                    .iter()
+                    .flat_map(|w| w.to_be_bytes().to_vec())
+                    .map(printable)
+                    .collect();
+                let width = per_row * 9;
+                format!(
+                    "{:>8}  {:<width$}  {}",
+                    offset,
+                    hex.join(" "),
+                    ascii,
+                    width = width
+                )
+            })
+            .collect()
+    }
+
+    fn console(&self, rows: usize) -> Vec<String> {
+        let shared = self.shared.borrow();
+        let text = String::from_utf8_lossy(&shared.output);
+        let mut lines: Vec<String> = text.split('\n').map(|l| l.to_string()).collect();
+        if let Mode::Input(_) = self.mode {
+            lines.push(format!("input> {}_", self.line));
+        }
+        let skip = lines.len().saturating_sub(rows);
+        lines.split_off(skip)
+    }
+
+    fn status(&self) -> String {
+        let state = match &self.mode {
+            Mode::Paused => "paused".to_string(),
+            Mode::Running => "running".to_string(),
+            Mode::Input(_) => "waiting for input, enter to send".to_string(),
+            Mode::Stopped(why) => why.clone(),
+        };
+        format!(
+            "[{}]  space run/pause  s step  n step 1000  [ ] array  j k scroll  q quit",
+            state
+        )
+    }
+
+    /// Lay out every pane into `height` lines of `width` columns
+    pub fn render(&self, width: usize, height: usize) -> Vec<String> {
+        if width < MIN_WIDTH || height < MIN_HEIGHT {
+            let message = format!("terminal too small, need {}x{}", MIN_WIDTH, MIN_HEIGHT);
+            return vec![pad(&message, width)];
+        }
+        let left = (width / 2).max(24);
+        let right = width.saturating_sub(left);
+        let top = 12;
+        let console_rows = (height / 4).max(4);
+        let middle = height.saturating_sub(top + console_rows + 1).max(4);
+
+        let registers = boxed("registers", &self.registers(), left, top);
+        let arrays = boxed("arrays (id, size)", &self.arrays(), right, top);
+        let code = boxed("program", &self.disassembly(middle - 2), left, middle);
+        let title = format!("array {}", self.selected);
+        let hex_rows = self.hex_view(middle - 2, right - 2);
+        let hex = boxed(&title, &hex_rows, right, middle);
+        let console = boxed(
+            "console",
+            &self.console(console_rows - 2),
+            width,
+            console_rows,
+        );
+
+        let mut lines = Vec::new();
+        for (l, r) in registers.iter().zip(arrays.iter()) {
+            lines.push(format!("{}{}", l, r));
+        }
+        for (l, r) in code.iter().zip(hex.iter()) {
+            lines.push(format!("{}{}", l, r));
+        }
+        lines.extend(console);
+        lines.push(pad(&self.status(), width));
+        lines
+    }
+
+    fn needs_input(&self) -> bool {
+        let cpu = &self.cpu;
+        let next = cpu
+            .instruction_platter
+            .get(cpu.instruction_pointer as usize);
+        let shared = self.shared.borrow();
+        next.is_some_and(|w| upper_byte(*w) == OpCode::IN as u8)
+            && shared.input.is_empty()
+            && !shared.end_of_input
+    }
+
+    /// Execute up to `count` instructions, stopping early for input or faults
+    fn advance(&mut self, count: u64) {
+        for _ in 0..count {
+            if !self.cpu.status {
+                self.mode = Mode::Stopped("halted".to_string());
+                return;
+            }
+            if self.needs_input() {
+                self.mode = Mode::Input(self.mode == Mode::Running);
+                return;
+            }
+            if let Err(fault) = self.cpu.step() {
+                self.mode = Mode::Stopped(format!("fault: {}", fault));
+                return;
+            }
+        }
+    }
+
+    fn select(&mut self, forward: bool) {
+        let cpu = &self.cpu;
+        let ids: Vec<Data> = std::iter::once(0)
+            .chain(cpu.memory.keys().copied())
+            .collect();
+        let position = ids.iter().position(|id| *id == self.selected).unwrap_or(0);
+        let next = if forward {
+            (position + 1).min(ids.len() - 1)
+        } else {
+            position.saturating_sub(1)
+        };
+        self.selected = ids[next];
+        self.scroll = 0;
+    }
+
+    /// React to one key; returns false to quit
+    pub fn key(&mut self, key: u8) -> bool {
+        if let Mode::Input(resume) = self.mode {
+            match key {
+                b'\r' | b'\n' => {
+                    let mut shared = self.shared.borrow_mut();
+                    shared.input.extend(self.line.bytes());
+                    shared.input.push_back(b'\n');
+                    self.line.clear();
+                    self.mode = if resume { Mode::Running } else { Mode::Paused };
+                }
+                0x7F | 0x08 => {
+                    self.line.pop();
+                }
+                0x04 => {
+                    // ^D ends input: IN then reads all ones
+                    let mut shared = self.shared.borrow_mut();
+                    shared.input.extend(self.line.bytes());
+                    shared.end_of_input = true;
+                    self.line.clear();
+                    self.mode = if resume { Mode::Running } else { Mode::Paused };
+                }
+                c => self.line.push(c as char),
+            }
+            return true;
+        }
+        match key {
+            b'q' => return false,
+            b' ' => {
+                self.mode = match self.mode {
+                    Mode::Running => Mode::Paused,
+                    Mode::Paused => Mode::Running,
+                    _ => return true,
+                }
+            }
+            b's' | b'n' if self.mode == Mode::Paused => {
+                self.advance(if key == b's' { 1 } else { 1000 });
+            }
+            b'[' => self.select(false),
+            b']' => self.select(true),
+            b'j' => self.scroll += 1,
+            b'k' => self.scroll = self.scroll.saturating_sub(1),
+            _ => {}
+        }
+        true
+    }
+
+    fn draw(&self, out: &mut dyn Write, width: usize, height: usize) -> io::Result<()> {
+        let mut frame = String::from("\x1b[H");
+        for (i, line) in self.render(width, height).iter().enumerate() {
+            frame.push_str(&format!("\x1b[{};1H{}", i + 1, line));
+        }
+        out.write_all(frame.as_bytes())?;
+        out.flush()
+    }
+
+    /// Take over the terminal until the user quits
+    pub fn run(&mut self) -> io::Result<()> {
+        let _terminal = RawTerminal::enter()?;
+        self.event_loop()
+    }
+
+    fn event_loop(&mut self) -> io::Result<()> {
+        let mut stdout = io::stdout();
+        let mut stdin = io::stdin();
+        write!(stdout, "\x1b[?25l\x1b[2J")?;
+        let mut last_draw = Instant::now() - FRAME;
+        let (mut height, mut width) = terminal_size();
+        let mut last_resize = Instant::now();
+        loop {
+            if last_resize.elapsed() >= RESIZE {
+                let size = terminal_size();
+                if size != (height, width) {
+                    (height, width) = size;
+                    write!(stdout, "\x1b[2J")?;
+                }
+                last_resize = Instant::now();
+            }
+            if self.mode == Mode::Running {
+                self.advance(BATCH);
+            }
+            if last_draw.elapsed() >= FRAME {
+                self.draw(&mut stdout, width, height)?;
+                last_draw = Instant::now();
+            }
+            let mut keys = [0u8; 16];
+            let n = stdin.read(&mut keys)?;
+            for key in keys.iter().take(n) {
+                if !self.key(*key) {
+                    return Ok(());
+                }
+            }
+            if n > 0 {
+                self.draw(&mut stdout, width, height)?;
+            } else if self.mode != Mode::Running {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        }
+    }
+}
+
+// Non-canonical mode for as long as it lives, restored even on a panic
+struct RawTerminal {
+    saved: String,
+}
+
+impl RawTerminal {
+    fn enter() -> io::Result<RawTerminal> {
+        let saved = stty(&["-g"])?.trim().to_string();
+        stty(&["-icanon", "-echo", "min", "0", "time", "0"])?;
+        Ok(RawTerminal { saved })
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        let _ = stty(&[&self.saved]);
+        print!("\x1b[?25h\x1b[2J\x1b[H");
+        let _ = io::stdout().flush();
+    }
+}
+
+fn stty(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other("stty failed, is stdin a terminal?"));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// Rows and columns, falling back to 24x80
+fn terminal_size() -> (usize, usize) {
+    let size = stty(&["size"]).unwrap_or_default();
+    let mut parts = size.split_whitespace().map(|n| n.parse().ok());
+    match (parts.next().flatten(), parts.next().flatten()) {
+        (Some(rows), Some(columns)) => (rows, columns),
+        _ => (24, 80),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Tui {
+        Tui::new(vec![
+            0b10000000000000000000000000111000, // r7 <- ALLOC (r0)
+            0b11010000000000000000000001001000, // r0 <- 'H'
+            0b10100000000000000000000000000000, // OUT r0
+            0b10110000000000000000000000000001, // r1 <- IN
+            0b10100000000000000000000000000001, // OUT r1
+            0x70000000,
+        ])
+    }
+
+    fn screen(tui: &Tui) -> String {
+        tui.render(140, 40).join("\n")
+    }
+
+    #[test]
+    fn panes_follow_the_machine() {
+        let mut tui = sample();
+        tui.cpu.register_file[0] = 3;
+        assert!(tui.key(b's'));
+        let text = screen(&tui);
+        assert!(text.contains(">       1  d0000048  orth r0, 0x48"));
+        assert!(text.contains("r7 00000001"));
+        assert!(text.contains(">         0"));
+        assert!(text.contains("          1          3"));
+
+        tui.key(b']');
+        assert!(screen(&tui).contains("array 1"));
+        assert!(screen(&tui).contains("       0  00000000 00000000 00000000"));
+    }
+
+    #[test]
+    fn input_is_collected_before_in() {
+        let mut tui = sample();
+        tui.key(b' ');
+        tui.advance(BATCH);
+        assert!(tui.mode == Mode::Input(true));
+        assert!(screen(&tui).contains("input> _"));
+        for key in b"ok\n" {
+            tui.key(*key);
+        }
+        assert!(tui.mode == Mode::Running);
+        tui.advance(BATCH);
+        assert!(tui.mode == Mode::Stopped("halted".to_string()));
+        assert_eq!(tui.shared.borrow().output, b"Ho".to_vec());
+        assert!(screen(&tui).contains("|Ho"));
+        assert!(!tui.key(b'q'));
+    }
+
+    #[test]
+    fn end_of_input_reads_all_ones() {
+        let mut tui = sample();
+        tui.advance(3);
+        tui.advance(1);
+        assert!(tui.mode == Mode::Input(false));
+        tui.key(0x04);
+        assert!(tui.mode == Mode::Paused);
+        tui.advance(1);
+        assert_eq!(tui.cpu.register_file[1], Data::MAX);
+    }
+
+    #[test]
+    fn small_terminals_get_a_message() {
+        let tui = sample();
+        for width in 0..MIN_WIDTH + 4 {
+            for height in 0..MIN_HEIGHT + 4 {
+                let lines = tui.render(width, height);
+                assert!(lines.len() <= height.max(1), "{}x{}", width, height);
+                for line in lines {
+                    assert!(line.chars().count() <= width, "{}x{}", width, height);
+                }
+            }
+        }
+        let small = tui.render(40, 30);
+        assert_eq!(small.len(), 1);
+        assert_eq!(small[0].trim_end(), "terminal too small, need 48x22");
+        assert_eq!(tui.render(MIN_WIDTH, MIN_HEIGHT).len(), MIN_HEIGHT);
+    }
+
+    #[test]
+    fn hex_view_shows_ascii() {
+        let mut tui = Tui::new(vec![0x48656c6c, 0x6f000000, 0x70000000]);
+        tui.scroll = 0;
+        let text = screen(&tui);
+        assert!(text.contains("       0  48656c6c 6f000000 70000000"));
+        assert!(text.contains("Hello...p..."));
+    }
+}